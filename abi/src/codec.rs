@@ -0,0 +1,310 @@
+//! Audio codec of [`Message`](crate::pb::Message) audio data.
+//!
+//! Audio is mono at [`SAMPLE_RATE`], cut into frames of [`FRAME_DURATION`] and encoded in Opus,
+//! see [`Codec::Opus`]. Every frame is sent in one message with a sequence number,
+//! so that the receiver can conceal lost frames and drop late ones.
+//!
+//! Old clients send raw f32 samples without codec, which reads as [`Codec::PcmF32`].
+
+use crate::pb::Codec;
+use audiopus::coder::{Decoder as OpusDecoder, Encoder as OpusEncoder, GenericCtl};
+use audiopus::{Application, Bitrate, Channels, SampleRate};
+use std::time::Duration;
+
+/// Sample rate of audio, one channel, f32.
+pub const SAMPLE_RATE: u32 = 48000;
+/// Duration of an encoded frame.
+pub const FRAME_DURATION: Duration = Duration::from_millis(20);
+/// Samples of an encoded frame.
+pub const FRAME_SIZE: usize = SAMPLE_RATE as usize * FRAME_DURATION.as_millis() as usize / 1000;
+/// Bitrate of encoded audio, enough for clear voice.
+pub const BITRATE: i32 = 32000;
+
+// recommended by libopus for a packet buffer
+const MAX_PACKET_SIZE: usize = 4000;
+// a longer gap is a pause of the speaker, not worth concealing, the audio just resumes
+const MAX_CONCEALED_FRAMES: u32 = 5;
+
+/// An encoded frame.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub seq: u32,
+    pub data: Vec<u8>,
+}
+
+/// Encoder cuts samples into frames and encodes every complete one in Opus.
+///
+/// Samples of an incomplete frame are kept until more come.
+#[derive(Debug)]
+pub struct Encoder {
+    inner: OpusEncoder,
+    pending: Vec<f32>,
+    seq: u32,
+}
+
+impl Encoder {
+    pub fn new() -> crate::Result<Self> {
+        let mut inner = OpusEncoder::new(SampleRate::Hz48000, Channels::Mono, Application::Voip)?;
+        inner.set_bitrate(Bitrate::BitsPerSecond(BITRATE))?;
+        Ok(Self {
+            inner,
+            pending: Vec::with_capacity(FRAME_SIZE),
+            seq: 0,
+        })
+    }
+
+    /// Encode all complete frames with `samples` appended.
+    pub fn encode(&mut self, samples: &[f32]) -> crate::Result<Vec<Frame>> {
+        self.pending.extend_from_slice(samples);
+        let mut frames = Vec::with_capacity(self.pending.len() / FRAME_SIZE);
+        let mut packet = [0u8; MAX_PACKET_SIZE];
+        for chunk in self.pending.chunks_exact(FRAME_SIZE) {
+            let len = self.inner.encode_float(chunk, &mut packet)?;
+            frames.push(Frame {
+                seq: self.seq,
+                data: packet[..len].to_vec(),
+            });
+            self.seq = self.seq.wrapping_add(1);
+        }
+        let encoded = frames.len() * FRAME_SIZE;
+        self.pending.drain(..encoded);
+        Ok(frames)
+    }
+}
+
+/// Decoder decodes frames of one speaker in order of their sequence numbers.
+#[derive(Debug)]
+pub struct Decoder {
+    inner: OpusDecoder,
+    next_seq: Option<u32>,
+}
+
+impl Decoder {
+    pub fn new() -> crate::Result<Self> {
+        Ok(Self {
+            inner: OpusDecoder::new(SampleRate::Hz48000, Channels::Mono)?,
+            next_seq: None,
+        })
+    }
+
+    /// Decode a frame into samples.
+    ///
+    /// A few frames lost before it are concealed. A longer gap is a pause, as the speaker
+    /// sends nothing while not talking: the decoder starts afresh, with nothing concealed.
+    /// A frame older than the last decoded one is late, and it's dropped with nothing returned.
+    pub fn decode(&mut self, seq: u32, data: &[u8]) -> crate::Result<Vec<f32>> {
+        let lost = match self.next_seq {
+            Some(next) if (seq.wrapping_sub(next) as i32) < 0 => return Ok(vec![]),
+            Some(next) if seq.wrapping_sub(next) > MAX_CONCEALED_FRAMES => {
+                self.inner.reset_state()?;
+                0
+            }
+            Some(next) => seq.wrapping_sub(next),
+            None => 0,
+        };
+
+        let mut samples = Vec::with_capacity((lost as usize + 1) * FRAME_SIZE);
+        for _ in 0..lost {
+            samples.extend(self.decode_opus(None)?);
+        }
+        samples.extend(self.decode_opus(Some(data))?);
+        self.next_seq = Some(seq.wrapping_add(1));
+        Ok(samples)
+    }
+
+    /// Decode the frame next to the last one, when frames are already ordered by the caller.
+    pub fn decode_frame(&mut self, data: &[u8]) -> crate::Result<Vec<f32>> {
+        self.decode_opus(Some(data))
+    }
+
+    // decode a frame, or conceal a lost one
+    fn decode_opus(&mut self, data: Option<&[u8]>) -> crate::Result<Vec<f32>> {
+        let mut samples = vec![0.0; FRAME_SIZE];
+        let packet = data.map(|data| data.try_into()).transpose()?;
+        let len = self
+            .inner
+            .decode_float(packet, (&mut samples).try_into()?, false)?;
+        samples.truncate(len);
+        Ok(samples)
+    }
+}
+
+/// Decode raw little-endian f32 samples of [`Codec::PcmF32`], extra bytes are discarded.
+pub fn decode_pcm(bytes: &[u8]) -> Vec<f32> {
+    bytes
+        .chunks_exact(4)
+        .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .collect()
+}
+
+impl Codec {
+    /// Whether audio in this codec comes from an old client sending raw samples.
+    pub fn is_legacy(&self) -> bool {
+        *self == Codec::PcmF32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::PI;
+
+    fn sine(freq: f32, amplitude: f32, len: usize) -> Vec<f32> {
+        (0..len)
+            .map(|i| amplitude * (2.0 * PI * freq * i as f32 / SAMPLE_RATE as f32).sin())
+            .collect()
+    }
+
+    // a sweep from 200Hz to 3kHz, covering most of voice band
+    fn chirp(amplitude: f32, len: usize) -> Vec<f32> {
+        let (f0, f1) = (200.0, 3000.0);
+        let duration = len as f32 / SAMPLE_RATE as f32;
+        (0..len)
+            .map(|i| {
+                let t = i as f32 / SAMPLE_RATE as f32;
+                let phase = 2.0 * PI * (f0 * t + (f1 - f0) * t * t / (2.0 * duration));
+                amplitude * phase.sin()
+            })
+            .collect()
+    }
+
+    fn round_trip(samples: &[f32]) -> Vec<f32> {
+        let mut encoder = Encoder::new().unwrap();
+        let mut decoder = Decoder::new().unwrap();
+        let mut decoded = vec![];
+        // samples come in pieces not aligned to frames
+        for chunk in samples.chunks(700) {
+            for frame in encoder.encode(chunk).unwrap() {
+                decoded.extend(decoder.decode(frame.seq, &frame.data).unwrap());
+            }
+        }
+        decoded
+    }
+
+    // SNR in dB of `decoded` against `reference`, aligned by the best delay of the codec.
+    // The first frames are skipped, while the codec is warming up.
+    fn snr(reference: &[f32], decoded: &[f32]) -> f64 {
+        let skip = 5 * FRAME_SIZE;
+        let len = decoded.len() - skip - FRAME_SIZE;
+        (0..FRAME_SIZE)
+            .map(|delay| {
+                let (mut signal, mut noise) = (0.0, 0.0);
+                for i in skip..skip + len {
+                    let r = reference[i - delay] as f64;
+                    signal += r * r;
+                    noise += (decoded[i] as f64 - r).powi(2);
+                }
+                10.0 * (signal / noise).log10()
+            })
+            .fold(f64::MIN, f64::max)
+    }
+
+    #[test]
+    fn test_frame_size() {
+        assert_eq!(FRAME_SIZE, 960);
+    }
+
+    #[test]
+    fn test_encode_complete_frames() {
+        let mut encoder = Encoder::new().unwrap();
+        assert!(encoder
+            .encode(&sine(440.0, 0.5, FRAME_SIZE - 1))
+            .unwrap()
+            .is_empty());
+
+        let frames = encoder.encode(&sine(440.0, 0.5, FRAME_SIZE + 2)).unwrap();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].seq, 0);
+        assert_eq!(frames[1].seq, 1);
+        assert!(frames.iter().all(|f| !f.data.is_empty()));
+
+        // one sample is pending
+        let frames = encoder.encode(&sine(440.0, 0.5, FRAME_SIZE - 1)).unwrap();
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].seq, 2);
+    }
+
+    #[test]
+    fn test_round_trip_sine() {
+        let samples = sine(440.0, 0.5, 50 * FRAME_SIZE);
+        let decoded = round_trip(&samples);
+        assert_eq!(decoded.len(), samples.len());
+        let snr = snr(&samples, &decoded);
+        assert!(snr > 20.0, "snr: {:.1}dB", snr);
+    }
+
+    #[test]
+    fn test_round_trip_chirp() {
+        let samples = chirp(0.5, 50 * FRAME_SIZE);
+        let decoded = round_trip(&samples);
+        assert_eq!(decoded.len(), samples.len());
+        let snr = snr(&samples, &decoded);
+        assert!(snr > 10.0, "snr: {:.1}dB", snr);
+    }
+
+    #[test]
+    fn test_round_trip_silence() {
+        let decoded = round_trip(&[0.0; 10 * FRAME_SIZE]);
+        assert!(decoded.iter().all(|v| v.abs() < 1e-3));
+    }
+
+    #[test]
+    fn test_decode_conceals_lost_frames() {
+        let mut encoder = Encoder::new().unwrap();
+        let mut decoder = Decoder::new().unwrap();
+        let frames = encoder.encode(&sine(440.0, 0.5, 20 * FRAME_SIZE)).unwrap();
+
+        assert_eq!(
+            decoder.decode(0, &frames[0].data).unwrap().len(),
+            FRAME_SIZE
+        );
+        // 1 and 2 are lost
+        let samples = decoder.decode(3, &frames[3].data).unwrap();
+        assert_eq!(samples.len(), 3 * FRAME_SIZE);
+        // concealment continues the sine, not silence
+        assert!(samples[..FRAME_SIZE].iter().any(|v| v.abs() > 0.1));
+
+        // up to a few frames are concealed
+        let seq = 4 + MAX_CONCEALED_FRAMES;
+        let samples = decoder.decode(seq, &frames[seq as usize].data).unwrap();
+        assert_eq!(
+            samples.len(),
+            (MAX_CONCEALED_FRAMES as usize + 1) * FRAME_SIZE
+        );
+        // a longer gap is a pause, audio resumes without concealment
+        let samples = decoder.decode(19, &frames[19].data).unwrap();
+        assert_eq!(samples.len(), FRAME_SIZE);
+    }
+
+    #[test]
+    fn test_decode_drops_late_frames() {
+        let mut encoder = Encoder::new().unwrap();
+        let mut decoder = Decoder::new().unwrap();
+        let frames = encoder.encode(&sine(440.0, 0.5, 3 * FRAME_SIZE)).unwrap();
+
+        decoder.decode(0, &frames[0].data).unwrap();
+        decoder.decode(2, &frames[2].data).unwrap();
+        assert!(decoder.decode(1, &frames[1].data).unwrap().is_empty());
+        assert!(decoder.decode(2, &frames[2].data).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_seq_wraps() {
+        let mut encoder = Encoder::new().unwrap();
+        let mut decoder = Decoder::new().unwrap();
+        let frames = encoder.encode(&sine(440.0, 0.5, 2 * FRAME_SIZE)).unwrap();
+
+        decoder.decode(u32::MAX, &frames[0].data).unwrap();
+        assert_eq!(
+            decoder.decode(0, &frames[1].data).unwrap().len(),
+            FRAME_SIZE
+        );
+    }
+
+    #[test]
+    fn test_decode_pcm() {
+        assert_eq!(decode_pcm(&[0, 0, 128, 63, 1]), vec![1.0]);
+        assert!(Codec::default().is_legacy());
+        assert!(!Codec::Opus.is_legacy());
+    }
+}
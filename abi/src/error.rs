@@ -9,9 +9,11 @@ pub enum Error {
     #[error("Connect error")]
     Connect(tonic::transport::Error),
     #[error("Rpc error: `{0}`")]
-    Rpc(Status),
+    Rpc(Box<Status>),
     #[error("Token not found")]
     TokenNotFound,
+    #[error("Unauthenticated: `{0}`")]
+    Unauthenticated(&'static str),
 
     // business logic error
     #[error("Invalid password")]
@@ -41,6 +43,17 @@ pub enum Error {
     #[error("Channel Broadcast Stopped")]
     ChannelBroadcastStopped,
 
+    // Audio Codec Error
+    #[cfg(feature = "codec")]
+    #[error("Codec error: `{0}`")]
+    Codec(audiopus::Error),
+
+    // Audio Backend Error
+    #[error("Audio device error: `{0}`")]
+    AudioDevice(String),
+    #[error("Audio file error: `{0}`")]
+    AudioFile(String),
+
     // intercept by limiter
     #[error("Intercepted By Limiter")]
     Limit,
@@ -58,9 +71,16 @@ impl From<tonic::transport::Error> for Error {
     }
 }
 
+#[cfg(feature = "codec")]
+impl From<audiopus::Error> for Error {
+    fn from(e: audiopus::Error) -> Self {
+        Error::Codec(e)
+    }
+}
+
 impl From<tonic::Status> for Error {
     fn from(e: tonic::Status) -> Self {
-        Error::Rpc(e)
+        Error::Rpc(Box::new(e))
     }
 }
 impl From<Error> for Status {
@@ -74,6 +94,12 @@ impl From<Error> for Status {
             Error::PermissionDenied(s) => {
                 Status::permission_denied(format!("Permission denied: `{}`", s))
             }
+            Error::InvalidRequest(s) => {
+                Status::invalid_argument(format!("Invalid Request: `{}`", s))
+            }
+            Error::Unauthenticated(s) => {
+                Status::unauthenticated(format!("Unauthenticated: `{}`", s))
+            }
             Error::ChannelBroadcastStopped => Status::aborted("Channel Broadcast Stopped"),
             _ => Status::internal(e.to_string()),
         }
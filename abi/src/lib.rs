@@ -1,3 +1,5 @@
+#[cfg(feature = "codec")]
+pub mod codec;
 pub mod pb;
 pub mod traits;
 
@@ -5,6 +5,36 @@ pub struct User {
     pub id: ::prost::alloc::string::String,
     #[prost(string, tag = "2")]
     pub name: ::prost::alloc::string::String,
+    /// on the channel, reported by chat servers
+    #[prost(message, optional, tag = "3")]
+    pub voice: ::core::option::Option<VoiceState>,
+}
+/// Voice state of a user on a channel, enforced by the chat server
+#[derive(Clone, Copy, PartialEq, ::prost::Message)]
+pub struct VoiceState {
+    /// by the user, its audio isn't forwarded
+    #[prost(bool, tag = "1")]
+    pub self_mute: bool,
+    /// by the user, no audio is delivered to it
+    #[prost(bool, tag = "2")]
+    pub self_deaf: bool,
+    /// by moderators, the user can't unmute it
+    #[prost(bool, tag = "3")]
+    pub server_mute: bool,
+}
+/// Mute a user on the channel or not, by moderators
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ServerMute {
+    #[prost(string, tag = "1")]
+    pub user_id: ::prost::alloc::string::String,
+    #[prost(bool, tag = "2")]
+    pub mute: bool,
+}
+/// Users on a channel, the joining one included
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Roster {
+    #[prost(message, repeated, tag = "1")]
+    pub users: ::prost::alloc::vec::Vec<User>,
 }
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Channel {
@@ -17,6 +47,19 @@ pub struct Channel {
     /// limit the num of users
     #[prost(int32, tag = "4")]
     pub limit: i32,
+    #[prost(enumeration = "ChannelMode", tag = "5")]
+    pub mode: i32,
+    /// messages buffered for each listener, 0 for chat server's default
+    #[prost(int32, tag = "6")]
+    pub buffer_size: i32,
+}
+/// A user of a channel to moderate
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct MemberRequest {
+    #[prost(int32, tag = "1")]
+    pub channel_id: i32,
+    #[prost(string, tag = "2")]
+    pub user_id: ::prost::alloc::string::String,
 }
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct ShutdownRequest {
@@ -30,6 +73,9 @@ pub struct ShutdownRequest {
 pub struct ListResponse {
     #[prost(message, repeated, tag = "1")]
     pub channels: ::prost::alloc::vec::Vec<Channel>,
+    /// chat servers with their latest metric, channels = \[\]
+    #[prost(message, repeated, tag = "2")]
+    pub servers: ::prost::alloc::vec::Vec<ChannelServer>,
 }
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct ChannelServer {
@@ -41,6 +87,9 @@ pub struct ChannelServer {
     pub name: ::prost::alloc::string::String,
     #[prost(message, repeated, tag = "4")]
     pub channels: ::prost::alloc::vec::Vec<Channel>,
+    /// latest heartbeat
+    #[prost(message, optional, tag = "5")]
+    pub metric: ::core::option::Option<Metric>,
 }
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct ListenResponse {
@@ -51,6 +100,26 @@ pub struct ListenResponse {
     #[prost(string, tag = "2")]
     pub token: ::prost::alloc::string::String,
 }
+#[derive(Clone, Copy, PartialEq, ::prost::Message)]
+pub struct HistoryRequest {
+    #[prost(int32, tag = "1")]
+    pub channel_id: i32,
+    /// `next_cursor` of the last page, 0 for the newest messages
+    #[prost(int64, tag = "2")]
+    pub cursor: i64,
+    /// messages of a page, 0 for default
+    #[prost(int32, tag = "3")]
+    pub limit: i32,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct HistoryResponse {
+    /// newest first
+    #[prost(message, repeated, tag = "1")]
+    pub messages: ::prost::alloc::vec::Vec<Message>,
+    /// cursor of the next page, 0 when there is no more
+    #[prost(int64, tag = "2")]
+    pub next_cursor: i64,
+}
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct ReportRequest {
     #[prost(message, optional, tag = "1")]
@@ -63,6 +132,21 @@ pub struct ReportRequest {
 pub struct ReportResponse {
     #[prost(message, optional, tag = "1")]
     pub shutdown: ::core::option::Option<ShutdownRequest>,
+    #[prost(message, optional, tag = "2")]
+    pub migrate: ::core::option::Option<MigrateRequest>,
+}
+/// Channel is reallocated to another chat server, the old server should drain it.
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct MigrateRequest {
+    #[prost(int32, tag = "1")]
+    pub channel_id: i32,
+    /// new chat server's addr
+    #[prost(string, tag = "2")]
+    pub addr: ::prost::alloc::string::String,
+    /// user_id - token to connect the new server
+    #[prost(map = "string, string", tag = "3")]
+    pub tokens:
+        ::std::collections::HashMap<::prost::alloc::string::String, ::prost::alloc::string::String>,
 }
 /// Metric like a heartbeat
 #[derive(Clone, PartialEq, ::prost::Message)]
@@ -89,8 +173,25 @@ pub struct LoginRequest {
 }
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct LoginResponse {
+    /// access token, short-lived
     #[prost(string, tag = "1")]
     pub token: ::prost::alloc::string::String,
+    /// for a new access token by `Refresh`, used once
+    #[prost(string, tag = "2")]
+    pub refresh_token: ::prost::alloc::string::String,
+    /// when the access token expires, in seconds
+    #[prost(int64, tag = "3")]
+    pub expires_at: i64,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RefreshRequest {
+    #[prost(string, tag = "1")]
+    pub refresh_token: ::prost::alloc::string::String,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct LogoutRequest {
+    #[prost(string, tag = "1")]
+    pub refresh_token: ::prost::alloc::string::String,
 }
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Message {
@@ -98,19 +199,155 @@ pub struct Message {
     pub user_id: ::prost::alloc::string::String,
     #[prost(int64, tag = "2")]
     pub timestamp: i64,
-    #[prost(oneof = "message::Content", tags = "3, 4")]
+    /// codec of `audio_data`, old clients leave it unset as PCM_F32
+    #[prost(enumeration = "Codec", tag = "6")]
+    pub codec: i32,
+    /// sequence number of the audio frame, per sender
+    #[prost(uint32, tag = "7")]
+    pub seq: u32,
+    /// for text, also send it back to the sender as an ack, with server's timestamp
+    #[prost(bool, tag = "8")]
+    pub echo: bool,
+    #[prost(oneof = "message::Content", tags = "3, 4, 5, 9, 10, 11, 12, 13, 14")]
     pub content: ::core::option::Option<message::Content>,
 }
 /// Nested message and enum types in `Message`.
 pub mod message {
     #[derive(Clone, PartialEq, ::prost::Oneof)]
     pub enum Content {
-        /// one channel, 44.1kHz, f32
+        /// one frame of one channel audio, encoded by `codec`
         #[prost(bytes, tag = "3")]
         AudioData(::prost::alloc::vec::Vec<u8>),
         /// text message content
         #[prost(string, tag = "4")]
         Text(::prost::alloc::string::String),
+        /// control message from server, only to one user
+        #[prost(message, tag = "5")]
+        Reconnect(super::Reconnect),
+        /// from a user, its own `self_mute` and `self_deaf`;
+        /// from server, state of `user_id` changed, to every user
+        #[prost(message, tag = "9")]
+        Voice(super::VoiceState),
+        /// control message from moderators
+        #[prost(message, tag = "10")]
+        ServerMute(super::ServerMute),
+        /// events from server, about `user_id`
+        ///
+        /// users on the channel, to the joining user only
+        #[prost(message, tag = "11")]
+        Roster(super::Roster),
+        /// joined, with its voice state, to every other user
+        #[prost(message, tag = "12")]
+        Join(super::User),
+        /// left or removed, to every other user
+        #[prost(message, tag = "13")]
+        Leave(()),
+        /// starts or stops talking, to every other user
+        #[prost(bool, tag = "14")]
+        Speaking(bool),
+    }
+}
+/// Channel is migrated, user should reconnect to `addr` with `token`.
+/// If token is empty, user should listen the channel again.
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Reconnect {
+    #[prost(string, tag = "1")]
+    pub addr: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub token: ::prost::alloc::string::String,
+}
+/// How audio is delivered on a channel
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum ChannelMode {
+    /// every user's audio is forwarded to others, and mixed by clients
+    Forward = 0,
+    /// audio is mixed by chat server, every user gets one stream without own voice
+    Mix = 1,
+}
+impl ChannelMode {
+    /// String value of the enum field names used in the ProtoBuf definition.
+    ///
+    /// The values are not transformed in any way and thus are considered stable
+    /// (if the ProtoBuf definition does not change) and safe for programmatic use.
+    pub fn as_str_name(&self) -> &'static str {
+        match self {
+            Self::Forward => "FORWARD",
+            Self::Mix => "MIX",
+        }
+    }
+    /// Creates an enum from field names used in the ProtoBuf definition.
+    pub fn from_str_name(value: &str) -> ::core::option::Option<Self> {
+        match value {
+            "FORWARD" => Some(Self::Forward),
+            "MIX" => Some(Self::Mix),
+            _ => None,
+        }
+    }
+}
+/// Role of a user on a channel, a user without any role is a member
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum Role {
+    Member = 0,
+    /// kick, ban and unban members
+    Moderator = 1,
+    /// who created the channel, grant and revoke moderators
+    Owner = 2,
+    /// can't listen or connect to the channel
+    Banned = 3,
+}
+impl Role {
+    /// String value of the enum field names used in the ProtoBuf definition.
+    ///
+    /// The values are not transformed in any way and thus are considered stable
+    /// (if the ProtoBuf definition does not change) and safe for programmatic use.
+    pub fn as_str_name(&self) -> &'static str {
+        match self {
+            Self::Member => "MEMBER",
+            Self::Moderator => "MODERATOR",
+            Self::Owner => "OWNER",
+            Self::Banned => "BANNED",
+        }
+    }
+    /// Creates an enum from field names used in the ProtoBuf definition.
+    pub fn from_str_name(value: &str) -> ::core::option::Option<Self> {
+        match value {
+            "MEMBER" => Some(Self::Member),
+            "MODERATOR" => Some(Self::Moderator),
+            "OWNER" => Some(Self::Owner),
+            "BANNED" => Some(Self::Banned),
+            _ => None,
+        }
+    }
+}
+/// Codec of audio data.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum Codec {
+    /// raw samples, f32 little-endian, from old clients
+    PcmF32 = 0,
+    /// 20ms Opus frame, 48kHz
+    Opus = 1,
+}
+impl Codec {
+    /// String value of the enum field names used in the ProtoBuf definition.
+    ///
+    /// The values are not transformed in any way and thus are considered stable
+    /// (if the ProtoBuf definition does not change) and safe for programmatic use.
+    pub fn as_str_name(&self) -> &'static str {
+        match self {
+            Self::PcmF32 => "PCM_F32",
+            Self::Opus => "OPUS",
+        }
+    }
+    /// Creates an enum from field names used in the ProtoBuf definition.
+    pub fn from_str_name(value: &str) -> ::core::option::Option<Self> {
+        match value {
+            "PCM_F32" => Some(Self::PcmF32),
+            "OPUS" => Some(Self::Opus),
+            _ => None,
+        }
     }
 }
 /// Generated client implementations.
@@ -264,6 +501,95 @@ pub mod channel_service_client {
                 .insert(GrpcMethod::new("echo.ChannelService", "Listen"));
             self.inner.unary(req, path, codec).await
         }
+        /// Text messages of a channel, newest first, page by page
+        pub async fn history(
+            &mut self,
+            request: impl tonic::IntoRequest<super::HistoryRequest>,
+        ) -> std::result::Result<tonic::Response<super::HistoryResponse>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::unknown(format!("Service was not ready: {}", e.into()))
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/echo.ChannelService/History");
+            let mut req = request.into_request();
+            req.extensions_mut()
+                .insert(GrpcMethod::new("echo.ChannelService", "History"));
+            self.inner.unary(req, path, codec).await
+        }
+        /// Make a user moderator of the channel, by its owner
+        pub async fn grant(
+            &mut self,
+            request: impl tonic::IntoRequest<super::MemberRequest>,
+        ) -> std::result::Result<tonic::Response<()>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::unknown(format!("Service was not ready: {}", e.into()))
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/echo.ChannelService/Grant");
+            let mut req = request.into_request();
+            req.extensions_mut()
+                .insert(GrpcMethod::new("echo.ChannelService", "Grant"));
+            self.inner.unary(req, path, codec).await
+        }
+        /// Take moderator rights back, by the channel's owner
+        pub async fn revoke(
+            &mut self,
+            request: impl tonic::IntoRequest<super::MemberRequest>,
+        ) -> std::result::Result<tonic::Response<()>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::unknown(format!("Service was not ready: {}", e.into()))
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/echo.ChannelService/Revoke");
+            let mut req = request.into_request();
+            req.extensions_mut()
+                .insert(GrpcMethod::new("echo.ChannelService", "Revoke"));
+            self.inner.unary(req, path, codec).await
+        }
+        /// Disconnect a user from the channel, by its owner or moderators
+        pub async fn kick(
+            &mut self,
+            request: impl tonic::IntoRequest<super::MemberRequest>,
+        ) -> std::result::Result<tonic::Response<()>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::unknown(format!("Service was not ready: {}", e.into()))
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/echo.ChannelService/Kick");
+            let mut req = request.into_request();
+            req.extensions_mut()
+                .insert(GrpcMethod::new("echo.ChannelService", "Kick"));
+            self.inner.unary(req, path, codec).await
+        }
+        /// Kick a user, and refuse it to listen or connect again until unbanned
+        pub async fn ban(
+            &mut self,
+            request: impl tonic::IntoRequest<super::MemberRequest>,
+        ) -> std::result::Result<tonic::Response<()>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::unknown(format!("Service was not ready: {}", e.into()))
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/echo.ChannelService/Ban");
+            let mut req = request.into_request();
+            req.extensions_mut()
+                .insert(GrpcMethod::new("echo.ChannelService", "Ban"));
+            self.inner.unary(req, path, codec).await
+        }
+        pub async fn unban(
+            &mut self,
+            request: impl tonic::IntoRequest<super::MemberRequest>,
+        ) -> std::result::Result<tonic::Response<()>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::unknown(format!("Service was not ready: {}", e.into()))
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/echo.ChannelService/Unban");
+            let mut req = request.into_request();
+            req.extensions_mut()
+                .insert(GrpcMethod::new("echo.ChannelService", "Unban"));
+            self.inner.unary(req, path, codec).await
+        }
         /// For channel servers
         /// Report something periodically
         pub async fn report(
@@ -562,6 +888,37 @@ pub mod user_service_client {
                 .insert(GrpcMethod::new("echo.UserService", "Login"));
             self.inner.unary(req, path, codec).await
         }
+        /// Swap a refresh token for a new access token, the refresh token is rotated
+        pub async fn refresh(
+            &mut self,
+            request: impl tonic::IntoRequest<super::RefreshRequest>,
+        ) -> std::result::Result<tonic::Response<super::LoginResponse>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::unknown(format!("Service was not ready: {}", e.into()))
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/echo.UserService/Refresh");
+            let mut req = request.into_request();
+            req.extensions_mut()
+                .insert(GrpcMethod::new("echo.UserService", "Refresh"));
+            self.inner.unary(req, path, codec).await
+        }
+        /// Revoke the refresh token and its session, and access tokens of all sessions of the
+        /// user, other sessions get new ones by `Refresh`
+        pub async fn logout(
+            &mut self,
+            request: impl tonic::IntoRequest<super::LogoutRequest>,
+        ) -> std::result::Result<tonic::Response<()>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::unknown(format!("Service was not ready: {}", e.into()))
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/echo.UserService/Logout");
+            let mut req = request.into_request();
+            req.extensions_mut()
+                .insert(GrpcMethod::new("echo.UserService", "Logout"));
+            self.inner.unary(req, path, codec).await
+        }
     }
 }
 /// Generated server implementations.
@@ -598,6 +955,35 @@ pub mod channel_service_server {
             &self,
             request: tonic::Request<super::Channel>,
         ) -> std::result::Result<tonic::Response<super::ListenResponse>, tonic::Status>;
+        /// Text messages of a channel, newest first, page by page
+        async fn history(
+            &self,
+            request: tonic::Request<super::HistoryRequest>,
+        ) -> std::result::Result<tonic::Response<super::HistoryResponse>, tonic::Status>;
+        /// Make a user moderator of the channel, by its owner
+        async fn grant(
+            &self,
+            request: tonic::Request<super::MemberRequest>,
+        ) -> std::result::Result<tonic::Response<()>, tonic::Status>;
+        /// Take moderator rights back, by the channel's owner
+        async fn revoke(
+            &self,
+            request: tonic::Request<super::MemberRequest>,
+        ) -> std::result::Result<tonic::Response<()>, tonic::Status>;
+        /// Disconnect a user from the channel, by its owner or moderators
+        async fn kick(
+            &self,
+            request: tonic::Request<super::MemberRequest>,
+        ) -> std::result::Result<tonic::Response<()>, tonic::Status>;
+        /// Kick a user, and refuse it to listen or connect again until unbanned
+        async fn ban(
+            &self,
+            request: tonic::Request<super::MemberRequest>,
+        ) -> std::result::Result<tonic::Response<()>, tonic::Status>;
+        async fn unban(
+            &self,
+            request: tonic::Request<super::MemberRequest>,
+        ) -> std::result::Result<tonic::Response<()>, tonic::Status>;
         /// Server streaming response type for the Report method.
         type ReportStream: tonic::codegen::tokio_stream::Stream<
                 Item = std::result::Result<super::ReportResponse, tonic::Status>,
@@ -835,6 +1221,235 @@ pub mod channel_service_server {
                     };
                     Box::pin(fut)
                 }
+                "/echo.ChannelService/History" => {
+                    #[allow(non_camel_case_types)]
+                    struct HistorySvc<T: ChannelService>(pub Arc<T>);
+                    impl<T: ChannelService> tonic::server::UnaryService<super::HistoryRequest> for HistorySvc<T> {
+                        type Response = super::HistoryResponse;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::HistoryRequest>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut = async move {
+                                <T as ChannelService>::history(&inner, request).await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let method = HistorySvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/echo.ChannelService/Grant" => {
+                    #[allow(non_camel_case_types)]
+                    struct GrantSvc<T: ChannelService>(pub Arc<T>);
+                    impl<T: ChannelService> tonic::server::UnaryService<super::MemberRequest> for GrantSvc<T> {
+                        type Response = ();
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::MemberRequest>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut =
+                                async move { <T as ChannelService>::grant(&inner, request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let method = GrantSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/echo.ChannelService/Revoke" => {
+                    #[allow(non_camel_case_types)]
+                    struct RevokeSvc<T: ChannelService>(pub Arc<T>);
+                    impl<T: ChannelService> tonic::server::UnaryService<super::MemberRequest> for RevokeSvc<T> {
+                        type Response = ();
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::MemberRequest>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut =
+                                async move { <T as ChannelService>::revoke(&inner, request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let method = RevokeSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/echo.ChannelService/Kick" => {
+                    #[allow(non_camel_case_types)]
+                    struct KickSvc<T: ChannelService>(pub Arc<T>);
+                    impl<T: ChannelService> tonic::server::UnaryService<super::MemberRequest> for KickSvc<T> {
+                        type Response = ();
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::MemberRequest>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut =
+                                async move { <T as ChannelService>::kick(&inner, request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let method = KickSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/echo.ChannelService/Ban" => {
+                    #[allow(non_camel_case_types)]
+                    struct BanSvc<T: ChannelService>(pub Arc<T>);
+                    impl<T: ChannelService> tonic::server::UnaryService<super::MemberRequest> for BanSvc<T> {
+                        type Response = ();
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::MemberRequest>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut =
+                                async move { <T as ChannelService>::ban(&inner, request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let method = BanSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/echo.ChannelService/Unban" => {
+                    #[allow(non_camel_case_types)]
+                    struct UnbanSvc<T: ChannelService>(pub Arc<T>);
+                    impl<T: ChannelService> tonic::server::UnaryService<super::MemberRequest> for UnbanSvc<T> {
+                        type Response = ();
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::MemberRequest>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut =
+                                async move { <T as ChannelService>::unban(&inner, request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let method = UnbanSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
                 "/echo.ChannelService/Report" => {
                     #[allow(non_camel_case_types)]
                     struct ReportSvc<T: ChannelService>(pub Arc<T>);
@@ -1232,6 +1847,17 @@ pub mod user_service_server {
             &self,
             request: tonic::Request<super::LoginRequest>,
         ) -> std::result::Result<tonic::Response<super::LoginResponse>, tonic::Status>;
+        /// Swap a refresh token for a new access token, the refresh token is rotated
+        async fn refresh(
+            &self,
+            request: tonic::Request<super::RefreshRequest>,
+        ) -> std::result::Result<tonic::Response<super::LoginResponse>, tonic::Status>;
+        /// Revoke the refresh token and its session, and access tokens of all sessions of the
+        /// user, other sessions get new ones by `Refresh`
+        async fn logout(
+            &self,
+            request: tonic::Request<super::LogoutRequest>,
+        ) -> std::result::Result<tonic::Response<()>, tonic::Status>;
     }
     /// Service about User Opts
     #[derive(Debug)]
@@ -1383,6 +2009,82 @@ pub mod user_service_server {
                     };
                     Box::pin(fut)
                 }
+                "/echo.UserService/Refresh" => {
+                    #[allow(non_camel_case_types)]
+                    struct RefreshSvc<T: UserService>(pub Arc<T>);
+                    impl<T: UserService> tonic::server::UnaryService<super::RefreshRequest> for RefreshSvc<T> {
+                        type Response = super::LoginResponse;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::RefreshRequest>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut =
+                                async move { <T as UserService>::refresh(&inner, request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let method = RefreshSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/echo.UserService/Logout" => {
+                    #[allow(non_camel_case_types)]
+                    struct LogoutSvc<T: UserService>(pub Arc<T>);
+                    impl<T: UserService> tonic::server::UnaryService<super::LogoutRequest> for LogoutSvc<T> {
+                        type Response = ();
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::LogoutRequest>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut =
+                                async move { <T as UserService>::logout(&inner, request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let method = LogoutSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
                 _ => Box::pin(async move {
                     let mut response = http::Response::new(empty_body());
                     let headers = response.headers_mut();
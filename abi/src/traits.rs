@@ -1,4 +1,7 @@
-use crate::pb::Channel;
+use crate::error::Error;
+use crate::pb::{
+    message::Content, Channel, HistoryRequest, MemberRequest, Message, Role, VoiceState,
+};
 use sqlx::Row;
 use sqlx::{postgres::PgRow, FromRow};
 use tonic::Request;
@@ -12,21 +15,99 @@ impl FromRow<'_, PgRow> for Channel {
             name: row.get("name"),
             users: vec![],
             limit,
+            mode: row.get("mode"),
+            buffer_size: row.get("buffer_size"),
         })
     }
 }
 
+impl FromRow<'_, PgRow> for Message {
+    fn from_row(row: &PgRow) -> sqlx::Result<Self, sqlx::Error> {
+        Ok(Message {
+            user_id: row.get("user_id"),
+            timestamp: row.get("created_at"),
+            content: Some(Content::Text(row.get("content"))),
+            ..Message::default()
+        })
+    }
+}
+
+/// Most messages buffered for each listener of a channel.
+pub const MAX_CHANNEL_BUFFER_SIZE: i32 = 1024;
+/// Messages of a history page, when not given.
+pub const DEFAULT_HISTORY_LIMIT: i32 = 50;
+/// Most messages of a history page.
+pub const MAX_HISTORY_LIMIT: i32 = 200;
+
 pub trait Validator {
     fn validate(&self) -> crate::Result<()>;
 }
 
 impl Validator for Channel {
     fn validate(&self) -> crate::Result<()> {
-        // todo
+        if !(0..=MAX_CHANNEL_BUFFER_SIZE).contains(&self.buffer_size) {
+            return Err(Error::InvalidRequest("buffer_size out of range"));
+        }
+        Ok(())
+    }
+}
+
+impl Validator for HistoryRequest {
+    fn validate(&self) -> crate::Result<()> {
+        if self.channel_id <= 0 {
+            return Err(Error::InvalidRequest("invalid channel id"));
+        }
+        if !(0..=MAX_HISTORY_LIMIT).contains(&self.limit) {
+            return Err(Error::InvalidRequest("limit out of range"));
+        }
+        if self.cursor < 0 {
+            return Err(Error::InvalidRequest("invalid cursor"));
+        }
+        Ok(())
+    }
+}
+
+impl Validator for MemberRequest {
+    fn validate(&self) -> crate::Result<()> {
+        if self.channel_id <= 0 {
+            return Err(Error::InvalidRequest("invalid channel id"));
+        }
+        if self.user_id.is_empty() {
+            return Err(Error::InvalidRequest("empty user id"));
+        }
         Ok(())
     }
 }
 
+impl Role {
+    // rank for moderating, banned users are ranked as members
+    fn rank(self) -> u8 {
+        match self {
+            Role::Member | Role::Banned => 0,
+            Role::Moderator => 1,
+            Role::Owner => 2,
+        }
+    }
+
+    /// Whether a user of this role can moderate a user of `target`: kick, ban, unban or mute.
+    /// Only users of lower roles are moderated: owner > moderator > member.
+    pub fn can_moderate(self, target: Role) -> bool {
+        matches!(self, Role::Owner | Role::Moderator) && self.rank() > target.rank()
+    }
+}
+
+impl VoiceState {
+    /// Whether the user's audio is not forwarded.
+    pub fn muted(&self) -> bool {
+        self.self_mute || self.server_mute
+    }
+
+    /// Whether no audio is delivered to the user.
+    pub fn deafened(&self) -> bool {
+        self.self_deaf
+    }
+}
+
 pub trait WithToken {
     fn with(self, token: &str) -> Self;
 }
@@ -40,3 +121,26 @@ impl<T> WithToken for Request<T> {
         self
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_can_moderate() {
+        use Role::*;
+        for target in [Member, Banned, Moderator] {
+            assert!(Owner.can_moderate(target));
+        }
+        assert!(Moderator.can_moderate(Member));
+        assert!(Moderator.can_moderate(Banned));
+        // not equals or superiors, nor by members
+        assert!(!Moderator.can_moderate(Moderator));
+        assert!(!Moderator.can_moderate(Owner));
+        assert!(!Owner.can_moderate(Owner));
+        for role in [Member, Banned] {
+            assert!(!role.can_moderate(Member));
+            assert!(!role.can_moderate(Banned));
+        }
+    }
+}
@@ -0,0 +1,130 @@
+//! Limiter of the mix bus.
+//!
+//! [`Limiter`] keeps the mix of several loud speakers from clipping. It looks
+//! [`LOOKAHEAD`] samples ahead, so the gain is down before a peak is played, then comes
+//! back up slowly. What little the gain misses is soft-clipped, so output never reaches
+//! full scale.
+
+use std::collections::VecDeque;
+
+use super::{from_db, SAMPLE_RATE};
+
+/// Samples the output is delayed by, to see peaks coming, 5ms.
+pub const LOOKAHEAD: usize = SAMPLE_RATE as usize / 200;
+/// Most level of output in dBFS, above it peaks are limited.
+pub const CEILING_DB: f32 = -1.0;
+// time constants of the gain, down well within the lookahead, and back up in 100ms
+const ATTACK: f32 = 8.0 / LOOKAHEAD as f32;
+const RELEASE: f32 = 1.0 / (SAMPLE_RATE as f32 / 10.0);
+
+/// Look-ahead limiter, fed with the mix in order.
+#[derive(Debug, Clone)]
+pub struct Limiter {
+    delay: VecDeque<f32>,
+    // gains needed by samples ahead, increasing, with the index of each
+    needed: VecDeque<(usize, f32)>,
+    index: usize, // of the next sample
+    gain: f32,
+    ceiling: f32,
+}
+
+impl Default for Limiter {
+    fn default() -> Self {
+        Self {
+            delay: VecDeque::from(vec![0.0; LOOKAHEAD]),
+            needed: VecDeque::with_capacity(LOOKAHEAD + 1),
+            index: 0,
+            gain: 1.0,
+            ceiling: from_db(CEILING_DB),
+        }
+    }
+}
+
+impl Limiter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Limit the next samples in place, delayed by [`LOOKAHEAD`].
+    pub fn process(&mut self, samples: &mut [f32]) {
+        for v in samples.iter_mut() {
+            let needed = (self.ceiling / v.abs()).min(1.0);
+            while self.needed.back().is_some_and(|(_, gain)| *gain >= needed) {
+                self.needed.pop_back();
+            }
+            self.needed.push_back((self.index, needed));
+            while self.needed[0].0 + LOOKAHEAD < self.index {
+                self.needed.pop_front();
+            }
+            self.delay.push_back(*v);
+            self.index += 1;
+
+            // the least gain needed by the samples ahead
+            let target = self.needed[0].1;
+            let rate = if target < self.gain { ATTACK } else { RELEASE };
+            self.gain += (target - self.gain) * rate;
+            *v = soft_clip(self.delay.pop_front().unwrap() * self.gain, self.ceiling);
+        }
+    }
+}
+
+/// Clip softly above `knee`, approaching but never reaching full scale.
+pub fn soft_clip(v: f32, knee: f32) -> f32 {
+    let level = v.abs();
+    if level <= knee {
+        return v;
+    }
+    let room = 1.0 - knee;
+    (knee + room * ((level - knee) / room).tanh()).copysign(v)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::PI;
+
+    fn tone(freq: f32, amplitude: f32, len: usize) -> Vec<f32> {
+        (0..len)
+            .map(|i| (2.0 * PI * freq * i as f32 / SAMPLE_RATE as f32).sin() * amplitude)
+            .collect()
+    }
+
+    #[test]
+    fn test_quiet_passes() {
+        let input = tone(440.0, 0.5, 4800);
+        let mut output = input.clone();
+        Limiter::new().process(&mut output);
+        assert!(output[..LOOKAHEAD].iter().all(|v| *v == 0.0));
+        assert_eq!(output[LOOKAHEAD..], input[..input.len() - LOOKAHEAD]);
+    }
+
+    #[test]
+    fn test_loud_is_bounded() {
+        let ceiling = from_db(CEILING_DB);
+        // a burst far over full scale after quiet, then back to quiet
+        let samples = [
+            tone(440.0, 0.2, 4800),
+            tone(440.0, 4.0, 4800),
+            tone(440.0, 0.2, 48000),
+        ]
+        .concat();
+        let mut output = samples.clone();
+        Limiter::new().process(&mut output);
+        assert!(output.iter().all(|v| v.abs() < 1.0));
+        let peak = output.iter().fold(0f32, |peak, v| peak.max(v.abs()));
+        assert!(peak <= ceiling * 1.01, "{}", peak);
+        assert!(peak > ceiling * 0.95, "{}", peak);
+        // released after the burst
+        let tail = &output[output.len() - 4800..];
+        let level = tail.iter().fold(0f32, |peak, v| peak.max(v.abs()));
+        assert!((level - 0.2).abs() < 0.005, "{}", level);
+    }
+
+    #[test]
+    fn test_soft_clip() {
+        assert_eq!(soft_clip(0.5, 0.9), 0.5);
+        assert_eq!(soft_clip(-0.9, 0.9), -0.9);
+        assert!(soft_clip(1.0, 0.9) < 1.0 && soft_clip(1.0, 0.9) > 0.95);
+        assert!(soft_clip(-100.0, 0.9) >= -1.0);
+    }
+}
@@ -0,0 +1,191 @@
+//! Loudness normalization of a speaker.
+//!
+//! [`Loudness`] measures a speaker in the way of EBU R128: samples are K-weighted, so that
+//! low frequencies count less as they do to the ear, then their mean square over the last
+//! 3 seconds gives the short-term loudness in LUFS. Only blocks of voice count, so pauses
+//! don't turn a speaker up. The speaker is brought to [`TARGET_LUFS`], by a gain which
+//! follows the measure smoothly.
+
+use std::collections::VecDeque;
+
+use super::{from_db, SAMPLE_RATE};
+
+/// Loudness speakers are brought to, in LUFS.
+pub const TARGET_LUFS: f32 = -18.0;
+/// Most a speaker is turned up or down, in dB.
+pub const MAX_NORMALIZE_DB: f32 = 12.0;
+// blocks of 100ms, 3 seconds of them for the short-term loudness
+const BLOCK_SIZE: usize = SAMPLE_RATE as usize / 10;
+const SHORT_TERM_BLOCKS: usize = 30;
+// quieter blocks are pauses, in LUFS
+const GATE_LUFS: f32 = -50.0;
+// time constant of the gain following the measure, 100ms
+const SMOOTHING: f32 = 1.0 / (SAMPLE_RATE as f32 / 10.0);
+
+// second-order IIR filter, coefficients normalized by a0
+#[derive(Debug, Clone, Copy)]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    x: [f64; 2],
+    y: [f64; 2],
+}
+
+impl Biquad {
+    const fn new(b: [f64; 3], a: [f64; 2]) -> Self {
+        Self {
+            b,
+            a,
+            x: [0.0; 2],
+            y: [0.0; 2],
+        }
+    }
+
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.b[1] * self.x[0] + self.b[2] * self.x[1]
+            - self.a[0] * self.y[0]
+            - self.a[1] * self.y[1];
+        self.x = [x, self.x[0]];
+        self.y = [y, self.y[0]];
+        y
+    }
+}
+
+// K-weighting of ITU-R BS.1770 at 48 kHz: a high shelf for the head, then a high pass
+const K_WEIGHTING: [Biquad; 2] = [
+    Biquad::new(
+        [1.53512485958697, -2.69169618940638, 1.19839281085285],
+        [-1.69065929318241, 0.73248077421585],
+    ),
+    Biquad::new([1.0, -2.0, 1.0], [-1.99004745483398, 0.99007225036621]),
+];
+
+/// Loudness meter and normalizer of one speaker, fed with its samples in order.
+#[derive(Debug, Clone)]
+pub struct Loudness {
+    filters: [Biquad; 2],
+    block: (f64, usize),   // sum of squares and count of the current block
+    blocks: VecDeque<f64>, // mean squares of the last voiced blocks
+    target: f32,           // of `target_gain`, linear, as of the last voiced block
+    gain: f32,             // applied now, linear
+}
+
+impl Default for Loudness {
+    fn default() -> Self {
+        Self {
+            filters: K_WEIGHTING,
+            block: (0.0, 0),
+            blocks: VecDeque::with_capacity(SHORT_TERM_BLOCKS),
+            target: 1.0,
+            gain: 1.0,
+        }
+    }
+}
+
+impl Loudness {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Short-term loudness in LUFS of the last voiced blocks, None before any.
+    pub fn loudness(&self) -> Option<f32> {
+        if self.blocks.is_empty() {
+            return None;
+        }
+        let mean = self.blocks.iter().sum::<f64>() / self.blocks.len() as f64;
+        Some(lufs(mean))
+    }
+
+    /// Gain in dB bringing the speaker to [`TARGET_LUFS`], 0 before it's heard.
+    pub fn target_gain(&self) -> f32 {
+        self.loudness().map_or(0.0, |loudness| {
+            (TARGET_LUFS - loudness).clamp(-MAX_NORMALIZE_DB, MAX_NORMALIZE_DB)
+        })
+    }
+
+    /// Measure the next samples, and bring them toward [`TARGET_LUFS`].
+    pub fn normalize(&mut self, samples: &mut [f32]) {
+        for v in samples.iter_mut() {
+            self.measure(*v);
+            self.gain += (self.target - self.gain) * SMOOTHING;
+            *v *= self.gain;
+        }
+    }
+
+    fn measure(&mut self, v: f32) {
+        let weighted = self
+            .filters
+            .iter_mut()
+            .fold(v as f64, |x, filter| filter.process(x));
+        self.block.0 += weighted * weighted;
+        self.block.1 += 1;
+        if self.block.1 == BLOCK_SIZE {
+            let mean = self.block.0 / BLOCK_SIZE as f64;
+            self.block = (0.0, 0);
+            if lufs(mean) > GATE_LUFS {
+                if self.blocks.len() == SHORT_TERM_BLOCKS {
+                    self.blocks.pop_front();
+                }
+                self.blocks.push_back(mean);
+                self.target = from_db(self.target_gain());
+            }
+        }
+    }
+}
+
+// loudness of a K-weighted mean square, of one channel
+fn lufs(mean_square: f64) -> f32 {
+    (-0.691 + 10.0 * mean_square.max(1e-12).log10()) as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::PI;
+
+    fn tone(freq: f32, amplitude: f32, ms: usize) -> Vec<f32> {
+        (0..SAMPLE_RATE as usize * ms / 1000)
+            .map(|i| (2.0 * PI * freq * i as f32 / SAMPLE_RATE as f32).sin() * amplitude)
+            .collect()
+    }
+
+    fn peak(samples: &[f32]) -> f32 {
+        samples.iter().fold(0.0, |peak, v| peak.max(v.abs()))
+    }
+
+    #[test]
+    fn test_measure() {
+        // a sine of 997 Hz at full scale is -3.01 LUFS
+        let mut loudness = Loudness::new();
+        assert_eq!(loudness.loudness(), None);
+        loudness.normalize(&mut tone(997.0, 0.1, 3000));
+        let measured = loudness.loudness().unwrap();
+        assert!((measured + 23.01).abs() < 0.05, "{}", measured);
+        // low frequencies count less
+        let mut low = Loudness::new();
+        low.normalize(&mut tone(40.0, 0.1, 3000));
+        assert!(low.loudness().unwrap() < measured - 1.0);
+    }
+
+    #[test]
+    fn test_normalize() {
+        for amplitude in [0.06, 0.5] {
+            let mut loudness = Loudness::new();
+            let mut samples = tone(997.0, amplitude, 6000);
+            loudness.normalize(&mut samples);
+            // a sine of -18 LUFS peaks at 0.178
+            let level = peak(&samples[samples.len() - 4800..]);
+            assert!((level - 0.178).abs() < 0.01, "{}: {}", amplitude, level);
+        }
+
+        // pauses don't turn up, nor very quiet speakers much
+        let mut loudness = Loudness::new();
+        loudness.normalize(&mut tone(997.0, 0.1, 3000));
+        let gain = loudness.target_gain();
+        loudness.normalize(&mut vec![0.0; SAMPLE_RATE as usize * 5]);
+        assert_eq!(loudness.target_gain(), gain);
+        let mut quiet = Loudness::new();
+        quiet.normalize(&mut tone(997.0, 0.005, 3000));
+        assert_eq!(quiet.target_gain(), MAX_NORMALIZE_DB);
+    }
+}
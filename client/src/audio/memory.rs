@@ -0,0 +1,136 @@
+//! In-memory audio backends, fed and read through channels, to test without a sound card.
+//!
+//! [`MemorySource`] records samples sent to it in real time, and silence while none are
+//! left, as a microphone in a quiet room. [`MemorySink`] sends every frame heard.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use super::{capture, render, AudioSink, AudioSource, AudioStream, Mixer};
+use crate::config::UserConfig;
+use crate::control::Control;
+use crate::jitter::Playback;
+use abi::codec::FRAME_SIZE;
+use abi::Result;
+use ringbuffer::AllocRingBuffer;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+/// Mono samples at [`SAMPLE_RATE`](abi::codec::SAMPLE_RATE), recorded as they're sent.
+#[derive(Debug, Clone)]
+pub struct MemorySource {
+    rx: Arc<Mutex<UnboundedReceiver<Vec<f32>>>>,
+}
+
+impl MemorySource {
+    /// A source and the sender of its samples.
+    pub fn new() -> (UnboundedSender<Vec<f32>>, Self) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let source = Self {
+            rx: Arc::new(Mutex::new(rx)),
+        };
+        (tx, source)
+    }
+}
+
+impl AudioSource for MemorySource {
+    fn start(
+        &mut self,
+        buffer: Arc<Mutex<AllocRingBuffer<f32>>>,
+        control: Control,
+    ) -> Result<AudioStream> {
+        let rx = Arc::clone(&self.rx);
+        let mut pending = VecDeque::new();
+        Ok(AudioStream::tick(move || {
+            let mut rx = rx.lock().unwrap();
+            while pending.len() < FRAME_SIZE {
+                match rx.try_recv() {
+                    Ok(samples) => pending.extend(samples),
+                    Err(_) => break,
+                }
+            }
+            let mut frame: Vec<f32> = pending.drain(..FRAME_SIZE.min(pending.len())).collect();
+            frame.resize(FRAME_SIZE, 0.0);
+            capture(&frame, &buffer, &control);
+            true
+        }))
+    }
+}
+
+/// Frames heard, mono at [`SAMPLE_RATE`](abi::codec::SAMPLE_RATE), silent ones included.
+#[derive(Debug, Clone)]
+pub struct MemorySink {
+    tx: UnboundedSender<Vec<f32>>,
+}
+
+impl MemorySink {
+    /// A sink and the receiver of its frames.
+    pub fn new() -> (Self, UnboundedReceiver<Vec<f32>>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        (Self { tx }, rx)
+    }
+}
+
+impl AudioSink for MemorySink {
+    fn start(
+        &mut self,
+        playback: Arc<Mutex<Playback>>,
+        config: Arc<UserConfig>,
+        control: Control,
+    ) -> Result<AudioStream> {
+        let tx = self.tx.clone();
+        let mut mixer = Mixer::new();
+        Ok(AudioStream::tick(move || {
+            let mut frame = vec![0.0; FRAME_SIZE];
+            render(&mut frame, &playback, &mut mixer, &config, &control);
+            tx.send(frame).is_ok()
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::limiter::LOOKAHEAD;
+    use crate::utils::{ToBytes, RING_BUFFER_SIZE};
+    use abi::pb::Codec;
+    use ringbuffer::RingBuffer;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_memory_backend() {
+        let config = Arc::new(UserConfig::default());
+        let control = Control::new(&config);
+        let buf = Arc::new(Mutex::new(AllocRingBuffer::new(RING_BUFFER_SIZE)));
+        let (tx, mut source) = MemorySource::new();
+        tx.send(vec![0.5; FRAME_SIZE * 3 / 2]).unwrap();
+        tx.send(vec![0.5; FRAME_SIZE / 2]).unwrap();
+        let stream = source.start(buf.clone(), control.clone()).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        drop(stream);
+        // paced by frames, then silence
+        let said = buf.lock().unwrap().to_vec();
+        assert!(said.len() >= FRAME_SIZE * 3, "{}", said.len());
+        assert_eq!(said.len() % FRAME_SIZE, 0);
+        assert!(said[..FRAME_SIZE * 2].iter().all(|v| *v == 0.5));
+        assert!(said[FRAME_SIZE * 2..].iter().all(|v| *v == 0.0));
+
+        let playback = Arc::new(Mutex::new(Playback::new()));
+        playback
+            .lock()
+            .unwrap()
+            .push("test_user", Codec::PcmF32, 0, 0, said.to_bytes());
+        let (mut sink, mut heard) = MemorySink::new();
+        let stream = sink.start(playback, config, control.clone()).unwrap();
+        // played after the lookahead of the limiter
+        let frame = heard.blocking_recv().unwrap();
+        assert!(frame[..LOOKAHEAD].iter().all(|v| *v == 0.0));
+        assert!(frame[LOOKAHEAD..].iter().all(|v| *v == 0.5));
+        // deafened, nothing is heard
+        control.set_deafen(true);
+        thread::sleep(Duration::from_millis(50));
+        drop(stream);
+        let last = std::iter::from_fn(|| heard.try_recv().ok()).last().unwrap();
+        assert!(last.iter().all(|v| *v == 0.0));
+    }
+}
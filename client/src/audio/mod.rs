@@ -0,0 +1,569 @@
+//! Audio Processor Module.
+//!
+//! Audio is captured from an [`AudioSource`] and played to an [`AudioSink`]: devices by
+//! [`cpal`], [`wav`] files, or [`memory`] channels to run without a sound card.
+//! Devices and files are taken in their own formats, and [`resample`]d to [`SAMPLE_RATE`].
+//! Support volume control in dB and audio mixing, with [`loudness`] normalization of each
+//! speaker and a [`limiter`] on the mix, and [`vad`] to tell speech from silence.
+
+pub mod limiter;
+pub mod loudness;
+pub mod memory;
+pub mod resample;
+pub mod vad;
+pub mod wav;
+
+use core::f32;
+use std::cmp::Ordering;
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Debug;
+use std::ops::Add;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Instant;
+
+use crate::config::UserConfig;
+use crate::control::Control;
+use crate::jitter::Playback;
+use abi::codec::FRAME_DURATION;
+use abi::error::Error;
+use abi::Result;
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use cpal::{
+    Device, FromSample, Sample, SampleFormat, SizedSample, Stream, StreamConfig,
+    SupportedStreamConfig,
+};
+use limiter::Limiter;
+use log::error;
+use loudness::Loudness;
+use resample::Resampler;
+use ringbuffer::AllocRingBuffer;
+
+pub use abi::codec::SAMPLE_RATE;
+/// Most volume in dB, four times the original amplitude.
+pub const MAX_VOLUME_DB: f32 = 12.0;
+
+/// Mixer of speakers on a channel, keeping what it measured between calls.
+#[derive(Debug, Default)]
+pub struct Mixer {
+    loudness: HashMap<String, Loudness>, // of each speaker, while normalizing
+    limiter: Limiter,
+}
+
+impl Mixer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Mix mono audio of users into `data`, normalized if `normalize` and at the volume of
+    /// each in `config`, then apply `volume` in dB and limit the mix.
+    pub fn mix(
+        &mut self,
+        data: &mut [f32],
+        audio: HashMap<String, Vec<f32>>,
+        config: &UserConfig,
+        volume: f32,
+        normalize: bool,
+    ) {
+        if normalize {
+            // speakers who left are forgotten
+            self.loudness.retain(|name, _| audio.contains_key(name));
+        } else {
+            self.loudness.clear();
+        }
+        for (name, mut audio) in audio.into_iter() {
+            if normalize {
+                let loudness = self.loudness.entry(name.clone()).or_default();
+                loudness.normalize(&mut audio);
+            }
+            // apply other volume configs
+            if let Some(volume) = config.other_volume.get(&name) {
+                apply_gain(&mut audio, *volume);
+            }
+            add(data, &audio);
+        }
+        // apply own volume
+        apply_gain(data, volume);
+        self.limiter.process(data);
+    }
+}
+
+/// Amplify audio data by `gain` in dB, up to [`MAX_VOLUME_DB`].
+///
+/// For example, 0 means no change, 6 about doubles the amplitude,
+/// -6 about halves it, and [`f32::NEG_INFINITY`] mutes.
+pub fn apply_gain(data: &mut [f32], gain: f32) {
+    let factor = from_db(gain.min(MAX_VOLUME_DB));
+    if factor != 1.0 {
+        data.iter_mut().for_each(|v| *v *= factor);
+    }
+}
+
+/// Linear factor of a gain in dB.
+pub fn from_db(db: f32) -> f32 {
+    10f32.powf(db / 20.0)
+}
+
+/// Add audio data from `audio` to `mix_audio`.
+///
+/// `mix_audio` and `audio` may have different lengths. The operation will only be performed
+/// up to the length of the shorter slice.
+pub fn add<T>(mix_audio: &mut [T], audio: &[T])
+where
+    T: Copy + Add<Output = T>,
+{
+    let len = mix_audio.len().min(audio.len());
+
+    for i in 0..len {
+        mix_audio[i] = mix_audio[i] + audio[i];
+    }
+}
+
+/// Resize audio data.
+///
+/// padding or down sample
+pub fn resize<T>(audio: &mut Vec<T>, size: usize)
+where
+    T: Clone + Default + Copy,
+{
+    match audio.len().cmp(&size) {
+        Ordering::Less => {
+            // padding data for backlog
+            audio.extend(vec![T::default(); size - audio.len()]);
+        }
+        Ordering::Equal => {}
+        Ordering::Greater => {
+            // down sample data for backlog
+            for i in 0..size {
+                let j = i * audio.len() / size;
+                audio[i] = audio[j];
+            }
+            audio.resize(size, T::default());
+        }
+    }
+}
+
+/// Average interleaved `data` of `channels` into mono samples.
+pub fn downmix<T>(data: &[T], channels: usize) -> Vec<f32>
+where
+    T: Sample,
+    f32: FromSample<T>,
+{
+    data.chunks_exact(channels)
+        .map(|frame| frame.iter().map(|v| f32::from_sample(*v)).sum::<f32>() / channels as f32)
+        .collect()
+}
+
+/// Copy mono samples into every channel of interleaved `data`, as many as fit.
+pub fn upmix<T>(mono: impl IntoIterator<Item = f32>, data: &mut [T], channels: usize)
+where
+    T: Sample + FromSample<f32>,
+{
+    for (frame, v) in data.chunks_exact_mut(channels).zip(mono) {
+        frame.fill(T::from_sample(v));
+    }
+}
+
+/// Where own audio comes from, mono samples at [`SAMPLE_RATE`].
+pub trait AudioSource: Send {
+    /// Start recording into `buffer` at the input gain of `control`, until the stream is dropped.
+    fn start(
+        &mut self,
+        buffer: Arc<Mutex<AllocRingBuffer<f32>>>,
+        control: Control,
+    ) -> Result<AudioStream>;
+}
+
+/// Where audio heard goes, mixed from every speaker of `playback`.
+pub trait AudioSink: Send {
+    /// Start playing audio pulled from `playback` at the output gain of `control`,
+    /// until the stream is dropped.
+    fn start(
+        &mut self,
+        playback: Arc<Mutex<Playback>>,
+        config: Arc<UserConfig>,
+        control: Control,
+    ) -> Result<AudioStream>;
+}
+
+/// A running source or sink on its own thread, stopped when dropped.
+#[derive(Debug)]
+pub struct AudioStream {
+    stop: Option<mpsc::Sender<()>>, // disconnected to stop
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl AudioStream {
+    // keep what `start` makes on a new thread until dropped, as device streams can't be sent
+    fn hold<T>(start: impl FnOnce() -> Result<T> + Send + 'static) -> Result<Self> {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let (started_tx, started) = mpsc::sync_channel(1);
+        let thread = thread::spawn(move || match start() {
+            Ok(held) => {
+                let _ = started_tx.send(Ok(()));
+                let _ = stopped.recv();
+                drop(held);
+            }
+            Err(e) => {
+                let _ = started_tx.send(Err(e));
+            }
+        });
+        started
+            .recv()
+            .map_err(|_| Error::AudioDevice("audio thread exited".to_string()))??;
+        Ok(Self {
+            stop: Some(stop),
+            thread: Some(thread),
+        })
+    }
+
+    // call `tick` once every frame on a new thread, until dropped or it returns false
+    fn tick(mut tick: impl FnMut() -> bool + Send + 'static) -> Self {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let thread = thread::spawn(move || {
+            let start = Instant::now();
+            for n in 1u32.. {
+                if !tick() {
+                    break;
+                }
+                let next = start + FRAME_DURATION * n;
+                let timeout = next.saturating_duration_since(Instant::now());
+                if !matches!(
+                    stopped.recv_timeout(timeout),
+                    Err(RecvTimeoutError::Timeout)
+                ) {
+                    break;
+                }
+            }
+        });
+        Self {
+            stop: Some(stop),
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for AudioStream {
+    fn drop(&mut self) {
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Record samples into `buffer` at the input gain of `control`.
+///
+/// A closed microphone records silence, so that frames go on.
+pub fn capture(data: &[f32], buffer: &Mutex<AllocRingBuffer<f32>>, control: &Control) {
+    let mut data = data.to_vec();
+    apply_gain(&mut data, control.input_gain());
+    buffer.lock().unwrap().extend(data);
+}
+
+/// Fill mono `data` with audio pulled from `playback` and mixed by `mixer`,
+/// at the output gain of `control`.
+pub fn render(
+    data: &mut [f32],
+    playback: &Mutex<Playback>,
+    mixer: &mut Mixer,
+    config: &UserConfig,
+    control: &Control,
+) {
+    data.fill(0.0);
+    let audio = playback.lock().unwrap().flush(data.len());
+    let (volume, normalize) = (control.output_gain(), control.is_normalizing());
+    mixer.mix(data, audio, config, volume, normalize);
+}
+
+fn device_error(e: impl std::fmt::Display) -> Error {
+    Error::AudioDevice(e.to_string())
+}
+
+/// Wrapper for playing audio data on some device, in its own format.
+///
+/// Audio is resampled to the device's rate, and copied into each of its channels.
+pub struct Speaker {
+    device: Device,
+    config: SupportedStreamConfig,
+}
+
+impl Speaker {
+    pub fn new(device: Device, config: SupportedStreamConfig) -> Self {
+        Self { device, config }
+    }
+
+    /// The default output device of the host in its default format, if there is one.
+    pub fn open_default() -> Result<Self> {
+        let device = cpal::default_host()
+            .default_output_device()
+            .ok_or_else(|| device_error("no output device available"))?;
+        let config = device.default_output_config().map_err(device_error)?;
+        Ok(Self::new(device, config))
+    }
+}
+
+impl AudioSink for Speaker {
+    fn start(
+        &mut self,
+        playback: Arc<Mutex<Playback>>,
+        config: Arc<UserConfig>,
+        control: Control,
+    ) -> Result<AudioStream> {
+        let (device, format) = (self.device.clone(), self.config.sample_format());
+        let stream_config = self.config.config();
+        AudioStream::hold(move || {
+            let output = Output {
+                playback,
+                config,
+                control,
+            };
+            let stream = match format {
+                SampleFormat::I8 => output.play::<i8>(&device, &stream_config),
+                SampleFormat::I16 => output.play::<i16>(&device, &stream_config),
+                SampleFormat::I32 => output.play::<i32>(&device, &stream_config),
+                SampleFormat::U8 => output.play::<u8>(&device, &stream_config),
+                SampleFormat::U16 => output.play::<u16>(&device, &stream_config),
+                SampleFormat::U32 => output.play::<u32>(&device, &stream_config),
+                SampleFormat::F32 => output.play::<f32>(&device, &stream_config),
+                SampleFormat::F64 => output.play::<f64>(&device, &stream_config),
+                format => Err(device_error(format!(
+                    "unsupported sample format {}",
+                    format
+                ))),
+            }?;
+            stream.play().map_err(device_error)?;
+            Ok(stream)
+        })
+    }
+}
+
+// what an output stream plays
+struct Output {
+    playback: Arc<Mutex<Playback>>,
+    config: Arc<UserConfig>,
+    control: Control,
+}
+
+impl Output {
+    fn play<T>(self, device: &Device, stream_config: &StreamConfig) -> Result<Stream>
+    where
+        T: SizedSample + FromSample<f32>,
+    {
+        let channels = stream_config.channels as usize;
+        let mut resampler = Resampler::new(SAMPLE_RATE, stream_config.sample_rate.0)
+            .ok_or_else(|| device_error("unsupported output sample rate"))?;
+        let mut resampled = VecDeque::new(); // at the device's rate
+        let mut mixer = Mixer::new();
+        device
+            .build_output_stream(
+                stream_config,
+                move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
+                    let frames = data.len() / channels;
+                    while resampled.len() < frames {
+                        let mut mono = vec![0.0; resampler.input_for(frames - resampled.len())];
+                        render(
+                            &mut mono,
+                            &self.playback,
+                            &mut mixer,
+                            &self.config,
+                            &self.control,
+                        );
+                        resampled.extend(resampler.process(&mono));
+                    }
+                    upmix(resampled.drain(..frames), data, channels);
+                },
+                |e| error!("output stream error: {}", e),
+                None, // None=blocking, Some(Duration)=timeout
+            )
+            .map_err(device_error)
+    }
+}
+
+/// Wrapper for recording audio data from some device, in its own format.
+///
+/// Audio is mixed down to mono, and resampled to [`SAMPLE_RATE`].
+pub struct Microphone {
+    device: Device,
+    config: SupportedStreamConfig,
+}
+
+impl Microphone {
+    pub fn new(device: Device, config: SupportedStreamConfig) -> Self {
+        Self { device, config }
+    }
+
+    /// The default input device of the host in its default format, if there is one.
+    pub fn open_default() -> Result<Self> {
+        let device = cpal::default_host()
+            .default_input_device()
+            .ok_or_else(|| device_error("no input device available"))?;
+        let config = device.default_input_config().map_err(device_error)?;
+        Ok(Self::new(device, config))
+    }
+}
+
+impl AudioSource for Microphone {
+    fn start(
+        &mut self,
+        buffer: Arc<Mutex<AllocRingBuffer<f32>>>,
+        control: Control,
+    ) -> Result<AudioStream> {
+        let (device, format) = (self.device.clone(), self.config.sample_format());
+        let stream_config = self.config.config();
+        AudioStream::hold(move || {
+            let input = Input { buffer, control };
+            let stream = match format {
+                SampleFormat::I8 => input.record::<i8>(&device, &stream_config),
+                SampleFormat::I16 => input.record::<i16>(&device, &stream_config),
+                SampleFormat::I32 => input.record::<i32>(&device, &stream_config),
+                SampleFormat::U8 => input.record::<u8>(&device, &stream_config),
+                SampleFormat::U16 => input.record::<u16>(&device, &stream_config),
+                SampleFormat::U32 => input.record::<u32>(&device, &stream_config),
+                SampleFormat::F32 => input.record::<f32>(&device, &stream_config),
+                SampleFormat::F64 => input.record::<f64>(&device, &stream_config),
+                format => Err(device_error(format!(
+                    "unsupported sample format {}",
+                    format
+                ))),
+            }?;
+            stream.play().map_err(device_error)?;
+            Ok(stream)
+        })
+    }
+}
+
+// where an input stream records to
+struct Input {
+    buffer: Arc<Mutex<AllocRingBuffer<f32>>>,
+    control: Control,
+}
+
+impl Input {
+    fn record<T>(self, device: &Device, stream_config: &StreamConfig) -> Result<Stream>
+    where
+        T: SizedSample,
+        f32: FromSample<T>,
+    {
+        let channels = stream_config.channels as usize;
+        let mut resampler = Resampler::new(stream_config.sample_rate.0, SAMPLE_RATE)
+            .ok_or_else(|| device_error("unsupported input sample rate"))?;
+        device
+            .build_input_stream(
+                stream_config,
+                move |data: &[T], _: &cpal::InputCallbackInfo| {
+                    let mono = downmix(data, channels);
+                    capture(&resampler.process(&mono), &self.buffer, &self.control);
+                },
+                |e| error!("input stream error: {}", e),
+                None, // None=blocking, Some(Duration)=timeout
+            )
+            .map_err(device_error)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{thread, time};
+
+    use crate::utils::{ToBytes, RING_BUFFER_SIZE};
+
+    use super::*;
+    use abi::pb::Codec;
+    use ringbuffer::RingBuffer;
+
+    #[test]
+    fn test_gains() {
+        let mut data = vec![0.5; 4];
+        apply_gain(&mut data, 0.0);
+        assert_eq!(data, [0.5; 4]);
+        apply_gain(&mut data, -20.0 * 2f32.log10());
+        assert!(data.iter().all(|v| (v - 0.25).abs() < 1e-6));
+        apply_gain(&mut data, 40.0);
+        assert!(data
+            .iter()
+            .all(|v| (v - 0.25 * from_db(MAX_VOLUME_DB)).abs() < 1e-6));
+        apply_gain(&mut data, f32::NEG_INFINITY);
+        assert_eq!(data, [0.0; 4]);
+    }
+
+    #[test]
+    fn test_mix() {
+        let config = UserConfig {
+            other_volume: HashMap::from([("b".to_string(), -6.0)]),
+            ..UserConfig::default()
+        };
+        let mut mixer = Mixer::new();
+        let audio = HashMap::from([
+            ("a".to_string(), vec![0.1; 960]),
+            ("b".to_string(), vec![0.2; 960]),
+        ]);
+        let mut data = vec![0.0; 960];
+        mixer.mix(&mut data, audio, &config, -3.0, false);
+        // after the lookahead, b at half and all at -3 dB
+        let expected = (0.1 + 0.2 * from_db(-6.0)) * from_db(-3.0);
+        assert!(data[limiter::LOOKAHEAD..]
+            .iter()
+            .all(|v| (v - expected).abs() < 1e-6));
+
+        // several loud speakers don't clip
+        let mut mixer = Mixer::new();
+        for n in 0..50 {
+            let audio = (0..4)
+                .map(|i| {
+                    let tone = (0..960)
+                        .map(|j| ((n * 960 + j) as f32 * 0.05 * (i + 1) as f32).sin() * 0.9)
+                        .collect();
+                    (i.to_string(), tone)
+                })
+                .collect();
+            let mut data = vec![0.0; 960];
+            mixer.mix(&mut data, audio, &UserConfig::default(), 6.0, true);
+            assert!(data.iter().all(|v| v.abs() < 1.0));
+        }
+    }
+
+    #[test]
+    fn test_channels() {
+        // stereo of device formats, to mono and back
+        let mono = downmix(&[i16::MIN, 0, i16::MAX, i16::MAX], 2);
+        assert_eq!(mono.len(), 2);
+        assert!((mono[0] + 0.5).abs() < 1e-3 && (mono[1] - 1.0).abs() < 1e-3);
+        assert_eq!(downmix(&[32768u16, 65535, 0], 1)[0], 0.0);
+
+        let mut data = [0u16; 6];
+        upmix([0.0, -1.0, 1.0, 0.5], &mut data, 2);
+        assert_eq!(data[..4], [32768, 32768, 0, 0]);
+        assert!(data[4] > 65000 && data[5] == data[4]);
+    }
+
+    #[ignore = "only manual test, with sound devices"]
+    #[test]
+    fn test_audio() {
+        let config = Arc::new(UserConfig::default());
+        let buf = Arc::new(Mutex::new(AllocRingBuffer::new(RING_BUFFER_SIZE)));
+        let mut mic = Microphone::open_default().unwrap();
+        dbg!(&mic.config);
+        let control = Control::new(&config);
+        let _input = mic.start(buf.clone(), control.clone()).unwrap();
+
+        let mut speaker = Speaker::open_default().unwrap();
+        dbg!(&speaker.config);
+        let playback = Arc::new(Mutex::new(Playback::new()));
+
+        let _output = speaker.start(playback.clone(), config, control).unwrap();
+        thread::sleep(time::Duration::from_millis(3000));
+        let mut buf = buf.lock().unwrap();
+        if buf.len() > 0 {
+            let data = buf.to_vec();
+            println!("buf.len: {}", buf.len());
+            buf.clear();
+            playback
+                .lock()
+                .unwrap()
+                .push("test_user", Codec::PcmF32, 0, 0, data.to_bytes());
+        }
+        thread::sleep(time::Duration::from_millis(3000));
+    }
+}
@@ -0,0 +1,231 @@
+//! Sample-rate conversion.
+//!
+//! [`Resampler`] converts a stream of mono samples between two rates, by a polyphase
+//! filter: the rates' ratio is reduced to `up / down`, and each output sample is filtered
+//! from the input by one of `up` branches of a Kaiser-windowed sinc, without ever
+//! computing the zeros of upsampling. The filter cuts below the lower Nyquist frequency,
+//! so downsampling doesn't alias.
+
+use std::f64::consts::PI;
+
+// taps of a branch, per input sample of the lower rate's period
+const TAPS: usize = 64;
+// cutoff relative to the lower Nyquist frequency, leaving room for the transition band
+const ROLLOFF: f64 = 0.9;
+// of the Kaiser window, for about 80 dB of stopband attenuation
+const KAISER_BETA: f64 = 8.0;
+// rates whose reduced ratio needs more branches are not supported
+const MAX_PHASES: usize = 4096;
+
+/// Streaming resampler of mono samples.
+#[derive(Debug, Clone)]
+pub struct Resampler {
+    up: usize,
+    down: usize,
+    taps: usize,       // of each branch
+    filter: Vec<f32>,  // branches one after another, each from the newest sample back
+    history: Vec<f32>, // input not consumed yet, after the taps before it
+    position: usize,   // of the next output, in upsampled samples from `history` start
+}
+
+impl Resampler {
+    /// Resampler from rate `from` to rate `to` in Hz.
+    ///
+    /// None if a rate is 0 or their ratio is too odd to convert, like 47999 to 48000.
+    pub fn new(from: u32, to: u32) -> Option<Self> {
+        if from == 0 || to == 0 {
+            return None;
+        }
+        let divisor = gcd(from, to);
+        let (up, down) = ((to / divisor) as usize, (from / divisor) as usize);
+        if up > MAX_PHASES {
+            return None;
+        }
+        if up == down {
+            return Some(Self {
+                up,
+                down,
+                taps: 1,
+                filter: vec![1.0],
+                history: vec![],
+                position: 0,
+            });
+        }
+
+        // a longer filter for downsampling, to cut as sharply below the lower rate
+        let taps = TAPS * down.div_ceil(up);
+        let len = taps * up;
+        // cutoff in cycles per upsampled sample
+        let cutoff = ROLLOFF * 0.5 / up.max(down) as f64;
+        let center = (len - 1) as f64 / 2.0;
+        let prototype: Vec<f64> = (0..len)
+            .map(|n| {
+                let x = n as f64 - center;
+                let window = bessel_i0(KAISER_BETA * (1.0 - (x / center).powi(2)).max(0.0).sqrt())
+                    / bessel_i0(KAISER_BETA);
+                // gain of `up` makes up for the zeros of upsampling
+                up as f64 * 2.0 * cutoff * sinc(2.0 * cutoff * x) * window
+            })
+            .collect();
+        let filter = (0..up)
+            .flat_map(|phase| (0..taps).map(move |k| (phase, k)))
+            .map(|(phase, k)| prototype[phase + k * up] as f32)
+            .collect();
+
+        Some(Self {
+            up,
+            down,
+            taps,
+            filter,
+            history: vec![0.0; taps - 1],
+            position: (taps - 1) * up,
+        })
+    }
+
+    /// Resample the next input, output as many samples as are ready.
+    ///
+    /// Output lags by half the filter, about a millisecond.
+    pub fn process(&mut self, input: &[f32]) -> Vec<f32> {
+        if self.up == self.down {
+            return input.to_vec();
+        }
+        self.history.extend_from_slice(input);
+        let mut output = Vec::with_capacity(input.len() * self.up / self.down + 1);
+        loop {
+            let (newest, phase) = (self.position / self.up, self.position % self.up);
+            if newest >= self.history.len() {
+                break;
+            }
+            let branch = &self.filter[phase * self.taps..(phase + 1) * self.taps];
+            let samples = self.history[newest + 1 - self.taps..=newest].iter().rev();
+            output.push(branch.iter().zip(samples).map(|(h, x)| h * x).sum());
+            self.position += self.down;
+        }
+        // keep the taps before the next output
+        let consumed = (self.position / self.up + 1 - self.taps).min(self.history.len());
+        self.history.drain(..consumed);
+        self.position -= consumed * self.up;
+        output
+    }
+
+    /// Input to give [`Resampler::process`] for about `output` samples.
+    pub fn input_for(&self, output: usize) -> usize {
+        (output * self.down).div_ceil(self.up).max(1)
+    }
+}
+
+fn gcd(mut a: u32, mut b: u32) -> u32 {
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a
+}
+
+fn sinc(x: f64) -> f64 {
+    if x == 0.0 {
+        1.0
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+// modified Bessel function of the first kind, of order 0
+fn bessel_i0(x: f64) -> f64 {
+    let (mut sum, mut term) = (1.0, 1.0);
+    for k in 1..50 {
+        term *= (x / 2.0 / k as f64).powi(2);
+        sum += term;
+        if term < sum * 1e-12 {
+            break;
+        }
+    }
+    sum
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::vad::energy_db;
+
+    fn tone(freq: f32, rate: u32, len: usize) -> Vec<f32> {
+        (0..len)
+            .map(|i| (2.0 * std::f32::consts::PI * freq * i as f32 / rate as f32).sin() * 0.5)
+            .collect()
+    }
+
+    // frequency of a tone by its rising zero crossings, interpolated between samples
+    fn frequency(samples: &[f32], rate: u32) -> f32 {
+        let crossings: Vec<f32> = samples
+            .windows(2)
+            .enumerate()
+            .filter(|(_, pair)| pair[0] < 0.0 && pair[1] >= 0.0)
+            .map(|(i, pair)| i as f32 + pair[0] / (pair[0] - pair[1]))
+            .collect();
+        let periods = (crossings.len() - 1) as f32;
+        periods * rate as f32 / (crossings[crossings.len() - 1] - crossings[0])
+    }
+
+    // skip the start, while the filter fills
+    fn steady(samples: &[f32]) -> &[f32] {
+        &samples[samples.len() / 10..]
+    }
+
+    #[test]
+    fn test_tone_frequency() {
+        for (from, to) in [
+            (44100, 48000),
+            (48000, 44100),
+            (16000, 48000),
+            (96000, 48000),
+        ] {
+            let mut resampler = Resampler::new(from, to).unwrap();
+            let output = resampler.process(&tone(1000.0, from, from as usize));
+            let expected = to as usize;
+            assert!(output.len().abs_diff(expected) <= 2, "{} to {}", from, to);
+            let freq = frequency(steady(&output), to);
+            assert!(
+                (freq - 1000.0).abs() < 0.1,
+                "{} to {}: {} Hz",
+                from,
+                to,
+                freq
+            );
+            // the level is kept
+            let level = energy_db(steady(&output)) - energy_db(&tone(1000.0, to, 4800));
+            assert!(level.abs() < 0.1, "{} to {}: {} dB", from, to, level);
+        }
+    }
+
+    #[test]
+    fn test_no_aliasing() {
+        // above 8 kHz, the Nyquist frequency at 16 kHz, it would alias to 6 kHz
+        let mut resampler = Resampler::new(48000, 16000).unwrap();
+        let output = resampler.process(&tone(10000.0, 48000, 48000));
+        assert!(energy_db(steady(&output)) < -70.0);
+    }
+
+    #[test]
+    fn test_streaming() {
+        let input = tone(440.0, 44100, 44100);
+        let mut resampler = Resampler::new(44100, 48000).unwrap();
+        let whole = resampler.process(&input);
+        let mut resampler = Resampler::new(44100, 48000).unwrap();
+        let mut chunked = vec![];
+        for chunk in input.chunks(441 + 7) {
+            chunked.extend(resampler.process(chunk));
+        }
+        assert_eq!(whole, chunked);
+        // a request for output gets at least about that much
+        let mut resampler = Resampler::new(48000, 44100).unwrap();
+        let n = resampler.input_for(441);
+        assert!(resampler.process(&vec![0.0; n]).len() + 1 >= 441);
+    }
+
+    #[test]
+    fn test_same_and_odd_rates() {
+        let mut resampler = Resampler::new(48000, 48000).unwrap();
+        assert_eq!(resampler.process(&[0.1, 0.2]), [0.1, 0.2]);
+        assert!(Resampler::new(47999, 48000).is_none());
+        assert!(Resampler::new(0, 48000).is_none());
+    }
+}
@@ -0,0 +1,325 @@
+//! Voice Activity Detection.
+//!
+//! [`Vad`] tells whether a frame of the microphone is speech, so silence is not sent.
+//! A frame is voiced when its energy rises above the noise floor and it crosses zero
+//! rarely: hiss and other broadband noise cross zero far more often than voice does.
+//! The noise floor follows unvoiced frames, and rises only slowly while voiced.
+//!
+//! Talking starts after [`ONSET_FRAMES`] voiced frames in a row, and stops after
+//! [`HANGOVER_FRAMES`] unvoiced ones, so pauses between words don't cut it.
+//! Starting needs a louder frame than keeping on talking does.
+
+/// Voiced frames in a row to start talking.
+pub const ONSET_FRAMES: usize = 2;
+/// Unvoiced frames in a row to stop talking, 300ms.
+pub const HANGOVER_FRAMES: u32 = 15;
+// above the noise floor in dB, to start talking, and to keep on talking
+const START_DB: f32 = 12.0;
+const CONTINUE_DB: f32 = 6.0;
+// quieter frames are never speech, in dBFS
+const MIN_SPEECH_DB: f32 = -55.0;
+// voice crosses zero at most this often per sample
+const MAX_ZCR: f32 = 0.3;
+// noise floor in dBFS, at first and at least
+const INITIAL_FLOOR_DB: f32 = -60.0;
+const MIN_FLOOR_DB: f32 = -90.0;
+// how much the floor moves toward unvoiced frames, louder or quieter ones
+const FLOOR_RISE: f32 = 0.05;
+const FLOOR_FALL: f32 = 0.2;
+// most the floor rises by a voiced frame in dB, so a steady new noise is caught up in seconds
+const VOICED_FLOOR_RISE_DB: f32 = 0.02;
+
+/// Voice activity of a frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Activity {
+    /// Not talking.
+    Silent,
+    /// Talking starts with this frame.
+    Started,
+    /// Still talking, the frame is voiced or in the hangover.
+    Speaking,
+    /// Talking stopped, the frame is silent.
+    Stopped,
+}
+
+impl Activity {
+    /// Whether the frame is talked, and should be sent.
+    pub fn is_active(self) -> bool {
+        matches!(self, Activity::Started | Activity::Speaking)
+    }
+}
+
+/// Voice activity detector of one microphone, fed with its frames in order.
+#[derive(Debug)]
+pub struct Vad {
+    floor: f32, // in dBFS
+    talking: bool,
+    voiced_in_row: usize,
+    unvoiced_in_row: u32,
+}
+
+impl Default for Vad {
+    fn default() -> Self {
+        Self {
+            floor: INITIAL_FLOOR_DB,
+            talking: false,
+            voiced_in_row: 0,
+            unvoiced_in_row: 0,
+        }
+    }
+}
+
+impl Vad {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether the user is talking, as of the last frame.
+    pub fn is_talking(&self) -> bool {
+        self.talking
+    }
+
+    /// Estimated noise floor in dBFS.
+    pub fn noise_floor(&self) -> f32 {
+        self.floor
+    }
+
+    /// Voice activity of the next frame.
+    pub fn detect(&mut self, frame: &[f32]) -> Activity {
+        let energy = energy_db(frame);
+        let threshold = if self.talking { CONTINUE_DB } else { START_DB };
+        let voiced = energy >= MIN_SPEECH_DB
+            && energy - self.floor >= threshold
+            && zero_crossing_rate(frame) <= MAX_ZCR;
+        self.track_floor(energy, voiced);
+
+        if voiced {
+            self.voiced_in_row += 1;
+            self.unvoiced_in_row = 0;
+        } else {
+            self.voiced_in_row = 0;
+            self.unvoiced_in_row += 1;
+        }
+        match self.talking {
+            false if self.voiced_in_row >= ONSET_FRAMES => {
+                self.talking = true;
+                Activity::Started
+            }
+            false => Activity::Silent,
+            true if self.unvoiced_in_row >= HANGOVER_FRAMES => {
+                self.talking = false;
+                Activity::Stopped
+            }
+            true => Activity::Speaking,
+        }
+    }
+
+    fn track_floor(&mut self, energy: f32, voiced: bool) {
+        if energy < self.floor {
+            self.floor += (energy - self.floor) * FLOOR_FALL;
+        } else if voiced {
+            self.floor += VOICED_FLOOR_RISE_DB.min(energy - self.floor);
+        } else {
+            self.floor += (energy - self.floor) * FLOOR_RISE;
+        }
+        self.floor = self.floor.max(MIN_FLOOR_DB);
+    }
+}
+
+/// Mean energy of samples in dBFS, a full scale sine is about -3 dB.
+pub fn energy_db(samples: &[f32]) -> f32 {
+    if samples.is_empty() {
+        return f32::NEG_INFINITY;
+    }
+    let power = samples.iter().map(|v| v * v).sum::<f32>() / samples.len() as f32;
+    10.0 * power.max(1e-12).log10()
+}
+
+/// Rate of sign changes between adjacent samples, from 0 to 1.
+pub fn zero_crossing_rate(samples: &[f32]) -> f32 {
+    if samples.len() < 2 {
+        return 0.0;
+    }
+    let crossings = samples
+        .windows(2)
+        .filter(|pair| (pair[0] >= 0.0) != (pair[1] >= 0.0))
+        .count();
+    crossings as f32 / (samples.len() - 1) as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use abi::codec::{FRAME_SIZE, SAMPLE_RATE};
+    use std::f32::consts::PI;
+
+    // deterministic white noise
+    struct Noise(u32);
+
+    impl Noise {
+        fn next(&mut self) -> f32 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 17;
+            self.0 ^= self.0 << 5;
+            self.0 as f32 / u32::MAX as f32 * 2.0 - 1.0
+        }
+    }
+
+    // scale samples to the energy of `db`
+    fn level(mut samples: Vec<f32>, db: f32) -> Vec<f32> {
+        let gain = 10f32.powf((db - energy_db(&samples)) / 20.0);
+        samples.iter_mut().for_each(|v| *v *= gain);
+        samples
+    }
+
+    fn len(ms: usize) -> usize {
+        SAMPLE_RATE as usize * ms / 1000
+    }
+
+    fn noise(ms: usize, db: f32) -> Vec<f32> {
+        let mut noise = Noise(0x2545f491);
+        level((0..len(ms)).map(|_| noise.next()).collect(), db)
+    }
+
+    // a quiet room under `talk`
+    fn in_room(mut talk: Vec<f32>) -> Vec<f32> {
+        let mut noise = Noise(0x2545f491);
+        let room = level((0..talk.len()).map(|_| noise.next()).collect(), -60.0);
+        talk.iter_mut().zip(room).for_each(|(v, n)| *v += n);
+        talk
+    }
+
+    // vowels of a gliding pitch with harmonics, in syllables of 4 Hz
+    fn speech(ms: usize, db: f32) -> Vec<f32> {
+        let mut phase = 0.0;
+        let samples = (0..len(ms))
+            .map(|i| {
+                let t = i as f32 / SAMPLE_RATE as f32;
+                let pitch = 140.0 + 20.0 * (2.0 * PI * 3.0 * t).sin();
+                phase += 2.0 * PI * pitch / SAMPLE_RATE as f32;
+                let voice: f32 = (1..=8).map(|k| (k as f32 * phase).sin() / k as f32).sum();
+                let syllable = 0.3 + 0.7 * (PI * 4.0 * t).sin().abs();
+                voice * syllable
+            })
+            .collect();
+        level(samples, db)
+    }
+
+    fn detect(vad: &mut Vad, samples: &[f32]) -> Vec<Activity> {
+        samples
+            .chunks_exact(FRAME_SIZE)
+            .map(|frame| vad.detect(frame))
+            .collect()
+    }
+
+    // indexes of frames where talking starts and stops
+    fn edges(activities: &[Activity]) -> (Vec<usize>, Vec<usize>) {
+        let find = |target| {
+            (activities.iter().enumerate())
+                .filter(|(_, a)| **a == target)
+                .map(|(i, _)| i)
+                .collect()
+        };
+        (find(Activity::Started), find(Activity::Stopped))
+    }
+
+    #[test]
+    fn test_features() {
+        assert_eq!(energy_db(&[0.0; 480]), -120.0);
+        assert!((energy_db(&[0.5; 480]) + 6.02).abs() < 0.01);
+        assert_eq!(zero_crossing_rate(&[1.0, -1.0, 1.0, -1.0, 1.0]), 1.0);
+        assert!(zero_crossing_rate(&speech(100, -20.0)) < 0.05);
+        assert!(zero_crossing_rate(&noise(100, -20.0)) > 0.4);
+    }
+
+    #[test]
+    fn test_silence_and_noise() {
+        let mut vad = Vad::new();
+        assert!(detect(&mut vad, &vec![0.0; len(1000)])
+            .iter()
+            .all(|a| *a == Activity::Silent));
+        // loud noise is not speech, it raises the floor instead
+        let mut vad = Vad::new();
+        for db in [-50.0, -30.0, -10.0] {
+            let activities = detect(&mut vad, &noise(2000, db));
+            assert!(activities.iter().all(|a| *a == Activity::Silent), "{}", db);
+        }
+        assert!(vad.noise_floor() > -15.0, "floor: {}", vad.noise_floor());
+    }
+
+    #[test]
+    fn test_speech_in_noise() {
+        let talk = [
+            vec![0.0; len(1000)],
+            speech(1000, -25.0),
+            vec![0.0; len(1000)],
+        ]
+        .concat();
+        let mut vad = Vad::new();
+        let activities = detect(&mut vad, &in_room(talk));
+
+        // starts once the onset is voiced, stops after the hangover
+        let frames = 1000 / 20;
+        let (started, stopped) = edges(&activities);
+        assert_eq!(started, [frames + ONSET_FRAMES - 1]);
+        let stop = 2 * frames + HANGOVER_FRAMES as usize - 1;
+        assert!(
+            stopped.len() == 1 && stopped[0].abs_diff(stop) <= 2,
+            "{:?}",
+            stopped
+        );
+        assert!(activities[started[0]..stopped[0]]
+            .iter()
+            .all(|a| a.is_active()));
+        assert!(!activities[..started[0]].iter().any(|a| a.is_active()));
+        assert!(!activities[stopped[0]..].iter().any(|a| a.is_active()));
+        assert!(!vad.is_talking());
+    }
+
+    #[test]
+    fn test_hangover_bridges_pauses() {
+        let pause = (HANGOVER_FRAMES as usize - 5) * 20;
+        let talk = [
+            speech(500, -25.0),
+            vec![0.0; len(pause)],
+            speech(500, -25.0),
+            vec![0.0; len(1000)],
+        ]
+        .concat();
+        let mut vad = Vad::new();
+        let activities = detect(&mut vad, &in_room(talk));
+        let (started, stopped) = edges(&activities);
+        assert_eq!(started.len(), 1);
+        assert_eq!(stopped.len(), 1);
+        assert!(stopped[0] > (1000 + pause) / 20);
+
+        // a longer pause stops talking
+        let pause = (HANGOVER_FRAMES as usize + 5) * 20;
+        let talk = [
+            speech(500, -25.0),
+            vec![0.0; len(pause)],
+            speech(500, -25.0),
+        ]
+        .concat();
+        let mut vad = Vad::new();
+        let activities = detect(&mut vad, &in_room(talk));
+        let (started, stopped) = edges(&activities);
+        assert_eq!((started.len(), stopped.len()), (2, 1));
+    }
+
+    #[test]
+    fn test_hysteresis() {
+        // between the thresholds to start and to keep on talking
+        let quiet = -60.0 + (START_DB + CONTINUE_DB) / 2.0;
+        let mut vad = Vad::new();
+        let activities = detect(&mut vad, &in_room(speech(1000, quiet)));
+        assert!(activities.iter().all(|a| *a == Activity::Silent));
+
+        let talk = [speech(500, -25.0), speech(1000, quiet)].concat();
+        let mut vad = Vad::new();
+        let activities = detect(&mut vad, &in_room(talk));
+        let (started, stopped) = edges(&activities);
+        assert_eq!((started.len(), stopped.len()), (1, 0));
+        assert!(vad.is_talking());
+    }
+}
@@ -0,0 +1,217 @@
+//! WAV files as audio backends, to run without a sound card.
+//!
+//! [`WavSource`] plays a file as the microphone in real time, and [`WavSink`] writes
+//! everything heard to a file. Files heard are mono at [`SAMPLE_RATE`], files played may
+//! be of any rate and channels.
+
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use super::resample::Resampler;
+use super::{capture, downmix, render, AudioSink, AudioSource, AudioStream, Mixer};
+use crate::config::UserConfig;
+use crate::control::Control;
+use crate::jitter::Playback;
+use abi::codec::{FRAME_SIZE, SAMPLE_RATE};
+use abi::error::Error;
+use abi::Result;
+use hound::{SampleFormat, WavReader, WavSpec, WavWriter};
+use log::error;
+use ringbuffer::AllocRingBuffer;
+
+fn file_error(e: impl std::fmt::Display) -> Error {
+    Error::AudioFile(e.to_string())
+}
+
+/// A WAV file recorded as the microphone, from the start each time it's started.
+/// Recording stops at the end of the file.
+#[derive(Debug, Clone)]
+pub struct WavSource {
+    samples: Arc<[f32]>,
+}
+
+impl WavSource {
+    /// Read a file of integer or float samples, mixed down to mono and resampled to
+    /// [`SAMPLE_RATE`].
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let mut reader = WavReader::open(path).map_err(file_error)?;
+        let spec = reader.spec();
+        let mut resampler = Resampler::new(spec.sample_rate, SAMPLE_RATE).ok_or_else(|| {
+            Error::AudioFile(format!("unsupported sample rate {}", spec.sample_rate))
+        })?;
+        let samples: std::result::Result<Vec<f32>, hound::Error> = match spec.sample_format {
+            SampleFormat::Float => reader.samples::<f32>().collect(),
+            SampleFormat::Int => {
+                let scale = 1.0 / (1i64 << (spec.bits_per_sample - 1)) as f32;
+                reader
+                    .samples::<i32>()
+                    .map(|v| v.map(|v| v as f32 * scale))
+                    .collect()
+            }
+        };
+        let mono = downmix(&samples.map_err(file_error)?, spec.channels as usize);
+        Ok(Self {
+            samples: resampler.process(&mono).into(),
+        })
+    }
+
+    /// Duration of the file.
+    pub fn duration(&self) -> Duration {
+        Duration::from_secs_f64(self.samples.len() as f64 / SAMPLE_RATE as f64)
+    }
+}
+
+impl AudioSource for WavSource {
+    fn start(
+        &mut self,
+        buffer: Arc<Mutex<AllocRingBuffer<f32>>>,
+        control: Control,
+    ) -> Result<AudioStream> {
+        let samples = Arc::clone(&self.samples);
+        let mut pos = 0;
+        Ok(AudioStream::tick(move || {
+            let end = (pos + FRAME_SIZE).min(samples.len());
+            capture(&samples[pos..end], &buffer, &control);
+            pos = end;
+            pos < samples.len()
+        }))
+    }
+}
+
+/// A WAV file of everything heard, as 32-bit float mono at [`SAMPLE_RATE`].
+///
+/// The file is created anew each time it's started, and complete once the stream is dropped.
+#[derive(Debug, Clone)]
+pub struct WavSink {
+    path: PathBuf,
+}
+
+impl WavSink {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+}
+
+impl AudioSink for WavSink {
+    fn start(
+        &mut self,
+        playback: Arc<Mutex<Playback>>,
+        config: Arc<UserConfig>,
+        control: Control,
+    ) -> Result<AudioStream> {
+        let spec = WavSpec {
+            channels: 1,
+            sample_rate: SAMPLE_RATE,
+            bits_per_sample: 32,
+            sample_format: SampleFormat::Float,
+        };
+        let mut writer = WavWriter::create(&self.path, spec).map_err(file_error)?;
+        let mut mixer = Mixer::new();
+        Ok(AudioStream::tick(move || {
+            let mut data = [0.0; FRAME_SIZE];
+            render(&mut data, &playback, &mut mixer, &config, &control);
+            match data.iter().try_for_each(|v| writer.write_sample(*v)) {
+                Ok(()) => true,
+                Err(e) => {
+                    error!("failed to write wav: {}", e);
+                    false
+                }
+            }
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::{ToBytes, RING_BUFFER_SIZE};
+    use abi::pb::Codec;
+    use ringbuffer::RingBuffer;
+    use std::thread;
+
+    #[test]
+    fn test_wav_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let spec = WavSpec {
+            channels: 1,
+            sample_rate: SAMPLE_RATE,
+            bits_per_sample: 16,
+            sample_format: SampleFormat::Int,
+        };
+        let path = dir.path().join("said.wav");
+        let mut writer = WavWriter::create(&path, spec).unwrap();
+        for _ in 0..FRAME_SIZE * 2 {
+            writer.write_sample(i16::MAX / 2 + 1).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        let config = Arc::new(UserConfig::default());
+        let control = Control::new(&config);
+        let mut source = WavSource::open(&path).unwrap();
+        assert_eq!(source.duration(), Duration::from_millis(40));
+        let buf = Arc::new(Mutex::new(AllocRingBuffer::new(RING_BUFFER_SIZE)));
+        let stream = source.start(buf.clone(), control.clone()).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        drop(stream);
+        // the file ended, nothing more is recorded
+        let said = buf.lock().unwrap().to_vec();
+        assert_eq!(said, vec![0.5; FRAME_SIZE * 2]);
+
+        let playback = Arc::new(Mutex::new(Playback::new()));
+        playback
+            .lock()
+            .unwrap()
+            .push("test_user", Codec::PcmF32, 0, 0, said.to_bytes());
+        let path = dir.path().join("heard.wav");
+        let stream = WavSink::new(&path)
+            .start(playback, config, control)
+            .unwrap();
+        thread::sleep(Duration::from_millis(100));
+        drop(stream);
+
+        let heard = WavSource::open(&path).unwrap();
+        assert!(heard.samples.len() >= FRAME_SIZE * 2);
+        assert!(heard.samples.iter().any(|v| *v != 0.0));
+        assert!(heard.samples.iter().all(|v| *v == 0.0 || *v == 0.5));
+    }
+
+    #[test]
+    fn test_converted_wav() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("stereo.wav");
+        let spec = WavSpec {
+            channels: 2,
+            sample_rate: 44100,
+            bits_per_sample: 16,
+            sample_format: SampleFormat::Int,
+        };
+        let mut writer = WavWriter::create(&path, spec).unwrap();
+        for i in 0..44100 {
+            let v = (2.0 * std::f32::consts::PI * 441.0 * i as f32 / 44100.0).sin();
+            // the left channel only, at half the level when mixed down
+            writer.write_sample((v * i16::MAX as f32) as i16).unwrap();
+            writer.write_sample(0i16).unwrap();
+        }
+        writer.finalize().unwrap();
+        let source = WavSource::open(&path).unwrap();
+        assert!(source.duration().abs_diff(Duration::from_secs(1)) < Duration::from_millis(2));
+        let peak = source
+            .samples
+            .iter()
+            .fold(0f32, |peak, v| peak.max(v.abs()));
+        assert!((peak - 0.5).abs() < 0.01, "{}", peak);
+
+        let path = dir.path().join("odd.wav");
+        let spec = WavSpec {
+            sample_rate: 47999,
+            ..spec
+        };
+        WavWriter::create(&path, spec).unwrap().finalize().unwrap();
+        assert!(matches!(WavSource::open(&path), Err(Error::AudioFile(_))));
+        assert!(matches!(
+            WavSource::open(dir.path().join("missing.wav")),
+            Err(Error::AudioFile(_))
+        ));
+    }
+}
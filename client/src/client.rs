@@ -1,24 +1,66 @@
-use crate::audio::{Microphone, Speaker};
+use crate::audio::vad::{Activity, Vad, ONSET_FRAMES};
+use crate::audio::{AudioSink, AudioSource, Microphone, Speaker};
 use crate::config::UserConfig;
-use crate::utils::{Buffer, RING_BUFFER_SIZE};
-use crate::utils::{FromBytes, ToBytes};
+use crate::control::Control;
+use crate::jitter::Playback;
+use crate::utils::RING_BUFFER_SIZE;
+use abi::codec::{self, Encoder};
 use abi::error::Error;
 use abi::pb::message::Content;
 use abi::pb::{
     channel_service_client::ChannelServiceClient, chat_service_client::ChatServiceClient,
-    user_service_client::UserServiceClient, Channel, RegisterRequest,
+    user_service_client::UserServiceClient, Channel, HistoryRequest, HistoryResponse,
+    MemberRequest, RegisterRequest,
+};
+use abi::pb::{
+    Codec, LoginRequest, LoginResponse, LogoutRequest, Message, Reconnect, RefreshRequest, User,
+    VoiceState,
 };
-use abi::pb::{LoginRequest, Message};
 use abi::traits::WithToken;
 use abi::Result;
-use cpal::traits::StreamTrait;
-use log::error;
+use log::{error, info};
+use rand::Rng;
 use ringbuffer::{AllocRingBuffer, RingBuffer};
+use std::collections::VecDeque;
 use std::str::FromStr;
 use std::sync::{Arc, Mutex};
-use tokio::sync::mpsc::Receiver;
-use tonic::transport::Endpoint;
-use tonic::Request;
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tokio::sync::mpsc::{Receiver, Sender};
+use tonic::transport::{Certificate, ClientTlsConfig, Endpoint};
+use tonic::{Request, Streaming};
+
+// events kept for a slow subscriber, older ones are skipped
+const EVENT_BUFFER_SIZE: usize = 64;
+// reconnecting after a migration, given up after the attempts. Delays double from the
+// least, jittered in their upper half, so listening again keeps under the manager's limit
+// of once a second, and users of a migrated channel don't all come at once.
+const RECONNECT_ATTEMPTS: u32 = 5;
+const RECONNECT_DELAY_MIN: Duration = Duration::from_secs(2);
+const RECONNECT_DELAY_MAX: Duration = Duration::from_secs(10);
+
+/// Event on the listening channel, for the user interface.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    /// A text message from some user, or own one echoed back by the server as `echo`.
+    Text {
+        user_id: String,
+        timestamp: i64, // server's, in milliseconds
+        text: String,
+        echo: bool,
+    },
+    /// Voice state of some user, or own one, changed by the user or a moderator.
+    Voice { user_id: String, state: VoiceState },
+    /// Users on the channel with their voice states, own one included, once connected.
+    Roster { users: Vec<User> },
+    /// Some user joined the channel.
+    Join { user: User },
+    /// Some user left the channel, or was removed from it.
+    Leave { user_id: String },
+    /// Some user starts or stops talking, or own one as detected by [`Vad`].
+    Speaking { user_id: String, speaking: bool },
+}
+
 /// Audio Client
 pub struct Client {
     // User ID, currently logged in.
@@ -30,6 +72,9 @@ pub struct Client {
     // Token of logging in.
     token: Option<String>,
 
+    // Token to refresh `token` when it expires, used once.
+    refresh_token: Option<String>,
+
     // User Service Client.
     // For now, addr is same as manager addr.
     user_client: UserServiceClient<tonic::transport::Channel>,
@@ -37,34 +82,60 @@ pub struct Client {
     // Channel Manager Client. Manager addr must be provided at **new()**.
     mgr_client: ChannelServiceClient<tonic::transport::Channel>,
 
-    // hold the audio data buffer from the listening channel.
-    buffer: Arc<Mutex<Buffer<f32>>>,
+    // TLS to manager and chat servers of https addrs, see [`Client::with_root_cas`].
+    tls: Option<ClientTlsConfig>,
+
+    // hold the audio data from the listening channel until played.
+    playback: Arc<Mutex<Playback>>,
 
     // hold the audio data of own microphone.
     buf: Arc<Mutex<AllocRingBuffer<f32>>>,
 
-    speaker: Speaker,
+    // events to subscribers, see [`Client::events`].
+    events: broadcast::Sender<Event>,
+
+    // runtime controls, see [`Client::control`].
+    control: Control,
 
-    microphone: Microphone,
+    // where own audio comes from, the default microphone if unset, see [`Client::set_audio`].
+    source: Option<Box<dyn AudioSource>>,
+
+    // where audio heard goes, the default speaker if unset.
+    sink: Option<Box<dyn AudioSink>>,
 }
 
 /// Impl Client Methods for User Service
 #[allow(dead_code)]
 impl Client {
     pub async fn new(mgr_addr: String) -> Result<Client> {
-        let conn = Endpoint::from_str(&mgr_addr)?.connect().await?;
+        Self::connect(mgr_addr, None).await
+    }
+
+    /// Connect servers by TLS, trusting only the root CAs given in PEM.
+    pub async fn with_root_cas(mgr_addr: String, cas: &[impl AsRef<[u8]>]) -> Result<Client> {
+        let tls = ClientTlsConfig::new().ca_certificates(cas.iter().map(Certificate::from_pem));
+        Self::connect(mgr_addr, Some(tls)).await
+    }
+
+    async fn connect(mgr_addr: String, tls: Option<ClientTlsConfig>) -> Result<Client> {
+        let conn = endpoint(&mgr_addr, tls.as_ref())?.connect().await?;
+        let config = UserConfig::default();
         Ok(Client {
             user_id: None,
-            config: Arc::new(UserConfig::default()),
+            control: Control::new(&config),
+            config: Arc::new(config),
             token: None,
+            refresh_token: None,
             mgr_client: ChannelServiceClient::new(conn.clone()),
             user_client: UserServiceClient::new(conn),
+            tls,
 
-            buffer: Arc::new(Mutex::new(Buffer::new())),
+            playback: Arc::new(Mutex::new(Playback::new())),
             buf: Arc::new(Mutex::new(AllocRingBuffer::new(RING_BUFFER_SIZE))),
+            events: broadcast::channel(EVENT_BUFFER_SIZE).0,
 
-            speaker: Speaker::default(),
-            microphone: Microphone::default(),
+            source: None,
+            sink: None,
         })
     }
 
@@ -87,9 +158,36 @@ impl Client {
         self.user_id = Some(user_id.clone());
         let req = LoginRequest { user_id, password };
         let res = self.user_client.login(req).await?.into_inner();
-        self.token = Some(res.token);
+        self.set_tokens(res);
+        Ok(())
+    }
+
+    /// Refresh the access token before it expires, the refresh token is rotated too.
+    pub async fn refresh(&mut self) -> Result<()> {
+        let refresh_token = check_token(&self.refresh_token)?.clone();
+        let req = RefreshRequest { refresh_token };
+        let res = self.user_client.refresh(req).await?.into_inner();
+        self.set_tokens(res);
         Ok(())
     }
+
+    /// Logout, tokens of this session are revoked on server.
+    ///
+    /// Access tokens of the user's other sessions are revoked too, they refresh theirs.
+    pub async fn logout(&mut self) -> Result<()> {
+        let refresh_token = check_token(&self.refresh_token)?.clone();
+        self.user_client
+            .logout(LogoutRequest { refresh_token })
+            .await?;
+        self.token = None;
+        self.refresh_token = None;
+        Ok(())
+    }
+
+    fn set_tokens(&mut self, res: LoginResponse) {
+        self.token = Some(res.token);
+        self.refresh_token = Some(res.refresh_token);
+    }
 }
 
 /// Impl Client Methods for Channel Service
@@ -132,8 +230,66 @@ impl Client {
         Ok(rsp.channels)
     }
 
-    /// Connect to a channel.
-    async fn connect(&mut self, id: i32, input: Receiver<Message>) -> Result<Receiver<Message>> {
+    /// Get a page of a channel's text history, newest first.
+    ///
+    /// `cursor` is 0 for the newest messages, or `next_cursor` of the last page;
+    /// `limit` is 0 for the server's default.
+    pub async fn history(&mut self, id: i32, cursor: i64, limit: i32) -> Result<HistoryResponse> {
+        let token = check_token(&self.token)?;
+        let req = Request::new(HistoryRequest {
+            channel_id: id,
+            cursor,
+            limit,
+        })
+        .with(token);
+        Ok(self.mgr_client.history(req).await?.into_inner())
+    }
+
+    /// Make a user moderator of a channel, by its owner.
+    pub async fn grant(&mut self, id: i32, user_id: String) -> Result<()> {
+        let req = self.member_request(id, user_id)?;
+        self.mgr_client.grant(req).await?;
+        Ok(())
+    }
+
+    /// Take moderator rights of a user back, by the channel's owner.
+    pub async fn revoke(&mut self, id: i32, user_id: String) -> Result<()> {
+        let req = self.member_request(id, user_id)?;
+        self.mgr_client.revoke(req).await?;
+        Ok(())
+    }
+
+    /// Disconnect a user from a channel, by its owner or moderators.
+    pub async fn kick(&mut self, id: i32, user_id: String) -> Result<()> {
+        let req = self.member_request(id, user_id)?;
+        self.mgr_client.kick(req).await?;
+        Ok(())
+    }
+
+    /// Kick a user, and keep it out of a channel until unbanned.
+    pub async fn ban(&mut self, id: i32, user_id: String) -> Result<()> {
+        let req = self.member_request(id, user_id)?;
+        self.mgr_client.ban(req).await?;
+        Ok(())
+    }
+
+    pub async fn unban(&mut self, id: i32, user_id: String) -> Result<()> {
+        let req = self.member_request(id, user_id)?;
+        self.mgr_client.unban(req).await?;
+        Ok(())
+    }
+
+    fn member_request(&self, id: i32, user_id: String) -> Result<Request<MemberRequest>> {
+        let token = check_token(&self.token)?;
+        Ok(Request::new(MemberRequest {
+            channel_id: id,
+            user_id,
+        })
+        .with(token))
+    }
+
+    /// Listen to a channel, return the chat server's addr and token to connect it.
+    async fn listen(&mut self, id: i32) -> Result<(String, String)> {
         let token = check_token(&self.token)?;
         let req = Request::new(Channel {
             id,
@@ -142,89 +298,343 @@ impl Client {
         .with(token);
         let rsp = self.mgr_client.listen(req).await?.into_inner();
         if let Some(server) = rsp.server {
-            let mut client = ChatServiceClient::connect(server.addr).await?;
-            let send_stream = tokio_stream::wrappers::ReceiverStream::new(input);
-            let rsp = client.conn(Request::new(send_stream).with(token)).await?;
-            let mut recv_stream = rsp.into_inner();
-            let (tx, rx) = tokio::sync::mpsc::channel(32);
-            tokio::spawn(async move {
-                while let Ok(msg) = recv_stream.message().await {
-                    if let Some(msg) = msg {
-                        tx.send(msg).await.unwrap();
-                    } else {
-                        break;
-                    }
-                }
-            });
-            Ok(rx)
+            Ok((server.addr, rsp.token))
         } else {
             Err(Error::ServerNotFound)
         }
     }
 
+    /// Subscribe events of the channel being communicated on.
+    pub fn events(&self) -> broadcast::Receiver<Event> {
+        self.events.subscribe()
+    }
+
+    /// Handle to control talking and hearing, while communicating or before.
+    pub fn control(&self) -> Control {
+        self.control.clone()
+    }
+
+    /// Use `source` as the microphone and `sink` as the speaker, instead of sound devices.
+    ///
+    /// Set before communicating, e.g. [`wav`](crate::audio::wav) files to run headless.
+    pub fn set_audio(
+        &mut self,
+        source: impl AudioSource + 'static,
+        sink: impl AudioSink + 'static,
+    ) {
+        self.source = Some(Box::new(source));
+        self.sink = Some(Box::new(sink));
+    }
+
+    /// Communicate on a channel until `shutdown`.
+    ///
+    /// Audio is played and recorded until then, by the default sound devices unless
+    /// [`Client::set_audio`] was called.
+    /// When the channel is migrated to another chat server, reconnect to it transparently.
+    /// Own voice state of [`Client::control`] is told to every chat server connected.
     pub async fn communicate(
         &mut self,
         id: i32,
         mut shutdown: tokio::sync::broadcast::Receiver<()>,
     ) -> Result<()> {
-        let (tx, rx) = tokio::sync::mpsc::channel(32);
-        let mut rx = self.connect(id, rx).await?;
-        let speak_stream = self.speaker.play(self.buffer.clone(), self.config.clone());
-        speak_stream.play().unwrap();
+        let (tx, mut rx) = tokio::sync::mpsc::channel(32);
+        let sink = match &mut self.sink {
+            Some(sink) => sink,
+            None => self.sink.insert(Box::new(Speaker::open_default()?)),
+        };
+        // streams stop when dropped, as communicating stops
+        let _output = sink.start(
+            self.playback.clone(),
+            self.config.clone(),
+            self.control.clone(),
+        )?;
+        let source = match &mut self.source {
+            Some(source) => source,
+            None => self.source.insert(Box::new(Microphone::open_default()?)),
+        };
+        let _input = source.start(Arc::clone(&self.buf), self.control.clone())?;
 
-        let buffer = Arc::clone(&self.buffer);
-        let user_id = self.user_id.clone().unwrap();
-        let output = tokio::spawn(async move {
-            while let Some(msg) = rx.recv().await {
-                if msg.user_id != user_id {
-                    // todo: add audio_data field or define a serialization method
-                    if let Some(content) = msg.content {
-                        match content {
-                            Content::Text(_) => todo!("handle text"),
-                            Content::AudioData(data) => buffer
-                                .lock()
-                                .unwrap()
-                                .extend(msg.user_id, FromBytes::from_bytes(data)),
+        // changes of own voice state, until communicating stops
+        let mut voice = self.control.subscribe_voice();
+        let voice_tx = tx.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = voice_tx.closed() => break,
+                    changed = voice.changed() => {
+                        let state = *voice.borrow_and_update();
+                        if changed.is_err() || voice_tx.send(voice_message(state)).await.is_err() {
+                            break;
                         }
                     }
                 }
             }
         });
 
-        let input_stream = self
-            .microphone
-            .record(Arc::clone(&self.buf), self.config.clone());
-        input_stream.play().unwrap();
-
+        // recorded audio is encoded in frames, which are sent only while talking
         let buf = Arc::clone(&self.buf);
         let user_id = self.user_id.clone().unwrap();
-        let input = tokio::spawn(async move {
+        let events = self.events.clone();
+        let mut encoder = Encoder::new()?;
+        let mut vad = Vad::new();
+        let mut input = tokio::spawn(async move {
+            let mut interval = tokio::time::interval(codec::FRAME_DURATION);
+            let mut samples = Vec::new(); // of an incomplete frame
+            let mut onset = VecDeque::with_capacity(ONSET_FRAMES); // frames before talking starts
             loop {
-                let audio_data = buf.lock().unwrap().drain().collect::<Vec<f32>>().to_bytes();
-                if let Err(e) = tx
-                    .send(Message {
-                        user_id: user_id.clone(),
-                        timestamp: chrono::Utc::now().timestamp(),
-                        content: Some(Content::AudioData(audio_data)),
-                    })
-                    .await
-                {
-                    error!("error sending message: {}", e);
-                    break;
+                interval.tick().await;
+                samples.extend(buf.lock().unwrap().drain());
+                let complete = samples.len() - samples.len() % codec::FRAME_SIZE;
+                let mut frames = vec![];
+                for chunk in samples[..complete].chunks_exact(codec::FRAME_SIZE) {
+                    // every frame is encoded, so seq goes on in silence
+                    let activity = vad.detect(chunk);
+                    let frame = match encoder.encode(chunk) {
+                        Ok(mut encoded) => encoded.remove(0),
+                        Err(e) => {
+                            error!("error encoding audio: {}", e);
+                            return;
+                        }
+                    };
+                    match activity {
+                        Activity::Started => {
+                            frames.extend(onset.drain(..));
+                            frames.push(frame);
+                        }
+                        Activity::Speaking => frames.push(frame),
+                        Activity::Silent | Activity::Stopped => {
+                            if onset.len() == ONSET_FRAMES - 1 {
+                                onset.pop_front();
+                            }
+                            onset.push_back(frame);
+                        }
+                    }
+                    if matches!(activity, Activity::Started | Activity::Stopped) {
+                        let _ = events.send(Event::Speaking {
+                            user_id: user_id.clone(),
+                            speaking: activity.is_active(),
+                        });
+                    }
+                }
+                samples.drain(..complete);
+
+                for frame in frames {
+                    if let Err(e) = tx
+                        .send(Message {
+                            user_id: user_id.clone(),
+                            timestamp: chrono::Utc::now().timestamp(),
+                            content: Some(Content::AudioData(frame.data)),
+                            codec: Codec::Opus.into(),
+                            seq: frame.seq,
+                            echo: false,
+                        })
+                        .await
+                    {
+                        error!("error sending message: {}", e);
+                        return;
+                    }
                 }
             }
         });
 
-        drop(speak_stream);
-        drop(input_stream);
-        // any of these can be cancelled
-        tokio::select! {
-            _ = shutdown.recv() => {},
-            _ = input => {},
-            _ = output => {},
+        let user_id = self.user_id.clone().unwrap();
+        let (addr, token) = self.listen(id).await?;
+        let (conn_tx, conn_rx) = tokio::sync::mpsc::channel(32);
+        let mut conn = (
+            conn_tx,
+            connect(&addr, self.tls.as_ref(), &token, conn_rx).await?,
+        );
+        loop {
+            let (conn_tx, inbound) = conn;
+            // a new connection starts with the default voice state
+            let state = self.control.voice();
+            if state != VoiceState::default() {
+                let _ = conn_tx.send(voice_message(state)).await;
+            }
+            let (stop_tx, stop_rx) = tokio::sync::oneshot::channel();
+            let forwarder = tokio::spawn(forward(rx, conn_tx, stop_rx));
+
+            // any of these can be cancelled
+            let reconnect = tokio::select! {
+                _ = shutdown.recv() => None,
+                _ = &mut input => None,
+                reconnect = receive(inbound, Arc::clone(&self.playback), &self.events, &user_id) => reconnect,
+            };
+
+            let _ = stop_tx.send(());
+            rx = match forwarder.await {
+                Ok(rx) => rx,
+                Err(e) => {
+                    error!("forwarding to channel: {} failed: {}", id, e);
+                    break;
+                }
+            };
+            let Some(reconnect) = reconnect else {
+                break;
+            };
+            info!("reconnect to {}", reconnect.addr);
+            // users not reported to the manager yet get no token, and listen again
+            let target = (!reconnect.token.is_empty()).then_some((reconnect.addr, reconnect.token));
+            conn = tokio::select! {
+                _ = shutdown.recv() => break,
+                conn = self.reconnect(id, target) => conn?,
+            };
         }
         Ok(())
     }
+
+    /// Connect to a channel again, by `target` server and token if given, else by listening.
+    ///
+    /// Failures are retried with backoff, by listening again, as the channel may have moved
+    /// on. The last error is returned after [`RECONNECT_ATTEMPTS`].
+    async fn reconnect(
+        &mut self,
+        id: i32,
+        mut target: Option<(String, String)>,
+    ) -> Result<(Sender<Message>, Streaming<Message>)> {
+        let mut ceil = RECONNECT_DELAY_MIN;
+        for attempt in 1.. {
+            let result: Result<_> = async {
+                let (addr, token) = match target.take() {
+                    Some(target) => target,
+                    None => self.listen(id).await?,
+                };
+                let (conn_tx, conn_rx) = tokio::sync::mpsc::channel(32);
+                let inbound = connect(&addr, self.tls.as_ref(), &token, conn_rx).await?;
+                Ok((conn_tx, inbound))
+            }
+            .await;
+            match result {
+                Ok(conn) => return Ok(conn),
+                Err(e) if attempt == RECONNECT_ATTEMPTS => return Err(e),
+                Err(e) => error!("reconnect to channel: {} failed: {}", id, e),
+            }
+            let delay = ceil.mul_f64(rand::thread_rng().gen_range(0.5..=1.0));
+            tokio::time::sleep(delay).await;
+            ceil = (ceil * 2).min(RECONNECT_DELAY_MAX);
+        }
+        unreachable!("attempts are bounded")
+    }
+}
+
+// own voice state, for the chat server
+fn voice_message(state: VoiceState) -> Message {
+    Message {
+        content: Some(Content::Voice(state)),
+        ..Message::default()
+    }
+}
+
+/// Connect to a chat server with listen token, messages from `input` will be sent to it.
+async fn connect(
+    addr: &str,
+    tls: Option<&ClientTlsConfig>,
+    token: &str,
+    input: Receiver<Message>,
+) -> Result<Streaming<Message>> {
+    let conn = endpoint(addr, tls)?.connect().await?;
+    let mut client = ChatServiceClient::new(conn);
+    let send_stream = tokio_stream::wrappers::ReceiverStream::new(input);
+    let rsp = client.conn(Request::new(send_stream).with(token)).await?;
+    Ok(rsp.into_inner())
+}
+
+/// Endpoint of a server, by TLS if `tls` is set and the addr is https.
+fn endpoint(addr: &str, tls: Option<&ClientTlsConfig>) -> Result<Endpoint> {
+    let endpoint = Endpoint::from_str(addr)?;
+    match tls {
+        Some(tls) if addr.starts_with("https://") => Ok(endpoint.tls_config(tls.clone())?),
+        _ => Ok(endpoint),
+    }
+}
+
+/// Forward messages from `input` to `output` until `stop`, then give `input` back.
+async fn forward(
+    mut input: Receiver<Message>,
+    output: Sender<Message>,
+    mut stop: tokio::sync::oneshot::Receiver<()>,
+) -> Receiver<Message> {
+    loop {
+        tokio::select! {
+            _ = &mut stop => break,
+            msg = input.recv() => match msg {
+                Some(msg) => {
+                    if output.send(msg).await.is_err() {
+                        break;
+                    }
+                }
+                None => break,
+            },
+        }
+    }
+    input
+}
+
+/// Handle messages from chat server until disconnected.
+///
+/// Return the reconnect request if the channel is migrated.
+async fn receive(
+    mut inbound: Streaming<Message>,
+    playback: Arc<Mutex<Playback>>,
+    events: &broadcast::Sender<Event>,
+    user_id: &str,
+) -> Option<Reconnect> {
+    while let Ok(Some(msg)) = inbound.message().await {
+        let (codec, seq) = (msg.codec(), msg.seq);
+        match msg.content {
+            // own text comes back only as an echo
+            Some(Content::Text(text)) => {
+                // no subscriber is fine
+                let _ = events.send(Event::Text {
+                    echo: msg.user_id == user_id,
+                    user_id: msg.user_id,
+                    timestamp: msg.timestamp,
+                    text,
+                });
+            }
+            Some(Content::Voice(state)) => {
+                let _ = events.send(Event::Voice {
+                    user_id: msg.user_id,
+                    state,
+                });
+            }
+            Some(Content::Roster(roster)) => {
+                let _ = events.send(Event::Roster {
+                    users: roster.users,
+                });
+            }
+            Some(Content::Join(user)) => {
+                let _ = events.send(Event::Join { user });
+            }
+            Some(Content::Leave(())) => {
+                playback.lock().unwrap().remove(&msg.user_id);
+                let _ = events.send(Event::Leave {
+                    user_id: msg.user_id,
+                });
+            }
+            Some(Content::Speaking(speaking)) => {
+                let _ = events.send(Event::Speaking {
+                    user_id: msg.user_id,
+                    speaking,
+                });
+            }
+            Some(Content::AudioData(data)) if msg.user_id != user_id => {
+                playback.lock().unwrap().push(
+                    &msg.user_id,
+                    codec,
+                    seq,
+                    chrono::Utc::now().timestamp_millis(),
+                    data,
+                )
+            }
+            // only the server moves users, a user's reconnect is never obeyed
+            Some(Content::Reconnect(reconnect)) if msg.user_id.is_empty() => {
+                return Some(reconnect)
+            }
+            _ => {}
+        }
+    }
+    None
 }
 
 fn check_token(token: &Option<String>) -> Result<&String> {
@@ -1,23 +1,25 @@
 use std::collections::HashMap;
 
 pub struct UserConfig {
-    pub other_volume: HashMap<String, u8>, // percent
-    pub input_volume: u8,
-    pub output_volume: u8,
+    pub other_volume: HashMap<String, f32>, // dB
+    pub input_volume: f32,                  // dB
+    pub output_volume: f32,                 // dB
+    pub normalize: bool,                    // loudness of speakers, see [`crate::audio::loudness`]
 }
 
 impl UserConfig {
-    pub fn new(input_volume: u8, output_volume: u8) -> Self {
+    pub fn new(input_volume: f32, output_volume: f32) -> Self {
         Self {
             other_volume: HashMap::new(),
             input_volume,
             output_volume,
+            normalize: false,
         }
     }
 }
 
 impl Default for UserConfig {
     fn default() -> Self {
-        Self::new(100, 100)
+        Self::new(0.0, 0.0)
     }
 }
@@ -0,0 +1,210 @@
+//! Runtime controls of a [`Client`](crate::client::Client).
+//!
+//! [`Control`] is a cheap handle, cloned to the user interface and into the audio callbacks.
+//! Callbacks read it by atomics, never waiting on a lock. Self-mute and deafen are also
+//! published on a watch channel, so that the chat server is told of them.
+//!
+//! A closed microphone, muted or with push-to-talk released, records silence instead of
+//! nothing, so frames go on and only voice activity detection decides what is sent.
+
+use crate::audio::MAX_VOLUME_DB;
+use crate::config::UserConfig;
+use abi::pb::VoiceState;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::Arc;
+use tokio::sync::watch;
+
+#[derive(Debug)]
+struct Controls {
+    push_to_talk: AtomicBool,
+    pressed: AtomicBool, // the push-to-talk key is held
+    self_mute: AtomicBool,
+    deafen: AtomicBool,
+    normalize: AtomicBool,
+    input_volume: AtomicU32,  // bits of f32 in dB
+    output_volume: AtomicU32, // bits of f32 in dB
+    voice: watch::Sender<VoiceState>,
+}
+
+/// Handle to control a client while it communicates.
+#[derive(Debug, Clone)]
+pub struct Control {
+    inner: Arc<Controls>,
+}
+
+impl Control {
+    /// Controls with volumes and normalization of `config`, talking freely.
+    pub fn new(config: &UserConfig) -> Self {
+        Self {
+            inner: Arc::new(Controls {
+                push_to_talk: AtomicBool::new(false),
+                pressed: AtomicBool::new(false),
+                self_mute: AtomicBool::new(false),
+                deafen: AtomicBool::new(false),
+                normalize: AtomicBool::new(config.normalize),
+                input_volume: AtomicU32::new(config.input_volume.min(MAX_VOLUME_DB).to_bits()),
+                output_volume: AtomicU32::new(config.output_volume.min(MAX_VOLUME_DB).to_bits()),
+                voice: watch::Sender::new(VoiceState::default()),
+            }),
+        }
+    }
+
+    /// Talk only while the push-to-talk key is held, or freely.
+    pub fn set_push_to_talk(&self, enabled: bool) {
+        self.inner.push_to_talk.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Hold or release the push-to-talk key.
+    pub fn set_pressed(&self, pressed: bool) {
+        self.inner.pressed.store(pressed, Ordering::Relaxed);
+    }
+
+    /// Close the microphone, other users are told by the chat server.
+    pub fn set_self_mute(&self, mute: bool) {
+        self.inner.self_mute.store(mute, Ordering::Relaxed);
+        self.inner.voice.send_if_modified(|voice| {
+            let changed = voice.self_mute != mute;
+            voice.self_mute = mute;
+            changed
+        });
+    }
+
+    /// Stop hearing others, the chat server stops delivering audio too.
+    pub fn set_deafen(&self, deafen: bool) {
+        self.inner.deafen.store(deafen, Ordering::Relaxed);
+        self.inner.voice.send_if_modified(|voice| {
+            let changed = voice.self_deaf != deafen;
+            voice.self_deaf = deafen;
+            changed
+        });
+    }
+
+    /// Volume of the microphone in dB, up to [`MAX_VOLUME_DB`].
+    pub fn set_input_volume(&self, db: f32) {
+        self.inner
+            .input_volume
+            .store(db.min(MAX_VOLUME_DB).to_bits(), Ordering::Relaxed);
+    }
+
+    /// Volume of the speaker in dB, up to [`MAX_VOLUME_DB`].
+    pub fn set_output_volume(&self, db: f32) {
+        self.inner
+            .output_volume
+            .store(db.min(MAX_VOLUME_DB).to_bits(), Ordering::Relaxed);
+    }
+
+    /// Bring every speaker heard to the same loudness, or hear them as they are.
+    pub fn set_normalize(&self, normalize: bool) {
+        self.inner.normalize.store(normalize, Ordering::Relaxed);
+    }
+
+    pub fn is_push_to_talk(&self) -> bool {
+        self.inner.push_to_talk.load(Ordering::Relaxed)
+    }
+
+    pub fn is_self_muted(&self) -> bool {
+        self.inner.self_mute.load(Ordering::Relaxed)
+    }
+
+    pub fn is_deafened(&self) -> bool {
+        self.inner.deafen.load(Ordering::Relaxed)
+    }
+
+    pub fn is_normalizing(&self) -> bool {
+        self.inner.normalize.load(Ordering::Relaxed)
+    }
+
+    pub fn input_volume(&self) -> f32 {
+        f32::from_bits(self.inner.input_volume.load(Ordering::Relaxed))
+    }
+
+    pub fn output_volume(&self) -> f32 {
+        f32::from_bits(self.inner.output_volume.load(Ordering::Relaxed))
+    }
+
+    /// Whether the microphone is open: not muted, and the key held if push-to-talk.
+    pub fn is_transmitting(&self) -> bool {
+        !self.is_self_muted()
+            && (!self.is_push_to_talk() || self.inner.pressed.load(Ordering::Relaxed))
+    }
+
+    /// Volume in dB to apply to the microphone, silencing it when it's closed.
+    pub fn input_gain(&self) -> f32 {
+        if self.is_transmitting() {
+            self.input_volume()
+        } else {
+            f32::NEG_INFINITY
+        }
+    }
+
+    /// Volume in dB to apply to the speaker, silencing it when deafened.
+    pub fn output_gain(&self) -> f32 {
+        if self.is_deafened() {
+            f32::NEG_INFINITY
+        } else {
+            self.output_volume()
+        }
+    }
+
+    /// Own voice state to tell the chat server.
+    pub fn voice(&self) -> VoiceState {
+        *self.inner.voice.borrow()
+    }
+
+    /// Changes of the own voice state.
+    pub fn subscribe_voice(&self) -> watch::Receiver<VoiceState> {
+        self.inner.voice.subscribe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gains() {
+        let control = Control::new(&UserConfig::new(3.5, -6.0));
+        assert_eq!((control.input_gain(), control.output_gain()), (3.5, -6.0));
+
+        control.set_push_to_talk(true);
+        assert_eq!(control.input_gain(), f32::NEG_INFINITY);
+        control.set_pressed(true);
+        assert_eq!(control.input_gain(), 3.5);
+        control.set_self_mute(true);
+        assert!(!control.is_transmitting());
+        control.set_self_mute(false);
+        control.set_pressed(false);
+        assert!(!control.is_transmitting());
+        control.set_push_to_talk(false);
+        assert!(control.is_transmitting());
+
+        control.set_deafen(true);
+        assert_eq!(control.output_gain(), f32::NEG_INFINITY);
+        control.set_deafen(false);
+        control.set_output_volume(40.0);
+        assert_eq!(control.output_gain(), MAX_VOLUME_DB);
+        control.set_output_volume(-120.0);
+        assert_eq!(control.output_gain(), -120.0);
+    }
+
+    #[test]
+    fn test_voice_changes() {
+        let control = Control::new(&UserConfig::default());
+        let mut voice = control.subscribe_voice();
+        // push-to-talk, volumes and normalization are not told to the server
+        control.set_push_to_talk(true);
+        control.set_input_volume(-10.0);
+        control.set_normalize(true);
+        control.set_self_mute(false);
+        assert!(!voice.has_changed().unwrap());
+
+        control.set_self_mute(true);
+        control.set_deafen(true);
+        assert!(voice.has_changed().unwrap());
+        let state = *voice.borrow_and_update();
+        assert!(state.self_mute && state.self_deaf);
+        assert_eq!(control.voice(), state);
+        control.set_deafen(true);
+        assert!(!voice.has_changed().unwrap());
+    }
+}
@@ -0,0 +1,499 @@
+//! Adaptive Jitter Buffer.
+//!
+//! Frames of a speaker arrive late, lost or out of order. [`JitterBuffer`] holds them by
+//! sequence number, and plays them out in order after a delay, which adapts to the jitter
+//! measured on their arrival. [`Playback`] decodes the played frames of every speaker,
+//! and conceals lost ones, for the speaker callback to pull samples from.
+
+use abi::codec::{self, Decoder, FRAME_DURATION, FRAME_SIZE};
+use abi::pb::Codec;
+use abi::Result;
+use log::warn;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+const FRAME_MS: f64 = FRAME_DURATION.as_millis() as f64;
+/// Least playout delay in frames.
+pub const MIN_DELAY_FRAMES: usize = 1;
+/// Most playout delay in frames.
+pub const MAX_DELAY_FRAMES: usize = 10;
+// playout delay covers this many times of the jitter
+const JITTER_FACTOR: f64 = 3.0;
+// frames buffered beyond the target delay, before the oldest ones are skipped to catch up
+const MAX_EXCESS_FRAMES: usize = 2;
+// missing frames concealed in a row while stalled, before going silent
+const MAX_LOST_FRAMES: u32 = 5;
+
+/// What to play for the next frame.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Playout<T> {
+    /// The frame arrived in time.
+    Frame(T),
+    /// The frame is missing, and should be concealed.
+    Lost,
+    /// Nothing to play, waiting for enough frames to reach the target delay.
+    Buffering,
+}
+
+/// Jitter buffer of one speaker's frames.
+///
+/// Every frame lasts [`FRAME_DURATION`], so the media time of a frame is given by its
+/// sequence number. The jitter is estimated as in RFC 3550, by the difference between
+/// arrival intervals and media intervals of frames.
+///
+/// Playing starts once frames of the target delay are buffered. When it runs out of frames,
+/// it stalls until the target delay is buffered again, so the delay grows with the jitter.
+/// When too many frames are buffered, the oldest ones are skipped, so the delay shrinks.
+#[derive(Debug)]
+pub struct JitterBuffer<T> {
+    frames: BTreeMap<i64, T>,         // extended seq - frame
+    last_arrival: Option<(i64, i64)>, // extended seq and arrival timestamp of the last frame
+    next: Option<i64>,                // next seq to play, frames before it are late
+    playing: bool,
+    jitter: f64, // in milliseconds
+    lost_in_row: u32,
+    late: u64,
+}
+
+impl<T> Default for JitterBuffer<T> {
+    fn default() -> Self {
+        Self {
+            frames: BTreeMap::new(),
+            last_arrival: None,
+            next: None,
+            playing: false,
+            jitter: 0.0,
+            lost_in_row: 0,
+            late: 0,
+        }
+    }
+}
+
+impl<T> JitterBuffer<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Estimated jitter in milliseconds.
+    pub fn jitter(&self) -> f64 {
+        self.jitter
+    }
+
+    /// Frames dropped for arriving too late or twice.
+    pub fn late(&self) -> u64 {
+        self.late
+    }
+
+    /// Frames to buffer before playing, covering the jitter.
+    pub fn target_delay(&self) -> usize {
+        let frames = ((FRAME_MS + JITTER_FACTOR * self.jitter) / FRAME_MS).ceil() as usize;
+        frames.clamp(MIN_DELAY_FRAMES, MAX_DELAY_FRAMES)
+    }
+
+    /// Frames buffered.
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// Put a frame arrived at `timestamp` in milliseconds.
+    ///
+    /// Return false if it's dropped, as its turn to play has passed or it's a duplicate.
+    pub fn push(&mut self, seq: u32, timestamp: i64, frame: T) -> bool {
+        let seq = self.extend(seq);
+        if let Some((last_seq, last_timestamp)) = self.last_arrival {
+            let d = (timestamp - last_timestamp) as f64 - (seq - last_seq) as f64 * FRAME_MS;
+            self.jitter += (d.abs() - self.jitter) / 16.0;
+        }
+        self.last_arrival = Some((seq, timestamp));
+
+        if self.next.is_some_and(|next| seq < next) || self.frames.contains_key(&seq) {
+            self.late += 1;
+            return false;
+        }
+        self.frames.insert(seq, frame);
+        // never hold more than the max delay
+        while self.frames.len() > MAX_DELAY_FRAMES + MAX_EXCESS_FRAMES {
+            self.skip_oldest();
+        }
+        true
+    }
+
+    /// Take what to play for the next frame.
+    pub fn pop(&mut self) -> Playout<T> {
+        if !self.playing {
+            let Some((&first, _)) = self.frames.first_key_value() else {
+                return self.stall();
+            };
+            if self.frames.len() < self.target_delay() {
+                return self.stall();
+            }
+            self.playing = true;
+            self.next = Some(first);
+        }
+
+        let next = self.next.unwrap_or_default();
+        if let Some(frame) = self.frames.remove(&next) {
+            self.next = Some(next + 1);
+            self.lost_in_row = 0;
+            // too far behind the target delay, catch up
+            while self.frames.len() > self.target_delay() + MAX_EXCESS_FRAMES {
+                self.skip_oldest();
+            }
+            return Playout::Frame(frame);
+        }
+        if self.frames.is_empty() {
+            // run out of frames, buffer again
+            self.playing = false;
+            return self.stall();
+        }
+        // lost, or not in time
+        self.next = Some(next + 1);
+        self.lost_in_row += 1;
+        Playout::Lost
+    }
+
+    // wait for frames without moving on, the gap after some frame is concealed for a while
+    fn stall(&mut self) -> Playout<T> {
+        if self.next.is_none() || self.lost_in_row >= MAX_LOST_FRAMES {
+            return Playout::Buffering;
+        }
+        self.lost_in_row += 1;
+        Playout::Lost
+    }
+
+    fn skip_oldest(&mut self) {
+        if let Some((seq, _)) = self.frames.pop_first() {
+            self.next = self.next.map(|next| next.max(seq + 1));
+        }
+    }
+
+    // extend a wrapping seq to be comparable with all seen ones
+    fn extend(&self, seq: u32) -> i64 {
+        match self.last_arrival {
+            Some((last, _)) => last + seq.wrapping_sub(last as u32) as i32 as i64,
+            None => seq as i64,
+        }
+    }
+}
+
+/// Playout of one speaker.
+#[derive(Debug, Default)]
+struct SpeakerPlayout {
+    jitter: JitterBuffer<Vec<u8>>,
+    decoder: Option<Decoder>, // created on the first frame
+    samples: VecDeque<f32>,   // decoded, to be pulled
+    last: Vec<f32>,           // last played frame, for concealment
+    lost_in_row: i32,
+    legacy: bool, // sends raw PCM, from an old client
+}
+
+impl SpeakerPlayout {
+    // decode played frames until `length` samples are ready, or nothing to play
+    fn fill(&mut self, length: usize) -> Result<()> {
+        while self.samples.len() < length {
+            let frame = match self.jitter.pop() {
+                Playout::Frame(data) => {
+                    let decoder = match &mut self.decoder {
+                        Some(decoder) => decoder,
+                        None => self.decoder.insert(Decoder::new()?),
+                    };
+                    self.lost_in_row = 0;
+                    self.last = decoder.decode_frame(&data)?;
+                    self.last.clone()
+                }
+                Playout::Lost => {
+                    self.lost_in_row += 1;
+                    conceal(&self.last, self.lost_in_row)
+                }
+                Playout::Buffering => break,
+            };
+            self.samples.extend(frame);
+        }
+        Ok(())
+    }
+}
+
+/// Repeat the last frame, fading out by half for each frame lost in a row.
+pub fn conceal(last: &[f32], lost_in_row: i32) -> Vec<f32> {
+    if last.is_empty() {
+        return vec![0.0; FRAME_SIZE];
+    }
+    let (from, to) = (0.5f32.powi(lost_in_row - 1), 0.5f32.powi(lost_in_row));
+    let len = last.len() as f32;
+    last.iter()
+        .enumerate()
+        .map(|(i, v)| v * (from + (to - from) * i as f32 / len))
+        .collect()
+}
+
+/// Playback holds every speaker's audio on a channel, until the speaker callback pulls it.
+///
+/// Shared with [`std::sync::Arc`] and [`std::sync::Mutex`] between receiving and playing.
+#[derive(Debug, Default)]
+pub struct Playback {
+    speakers: HashMap<String, SpeakerPlayout>,
+}
+
+impl Playback {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Put a frame of a speaker, arrived at `timestamp` in milliseconds.
+    ///
+    /// Raw PCM of old clients has no frames, it's played as it comes.
+    pub fn push(&mut self, user_id: &str, codec: Codec, seq: u32, timestamp: i64, data: Vec<u8>) {
+        let speaker = self.speakers.entry(user_id.to_string()).or_default();
+        match codec {
+            Codec::PcmF32 => {
+                if !speaker.legacy {
+                    speaker.legacy = true;
+                    warn!("{} sends raw PCM audio, it's an old client", user_id);
+                }
+                speaker.samples.extend(codec::decode_pcm(&data));
+            }
+            Codec::Opus => {
+                speaker.jitter.push(seq, timestamp, data);
+            }
+        }
+    }
+
+    /// Drop the audio of a speaker who left.
+    pub fn remove(&mut self, user_id: &str) {
+        self.speakers.remove(user_id);
+    }
+
+    /// Pull at most `length` samples of every speaker.
+    pub fn flush(&mut self, length: usize) -> HashMap<String, Vec<f32>> {
+        let mut flushed = HashMap::new();
+        for (user_id, speaker) in self.speakers.iter_mut() {
+            if let Err(e) = speaker.fill(length) {
+                warn!("error decoding audio from {}: {}", user_id, e);
+            }
+            let n = length.min(speaker.samples.len());
+            flushed.insert(user_id.clone(), speaker.samples.drain(..n).collect());
+        }
+        flushed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use abi::codec::Encoder;
+
+    const MS: i64 = FRAME_DURATION.as_millis() as i64;
+
+    // play out `n` frames
+    fn play<T>(buffer: &mut JitterBuffer<T>, n: usize) -> Vec<Playout<T>> {
+        (0..n).map(|_| buffer.pop()).collect()
+    }
+
+    #[test]
+    fn test_in_order_without_jitter() {
+        let mut buffer = JitterBuffer::new();
+        for seq in 0..10u32 {
+            assert!(buffer.push(seq, seq as i64 * MS, seq));
+        }
+        assert_eq!(buffer.jitter(), 0.0);
+        assert_eq!(buffer.target_delay(), MIN_DELAY_FRAMES);
+
+        // frames beyond the target delay are skipped to catch up
+        let played = play(&mut buffer, 2);
+        assert_eq!(played[0], Playout::Frame(0));
+        assert_eq!(played[1], Playout::Frame(7));
+    }
+
+    #[test]
+    fn test_playout_in_real_time() {
+        // each frame is played right after it arrives
+        let mut buffer = JitterBuffer::new();
+        for seq in 0..10u32 {
+            buffer.push(seq, seq as i64 * MS, seq);
+            assert_eq!(buffer.pop(), Playout::Frame(seq));
+        }
+        assert_eq!(buffer.pop(), Playout::Lost);
+    }
+
+    #[test]
+    fn test_reorder() {
+        let mut buffer = JitterBuffer::new();
+        // arrival: 0, 2, 1, 3, 5, 4
+        for (i, seq) in [0u32, 2, 1, 3, 5, 4].into_iter().enumerate() {
+            assert!(buffer.push(seq, i as i64 * MS, seq));
+        }
+        assert!(buffer.jitter() > 0.0);
+        let played = play(&mut buffer, 3);
+        assert_eq!(
+            played,
+            [Playout::Frame(0), Playout::Frame(1), Playout::Frame(2)]
+        );
+    }
+
+    #[test]
+    fn test_loss_is_concealed() {
+        let mut buffer = JitterBuffer::new();
+        buffer.push(0, 0, 0);
+        assert_eq!(buffer.pop(), Playout::Frame(0));
+        // 1 is lost
+        buffer.push(2, 2 * MS, 2);
+        assert_eq!(play(&mut buffer, 2), [Playout::Lost, Playout::Frame(2)]);
+    }
+
+    #[test]
+    fn test_late_frame_dropped() {
+        let mut buffer = JitterBuffer::new();
+        buffer.push(0, 0, 0);
+        buffer.push(2, 2 * MS, 2);
+        // 1 is not in time
+        assert_eq!(
+            play(&mut buffer, 3),
+            [Playout::Frame(0), Playout::Lost, Playout::Frame(2)]
+        );
+        assert!(!buffer.push(1, 3 * MS, 1));
+        // duplicate
+        assert!(buffer.push(3, 3 * MS, 3));
+        assert!(!buffer.push(3, 3 * MS, 3));
+        assert_eq!(buffer.late(), 2);
+        assert_eq!(buffer.pop(), Playout::Frame(3));
+    }
+
+    #[test]
+    fn test_stall_grows_delay() {
+        let mut buffer = JitterBuffer::new();
+        buffer.push(0, 0, 0);
+        assert_eq!(buffer.pop(), Playout::Frame(0));
+        // 1 is 2 frames late, playing stalls instead of skipping it
+        assert_eq!(play(&mut buffer, 2), [Playout::Lost, Playout::Lost]);
+        assert!(buffer.push(1, 3 * MS, 1));
+        assert!(buffer.target_delay() > 1);
+        // buffer again to the target delay
+        assert_eq!(buffer.pop(), Playout::Lost);
+        buffer.push(2, 3 * MS, 2);
+        buffer.push(3, 3 * MS, 3);
+        assert_eq!(play(&mut buffer, 2), [Playout::Frame(1), Playout::Frame(2)]);
+    }
+
+    #[test]
+    fn test_rebuffer_after_silence() {
+        let mut buffer = JitterBuffer::new();
+        buffer.push(0, 0, 0);
+        buffer.pop();
+        let played = play(&mut buffer, MAX_LOST_FRAMES as usize + 1);
+        assert!(played[..MAX_LOST_FRAMES as usize]
+            .iter()
+            .all(|p| *p == Playout::Lost));
+        assert_eq!(played[MAX_LOST_FRAMES as usize], Playout::Buffering);
+
+        // the speaker talks again, with seq going on
+        buffer.push(100, 100 * MS, 100);
+        assert_eq!(buffer.pop(), Playout::Frame(100));
+    }
+
+    #[test]
+    fn test_delay_adapts_to_jitter() {
+        // frames arrive in bursts of 5 every 100ms
+        let mut buffer = JitterBuffer::new();
+        for seq in 0..200u32 {
+            buffer.push(seq, (seq / 5) as i64 * 5 * MS, seq);
+        }
+        let bursty = buffer.target_delay();
+        assert!(bursty > 3, "target delay: {}", bursty);
+        assert!(bursty <= MAX_DELAY_FRAMES);
+
+        // then steady, the delay shrinks back
+        for seq in 200..600u32 {
+            buffer.push(seq, seq as i64 * MS, seq);
+        }
+        assert!(buffer.target_delay() < bursty);
+    }
+
+    #[test]
+    fn test_schedule_with_loss_and_reorder() {
+        // real time playout every 20ms, frames arrive with up to 50ms of jitter,
+        // every 10th frame is lost and every 7th frame is swapped with the next.
+        let mut arrivals: Vec<(i64, u32)> = (0..500u32)
+            .filter(|seq| seq % 10 != 9)
+            .map(|seq| {
+                let jitter = (seq as i64 * 37) % 50;
+                (seq as i64 * MS + jitter, seq)
+            })
+            .collect();
+        for i in (0..arrivals.len() - 1).step_by(7) {
+            let (a, b) = (arrivals[i].1, arrivals[i + 1].1);
+            arrivals[i].1 = b;
+            arrivals[i + 1].1 = a;
+        }
+        arrivals.sort_by_key(|(at, _)| *at);
+
+        let mut buffer = JitterBuffer::new();
+        let mut played = vec![];
+        let mut arrivals = arrivals.into_iter().peekable();
+        for tick in 0..600i64 {
+            while let Some((_, seq)) = arrivals.next_if(|(at, _)| *at <= tick * MS) {
+                buffer.push(seq, tick * MS, seq);
+            }
+            played.push(buffer.pop());
+        }
+
+        let frames: Vec<u32> = played
+            .iter()
+            .filter_map(|p| match p {
+                Playout::Frame(seq) => Some(*seq),
+                _ => None,
+            })
+            .collect();
+        // played in order
+        assert!(frames.windows(2).all(|w| w[0] < w[1]));
+        // most of frames arrived are played, the rest were dropped as late
+        assert!(frames.len() > 400, "played: {}", frames.len());
+        assert!(buffer.target_delay() > MIN_DELAY_FRAMES);
+    }
+
+    #[test]
+    fn test_seq_wraps() {
+        let mut buffer = JitterBuffer::new();
+        buffer.push(u32::MAX, 0, 0);
+        buffer.push(0, MS, 1);
+        assert_eq!(play(&mut buffer, 2), [Playout::Frame(0), Playout::Frame(1)]);
+    }
+
+    #[test]
+    fn test_conceal_fades() {
+        let last = vec![1.0; FRAME_SIZE];
+        let first = conceal(&last, 1);
+        assert_eq!(first[0], 1.0);
+        assert!((first[FRAME_SIZE - 1] - 0.5).abs() < 0.01);
+        let second = conceal(&last, 2);
+        assert_eq!(second[0], 0.5);
+        assert!(second.iter().all(|v| *v <= 0.5));
+        // nothing to repeat
+        assert_eq!(conceal(&[], 1), vec![0.0; FRAME_SIZE]);
+    }
+
+    #[test]
+    fn test_playback() {
+        let mut encoder = Encoder::new().unwrap();
+        let frames = encoder.encode(&[0.1; 3 * FRAME_SIZE]).unwrap();
+        let mut playback = Playback::new();
+        for frame in frames.iter() {
+            playback.push(
+                "a",
+                Codec::Opus,
+                frame.seq,
+                frame.seq as i64 * MS,
+                frame.data.clone(),
+            );
+        }
+        playback.push("b", Codec::PcmF32, 0, 0, vec![0, 0, 128, 63]);
+
+        let flushed = playback.flush(100);
+        assert_eq!(flushed["a"].len(), 100);
+        assert_eq!(flushed["b"], vec![1.0]);
+        // the rest of the frame is kept
+        assert_eq!(playback.flush(FRAME_SIZE)["a"].len(), FRAME_SIZE);
+    }
+}
@@ -1,4 +1,6 @@
 pub mod audio;
 pub mod client;
 pub mod config;
+pub mod control;
+pub mod jitter;
 pub mod utils;
@@ -1,7 +1,3 @@
-use std::collections::HashMap;
-
-use ringbuffer::{AllocRingBuffer, RingBuffer};
-
 use crate::audio::SAMPLE_RATE;
 
 /// Convert bytes to T.
@@ -85,43 +81,6 @@ impl_to_bytes!(u64);
 
 pub const RING_BUFFER_SIZE: usize = SAMPLE_RATE as usize * 10;
 
-/// Buffer is holding audio data for each user.
-/// inner mutable, use [`Arc`] and [`Mutex`] to share data between threads.
-#[derive(Default)]
-pub struct Buffer<T> {
-    data: HashMap<String, AllocRingBuffer<T>>, // user - [audio data]
-}
-
-impl<T: Clone> Buffer<T> {
-    pub fn new() -> Self {
-        Self {
-            data: HashMap::new(),
-        }
-    }
-
-    /// Extend data for a user.
-    pub fn extend(&mut self, user: String, data: impl IntoIterator<Item = T>) {
-        self.data
-            .entry(user)
-            .or_insert_with(|| AllocRingBuffer::new(RING_BUFFER_SIZE))
-            .extend(data);
-    }
-
-    /// Flush all users' data
-    pub fn flush(&mut self, length: usize) -> HashMap<String, Vec<T>> {
-        let mut flushed = HashMap::new();
-        for (user, buffer) in self.data.iter_mut() {
-            let drained: Vec<T> = buffer.drain().collect();
-            let remaining: Vec<T> = drained.iter().skip(length).cloned().collect();
-            let flushed_data: Vec<T> = drained.iter().take(length).cloned().collect();
-            println!("{}, {}", flushed_data.len(), remaining.len());
-            flushed.insert(user.clone(), flushed_data);
-            buffer.extend(remaining);
-        }
-        flushed
-    }
-}
-
 #[cfg(test)]
 mod test {
     use super::*;
@@ -173,28 +132,4 @@ mod test {
         let result = data.to_bytes();
         assert_eq!(result, expected);
     }
-
-    #[test]
-    fn test_buffer_extend_and_flush() {
-        let mut buffer = Buffer::new();
-        let user = String::from("user1");
-
-        // Extend buffer with some data
-        buffer.extend(user.clone(), vec![1, 2, 3, 4, 5]);
-
-        // Check internal state of data after extending
-        assert_eq!(
-            buffer.data.get(&user).unwrap().to_vec(),
-            vec![1, 2, 3, 4, 5]
-        );
-
-        // Flush the buffer with a given length
-        let flushed_data = buffer.flush(3);
-
-        // Check the flushed data
-        assert_eq!(flushed_data.get(&user).unwrap(), &vec![1, 2, 3]);
-
-        // Check the remaining data in the buffer for the user
-        assert_eq!(buffer.data.get(&user).unwrap().to_vec(), vec![4, 5]);
-    }
 }
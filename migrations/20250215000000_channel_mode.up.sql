@@ -0,0 +1,2 @@
+-- Add up migration script here
+ALTER TABLE chat.channels ADD COLUMN mode INT NOT NULL DEFAULT 0; -- 0: forward, 1: mix
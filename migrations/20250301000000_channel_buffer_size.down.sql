@@ -0,0 +1,2 @@
+-- Add down migration script here
+ALTER TABLE chat.channels DROP COLUMN buffer_size;
@@ -0,0 +1,2 @@
+-- Add up migration script here
+ALTER TABLE chat.channels ADD COLUMN buffer_size INT NOT NULL DEFAULT 0; -- 0: chat server's default
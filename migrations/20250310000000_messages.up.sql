@@ -0,0 +1,11 @@
+-- Add up migration script here
+CREATE TABLE chat.messages (
+    id BIGSERIAL PRIMARY KEY, -- also the cursor of history, increasing by time
+    channel_id INT NOT NULL,
+    user_id VARCHAR(64) NOT NULL,
+    content TEXT NOT NULL,
+    created_at BIGINT NOT NULL, -- chat server's timestamp in milliseconds
+    FOREIGN KEY (channel_id) REFERENCES chat.channels(id) ON DELETE CASCADE
+);
+
+CREATE INDEX messages_channel_id_idx ON chat.messages (channel_id, id);
@@ -0,0 +1,3 @@
+-- Add down migration script here
+DROP TABLE chat.refresh_tokens;
+ALTER TABLE chat.users DROP COLUMN token_version;
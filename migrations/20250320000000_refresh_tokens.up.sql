@@ -0,0 +1,13 @@
+-- Add up migration script here
+ALTER TABLE chat.users ADD COLUMN token_version INT NOT NULL DEFAULT 0; -- bumped to revoke all access tokens
+
+CREATE TABLE chat.refresh_tokens (
+    token_hash VARCHAR(64) PRIMARY KEY, -- sha256 of the token, the token itself is never stored
+    user_id VARCHAR(64) NOT NULL,
+    family VARCHAR(64) NOT NULL, -- tokens rotated from the same login
+    expires_at BIGINT NOT NULL, -- in seconds
+    revoked BOOLEAN NOT NULL DEFAULT FALSE, -- used or logged out
+    FOREIGN KEY (user_id) REFERENCES chat.users(id) ON DELETE CASCADE
+);
+
+CREATE INDEX refresh_tokens_family_idx ON chat.refresh_tokens (family);
@@ -0,0 +1,10 @@
+-- Add up migration script here
+CREATE TABLE chat.channel_roles (
+    channel_id INT NOT NULL,
+    user_id VARCHAR(64) NOT NULL,
+    role INT NOT NULL, -- 1: moderator, 3: banned, owners are `channels.owner_id`, others are members
+    PRIMARY KEY (channel_id, user_id),
+    FOREIGN KEY (channel_id) REFERENCES chat.channels(id) ON DELETE CASCADE,
+    FOREIGN KEY (user_id) REFERENCES chat.users(id) ON DELETE CASCADE,
+    CONSTRAINT check_role CHECK (role IN (1, 3))
+);
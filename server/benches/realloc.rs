@@ -0,0 +1,56 @@
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use echo_server::servers::manager::server::ServerManager;
+
+const CHANNELS: i32 = 100_000;
+const SERVERS: usize = 50;
+
+fn server(i: usize) -> String {
+    format!("http://10.0.0.{}:50052", i)
+}
+
+// 100k channels on 50 servers
+fn manager() -> ServerManager {
+    let mut mgr = ServerManager::new();
+    for i in 0..SERVERS {
+        mgr.add_server(&server(i), 1);
+    }
+    for id in 1..=CHANNELS {
+        mgr.add_channel(&id);
+    }
+    mgr
+}
+
+fn bench_realloc(c: &mut Criterion) {
+    let mut mgr = manager();
+    let mut group = c.benchmark_group("realloc 100k channels 50 servers");
+    group.sample_size(20);
+
+    group.bench_function("add server", |b| {
+        b.iter_batched_ref(
+            || {
+                let mut mgr = manager();
+                mgr.delete_server(&server(SERVERS - 1));
+                mgr
+            },
+            |mgr| mgr.add_server(&server(SERVERS - 1), 1),
+            BatchSize::LargeInput,
+        )
+    });
+    group.bench_function("delete server", |b| {
+        b.iter_batched_ref(
+            manager,
+            |mgr| mgr.delete_server(&server(0)),
+            BatchSize::LargeInput,
+        )
+    });
+    group.bench_function("add and delete channel", |b| {
+        b.iter(|| {
+            mgr.add_channel(&(CHANNELS + 1));
+            mgr.delete_channel(&(CHANNELS + 1));
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_realloc);
+criterion_main!(benches);
@@ -1,66 +1,242 @@
-use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+//! JWT tokens of the services, a claims type for each kind of token.
+//!
+//! A token is checked for its type `typ` and its audience `aud`, so that one kind of token
+//! can't be used where another is expected:
+//! - [`AccessClaims`]: user's login token, for user-facing RPCs on manager.
+//! - [`ListenClaims`]: to join a channel, only on the chat server in `aud`.
+//! - [`ServerClaims`]: between manager and chat servers, reports to manager,
+//!   or channels pushed to the chat server in `aud`.
+//!
+//! Tokens are signed and verified by a [`KeySet`].
+
+use super::keys::KeySet;
+use jsonwebtoken::{decode, encode, Validation};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
-#[derive(Debug, Serialize, Deserialize)]
-pub struct Claims {
-    pub sub: String,
+
+/// Audience of tokens for manager. Chat servers are addressed by their addr.
+pub const MANAGER_AUDIENCE: &str = "manager";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenType {
+    Access,
+    Listen,
+    Server,
+}
+
+impl TokenType {
+    /// Whether it's a user's token, signed by manager's key pair if there is.
+    pub fn is_user(self) -> bool {
+        matches!(self, Self::Access | Self::Listen)
+    }
+}
+
+/// Claims of some kind of token.
+pub trait TypedClaims: Serialize + DeserializeOwned {
+    /// Type of tokens with these claims.
+    const TYPE: TokenType;
+
+    /// Type in the token, checked to be [`Self::TYPE`].
+    fn typ(&self) -> TokenType;
+}
+
+/// User's login token.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AccessClaims {
+    pub typ: TokenType,
+    pub aud: String,
     pub exp: i64,
+    pub user_id: String,
+    pub ver: i32, // user's token version when issued, see `TokenVersions`
+}
 
-    // other fields
+impl AccessClaims {
+    pub fn new(user_id: &str, ver: i32, exp: i64) -> Self {
+        Self {
+            typ: TokenType::Access,
+            aud: MANAGER_AUDIENCE.to_string(),
+            exp,
+            user_id: user_id.to_string(),
+            ver,
+        }
+    }
+}
+
+impl TypedClaims for AccessClaims {
+    const TYPE: TokenType = TokenType::Access;
+
+    fn typ(&self) -> TokenType {
+        self.typ
+    }
+}
+
+/// Token for a user to join a channel on the chat server `aud`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ListenClaims {
+    pub typ: TokenType,
+    pub aud: String, // chat server's addr
+    pub exp: i64,
     pub user_id: String,
     pub channel_id: i32,
-    pub addr: String,
 }
 
-impl Default for Claims {
-    fn default() -> Self {
-        Claims {
-            sub: "".to_string(),
-            exp: 0,
-            user_id: "".to_string(),
-            channel_id: 0,
-            addr: "".to_string(),
+impl ListenClaims {
+    pub fn new(user_id: &str, channel_id: i32, addr: &str, exp: i64) -> Self {
+        Self {
+            typ: TokenType::Listen,
+            aud: addr.to_string(),
+            exp,
+            user_id: user_id.to_string(),
+            channel_id,
         }
     }
 }
 
-pub fn extract<T: DeserializeOwned>(secret: &str, token: &str) -> Result<T, tonic::Status> {
-    let decoding_key = DecodingKey::from_secret(secret.as_ref());
-    let validation = Validation::new(Algorithm::HS256);
-    decode::<T>(token, &decoding_key, &validation)
+impl TypedClaims for ListenClaims {
+    const TYPE: TokenType = TokenType::Listen;
+
+    fn typ(&self) -> TokenType {
+        self.typ
+    }
+}
+
+/// Token between manager and the chat server `addr`, for `aud`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServerClaims {
+    pub typ: TokenType,
+    pub aud: String, // manager, or the chat server's addr
+    pub exp: i64,
+    pub addr: String, // chat server's addr
+}
+
+impl ServerClaims {
+    pub fn new(aud: &str, addr: &str, exp: i64) -> Self {
+        Self {
+            typ: TokenType::Server,
+            aud: aud.to_string(),
+            exp,
+            addr: addr.to_string(),
+        }
+    }
+}
+
+impl TypedClaims for ServerClaims {
+    const TYPE: TokenType = TokenType::Server;
+
+    fn typ(&self) -> TokenType {
+        self.typ
+    }
+}
+
+/// Extract claims of a token for `audience`, the token must be of the claims' type.
+///
+/// Users' tokens are verified by the key of `kid` in their header, with the algorithm of the key.
+#[allow(clippy::result_large_err)] // a status, as handlers return
+pub fn extract<T: TypedClaims>(
+    keys: &KeySet,
+    token: &str,
+    audience: &str,
+) -> Result<T, tonic::Status> {
+    let (algorithm, decoding_key) = keys.verifying_key(T::TYPE, token)?;
+    let mut validation = Validation::new(algorithm);
+    validation.set_audience(&[audience]);
+    validation.set_required_spec_claims(&["exp", "aud"]);
+    let claims: T = decode::<T>(token, decoding_key, &validation)
         .map(|data| data.claims)
-        .map_err(|e| tonic::Status::unauthenticated(format!("Invalid token {}", e)))
+        .map_err(|e| tonic::Status::unauthenticated(format!("Invalid token {}", e)))?;
+    if claims.typ() != T::TYPE {
+        return Err(tonic::Status::unauthenticated("Invalid token type"));
+    }
+    Ok(claims)
 }
 
-pub fn encrypt<T: Serialize>(secret: &str, claims: &T) -> String {
-    let encoding_key = EncodingKey::from_secret(secret.as_ref());
-    encode(&Header::default(), claims, &encoding_key).unwrap()
+/// Sign claims, users' tokens by manager's private key with its `kid` if there is.
+pub fn encrypt<T: TypedClaims>(keys: &KeySet, claims: &T) -> String {
+    let (header, encoding_key) = keys.signing_key(T::TYPE);
+    encode(&header, claims, encoding_key).unwrap()
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use jsonwebtoken::{EncodingKey, Header};
+    use std::sync::LazyLock;
+    use tonic::Code;
+
+    static SECRET: LazyLock<KeySet> = LazyLock::new(|| KeySet::from_secret("secret"));
+    const CHAT: &str = "http://127.0.0.1:50052";
+    const EXP: i64 = 10000000000;
 
     #[tokio::test]
     async fn test_extract_and_encrypt() {
-        let secret = "secret";
-        let claims = Claims {
-            sub: "123".to_string(),
-            exp: 10000000000,
-            user_id: "user_123".to_string(),
-            channel_id: 1,
-            ..Default::default()
-        };
-
-        // Encrypt the claims to create a token
-        let token = encrypt(secret, &claims);
-
-        // Extract the claims from the token
-        let extracted_claims: Claims = extract(secret, &token).unwrap();
-
-        // Assert that the extracted claims match the original claims
-        assert_eq!(claims.sub, extracted_claims.sub);
-        assert_eq!(claims.exp, extracted_claims.exp);
-        assert_eq!(claims.user_id, extracted_claims.user_id);
-        assert_eq!(claims.channel_id, extracted_claims.channel_id);
+        let claims = AccessClaims::new("user_123", 1, EXP);
+        let token = encrypt(&SECRET, &claims);
+        let extracted: AccessClaims = extract(&SECRET, &token, MANAGER_AUDIENCE).unwrap();
+        assert_eq!(claims, extracted);
+
+        let claims = ListenClaims::new("user_123", 1, CHAT, EXP);
+        let token = encrypt(&SECRET, &claims);
+        let extracted: ListenClaims = extract(&SECRET, &token, CHAT).unwrap();
+        assert_eq!(claims, extracted);
+
+        let claims = ServerClaims::new(MANAGER_AUDIENCE, CHAT, EXP);
+        let token = encrypt(&SECRET, &claims);
+        let extracted: ServerClaims = extract(&SECRET, &token, MANAGER_AUDIENCE).unwrap();
+        assert_eq!(claims, extracted);
+    }
+
+    #[test]
+    fn test_wrong_type() {
+        let access = encrypt(&SECRET, &AccessClaims::new("user", 0, EXP));
+        let report = encrypt(&SECRET, &ServerClaims::new(MANAGER_AUDIENCE, CHAT, EXP));
+        let listen = encrypt(&SECRET, &ListenClaims::new("user", 1, CHAT, EXP));
+        let push = encrypt(&SECRET, &ServerClaims::new(CHAT, CHAT, EXP));
+
+        // same audience, other types
+        let err = extract::<AccessClaims>(&SECRET, &report, MANAGER_AUDIENCE).unwrap_err();
+        assert_eq!(err.code(), Code::Unauthenticated);
+        assert!(extract::<ServerClaims>(&SECRET, &access, MANAGER_AUDIENCE).is_err());
+        assert!(extract::<ServerClaims>(&SECRET, &listen, CHAT).is_err());
+        assert!(extract::<ListenClaims>(&SECRET, &push, CHAT).is_err());
+    }
+
+    #[test]
+    fn test_wrong_audience() {
+        let access = encrypt(&SECRET, &AccessClaims::new("user", 0, EXP));
+        let listen = encrypt(&SECRET, &ListenClaims::new("user", 1, CHAT, EXP));
+        let push = encrypt(&SECRET, &ServerClaims::new(CHAT, CHAT, EXP));
+
+        assert!(extract::<AccessClaims>(&SECRET, &access, CHAT).is_err());
+        assert!(extract::<ListenClaims>(&SECRET, &listen, MANAGER_AUDIENCE).is_err());
+        assert!(extract::<ListenClaims>(&SECRET, &listen, "http://127.0.0.1:50053").is_err());
+        assert!(extract::<ServerClaims>(&SECRET, &push, MANAGER_AUDIENCE).is_err());
+    }
+
+    #[test]
+    fn test_invalid_tokens() {
+        // tokens of old versions have neither type nor audience
+        #[derive(Serialize)]
+        struct Claims {
+            exp: i64,
+            user_id: String,
+        }
+        let token = encode(
+            &Header::default(),
+            &Claims {
+                exp: EXP,
+                user_id: "user".to_string(),
+            },
+            &EncodingKey::from_secret(b"secret"),
+        )
+        .unwrap();
+        assert!(extract::<AccessClaims>(&SECRET, &token, MANAGER_AUDIENCE).is_err());
+
+        let expired = encrypt(&SECRET, &AccessClaims::new("user", 0, 1));
+        assert!(extract::<AccessClaims>(&SECRET, &expired, MANAGER_AUDIENCE).is_err());
+        let access = encrypt(&SECRET, &AccessClaims::new("user", 0, EXP));
+        assert!(
+            extract::<AccessClaims>(&KeySet::from_secret("other"), &access, MANAGER_AUDIENCE)
+                .is_err()
+        );
     }
 }
@@ -0,0 +1,313 @@
+//! Keys to sign and verify tokens.
+//!
+//! Users' tokens, access and listen ones, are signed by manager's private key and verified by
+//! public keys, so a chat server holding only public keys can't mint them. Keys are identified
+//! by `kid` in the token header. Every key in the set is accepted, so a new key can be rolled
+//! out while tokens of the old one are still alive.
+//!
+//! Server tokens between manager and chat servers are signed by the shared `secret`.
+//! Without [`JwtConfig`], all tokens are signed by `secret`.
+//!
+//! As every chat server holds `secret`, a chat server can mint server tokens for any addr:
+//! report to manager as another chat server, or push channels to another one as manager.
+//! Chat servers are trusted as much as manager by server tokens alone, so with TLS the peer's
+//! certificate must be valid for the addr too, see [`check_peer`](super::tls::check_peer).
+
+use super::interceptor::TokenType;
+use crate::config::{JwtConfig, KeyAlgorithm, KeyConfig, ServerConfig};
+use abi::error::Error;
+use jsonwebtoken::{decode_header, Algorithm, DecodingKey, EncodingKey, Header};
+use std::collections::HashMap;
+use std::fmt;
+
+#[derive(Clone)]
+struct SigningKey {
+    kid: String,
+    alg: Algorithm,
+    key: EncodingKey,
+}
+
+#[derive(Clone)]
+pub struct KeySet {
+    secret: (EncodingKey, DecodingKey),
+    signing: Option<SigningKey>,
+    verifying: HashMap<String, (Algorithm, DecodingKey)>, // kid - key
+}
+
+impl fmt::Debug for KeySet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut kids: Vec<_> = self.verifying.keys().collect();
+        kids.sort();
+        f.debug_struct("KeySet")
+            .field("signing", &self.signing.as_ref().map(|key| &key.kid))
+            .field("verifying", &kids)
+            .finish()
+    }
+}
+
+impl KeySet {
+    /// Keys of `secret`, and key pairs of `jwt` if set.
+    pub fn new(config: &ServerConfig) -> abi::Result<Self> {
+        let mut keys = Self::from_secret(&config.secret);
+        if let Some(jwt) = &config.jwt {
+            keys.load(jwt)?;
+        }
+        Ok(keys)
+    }
+
+    /// Keys of `secret` only.
+    pub fn from_secret(secret: &str) -> Self {
+        Self {
+            secret: (
+                EncodingKey::from_secret(secret.as_ref()),
+                DecodingKey::from_secret(secret.as_ref()),
+            ),
+            signing: None,
+            verifying: HashMap::new(),
+        }
+    }
+
+    fn load(&mut self, jwt: &JwtConfig) -> abi::Result<()> {
+        if jwt.verifying_keys.is_empty() {
+            return Err(Error::ConfigParse);
+        }
+        if let Some(conf) = &jwt.signing_key {
+            let pem = read_pem(conf)?;
+            let key = match conf.alg {
+                KeyAlgorithm::EdDSA => EncodingKey::from_ed_pem(&pem),
+                KeyAlgorithm::RS256 => EncodingKey::from_rsa_pem(&pem),
+            }
+            .map_err(|_| Error::ConfigParse)?;
+            self.signing = Some(SigningKey {
+                kid: conf.kid.clone(),
+                alg: algorithm(conf.alg),
+                key,
+            });
+        }
+        // tokens signed by a key not verified would be refused by manager itself
+        if let Some(signing) = &self.signing {
+            if !jwt
+                .verifying_keys
+                .iter()
+                .any(|conf| conf.kid == signing.kid)
+            {
+                return Err(Error::ConfigParse);
+            }
+        }
+        for conf in jwt.verifying_keys.iter() {
+            let pem = read_pem(conf)?;
+            let key = match conf.alg {
+                KeyAlgorithm::EdDSA => DecodingKey::from_ed_pem(&pem),
+                KeyAlgorithm::RS256 => DecodingKey::from_rsa_pem(&pem),
+            }
+            .map_err(|_| Error::ConfigParse)?;
+            self.verifying
+                .insert(conf.kid.clone(), (algorithm(conf.alg), key));
+        }
+        Ok(())
+    }
+
+    /// Whether users' tokens are signed by key pairs.
+    pub fn is_asymmetric(&self) -> bool {
+        !self.verifying.is_empty()
+    }
+
+    /// Whether users' tokens can be signed, which manager must do.
+    pub fn can_sign(&self) -> bool {
+        !self.is_asymmetric() || self.signing.is_some()
+    }
+
+    /// Header and key to sign a token of `typ`.
+    pub(super) fn signing_key(&self, typ: TokenType) -> (Header, &EncodingKey) {
+        match &self.signing {
+            Some(signing) if typ.is_user() => {
+                let mut header = Header::new(signing.alg);
+                header.kid = Some(signing.kid.clone());
+                (header, &signing.key)
+            }
+            _ => (Header::default(), &self.secret.0),
+        }
+    }
+
+    /// Algorithm and key to verify `token` of `typ`, by `kid` in its header.
+    #[allow(clippy::result_large_err)] // a status, as handlers return
+    pub(super) fn verifying_key(
+        &self,
+        typ: TokenType,
+        token: &str,
+    ) -> Result<(Algorithm, &DecodingKey), tonic::Status> {
+        if !typ.is_user() || !self.is_asymmetric() {
+            return Ok((Algorithm::HS256, &self.secret.1));
+        }
+        let header = decode_header(token)
+            .map_err(|e| tonic::Status::unauthenticated(format!("Invalid token {}", e)))?;
+        let kid = header
+            .kid
+            .ok_or_else(|| tonic::Status::unauthenticated("Invalid token without kid"))?;
+        let (alg, key) = self
+            .verifying
+            .get(&kid)
+            .ok_or_else(|| tonic::Status::unauthenticated("Invalid token of unknown key"))?;
+        Ok((*alg, key))
+    }
+}
+
+fn read_pem(conf: &KeyConfig) -> abi::Result<Vec<u8>> {
+    std::fs::read(&conf.path).map_err(|_| Error::ConfigRead)
+}
+
+fn algorithm(alg: KeyAlgorithm) -> Algorithm {
+    match alg {
+        KeyAlgorithm::EdDSA => Algorithm::EdDSA,
+        KeyAlgorithm::RS256 => Algorithm::RS256,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::interceptor::{
+        encrypt, extract, AccessClaims, ListenClaims, ServerClaims, MANAGER_AUDIENCE,
+    };
+    use crate::config::Config;
+
+    const CHAT: &str = "http://127.0.0.1:50052";
+    const EXP: i64 = 10000000000;
+
+    fn key(kid: &str, alg: KeyAlgorithm, path: &str) -> KeyConfig {
+        KeyConfig {
+            kid: kid.to_string(),
+            alg,
+            path: format!("tests/keys/{}", path),
+        }
+    }
+
+    fn ed(kid: &str) -> (KeyConfig, KeyConfig) {
+        (
+            key(kid, KeyAlgorithm::EdDSA, "ed25519.pem"),
+            key(kid, KeyAlgorithm::EdDSA, "ed25519.pub.pem"),
+        )
+    }
+
+    fn rsa(kid: &str) -> (KeyConfig, KeyConfig) {
+        (
+            key(kid, KeyAlgorithm::RS256, "rsa.pem"),
+            key(kid, KeyAlgorithm::RS256, "rsa.pub.pem"),
+        )
+    }
+
+    fn keys(signing: Option<KeyConfig>, verifying: Vec<KeyConfig>) -> KeySet {
+        let mut config = Config::load("../config/manager.yaml").unwrap().server;
+        config.jwt = Some(JwtConfig {
+            signing_key: signing,
+            verifying_keys: verifying,
+        });
+        KeySet::new(&config).unwrap()
+    }
+
+    #[test]
+    fn test_sign_and_verify() {
+        for (private, public) in [ed("ed"), rsa("rsa")] {
+            let manager = keys(Some(private), vec![public.clone()]);
+            let chat = keys(None, vec![public]);
+            assert!(manager.can_sign());
+            assert!(!chat.can_sign());
+
+            let access = encrypt(&manager, &AccessClaims::new("user", 0, EXP));
+            let claims: AccessClaims = extract(&manager, &access, MANAGER_AUDIENCE).unwrap();
+            assert_eq!(claims.user_id, "user");
+
+            let listen = encrypt(&manager, &ListenClaims::new("user", 1, CHAT, EXP));
+            let claims: ListenClaims = extract(&chat, &listen, CHAT).unwrap();
+            assert_eq!(claims.channel_id, 1);
+        }
+    }
+
+    #[test]
+    fn test_secret_not_accepted_for_users() {
+        let (private, public) = ed("ed");
+        let manager = keys(Some(private), vec![public]);
+        // what a chat server can mint by the secret
+        let secret = KeySet::from_secret("secret");
+        let access = encrypt(&secret, &AccessClaims::new("user", 0, EXP));
+        let listen = encrypt(&secret, &ListenClaims::new("user", 1, CHAT, EXP));
+        assert!(extract::<AccessClaims>(&manager, &access, MANAGER_AUDIENCE).is_err());
+        assert!(extract::<ListenClaims>(&manager, &listen, CHAT).is_err());
+
+        // server tokens are still by the secret
+        let report = encrypt(&secret, &ServerClaims::new(MANAGER_AUDIENCE, CHAT, EXP));
+        assert!(extract::<ServerClaims>(&manager, &report, MANAGER_AUDIENCE).is_ok());
+    }
+
+    #[test]
+    fn test_rotation() {
+        let (old_private, old_public) = rsa("2025-01");
+        let (new_private, new_public) = ed("2025-03");
+        let old = keys(Some(old_private), vec![old_public.clone()]);
+        let old_token = encrypt(&old, &AccessClaims::new("user", 0, EXP));
+
+        // both keys accepted while rotating
+        let rotating = keys(Some(new_private), vec![old_public, new_public.clone()]);
+        let new_token = encrypt(&rotating, &AccessClaims::new("user", 0, EXP));
+        for token in [&old_token, &new_token] {
+            assert!(extract::<AccessClaims>(&rotating, token, MANAGER_AUDIENCE).is_ok());
+        }
+        assert!(extract::<AccessClaims>(&old, &new_token, MANAGER_AUDIENCE).is_err());
+
+        // old key retired
+        let rotated = keys(None, vec![new_public]);
+        assert!(extract::<AccessClaims>(&rotated, &old_token, MANAGER_AUDIENCE).is_err());
+        assert!(extract::<AccessClaims>(&rotated, &new_token, MANAGER_AUDIENCE).is_ok());
+    }
+
+    #[test]
+    fn test_kid_and_algorithm_checked() {
+        let (private, public) = ed("ed");
+        let manager = keys(Some(private), vec![public]);
+        // same key file under another kid, or taken as another algorithm
+        let (other_private, _) = ed("other");
+        let other = keys(
+            Some(other_private),
+            vec![key("other", KeyAlgorithm::EdDSA, "ed25519.pub.pem")],
+        );
+        let token = encrypt(&other, &AccessClaims::new("user", 0, EXP));
+        assert!(extract::<AccessClaims>(&manager, &token, MANAGER_AUDIENCE).is_err());
+
+        let (rsa_private, _) = rsa("ed");
+        let rsa = keys(
+            Some(rsa_private),
+            vec![key("ed", KeyAlgorithm::RS256, "rsa.pub.pem")],
+        );
+        let token = encrypt(&rsa, &AccessClaims::new("user", 0, EXP));
+        assert!(extract::<AccessClaims>(&manager, &token, MANAGER_AUDIENCE).is_err());
+    }
+
+    #[test]
+    fn test_bad_config() {
+        let mut config = Config::load("../config/manager.yaml").unwrap().server;
+        let (private, public) = ed("ed");
+        let jwt = |signing_key, verifying_keys| {
+            Some(JwtConfig {
+                signing_key,
+                verifying_keys,
+            })
+        };
+
+        config.jwt = jwt(Some(private.clone()), vec![]);
+        assert!(KeySet::new(&config).is_err());
+        // the signing key is not among the verifying ones
+        let (_, other_public) = ed("other");
+        config.jwt = jwt(Some(private.clone()), vec![other_public]);
+        assert!(matches!(KeySet::new(&config), Err(Error::ConfigParse)));
+        config.jwt = jwt(None, vec![key("ed", KeyAlgorithm::EdDSA, "missing.pem")]);
+        assert!(matches!(KeySet::new(&config), Err(Error::ConfigRead)));
+        // a public key is not a private one, nor an RSA one
+        config.jwt = jwt(Some(public.clone()), vec![public.clone()]);
+        assert!(matches!(KeySet::new(&config), Err(Error::ConfigParse)));
+        config.jwt = jwt(
+            None,
+            vec![key("ed", KeyAlgorithm::RS256, "ed25519.pub.pem")],
+        );
+        assert!(matches!(KeySet::new(&config), Err(Error::ConfigParse)));
+    }
+}
@@ -1,2 +1,5 @@
 pub mod interceptor;
+pub mod keys;
 pub mod limiter;
+pub mod revocation;
+pub mod tls;
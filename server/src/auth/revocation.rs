@@ -0,0 +1,57 @@
+//! Revocation of access tokens by version.
+//!
+//! Every user has a token version, which is put into an access token as `ver` when issued.
+//! Bumping the version revokes all access tokens issued before, e.g. at logout.
+//! Versions are cached, so that checking a token doesn't hit database every time.
+
+use super::interceptor::AccessClaims;
+use crate::db::SqlHelper;
+use abi::error::Error;
+use dashmap::DashMap;
+use std::sync::Arc;
+
+#[derive(Debug, Clone)]
+pub struct TokenVersions {
+    sql_helper: SqlHelper,
+    versions: Arc<DashMap<String, i32>>, // user id - token version
+}
+
+impl TokenVersions {
+    pub fn new(sql_helper: SqlHelper) -> Self {
+        Self {
+            sql_helper,
+            versions: Arc::new(DashMap::new()),
+        }
+    }
+
+    /// Current token version of a user, loaded from database at first.
+    pub async fn current(&self, user_id: &str) -> abi::Result<i32> {
+        if let Some(version) = self.versions.get(user_id) {
+            return Ok(*version);
+        }
+        let version = self
+            .sql_helper
+            .get_token_version(user_id)
+            .await?
+            .ok_or(Error::UserNotFound)?;
+        // a revocation may come while loading, keep the newer one
+        let mut entry = self.versions.entry(user_id.to_string()).or_insert(version);
+        *entry = (*entry).max(version);
+        Ok(*entry)
+    }
+
+    /// Check an access token is not revoked.
+    pub async fn check(&self, claims: &AccessClaims) -> abi::Result<()> {
+        if claims.ver < self.current(&claims.user_id).await? {
+            return Err(Error::Unauthenticated("token revoked"));
+        }
+        Ok(())
+    }
+
+    /// Revoke all access tokens of a user issued so far.
+    pub async fn revoke(&self, user_id: &str) -> abi::Result<()> {
+        let version = self.sql_helper.bump_token_version(user_id).await?;
+        self.versions.insert(user_id.to_string(), version);
+        Ok(())
+    }
+}
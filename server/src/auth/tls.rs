@@ -0,0 +1,91 @@
+//! TLS of manager and chat servers.
+//!
+//! A server with [`TlsConfig`] serves by TLS on its public listener. Users connect without
+//! certificates, and trust the server by their own root CAs.
+//!
+//! Manager and chat servers verify each other by `ca`: each one connects to the other with its
+//! own certificate, and RPCs between them, *report*, *add* and *remove*, require the peer's
+//! certificate, valid for the addr of the peer: the chat server reporting, or manager adding and
+//! removing channels. So certificates must be valid for both server and client auth.
+
+use crate::config::TlsConfig;
+use abi::error::Error;
+use rustls_pki_types::ServerName;
+use std::str::FromStr;
+use tonic::codegen::http::Uri;
+use tonic::transport::{Certificate, ClientTlsConfig, Endpoint, Identity, ServerTlsConfig};
+use tonic::{Request, Status};
+use webpki::EndEntityCert;
+
+fn read(path: &str) -> abi::Result<Vec<u8>> {
+    std::fs::read(path).map_err(|_| Error::ConfigRead)
+}
+
+fn identity(conf: &TlsConfig) -> abi::Result<Identity> {
+    Ok(Identity::from_pem(read(&conf.cert)?, read(&conf.key)?))
+}
+
+/// TLS of the listener, client certificates are verified by `ca` if given.
+pub fn server_tls_config(conf: &TlsConfig) -> abi::Result<ServerTlsConfig> {
+    Ok(ServerTlsConfig::new()
+        .identity(identity(conf)?)
+        .client_ca_root(Certificate::from_pem(read(&conf.ca)?))
+        .client_auth_optional(true))
+}
+
+/// TLS to connect other servers, by own certificate.
+pub fn client_tls_config(conf: &TlsConfig) -> abi::Result<ClientTlsConfig> {
+    Ok(ClientTlsConfig::new()
+        .ca_certificate(Certificate::from_pem(read(&conf.ca)?))
+        .identity(identity(conf)?))
+}
+
+/// Endpoint of another server, connected by own certificate if `tls` is set and the addr is https.
+pub fn endpoint(addr: &str, tls: Option<&TlsConfig>) -> abi::Result<Endpoint> {
+    let endpoint = Endpoint::from_str(addr)?;
+    match tls {
+        Some(conf) if addr.starts_with("https://") => {
+            Ok(endpoint.tls_config(client_tls_config(conf)?)?)
+        }
+        _ => Ok(endpoint),
+    }
+}
+
+/// Check the request is from the server at `addr`, by its certificate verified when connecting.
+///
+/// The certificate must be valid for the host of `addr`, as it would be for connecting to it,
+/// so a server can't act as another one by a token of the shared secret. Without `tls`, there
+/// are no certificates, servers are trusted by tokens only.
+#[allow(clippy::result_large_err)] // a status, as handlers return
+pub fn check_peer<T>(
+    request: &Request<T>,
+    tls: Option<&TlsConfig>,
+    addr: &str,
+) -> Result<(), Status> {
+    if tls.is_none() {
+        return Ok(());
+    }
+    let certs = match request.peer_certs() {
+        Some(certs) if !certs.is_empty() => certs,
+        _ => return Err(Status::unauthenticated("No client certificate provided")),
+    };
+    let name = addr
+        .parse::<Uri>()
+        .ok()
+        .and_then(|uri| {
+            uri.host()
+                .map(|host| host.trim_matches(['[', ']']).to_string())
+        })
+        .and_then(|host| ServerName::try_from(host).ok());
+    let valid = name.is_some_and(|name| {
+        EndEntityCert::try_from(&certs[0])
+            .is_ok_and(|cert| cert.verify_is_valid_for_subject_name(&name).is_ok())
+    });
+    if !valid {
+        return Err(Status::unauthenticated(format!(
+            "Client certificate is not of {}",
+            addr
+        )));
+    }
+    Ok(())
+}
@@ -1,6 +1,7 @@
 use serde::{Deserialize, Serialize};
 use std::{fs, path::Path};
 
+use crate::hash::MAX_WEIGHT;
 use abi::error::Error;
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
@@ -31,13 +32,106 @@ pub struct ServerConfig {
     pub secret: String,
     pub listen_interval: u64,
     pub report_duration: u64,
-    pub empty_live_time: i64,
+    #[serde(default = "default_weight")]
+    pub weight: u32, // for chat server, capacity relative to others, taken when registering
+    #[serde(default = "default_heartbeat_misses")]
+    pub heartbeat_misses: u32, // for manager, evict a chat server missing so many reports
+    #[serde(default = "default_max_cpu")]
+    pub max_cpu: u32, // for manager, no new channels on a chat server above this cpu percent
+    #[serde(default = "default_channel_buffer_size")]
+    pub channel_buffer_size: usize, // for chat server, messages buffered for each listener
+    #[serde(default)]
+    pub argon2: Argon2Config, // for manager, password hashing
+    #[serde(default = "default_access_token_ttl")]
+    pub access_token_ttl: i64, // for manager, seconds an access token lives
+    #[serde(default = "default_refresh_token_ttl")]
+    pub refresh_token_ttl: i64, // for manager, seconds a refresh token lives
+    #[serde(default)]
+    pub jwt: Option<JwtConfig>, // sign users' tokens by key pairs, instead of `secret`
+    #[serde(default)]
+    pub tls: Option<TlsConfig>, // serve by TLS, mutual TLS between manager and chat servers
+}
+
+/// PEM files for TLS, see [`crate::auth::tls`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TlsConfig {
+    pub cert: String, // certificate chain of this server
+    pub key: String,  // private key of `cert`
+    pub ca: String,   // CA of manager's and chat servers' certificates, to verify each other
+}
+
+/// Key pairs for users' tokens, see [`crate::auth::keys::KeySet`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JwtConfig {
+    #[serde(default)]
+    pub signing_key: Option<KeyConfig>, // private key, for manager only
+    pub verifying_keys: Vec<KeyConfig>, // public keys accepted, old and new ones when rotating
+}
+
+/// A PEM key file, identified by `kid` in tokens.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyConfig {
+    pub kid: String,
+    pub alg: KeyAlgorithm,
+    pub path: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum KeyAlgorithm {
+    EdDSA, // Ed25519
+    RS256,
+}
+
+/// Argon2 parameters for password hashing, defaults are argon2's recommended ones.
+///
+/// Changing them re-hashes a user's password at next login.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Argon2Config {
+    pub memory_cost: u32, // in KiB
+    pub time_cost: u32,   // iterations
+    pub parallelism: u32, // lanes
+}
+
+impl Default for Argon2Config {
+    fn default() -> Self {
+        Self {
+            memory_cost: argon2::Params::DEFAULT_M_COST,
+            time_cost: argon2::Params::DEFAULT_T_COST,
+            parallelism: argon2::Params::DEFAULT_P_COST,
+        }
+    }
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+fn default_heartbeat_misses() -> u32 {
+    3
+}
+
+fn default_max_cpu() -> u32 {
+    90
+}
+
+fn default_channel_buffer_size() -> usize {
+    32
+}
+
+fn default_access_token_ttl() -> i64 {
+    15 * 60
+}
+
+fn default_refresh_token_ttl() -> i64 {
+    30 * 24 * 60 * 60
 }
 
 impl Config {
     pub fn load(filename: impl AsRef<Path>) -> Result<Self, Error> {
         let config = fs::read_to_string(filename.as_ref()).map_err(|_| Error::ConfigRead)?;
-        serde_yaml::from_str(&config).map_err(|_| Error::ConfigParse)
+        let config: Self = serde_yaml::from_str(&config).map_err(|_| Error::ConfigParse)?;
+        config.server.validate()?;
+        Ok(config)
     }
 }
 
@@ -68,6 +162,18 @@ impl ServerConfig {
     pub fn url(&self) -> String {
         format!("{}:{}", self.host, self.port)
     }
+    /// Check values which parse but can't work, a chat server would be evicted at once
+    /// without reports or heartbeats, or flood the manager's hash ring by its weight.
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.report_duration == 0 || self.heartbeat_misses == 0 || self.weight > MAX_WEIGHT {
+            return Err(Error::ConfigParse);
+        }
+        Ok(())
+    }
+    /// Addr for others to connect, https if served by TLS.
+    pub fn addr(&self) -> String {
+        self.url_with(self.tls.is_some())
+    }
 }
 
 #[cfg(test)]
@@ -94,9 +200,43 @@ mod tests {
                     port: 50051,
                     listen_interval: 1,
                     report_duration: 3,
-                    empty_live_time: 30,
+                    weight: 1,
+                    heartbeat_misses: 3,
+                    max_cpu: 90,
+                    channel_buffer_size: 32,
+                    argon2: Argon2Config {
+                        memory_cost: 19456,
+                        time_cost: 2,
+                        parallelism: 1,
+                    },
+                    access_token_ttl: 900,
+                    refresh_token_ttl: 2592000,
+                    jwt: None,
+                    tls: None,
                 },
             }
         )
     }
+
+    #[test]
+    fn test_validate() {
+        let config = Config::load("../config/manager.yaml").unwrap();
+        assert!(config.server.validate().is_ok());
+        for server in [
+            ServerConfig {
+                report_duration: 0,
+                ..config.server.clone()
+            },
+            ServerConfig {
+                heartbeat_misses: 0,
+                ..config.server.clone()
+            },
+            ServerConfig {
+                weight: MAX_WEIGHT + 1,
+                ..config.server.clone()
+            },
+        ] {
+            assert!(matches!(server.validate(), Err(Error::ConfigParse)));
+        }
+    }
 }
@@ -1,7 +1,7 @@
 use crate::config::DbConfig;
-use abi::pb::Channel;
+use abi::pb::{Channel, Message, Role};
 use abi::Result;
-use sqlx::{postgres::PgPoolOptions, PgPool, Postgres};
+use sqlx::{postgres::PgPoolOptions, FromRow, PgPool, Postgres, Row};
 
 /// SqlHelper is a helper for sqlx, concurrent safe.
 #[derive(Debug, Clone)]
@@ -38,29 +38,129 @@ impl SqlHelper {
         Ok(password_hash)
     }
 
+    pub async fn update_user_password(&self, id: &str, password_hash: &str) -> Result<()> {
+        sqlx::query("UPDATE chat.users SET password_hash = $2 WHERE id = $1")
+            .bind(id)
+            .bind(password_hash)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn exist_user(&self, id: &str) -> Result<bool> {
+        let exist = sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM chat.users WHERE id = $1)")
+            .bind(id)
+            .fetch_one(&self.pool)
+            .await?;
+        Ok(exist)
+    }
+
+    pub async fn get_token_version(&self, id: &str) -> Result<Option<i32>> {
+        let version = sqlx::query_scalar("SELECT token_version FROM chat.users WHERE id = $1")
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(version)
+    }
+
+    /// Bump the token version of a user, return the new one.
+    pub async fn bump_token_version(&self, id: &str) -> Result<i32> {
+        let version = sqlx::query_scalar(
+            "UPDATE chat.users SET token_version = token_version + 1 WHERE id = $1 RETURNING token_version",
+        )
+        .bind(id)
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(version)
+    }
+
+    pub async fn insert_refresh_token(
+        &self,
+        token_hash: &str,
+        user_id: &str,
+        family: &str,
+        expires_at: i64,
+    ) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO chat.refresh_tokens (token_hash, user_id, family, expires_at) VALUES ($1, $2, $3, $4)",
+        )
+        .bind(token_hash)
+        .bind(user_id)
+        .bind(family)
+        .bind(expires_at)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Get a refresh token by its hash, as (user id, family, expires at), even if revoked.
+    pub async fn get_refresh_token(
+        &self,
+        token_hash: &str,
+    ) -> Result<Option<(String, String, i64)>> {
+        let token = sqlx::query_as(
+            "SELECT user_id, family, expires_at FROM chat.refresh_tokens WHERE token_hash = $1",
+        )
+        .bind(token_hash)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(token)
+    }
+
+    /// Revoke a refresh token, return false if it's already revoked,
+    /// so that only one of concurrent uses succeeds.
+    pub async fn revoke_refresh_token(&self, token_hash: &str) -> Result<bool> {
+        let result = sqlx::query(
+            "UPDATE chat.refresh_tokens SET revoked = TRUE WHERE token_hash = $1 AND NOT revoked",
+        )
+        .bind(token_hash)
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    pub async fn revoke_refresh_family(&self, family: &str) -> Result<()> {
+        sqlx::query("UPDATE chat.refresh_tokens SET revoked = TRUE WHERE family = $1")
+            .bind(family)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
     pub async fn get_channels(&self, channel_id: &i32) -> Result<Vec<Channel>> {
         Ok(match *channel_id {
             0 => {
-                sqlx::query_as("SELECT id, name, limit_num FROM chat.channels")
-                    .fetch_all(&self.pool)
-                    .await?
-            }
-            id => {
-                sqlx::query_as("SELECT id, name, limit_num FROM chat.channels WHERE id = $1")
-                    .bind(id)
+                sqlx::query_as("SELECT id, name, limit_num, mode, buffer_size FROM chat.channels")
                     .fetch_all(&self.pool)
                     .await?
             }
+            id => sqlx::query_as(
+                "SELECT id, name, limit_num, mode, buffer_size FROM chat.channels WHERE id = $1",
+            )
+            .bind(id)
+            .fetch_all(&self.pool)
+            .await?,
         })
     }
 
+    pub async fn get_channels_by_ids(&self, ids: &[i32]) -> Result<Vec<Channel>> {
+        Ok(sqlx::query_as(
+            "SELECT id, name, limit_num, mode, buffer_size FROM chat.channels WHERE id = ANY($1)",
+        )
+        .bind(ids)
+        .fetch_all(&self.pool)
+        .await?)
+    }
+
     pub async fn insert_channel(&self, channel: &Channel, user_id: &str) -> Result<i32> {
         let id = sqlx::query_scalar(
-            "INSERT INTO chat.channels (name, limit_num, owner_id) VALUES ($1, $2, $3) RETURNING id",
+            "INSERT INTO chat.channels (name, limit_num, owner_id, mode, buffer_size) VALUES ($1, $2, $3, $4, $5) RETURNING id",
         )
         .bind(channel.name.clone())
         .bind(channel.limit)
         .bind(user_id)
+        .bind(channel.mode)
+        .bind(channel.buffer_size)
         .fetch_one(&self.pool)
         .await?;
         Ok(id)
@@ -74,12 +174,83 @@ impl SqlHelper {
         Ok(())
     }
 
-    pub async fn get_channel_owner(&self, id: &i32) -> Result<Option<String>> {
-        let owner_id = sqlx::query_scalar("SELECT owner_id FROM chat.channels WHERE id = $1")
-            .bind(id)
-            .fetch_one(&self.pool)
+    /// Get the role of a user on a channel, none if the channel doesn't exist.
+    pub async fn get_role(&self, channel_id: &i32, user_id: &str) -> Result<Option<Role>> {
+        let role: Option<i32> = sqlx::query_scalar(
+            "SELECT CASE WHEN c.owner_id = $2 THEN $3 ELSE COALESCE(r.role, $4) END FROM chat.channels c LEFT JOIN chat.channel_roles r ON r.channel_id = c.id AND r.user_id = $2 WHERE c.id = $1",
+        )
+        .bind(channel_id)
+        .bind(user_id)
+        .bind(Role::Owner as i32)
+        .bind(Role::Member as i32)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(role.map(|role| Role::try_from(role).unwrap_or_default()))
+    }
+
+    /// Set the role of a user on a channel, a member has no role stored.
+    ///
+    /// Owners are not set here, but by creating the channel.
+    pub async fn set_role(&self, channel_id: &i32, user_id: &str, role: Role) -> Result<()> {
+        if role == Role::Member {
+            sqlx::query("DELETE FROM chat.channel_roles WHERE channel_id = $1 AND user_id = $2")
+                .bind(channel_id)
+                .bind(user_id)
+                .execute(&self.pool)
+                .await?;
+        } else {
+            sqlx::query(
+                "INSERT INTO chat.channel_roles (channel_id, user_id, role) VALUES ($1, $2, $3) ON CONFLICT (channel_id, user_id) DO UPDATE SET role = $3",
+            )
+            .bind(channel_id)
+            .bind(user_id)
+            .bind(role as i32)
+            .execute(&self.pool)
             .await?;
-        Ok(owner_id)
+        }
+        Ok(())
+    }
+
+    /// Store a text message of a channel, return its id.
+    pub async fn insert_message(
+        &self,
+        channel_id: &i32,
+        user_id: &str,
+        text: &str,
+        timestamp: i64,
+    ) -> Result<i64> {
+        let id = sqlx::query_scalar(
+            "INSERT INTO chat.messages (channel_id, user_id, content, created_at) VALUES ($1, $2, $3, $4) RETURNING id",
+        )
+        .bind(channel_id)
+        .bind(user_id)
+        .bind(text)
+        .bind(timestamp)
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(id)
+    }
+
+    /// Get at most `limit` text messages of a channel older than `cursor`, newest first,
+    /// with their ids. The newest ones are got if `cursor` is 0.
+    pub async fn get_messages(
+        &self,
+        channel_id: &i32,
+        cursor: i64,
+        limit: i64,
+    ) -> Result<Vec<(i64, Message)>> {
+        let rows = sqlx::query(
+            "SELECT id, user_id, content, created_at FROM chat.messages WHERE channel_id = $1 AND ($2 = 0 OR id < $2) ORDER BY id DESC LIMIT $3",
+        )
+        .bind(channel_id)
+        .bind(cursor)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows
+            .iter()
+            .map(|row| Ok((row.try_get("id")?, Message::from_row(row)?)))
+            .collect::<sqlx::Result<_>>()?)
     }
 }
 
@@ -1,6 +1,13 @@
-use std::collections::hash_map::DefaultHasher;
-use std::collections::BTreeMap;
-use std::hash::{Hash, Hasher};
+use siphasher::sip::SipHasher13;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hasher;
+
+/// Default load bound factor ε, a server takes at most (1 + ε) times its fair share of keys.
+pub const DEFAULT_EPSILON: f64 = 0.25;
+
+/// Largest weight of a server, heavier ones are taken as this, as their virtual nodes
+/// would flood the ring.
+pub const MAX_WEIGHT: u32 = 100;
 
 /// A consistent hashing implementation that distributes keys across a set of servers.
 ///
@@ -8,6 +15,14 @@ use std::hash::{Hash, Hasher};
 /// remapped when servers are added or removed. This implementation uses virtual nodes
 /// to ensure a more even distribution of keys across servers.
 ///
+/// Servers are weighted, a server with weight `w` owns `w` times the virtual nodes, and its
+/// fair share of keys is `w / total weight`. With [`ConsistentHash::get_server_bounded`],
+/// it implements "consistent hashing with bounded loads", so that no server takes more than
+/// (1 + ε) times its fair share.
+///
+/// The hash function is stable, see [`ConsistentHash::hash`], so the ring is the same
+/// across manager restarts and versions.
+///
 /// # Examples
 ///
 /// ```
@@ -21,10 +36,12 @@ use std::hash::{Hash, Hasher};
 /// let server = ch.get_server(key).unwrap();
 /// println!("Key '{}' is assigned to server '{}'", key, server);
 /// ```
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct ConsistentHash {
     ring: BTreeMap<u64, String>, // The hash ring: maps hash values to server addresses
-    virtual_nodes: usize,        // Number of virtual nodes per server
+    virtual_nodes: usize,        // Number of virtual nodes per weight of server
+    weights: HashMap<String, u32>, // Weight of each server
+    epsilon: f64,                // Load bound factor
 }
 
 impl Default for ConsistentHash {
@@ -33,7 +50,8 @@ impl Default for ConsistentHash {
     }
 }
 impl ConsistentHash {
-    /// Creates a new `ConsistentHash` instance with a default of 10 virtual nodes per server.
+    /// Creates a new `ConsistentHash` instance with a default of 10 virtual nodes per server,
+    /// and [`DEFAULT_EPSILON`] as load bound factor.
     ///
     /// # Examples
     ///
@@ -43,13 +61,32 @@ impl ConsistentHash {
     /// let ch = ConsistentHash::new();
     /// ```
     pub fn new() -> Self {
+        Self::with_epsilon(DEFAULT_EPSILON)
+    }
+
+    /// Creates a new `ConsistentHash` instance with a specific load bound factor.
+    ///
+    /// # Arguments
+    ///
+    /// * `epsilon` - A server takes at most (1 + epsilon) times its fair share of keys.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use echo_server::hash::ConsistentHash;
+    ///
+    /// let ch = ConsistentHash::with_epsilon(0.1);
+    /// ```
+    pub fn with_epsilon(epsilon: f64) -> Self {
         Self {
             ring: BTreeMap::new(),
             virtual_nodes: 10,
+            weights: HashMap::new(),
+            epsilon,
         }
     }
 
-    /// Adds a new server to the hash ring.
+    /// Adds a new server with weight 1 to the hash ring.
     ///
     /// Each server is represented by multiple virtual nodes to ensure a more even
     /// distribution of keys.
@@ -67,7 +104,33 @@ impl ConsistentHash {
     /// ch.add_server("server1");
     /// ```
     pub fn add_server(&mut self, server: &str) {
-        for i in 0..self.virtual_nodes {
+        self.add_weighted_server(server, 1);
+    }
+
+    /// Adds a new server with some weight to the hash ring.
+    ///
+    /// The server owns `weight` times the virtual nodes, a zero weight is taken as 1,
+    /// and one above [`MAX_WEIGHT`] as [`MAX_WEIGHT`].
+    /// If the server exists, its weight is updated.
+    ///
+    /// # Arguments
+    ///
+    /// * `server` - The address of the server to add (e.g., "127.0.0.1:8080").
+    /// * `weight` - Capacity of the server relative to others.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use echo_server::hash::ConsistentHash;
+    ///
+    /// let mut ch = ConsistentHash::new();
+    /// ch.add_weighted_server("server1", 2);
+    /// ```
+    pub fn add_weighted_server(&mut self, server: &str, weight: u32) {
+        self.remove_server(server);
+        let weight = weight.clamp(1, MAX_WEIGHT);
+        self.weights.insert(server.to_string(), weight);
+        for i in 0..self.virtual_nodes * weight as usize {
             let virtual_node = format!("{}#{}", server, i);
             let hash = self.hash(&virtual_node);
             self.ring.insert(hash, server.to_string());
@@ -93,7 +156,10 @@ impl ConsistentHash {
     /// ch.remove_server("server1");
     /// ```
     pub fn remove_server(&mut self, server: &str) {
-        for i in 0..self.virtual_nodes {
+        let Some(weight) = self.weights.remove(server) else {
+            return;
+        };
+        for i in 0..self.virtual_nodes * weight as usize {
             let virtual_node = format!("{}#{}", server, i);
             let hash = self.hash(&virtual_node);
             self.ring.remove(&hash);
@@ -129,16 +195,137 @@ impl ConsistentHash {
     /// }
     /// ```
     pub fn get_server(&self, key: &str) -> Option<&String> {
-        let hash = self.hash(&key);
+        let hash = self.hash(key);
         self.ring
             .range(hash..)
             .next()
             .map_or_else(|| self.ring.values().next(), |(_, server)| Some(server))
     }
 
+    /// Gets the server responsible for a particular key under bounded loads.
+    ///
+    /// Starting from the key's position, the ring is walked clockwise, and the first server
+    /// whose load is below its [`ConsistentHash::capacity`] is chosen.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The key to look up (e.g., "my_key").
+    /// * `loads` - The number of keys already assigned to each server.
+    /// * `keys` - The total number of keys, including this one.
+    ///
+    /// # Returns
+    ///
+    /// An `Option<&String>` containing the address of the server responsible for the key.
+    /// If no servers are available, `None` is returned.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::collections::HashMap;
+    /// use echo_server::hash::ConsistentHash;
+    ///
+    /// let mut ch = ConsistentHash::new();
+    /// ch.add_server("server1");
+    /// ch.add_server("server2");
+    ///
+    /// // server1 is full, the key goes to server2
+    /// let loads = HashMap::from([("server1".to_string(), 3)]);
+    /// assert_eq!(ch.get_server_bounded("my_key", &loads, 4).unwrap(), "server2");
+    /// ```
+    pub fn get_server_bounded(
+        &self,
+        key: &str,
+        loads: &HashMap<String, usize>,
+        keys: usize,
+    ) -> Option<&String> {
+        let hash = self.hash(key);
+        self.ring
+            .range(hash..)
+            .chain(self.ring.range(..hash))
+            .map(|(_, server)| server)
+            .find(|server| loads.get(*server).copied().unwrap_or(0) < self.capacity(server, keys))
+    }
+
+    /// Gets the max number of keys a server can take: ⌈(1 + ε) · keys · weight / total weight⌉.
+    ///
+    /// The capacities of all servers sum to at least `keys`, so a key can always be placed.
+    ///
+    /// # Arguments
+    ///
+    /// * `server` - The address of the server.
+    /// * `keys` - The total number of keys.
+    ///
+    /// # Returns
+    ///
+    /// The capacity of the server, 0 if it's not on the ring.
+    pub fn capacity(&self, server: &str, keys: usize) -> usize {
+        let total: u64 = self.weights.values().map(|&weight| weight as u64).sum();
+        let Some(weight) = self.weights.get(server) else {
+            return 0;
+        };
+        let share = keys as f64 * *weight as f64 / total as f64;
+        ((1.0 + self.epsilon) * share).ceil() as usize
+    }
+
+    /// Gets the arcs of the ring owned by a server's virtual nodes.
+    ///
+    /// An arc `(start, end)` holds the hashes in `(start, end]`, it wraps around the ring
+    /// if `start >= end`. Keys whose hash is in these arcs map to the server by
+    /// [`ConsistentHash::get_server`], so they are the only keys taken by a new server.
+    ///
+    /// # Arguments
+    ///
+    /// * `server` - The address of the server.
+    ///
+    /// # Returns
+    ///
+    /// The arcs of the server, empty if it's not on the ring.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use echo_server::hash::ConsistentHash;
+    ///
+    /// let mut ch = ConsistentHash::new();
+    /// ch.add_server("server1");
+    /// ch.add_server("server2");
+    ///
+    /// let hash = ch.hash("my_key");
+    /// let owner = ch.get_server("my_key").unwrap().clone();
+    /// let in_arc = |&(start, end): &(u64, u64)| {
+    ///     if start < end {
+    ///         start < hash && hash <= end
+    ///     } else {
+    ///         start < hash || hash <= end
+    ///     }
+    /// };
+    /// assert!(ch.arcs(&owner).iter().any(in_arc));
+    /// ```
+    pub fn arcs(&self, server: &str) -> Vec<(u64, u64)> {
+        let Some(weight) = self.weights.get(server) else {
+            return vec![];
+        };
+        (0..self.virtual_nodes * *weight as usize)
+            .map(|i| {
+                let end = self.hash(&format!("{}#{}", server, i));
+                let start = self
+                    .ring
+                    .range(..end)
+                    .next_back()
+                    .or_else(|| self.ring.iter().next_back())
+                    .map(|(hash, _)| *hash)
+                    .unwrap_or(end);
+                (start, end)
+            })
+            .collect()
+    }
+
     /// Computes the hash value for a given key.
     ///
-    /// This method uses Rust's `DefaultHasher` to compute a 64-bit hash value.
+    /// This method uses SipHash-1-3 with keys (0, 0) over the UTF-8 bytes of the key followed
+    /// by a `0xff` byte. It's specified here rather than relying on Rust's `DefaultHasher`,
+    /// whose algorithm may change between Rust versions, and it equals what `DefaultHasher`
+    /// computed for a `str`, so existing placement is kept.
     ///
     /// # Arguments
     ///
@@ -147,9 +334,10 @@ impl ConsistentHash {
     /// # Returns
     ///
     /// A `u64` hash value.
-    fn hash<T: Hash>(&self, key: &T) -> u64 {
-        let mut hasher = DefaultHasher::new();
-        key.hash(&mut hasher);
+    pub fn hash(&self, key: &str) -> u64 {
+        let mut hasher = SipHasher13::new_with_keys(0, 0);
+        hasher.write(key.as_bytes());
+        hasher.write_u8(0xff);
         hasher.finish()
     }
 }
@@ -157,6 +345,7 @@ impl ConsistentHash {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use proptest::prelude::*;
 
     #[test]
     fn test_add_server() {
@@ -277,4 +466,200 @@ mod tests {
         // Verify that no server is returned
         assert!(server.is_none());
     }
+
+    #[test]
+    fn test_hash_is_stable() {
+        // the ring must not change across versions, see `ConsistentHash::hash`
+        let ch = ConsistentHash::new();
+        assert_eq!(ch.hash("server1#0"), 1566157077735508518);
+        assert_eq!(ch.hash("key1"), 432334484231585307);
+        assert_eq!(ch.hash(""), 3476900567878811119);
+    }
+
+    #[test]
+    fn test_add_weighted_server() {
+        let mut ch = ConsistentHash::new();
+
+        ch.add_weighted_server("server1", 3);
+        ch.add_server("server2");
+        assert_eq!(ch.ring.len(), 40); // 3 * 10 + 1 * 10 virtual nodes
+
+        // update weight
+        ch.add_weighted_server("server1", 1);
+        assert_eq!(ch.ring.len(), 20);
+
+        ch.remove_server("server1");
+        assert_eq!(ch.ring.len(), 10);
+
+        // too heavy
+        ch.add_weighted_server("server1", u32::MAX);
+        assert_eq!(ch.ring.len(), 10 + 10 * MAX_WEIGHT as usize);
+    }
+
+    #[test]
+    fn test_capacity() {
+        let mut ch = ConsistentHash::with_epsilon(0.5);
+        ch.add_weighted_server("server1", 3);
+        ch.add_server("server2");
+
+        assert_eq!(ch.capacity("server1", 100), 113); // ⌈1.5 * 100 * 3 / 4⌉
+        assert_eq!(ch.capacity("server2", 100), 38); // ⌈1.5 * 100 * 1 / 4⌉
+        assert_eq!(ch.capacity("server3", 100), 0);
+    }
+
+    #[test]
+    fn test_get_server_bounded_empty_ring() {
+        let ch = ConsistentHash::new();
+        assert!(ch.get_server_bounded("key1", &HashMap::new(), 1).is_none());
+    }
+
+    #[test]
+    fn test_arcs() {
+        let mut ch = ConsistentHash::new();
+        ch.add_server("server1");
+        assert!(ch.arcs("server2").is_empty());
+
+        ch.add_weighted_server("server2", 2);
+        let arcs = ch.arcs("server2");
+        assert_eq!(arcs.len(), 20);
+
+        // keys map to server2 iff they are in its arcs
+        let in_arcs = |hash: u64| {
+            arcs.iter().any(|&(start, end)| {
+                if start < end {
+                    start < hash && hash <= end
+                } else {
+                    start < hash || hash <= end
+                }
+            })
+        };
+        for i in 0..1000 {
+            let key = format!("key{}", i);
+            let owned = ch.get_server(&key).unwrap() == "server2";
+            assert_eq!(owned, in_arcs(ch.hash(&key)));
+        }
+    }
+
+    // place keys one by one under bounded loads
+    fn place_bounded(ch: &ConsistentHash, keys: &[String]) -> Vec<String> {
+        let mut loads = HashMap::new();
+        keys.iter()
+            .map(|key| {
+                let server = ch
+                    .get_server_bounded(key, &loads, keys.len())
+                    .unwrap()
+                    .clone();
+                *loads.entry(server.clone()).or_insert(0) += 1;
+                server
+            })
+            .collect()
+    }
+
+    fn servers_strategy() -> impl Strategy<Value = Vec<(String, u32)>> {
+        prop::collection::vec(1u32..5, 1..10).prop_map(|weights| {
+            weights
+                .into_iter()
+                .enumerate()
+                .map(|(i, w)| (format!("10.0.0.{}:50052", i), w))
+                .collect()
+        })
+    }
+
+    fn keys_strategy() -> impl Strategy<Value = Vec<String>> {
+        prop::collection::hash_set(any::<i32>(), 1..1000)
+            .prop_map(|ids| ids.into_iter().map(|id| id.to_string()).collect())
+    }
+
+    proptest! {
+        #[test]
+        fn prop_bounded_load_is_balanced(
+            servers in servers_strategy(),
+            keys in keys_strategy(),
+            epsilon in 0.05f64..1.0,
+        ) {
+            let mut ch = ConsistentHash::with_epsilon(epsilon);
+            for (server, weight) in servers.iter() {
+                ch.add_weighted_server(server, *weight);
+            }
+
+            let placed = place_bounded(&ch, &keys);
+            let mut loads: HashMap<String, usize> = HashMap::new();
+            for server in placed {
+                *loads.entry(server).or_insert(0) += 1;
+            }
+
+            let total: u32 = servers.iter().map(|(_, w)| w).sum();
+            for (server, weight) in servers.iter() {
+                let load = loads.get(server).copied().unwrap_or(0) as f64;
+                let share = keys.len() as f64 * *weight as f64 / total as f64;
+                // at most ⌈(1 + ε) · fair share⌉
+                prop_assert!(load <= ((1.0 + epsilon) * share).ceil());
+            }
+        }
+
+        #[test]
+        fn prop_adding_server_only_moves_keys_to_it(
+            servers in servers_strategy(),
+            keys in keys_strategy(),
+        ) {
+            let mut ch = ConsistentHash::new();
+            for (server, weight) in servers.iter() {
+                ch.add_weighted_server(server, *weight);
+            }
+            let before: Vec<String> = keys.iter().map(|k| ch.get_server(k).unwrap().clone()).collect();
+
+            ch.add_server("new");
+            for (key, old) in keys.iter().zip(before) {
+                let new = ch.get_server(key).unwrap();
+                prop_assert!(*new == old || new == "new");
+            }
+        }
+
+        #[test]
+        fn prop_adding_server_moves_few_keys_under_bounded_load(
+            servers in servers_strategy(),
+            keys in keys_strategy(),
+            weight in 1u32..5,
+        ) {
+            let mut ch = ConsistentHash::new();
+            for (server, weight) in servers.iter() {
+                ch.add_weighted_server(server, *weight);
+            }
+            let before = place_bounded(&ch, &keys);
+
+            ch.add_weighted_server("new", weight);
+            let after = place_bounded(&ch, &keys);
+
+            // keys are forced to move to the new server, and off old servers whose loads are
+            // over their capacities ⌈(1 + ε) · k · w / W⌉, which shrink as W grows by the new
+            // weight. With few keys, rounding shrinks a capacity by a whole key even when the
+            // new server's share is below one. Each forced key cascades a few more.
+            let mut loads: HashMap<&String, usize> = HashMap::new();
+            for server in before.iter() {
+                *loads.entry(server).or_insert(0) += 1;
+            }
+            let overflowed: usize = loads
+                .iter()
+                .map(|(server, load)| load.saturating_sub(ch.capacity(server, keys.len())))
+                .sum();
+            let forced = ch.capacity("new", keys.len()) + overflowed;
+            let moved = before.iter().zip(after.iter()).filter(|(a, b)| a != b).count();
+            prop_assert!(moved <= 3 * forced, "moved {} of {} forced", moved, forced);
+        }
+
+        #[test]
+        fn prop_ring_is_independent_of_insertion_order(
+            servers in servers_strategy(),
+            keys in keys_strategy(),
+        ) {
+            let (mut a, mut b) = (ConsistentHash::new(), ConsistentHash::new());
+            for (server, weight) in servers.iter() {
+                a.add_weighted_server(server, *weight);
+            }
+            for (server, weight) in servers.iter().rev() {
+                b.add_weighted_server(server, *weight);
+            }
+            prop_assert_eq!(place_bounded(&a, &keys), place_bounded(&b, &keys));
+        }
+    }
 }
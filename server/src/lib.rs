@@ -2,7 +2,7 @@ use std::pin::Pin;
 
 use futures::Stream;
 
-mod auth;
+pub mod auth;
 pub mod config;
 pub mod db;
 pub mod hash;
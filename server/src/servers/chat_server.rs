@@ -1,28 +1,53 @@
-use super::client::ChannelClient;
+use super::client::{Backoff, ChannelClient};
+use super::fanout::{FanOut, RecvError, Subscriber};
+use super::metric::{Counters, Sampler, ServerMetric};
+use super::mixer::{self, Mixer};
+use crate::auth::interceptor::{ListenClaims, ServerClaims};
+use crate::auth::keys::KeySet;
+use crate::auth::tls::{check_peer, server_tls_config};
 use crate::{config::ServerConfig, db::SqlHelper, get_claims_from};
+use abi::codec::{self, Decoder, Encoder};
 use abi::error::Error;
 use abi::pb::{
-    chat_service_server::ChatServiceServer, Channel, Message, ReportRequest, ShutdownRequest, User,
+    chat_service_server::ChatServiceServer, message::Content, Channel, ChannelMode, Codec, Message,
+    Metric, MigrateRequest, Reconnect, ReportRequest, ReportResponse, Role, Roster, ServerMute,
+    ShutdownRequest, User, VoiceState,
 };
 use chrono::Utc;
-use dashmap::DashMap;
-use log::{error, info};
+use dashmap::{DashMap, DashSet};
+use log::{error, info, warn};
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
 use std::pin::Pin;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use tokio::sync::broadcast;
-use tokio::sync::mpsc::Sender;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use tokio_stream::wrappers::TcpListenerStream;
 use tokio_stream::Stream;
 use tonic::{Request, Response, Status, Streaming};
+
+// a user stops talking when no audio comes for this long
+const SPEAKING_TIMEOUT: Duration = Duration::from_millis(500);
+// texts and events queued for a user, who is disconnected instead of missing more
+const CONTROL_BUFFER_SIZE: usize = 256;
+// texts of a channel waiting to be stored and sent, senders wait beyond them
+const TEXT_BUFFER_SIZE: usize = 256;
+
 #[derive(Debug)]
 #[allow(unused)]
 pub struct ChatService {
     manager_addr: String,
     config: ServerConfig,
     sql_helper: SqlHelper,
+    keys: Arc<KeySet>, // public keys of users' tokens
 
     // for chat
     core: Arc<DashMap<i32, ChannelCore>>, // drop channel when no one exists
+    counters: Arc<Counters>,              // for metric
 }
 
 /// !Concurrent Safe Channel Core Logic
@@ -32,166 +57,703 @@ struct ChannelCore {
     pub id: i32,
     pub name: String,
     pub limit: i32,
-    pub broadcast: broadcast::Sender<Message>,
-    // record shutdown_tx for every user on this channel，Key is user_id
-    user_shutdown_txs: DashMap<String, broadcast::Sender<()>>,
+    pub mode: ChannelMode,
+    pub buffer_size: i32, // as set by the channel, 0 for default
+    // audio to users but the speaker, a lagging user skips the oldest ones
+    fanout: Arc<FanOut>,
+    // record connection for every user on this channel，Key is user_id
+    users: Arc<DashMap<String, UserConn>>,
+    // held while a user joins, so that joins pass the limit one by one
+    joining: Mutex<()>,
+    // users muted by moderators, kept when they reconnect
+    server_mutes: Arc<DashSet<String>>,
+    // mix users' audio in mix mode, text is still broadcast
+    mixer: Option<(Arc<Mutex<Mixer>>, JoinHandle<()>)>,
+    // texts of users to the text task, with the user excepted
+    texts: Sender<(Message, Option<String>)>,
+}
+
+/// User's connection on some channel.
+///
+/// Texts and events go by `control`, ahead of audio on the user's stream. They are never
+/// dropped: a user not taking them is disconnected, and gets a fresh roster by joining again.
+#[derive(Debug, Clone)]
+struct UserConn {
+    shutdown_tx: broadcast::Sender<()>,
+    tx: Sender<Result<Message, Status>>, // send audio to this user only
+    control: Sender<Result<Message, Status>>, // send texts and events to this user only
+    voice: Arc<Mutex<VoiceState>>,
+}
+
+impl UserConn {
+    // queue a text or an event for the user without waiting, or disconnect the user
+    fn notify(&self, user_id: &str, channel_id: i32, counters: &Counters, msg: Message) {
+        let len = prost::Message::encoded_len(&msg);
+        match self.control.try_send(Ok(msg)) {
+            Ok(()) => counters.add_bytes_out(len),
+            Err(TrySendError::Full(_)) => {
+                warn!(
+                    "{}-{} doesn't take events, disconnect it",
+                    user_id, channel_id
+                );
+                let _ = self.shutdown_tx.send(());
+            }
+            Err(TrySendError::Closed(_)) => {}
+        }
+    }
+}
+
+/// Where a user's message goes: the mixer for audio in mix mode, or fan-out to other users.
+/// Text goes to every user's own stream, so that it's never dropped for a lagging user,
+/// and it's echoed to the sender if asked. It's sent by the channel's text task, in order,
+/// so that neither the database nor a slow user delays the sender's audio.
+///
+/// Every connection has its own sender, which decodes the user's audio for the mixer.
+/// Text is stored as the channel's history before it goes.
+///
+/// Audio of a muted user goes nowhere. Voice states changed by the user, or by moderators
+/// for others, go to every user. Whether the user is talking, by its audio, goes to others,
+/// as the user knows it by its own voice activity detection. These events go as text does,
+/// see [`UserConn`].
+/// Events of the server can't be sent by users.
+#[derive(Debug)]
+struct ChannelSender {
+    channel_id: i32,
+    sql_helper: SqlHelper,
+    fanout: Arc<FanOut>,
+    users: Arc<DashMap<String, UserConn>>,
+    server_mutes: Arc<DashSet<String>>,
+    voice: Arc<Mutex<VoiceState>>,   // of the user sending
+    speaking_until: Option<Instant>, // talking until then, unless more audio comes
+    mixer: Option<Arc<Mutex<Mixer>>>,
+    decoder: Option<Decoder>, // created on the first Opus frame
+    legacy: bool,             // user sends raw PCM, from an old client
+    texts: Sender<(Message, Option<String>)>, // to the text task, with the user excepted
+    counters: Arc<Counters>,
+}
+
+impl ChannelSender {
+    async fn send(&mut self, msg: Message) {
+        match &msg.content {
+            Some(Content::Voice(state)) => {
+                let (self_mute, self_deaf) = (state.self_mute, state.self_deaf);
+                self.update_voice(&msg.user_id, |voice| {
+                    voice.self_mute = self_mute;
+                    voice.self_deaf = self_deaf;
+                });
+                return;
+            }
+            Some(Content::ServerMute(req)) => {
+                self.server_mute(&msg.user_id, req).await;
+                return;
+            }
+            Some(
+                Content::Roster(_)
+                | Content::Join(_)
+                | Content::Leave(_)
+                | Content::Speaking(_)
+                | Content::Reconnect(_),
+            ) => {
+                warn!(
+                    "user: {} sends a server event on channel: {}",
+                    msg.user_id, self.channel_id
+                );
+                return;
+            }
+            Some(Content::AudioData(_)) if self.voice.lock().unwrap().muted() => return,
+            Some(Content::AudioData(_)) => self.speak(&msg.user_id),
+            _ => {}
+        }
+        if let Some(Content::Text(_)) = &msg.content {
+            let except = (!msg.echo).then(|| msg.user_id.clone());
+            if self.texts.send((msg, except)).await.is_err() {
+                error!("text task of channel: {} is closed", self.channel_id);
+            }
+            return;
+        }
+        if let Some(Content::AudioData(data)) = &msg.content {
+            if msg.codec().is_legacy() && !self.legacy {
+                self.legacy = true;
+                warn!(
+                    "user: {} sends raw PCM audio, it's an old client",
+                    msg.user_id
+                );
+            }
+            if let Some(mixer) = self.mixer.clone() {
+                match self.decode(msg.codec(), msg.seq, data) {
+                    Ok(samples) => mixer.lock().unwrap().push(&msg.user_id, samples),
+                    Err(e) => error!("decode audio from {} failed: {:?}", msg.user_id, e),
+                }
+                return;
+            }
+        }
+        self.fanout.send(&msg, Some(&msg.user_id));
+    }
+
+    // change the voice state of a connected user, and tell every user
+    fn update_voice(&mut self, user_id: &str, f: impl FnOnce(&mut VoiceState)) {
+        let Some(voice) = self.users.get(user_id).map(|conn| Arc::clone(&conn.voice)) else {
+            return;
+        };
+        let state = {
+            let mut voice = voice.lock().unwrap();
+            f(&mut voice);
+            *voice
+        };
+        info!(
+            "voice of {}-{} changed: {:?}",
+            user_id, self.channel_id, state
+        );
+        let msg = event(user_id, Content::Voice(state));
+        notify_all(&self.users, &self.counters, self.channel_id, msg, None);
+    }
+
+    // audio keeps the user talking, and a new talk is told to other users
+    fn speak(&mut self, user_id: &str) {
+        let started = self.speaking_until.is_none();
+        self.speaking_until = Some(Instant::now() + SPEAKING_TIMEOUT);
+        if started {
+            let msg = event(user_id, Content::Speaking(true));
+            notify_all(
+                &self.users,
+                &self.counters,
+                self.channel_id,
+                msg,
+                Some(user_id),
+            );
+        }
+    }
+
+    // no audio for a while, the talk is over
+    fn stop_speaking(&mut self, user_id: &str) {
+        if self.speaking_until.take().is_some() {
+            let msg = event(user_id, Content::Speaking(false));
+            notify_all(
+                &self.users,
+                &self.counters,
+                self.channel_id,
+                msg,
+                Some(user_id),
+            );
+        }
+    }
+
+    // mute a user or not by a moderator, kept even if the user isn't connected yet
+    async fn server_mute(&mut self, moderator: &str, req: &ServerMute) {
+        match self.can_moderate(moderator, &req.user_id).await {
+            Ok(true) => {}
+            Ok(false) => {
+                warn!(
+                    "user: {} can't mute {} on channel: {}",
+                    moderator, req.user_id, self.channel_id
+                );
+                return;
+            }
+            Err(e) => {
+                error!("check role of {} failed: {}", moderator, e);
+                return;
+            }
+        }
+        if req.mute {
+            self.server_mutes.insert(req.user_id.clone());
+        } else {
+            self.server_mutes.remove(&req.user_id);
+        }
+        let mute = req.mute;
+        self.update_voice(&req.user_id, |voice| voice.server_mute = mute);
+    }
+
+    async fn can_moderate(&mut self, user_id: &str, target: &str) -> abi::Result<bool> {
+        let role = self.sql_helper.get_role(&self.channel_id, user_id).await?;
+        let target = self.sql_helper.get_role(&self.channel_id, target).await?;
+        Ok(role
+            .unwrap_or_default()
+            .can_moderate(target.unwrap_or_default()))
+    }
+
+    fn decode(&mut self, codec: Codec, seq: u32, data: &[u8]) -> abi::Result<Vec<f32>> {
+        match codec {
+            Codec::PcmF32 => Ok(codec::decode_pcm(data)),
+            Codec::Opus => {
+                let decoder = match &mut self.decoder {
+                    Some(decoder) => decoder,
+                    None => self.decoder.insert(Decoder::new()?),
+                };
+                decoder.decode(seq, data)
+            }
+        }
+    }
+}
+
+// an event of `user_id` from server
+fn event(user_id: &str, content: Content) -> Message {
+    Message {
+        user_id: user_id.to_string(),
+        timestamp: Utc::now().timestamp_millis(),
+        content: Some(content),
+        ..Message::default()
+    }
+}
+
+// send a text or an event to every user's own stream but `except`, without waiting for anyone.
+fn notify_all(
+    users: &DashMap<String, UserConn>,
+    counters: &Counters,
+    channel_id: i32,
+    msg: Message,
+    except: Option<&str>,
+) {
+    for entry in users.iter() {
+        if Some(entry.key().as_str()) == except {
+            continue;
+        }
+        entry
+            .value()
+            .notify(entry.key(), channel_id, counters, msg.clone());
+    }
+}
+
+// store texts of users, then send them to every user's own stream but the excepted one,
+// one after another, a slow database delays the text task instead of the senders.
+fn spawn_text_task(
+    channel_id: i32,
+    sql_helper: SqlHelper,
+    users: Arc<DashMap<String, UserConn>>,
+    counters: Arc<Counters>,
+    mut texts: Receiver<(Message, Option<String>)>,
+) {
+    tokio::spawn(async move {
+        while let Some((msg, except)) = texts.recv().await {
+            if let Some(Content::Text(text)) = &msg.content {
+                if let Err(e) = sql_helper
+                    .insert_message(&channel_id, &msg.user_id, text, msg.timestamp)
+                    .await
+                {
+                    error!("store message of channel: {} failed: {}", channel_id, e);
+                }
+            }
+            notify_all(&users, &counters, channel_id, msg, except.as_deref());
+        }
+    });
 }
 
 impl ChannelCore {
-    fn new(channel: Channel) -> Self {
+    /// Create a channel, `buffer_size` is used unless set by the channel.
+    fn new(
+        channel: Channel,
+        buffer_size: usize,
+        sql_helper: SqlHelper,
+        counters: Arc<Counters>,
+    ) -> Self {
+        let users = Arc::new(DashMap::new());
+        let (texts, rx) = mpsc::channel(TEXT_BUFFER_SIZE);
+        spawn_text_task(channel.id, sql_helper, Arc::clone(&users), counters, rx);
+        let mode = channel.mode();
+        let mixer = (mode == ChannelMode::Mix).then(|| {
+            let mixer = Arc::new(Mutex::new(Mixer::new()));
+            let task = spawn_mix_task(channel.id, Arc::clone(&mixer), Arc::clone(&users));
+            (mixer, task)
+        });
         Self {
             id: channel.id,
             name: channel.name,
             limit: channel.limit,
-            broadcast: broadcast::channel(32).0,
-            user_shutdown_txs: DashMap::new(),
+            mode,
+            buffer_size: channel.buffer_size,
+            fanout: Arc::new(FanOut::new(match channel.buffer_size {
+                0 => buffer_size,
+                n => n as usize,
+            })),
+            users,
+            joining: Mutex::new(()),
+            server_mutes: Arc::new(DashSet::new()),
+            mixer,
+            texts,
+        }
+    }
+
+    // sender of a user's connection, with the user's voice state
+    fn sender(
+        &self,
+        sql_helper: SqlHelper,
+        counters: Arc<Counters>,
+        voice: Arc<Mutex<VoiceState>>,
+    ) -> ChannelSender {
+        ChannelSender {
+            channel_id: self.id,
+            sql_helper,
+            fanout: Arc::clone(&self.fanout),
+            users: Arc::clone(&self.users),
+            server_mutes: Arc::clone(&self.server_mutes),
+            voice,
+            speaking_until: None,
+            mixer: self.mixer.as_ref().map(|(mixer, _)| Arc::clone(mixer)),
+            decoder: None,
+            legacy: false,
+            texts: self.texts.clone(),
+            counters,
         }
     }
 
     pub fn is_full(&self) -> bool {
-        self.user_shutdown_txs.len() >= self.limit as usize
+        self.users.len() >= self.limit as usize
     }
 
-    // add user's shutdown_tx
-    pub fn add_user_shutdown_tx(&self, user_id: String, shutdown_tx: broadcast::Sender<()>) {
-        self.user_shutdown_txs.insert(user_id, shutdown_tx);
+    // add user's connection, unless the user is connected already or the channel is full
+    fn add_user(&self, user_id: String, conn: UserConn) -> abi::Result<()> {
+        let _joining = self.joining.lock().unwrap();
+        // users only leave meanwhile, the count can't be read under the entry's shard lock
+        let full = self.is_full();
+        match self.users.entry(user_id) {
+            dashmap::Entry::Occupied(_) => Err(Error::InvalidRequest("user already in channel")),
+            dashmap::Entry::Vacant(_) if full => Err(Error::InvalidRequest("channel is full")),
+            dashmap::Entry::Vacant(entry) => {
+                entry.insert(conn);
+                Ok(())
+            }
+        }
+    }
+
+    // users on this channel with their voice states
+    fn roster(&self) -> Roster {
+        let users = self
+            .users
+            .iter()
+            .map(|v| User {
+                id: v.key().to_string(),
+                voice: Some(*v.voice.lock().unwrap()),
+                ..Default::default()
+            })
+            .collect();
+        Roster { users }
     }
 
     // remove specific user from current channel
     fn shutdown_user(&self, user_id: &str) {
-        let shutdown_tx = self.user_shutdown_txs.remove(user_id).map(|(_, tx)| tx);
-
-        if let Some(tx) = shutdown_tx {
-            let _ = tx.send(());
-        }
+        self.remove_user(user_id, |_| true);
     }
 
-    pub fn exist_user(&self, user_id: &str) -> bool {
-        self.user_shutdown_txs.contains_key(user_id)
+    // remove the user's connection if `f` holds for it
+    fn remove_user(&self, user_id: &str, f: impl FnOnce(&UserConn) -> bool) {
+        let Some((_, conn)) = self.users.remove_if(user_id, |_, conn| f(conn)) else {
+            return;
+        };
+        self.fanout.unsubscribe(user_id);
+        if let Some((mixer, _)) = &self.mixer {
+            mixer.lock().unwrap().remove(user_id);
+        }
+        let _ = conn.shutdown_tx.send(());
     }
-}
 
-impl Drop for ChannelCore {
-    fn drop(&mut self) {
+    // remove all users from current channel
+    fn shutdown_all(&self) {
         let txs: Vec<_> = self
-            .user_shutdown_txs
+            .users
             .iter()
-            .map(|entry| entry.value().clone())
+            .map(|entry| {
+                self.fanout.unsubscribe(entry.key());
+                entry.value().shutdown_tx.clone()
+            })
             .collect();
 
-        self.user_shutdown_txs.clear();
+        self.users.clear();
 
         // send signal outside lock
         for tx in txs {
             let _ = tx.send(());
         }
     }
+
+    // tell every user to reconnect to the new server, then remove them.
+    async fn migrate(&self, req: &MigrateRequest) {
+        let conns: Vec<_> = self
+            .users
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.value().clone()))
+            .collect();
+
+        for (user_id, conn) in conns {
+            let msg = Message {
+                timestamp: Utc::now().timestamp_millis(),
+                content: Some(Content::Reconnect(Reconnect {
+                    addr: req.addr.clone(),
+                    token: req.tokens.get(&user_id).cloned().unwrap_or_default(),
+                })),
+                ..Message::default()
+            };
+            // the user is disconnected right after anyway
+            if let Err(e) = conn.control.try_send(Ok(msg)) {
+                error!("send reconnect to {}-{} failed: {}", user_id, self.id, e);
+            }
+        }
+        self.shutdown_all();
+    }
+}
+
+impl Drop for ChannelCore {
+    fn drop(&mut self) {
+        self.shutdown_all();
+        if let Some((_, task)) = &self.mixer {
+            task.abort();
+        }
+    }
 }
+
+// every frame, mix users' audio and send each user the mix without own voice.
+// a user not keeping up loses frames, instead of delaying others.
+// every listener has an encoder, as Opus frames depend on previous ones.
+fn spawn_mix_task(
+    channel_id: i32,
+    mixer: Arc<Mutex<Mixer>>,
+    users: Arc<DashMap<String, UserConn>>,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(mixer::FRAME_DURATION);
+        let mut encoders: HashMap<String, Encoder> = HashMap::new();
+        loop {
+            interval.tick().await;
+            let listeners: Vec<_> = users
+                .iter()
+                .filter(|entry| !entry.value().voice.lock().unwrap().deafened())
+                .map(|entry| (entry.key().clone(), entry.value().tx.clone()))
+                .collect();
+            encoders.retain(|user_id, _| users.contains_key(user_id));
+            let mut mixed = mixer
+                .lock()
+                .unwrap()
+                .mix(listeners.iter().map(|(user_id, _)| user_id.as_str()));
+            for (user_id, tx) in listeners {
+                let Some(frame) = mixed.remove(&user_id) else {
+                    continue;
+                };
+                let encoded = match encode_mixed(&mut encoders, &user_id, &frame) {
+                    Ok(encoded) => encoded,
+                    Err(e) => {
+                        error!(
+                            "encode mixed audio for {}-{} failed: {:?}",
+                            user_id, channel_id, e
+                        );
+                        continue;
+                    }
+                };
+                let msg = Message {
+                    timestamp: Utc::now().timestamp_millis(),
+                    content: Some(Content::AudioData(encoded.data)),
+                    codec: Codec::Opus.into(),
+                    seq: encoded.seq,
+                    ..Message::default()
+                };
+                if let Err(e) = tx.try_send(Ok(msg)) {
+                    error!(
+                        "send mixed audio to {}-{} failed: {}",
+                        user_id, channel_id, e
+                    );
+                }
+            }
+        }
+    })
+}
+// encode a mixed frame with the listener's encoder, it makes exactly one encoded frame.
+fn encode_mixed(
+    encoders: &mut HashMap<String, Encoder>,
+    user_id: &str,
+    frame: &[f32],
+) -> abi::Result<codec::Frame> {
+    let encoder = match encoders.entry(user_id.to_string()) {
+        Entry::Occupied(entry) => entry.into_mut(),
+        Entry::Vacant(entry) => entry.insert(Encoder::new()?),
+    };
+    let mut frames = encoder.encode(frame)?;
+    Ok(frames.pop().expect("a mixed frame is one encoded frame"))
+}
+
+// backoff of reconnecting to manager
+const REPORT_BACKOFF_MIN: Duration = Duration::from_millis(100);
+const REPORT_BACKOFF_MAX: Duration = Duration::from_secs(10);
+
 impl ChatService {
-    pub async fn new(manager_addr: String, config: &ServerConfig, sql_helper: SqlHelper) -> Self {
+    pub fn new(
+        manager_addr: String,
+        config: &ServerConfig,
+        sql_helper: SqlHelper,
+        keys: Arc<KeySet>,
+    ) -> Self {
         Self {
             manager_addr,
             config: config.clone(),
             sql_helper,
+            keys,
             core: Arc::new(DashMap::new()),
+            counters: Arc::new(Counters::default()),
         }
         .register()
-        .await
     }
 
-    // register chat service on manager
-    async fn register(self) -> Self {
-        let (tx, rx) = tokio::sync::mpsc::channel(100);
-        let mut client = ChannelClient::new(&self.manager_addr, &self.config.secret).await;
-        let rsp = client
-            .report(self.config.url_with(false), rx)
-            .await
-            .unwrap(); // todo handle err, and deal with retrying.
-
+    // register chat service on manager, and keep reporting in background.
+    // report stream is reconnected with backoff when manager is down,
+    // users on this server keep chatting meanwhile.
+    fn register(self) -> Self {
+        let manager_addr = self.manager_addr.clone();
+        let config = self.config.clone();
+        let keys = Arc::clone(&self.keys);
         let core = Arc::clone(&self.core);
-        let mut stream = rsp.into_inner();
+        let counters = Arc::clone(&self.counters);
         tokio::spawn(async move {
-            while let Ok(rsp) = stream.message().await {
-                if let Some(rsp) = rsp {
-                    // 1. check shutdown signal
-                    if let Some(req) = rsp.shutdown {
-                        info!("shutdown channel req: {:?}", req);
-                        if let Some(user_id) = req.user_id {
-                            if let Some(channel_core) = core.get(&req.channel_id) {
-                                channel_core.shutdown_user(&user_id);
-                            } else {
-                                error!("channel: {} not found", req.channel_id);
-                            }
-                        } else {
-                            core.remove(&req.channel_id).unwrap();
-                        }
-                    }
-                } else {
-                    break;
+            let mut backoff = Backoff::new(REPORT_BACKOFF_MIN, REPORT_BACKOFF_MAX);
+            loop {
+                match report(
+                    &manager_addr,
+                    &config,
+                    &keys,
+                    &core,
+                    &counters,
+                    &mut backoff,
+                )
+                .await
+                {
+                    Ok(()) => error!("report stream to manager: {} closed", manager_addr),
+                    Err(e) => error!("report to manager: {} failed: {:?}", manager_addr, e),
                 }
+                let delay = backoff.next_delay();
+                info!("reconnect to manager: {} in {:?}", manager_addr, delay);
+                tokio::time::sleep(delay).await;
             }
         });
-        self.report(tx, Duration::from_secs(self.config.report_duration));
         self
     }
+}
 
-    fn report(&self, tx: Sender<ReportRequest>, d: Duration) {
-        // report channels
-        let core = Arc::clone(&self.core);
-        tokio::spawn(async move {
-            loop {
-                tokio::time::sleep(d).await;
-                let mut vec = vec![];
-                for channel in core.iter() {
-                    vec.push(Channel {
-                        id: channel.id,
-                        name: channel.name.clone(),
-                        limit: channel.limit,
-                        users: channel
-                            .user_shutdown_txs
-                            .iter()
-                            .map(|v| User {
-                                id: v.key().to_string(),
-                                ..Default::default()
-                            })
-                            .collect(),
-                    })
-                }
+// connect to manager and report until the stream breaks.
+async fn report(
+    manager_addr: &str,
+    config: &ServerConfig,
+    keys: &Arc<KeySet>,
+    core: &Arc<DashMap<i32, ChannelCore>>,
+    counters: &Arc<Counters>,
+    backoff: &mut Backoff,
+) -> abi::Result<()> {
+    let mut client =
+        ChannelClient::new(manager_addr, Arc::clone(keys), config.tls.as_ref()).await?;
+    let (tx, rx) = tokio::sync::mpsc::channel(100);
+    spawn_report_task(
+        Arc::clone(core),
+        Sampler::new(Arc::clone(counters)),
+        config.clone(),
+        tx,
+    );
+    let mut stream = client.report(config.addr(), rx).await?.into_inner();
+    info!("report stream to manager: {} established", manager_addr);
+    backoff.reset();
 
-                if let Err(e) = tx
-                    .send(ReportRequest {
-                        channels: vec,
-                        ..Default::default()
-                    })
-                    .await
-                {
-                    error!("report tx error: {}", e);
-                    break;
-                }
+    while let Some(rsp) = stream.message().await? {
+        handle_report_response(core, rsp);
+    }
+    Ok(())
+}
+
+fn handle_report_response(core: &DashMap<i32, ChannelCore>, rsp: ReportResponse) {
+    // 1. check shutdown signal
+    if let Some(req) = rsp.shutdown {
+        info!("shutdown channel req: {:?}", req);
+        if let Some(channel_core) = core.get(&req.channel_id) {
+            if let Some(user_id) = req.user_id {
+                channel_core.shutdown_user(&user_id);
+            } else {
+                channel_core.shutdown_all();
             }
-        });
+        } else {
+            error!("channel: {} not found", req.channel_id);
+        }
+    }
+    // 2. check migrate signal, refuse new joins before draining the channel
+    if let Some(req) = rsp.migrate {
+        info!("migrate channel req: {:?}", req);
+        if let Some((_, channel_core)) = core.remove(&req.channel_id) {
+            tokio::spawn(async move { channel_core.migrate(&req).await });
+        } else {
+            error!("channel: {} not found", req.channel_id);
+        }
     }
 }
 
+// report full state at once, manager registers this server by the first report,
+// then report periodically until the stream is closed.
+fn spawn_report_task(
+    core: Arc<DashMap<i32, ChannelCore>>,
+    mut sampler: Sampler,
+    config: ServerConfig,
+    tx: Sender<ReportRequest>,
+) {
+    tokio::spawn(async move {
+        loop {
+            let mut vec = vec![];
+            for channel in core.iter() {
+                vec.push(Channel {
+                    id: channel.id,
+                    name: channel.name.clone(),
+                    limit: channel.limit,
+                    mode: channel.mode.into(),
+                    buffer_size: channel.buffer_size,
+                    users: channel.roster().users,
+                })
+            }
+
+            let metric = ServerMetric {
+                weight: config.weight,
+                interval: config.report_duration,
+                users: vec.iter().map(|c| c.users.len() as u64).sum(),
+                channels: vec.len() as u64,
+                ..sampler.sample()
+            };
+            if let Err(e) = tx
+                .send(ReportRequest {
+                    metric: Some(Metric::from(&metric)),
+                    channels: vec,
+                })
+                .await
+            {
+                error!("report tx error: {}", e);
+                break;
+            }
+            tokio::time::sleep(Duration::from_secs(config.report_duration)).await;
+        }
+    });
+}
+
 async fn run_connection_tasks(
     user_id: String,
     channel_id: i32,
-    broadcast: broadcast::Sender<Message>,
+    sender: ChannelSender,
     inbound: Streaming<Message>,
-    outbound: broadcast::Receiver<Message>,
-    tx: tokio::sync::mpsc::Sender<Result<Message, Status>>,
-    shutdown_tx: broadcast::Sender<()>,
+    outbound: Subscriber,
+    conn: UserConn,
+    counters: Arc<Counters>,
 ) {
+    let UserConn {
+        shutdown_tx,
+        tx,
+        voice,
+    } = conn;
     let inbound_task = spawn_inbound_task(
         user_id.clone(),
         channel_id,
-        broadcast,
+        sender,
         inbound,
         shutdown_tx.clone(),
+        counters.clone(),
+    );
+    let outbound_task = spawn_outbound_task(
+        user_id.clone(),
+        channel_id,
+        outbound,
+        tx,
+        voice,
+        shutdown_tx,
+        counters.clone(),
     );
-    let outbound_task = spawn_outbound_task(user_id.clone(), channel_id, outbound, tx, shutdown_tx);
 
     let _ = tokio::join!(inbound_task, outbound_task); // make sure both tasks are finished
+    counters.remove_user(channel_id, &user_id);
     info!(
         "user_id: {}, channel_id: {} fully disconnected",
         user_id, channel_id
@@ -201,20 +763,23 @@ async fn run_connection_tasks(
 fn spawn_inbound_task(
     user_id: String,
     channel_id: i32,
-    broadcast: broadcast::Sender<Message>,
+    mut sender: ChannelSender,
     mut inbound: Streaming<Message>,
     shutdown_tx: broadcast::Sender<()>,
+    counters: Arc<Counters>,
 ) -> tokio::task::JoinHandle<()> {
     let mut shutdown_rx = shutdown_tx.subscribe();
     tokio::spawn(async move {
         loop {
+            let speaking_until = sender.speaking_until;
             tokio::select! {
                 res = inbound.message() => match res {
                     Ok(Some(mut msg)) => {
+                        counters.add_bytes_in(prost::Message::encoded_len(&msg));
                         msg.user_id = user_id.to_string();
                         msg.timestamp = Utc::now().timestamp_millis();
                         info!("receive msg: {:?} from {}-{}", msg, user_id, channel_id);
-                        broadcast.send(msg).unwrap(); // todo: handle err
+                        sender.send(msg).await;
                     }
                     Ok(None) => {
                         info!("receive None, closing connection for {}-{}", user_id, channel_id);
@@ -225,6 +790,8 @@ fn spawn_inbound_task(
                         break;
                     }
                 },
+                _ = tokio::time::sleep_until(speaking_until.unwrap_or_else(Instant::now)),
+                    if speaking_until.is_some() => sender.stop_speaking(&user_id),
                 _ = shutdown_rx.recv() => {
                     info!("inbound task received shutdown signal for {}-{}", user_id, channel_id);
                     break;
@@ -239,23 +806,37 @@ fn spawn_inbound_task(
 fn spawn_outbound_task(
     user_id: String,
     channel_id: i32,
-    mut outbound: broadcast::Receiver<Message>,
+    mut outbound: Subscriber,
     tx: tokio::sync::mpsc::Sender<Result<Message, Status>>,
+    voice: Arc<Mutex<VoiceState>>,
     shutdown_tx: broadcast::Sender<()>,
+    counters: Arc<Counters>,
 ) -> tokio::task::JoinHandle<()> {
     let mut shutdown_rx = shutdown_tx.subscribe();
     tokio::spawn(async move {
         loop {
             tokio::select! {
                 res = outbound.recv() => match res {
+                    // audio is not delivered to a deafened user
+                    Ok(msg) if matches!(msg.content, Some(Content::AudioData(_)))
+                        && voice.lock().unwrap().deafened() => {}
                     Ok(msg) => {
                         info!("send msg: {:?} to {}-{}", msg, user_id, channel_id);
+                        let len = prost::Message::encoded_len(&msg);
                         if let Err(err) = tx.send(Ok(msg)).await {
                             error!("send msg to {}-{} failed: {}", user_id, channel_id, err);
+                        } else {
+                            counters.add_bytes_out(len);
                         }
                     }
-                    Err(_) => {
-                        error!("outbound recv error, closing connection for {}-{}", user_id, channel_id);
+                    // skip the stale messages, instead of kicking a slow user
+                    Err(RecvError::Lagged(n)) => {
+                        warn!("{}-{} lagged, {} messages dropped", user_id, channel_id, n);
+                        counters.add_lag_event();
+                        counters.add_dropped(channel_id, &user_id, n);
+                    }
+                    Err(RecvError::Closed) => {
+                        error!("outbound closed, closing connection for {}-{}", user_id, channel_id);
                         break;
                     }
                 },
@@ -286,78 +867,139 @@ impl abi::pb::chat_service_server::ChatService for ChatService {
         request: Request<Streaming<Message>>,
     ) -> Result<Response<Self::ConnStream>, Status> {
         info!("conn request: {:?}", request);
-        let claims = get_claims_from!(request, &self.config.secret);
-        // check claims.addr is equal to localhost.
-        if claims.addr != self.config.url_with(false) {
-            return Err(Error::PermissionDenied("wrong request chat server's addr").into());
-        }
+        // listen token for this server only
+        let claims: ListenClaims = get_claims_from!(request, &self.keys, &self.config.addr());
 
         let (user_id, channel_id) = (claims.user_id.clone(), claims.channel_id);
-        // if channel not exists, add it
-        // channel not update when exists users, and will update util removed and first user join it
-        if !self.core.contains_key(&channel_id) {
-            let channel = self
-                .sql_helper
-                .get_channels(&channel_id)
-                .await?
-                .pop()
-                .unwrap();
-            self.core.insert(channel_id, ChannelCore::new(channel));
-        }
-        let channel_core = self.core.get_mut(&channel_id).unwrap();
-
-        // check if user is in channel
-        if channel_core.exist_user(&user_id) {
-            return Err(Error::InvalidRequest("user already in channel").into());
-        }
-
-        // check channel limit
-        if channel_core.is_full() {
-            return Err(Error::InvalidRequest("channel is full").into());
+        // a user banned after its token is issued
+        if self.sql_helper.get_role(&channel_id, &user_id).await? == Some(Role::Banned) {
+            return Err(Error::PermissionDenied("user is banned from the channel").into());
         }
+        // channels are pushed by manager, refuse to join a channel not on this server
+        let channel_core = self.core.get(&channel_id).ok_or(Error::ChannelNotFound)?;
 
         // Initializing streams and channels
-        let broadcast = channel_core.broadcast.clone();
-        let inbound = request.into_inner();
-        let outbound: broadcast::Receiver<Message> = channel_core.broadcast.subscribe();
-        let (tx, rx) = tokio::sync::mpsc::channel(32);
+        let voice = Arc::new(Mutex::new(VoiceState {
+            server_mute: channel_core.server_mutes.contains(&user_id),
+            ..VoiceState::default()
+        }));
+        let (tx, mut rx) = tokio::sync::mpsc::channel(32);
+        let (control, mut control_rx) = tokio::sync::mpsc::channel(CONTROL_BUFFER_SIZE);
         let (shutdown_tx, _) = broadcast::channel::<()>(1);
-        channel_core.add_user_shutdown_tx(user_id.clone(), shutdown_tx.clone());
+        let conn = UserConn {
+            shutdown_tx,
+            tx,
+            control,
+            voice: Arc::clone(&voice),
+        };
+        // check if user is in channel, and the channel limit
+        channel_core.add_user(user_id.clone(), conn.clone())?;
+        let sender =
+            channel_core.sender(self.sql_helper.clone(), Arc::clone(&self.counters), voice);
+        let inbound = request.into_inner();
+        let outbound = channel_core.fanout.subscribe(&user_id);
+        // the roster goes first on the new stream, other users are told after
+        let roster = event(&user_id, Content::Roster(channel_core.roster()));
+        let _ = conn.control.try_send(Ok(roster));
+        let join = event(
+            &user_id,
+            Content::Join(User {
+                id: user_id.clone(),
+                voice: Some(*conn.voice.lock().unwrap()),
+                ..Default::default()
+            }),
+        );
+        let users = Arc::clone(&channel_core.users);
+        drop(channel_core);
 
         let core: Arc<DashMap<i32, ChannelCore>> = Arc::clone(&self.core);
+        let counters = Arc::clone(&self.counters);
         tokio::spawn(async move {
+            notify_all(&users, &counters, channel_id, join, Some(&user_id));
+            let tx = conn.tx.clone();
             run_connection_tasks(
                 user_id.clone(),
                 channel_id,
-                broadcast,
+                sender,
                 inbound,
                 outbound,
-                tx,
-                shutdown_tx,
+                conn,
+                Arc::clone(&counters),
             )
             .await;
-            // to remove user from channel
-            if let Some(channel_core) = core.get_mut(&channel_id) {
-                channel_core.shutdown_user(&user_id);
+            // to remove user from channel, unless it's connected again
+            if let Some(channel_core) = core.get(&channel_id) {
+                channel_core.remove_user(&user_id, |conn| conn.tx.same_channel(&tx));
+            }
+            drop(tx);
+            if !users.contains_key(&user_id) {
+                let leave = event(&user_id, Content::Leave(()));
+                notify_all(&users, &counters, channel_id, leave, None);
             }
         });
-        Ok(Response::new(Box::pin(
-            tokio_stream::wrappers::ReceiverStream::new(rx),
-        )))
+        // texts and events go ahead of audio
+        let stream = async_stream::stream! {
+            loop {
+                let msg = tokio::select! {
+                    biased;
+                    Some(msg) = control_rx.recv() => msg,
+                    Some(msg) = rx.recv() => msg,
+                    else => break,
+                };
+                yield msg;
+            }
+        };
+        Ok(Response::new(Box::pin(stream)))
     }
 
-    async fn add(&self, _request: Request<Streaming<Channel>>) -> Result<Response<()>, Status> {
-        todo!()
+    /// Add channels pushed by manager until the stream is closed.
+    ///
+    /// An existing channel is kept as it is, so it's safe to push a channel more than once.
+    async fn add(&self, request: Request<Streaming<Channel>>) -> Result<Response<()>, Status> {
+        // only manager can add or remove channels, by a server token for this server
+        let _: ServerClaims = get_claims_from!(request, &self.keys, &self.config.addr());
+        check_peer(&request, self.config.tls.as_ref(), &self.manager_addr)?;
+
+        let mut stream = request.into_inner();
+        while let Some(channel) = stream.message().await? {
+            info!("add channel: {:?}", channel);
+            self.core.entry(channel.id).or_insert_with(|| {
+                ChannelCore::new(
+                    channel,
+                    self.config.channel_buffer_size,
+                    self.sql_helper.clone(),
+                    Arc::clone(&self.counters),
+                )
+            });
+        }
+        Ok(Response::new(()))
     }
 
-    async fn remove(&self, _request: Request<Streaming<Channel>>) -> Result<Response<()>, Status> {
-        todo!()
+    /// Remove channels pushed by manager until the stream is closed.
+    ///
+    /// Users on a removed channel are disconnected, and new users can't join it any more.
+    async fn remove(&self, request: Request<Streaming<Channel>>) -> Result<Response<()>, Status> {
+        // only manager can add or remove channels, by a server token for this server
+        let _: ServerClaims = get_claims_from!(request, &self.keys, &self.config.addr());
+        check_peer(&request, self.config.tls.as_ref(), &self.manager_addr)?;
+
+        let mut stream = request.into_inner();
+        while let Some(channel) = stream.message().await? {
+            info!("remove channel: {}", channel.id);
+            // dropping channel core will shutdown all its users
+            if self.core.remove(&channel.id).is_none() {
+                error!("channel: {} not found", channel.id);
+            }
+        }
+        Ok(Response::new(()))
     }
 
     /// deprecated
-    /// shutdown user-channel connection for manager.
+    /// shutdown user-channel connection for manager, which now comes by report responses.
     async fn shutdown(&self, _request: Request<ShutdownRequest>) -> Result<Response<()>, Status> {
-        todo!()
+        Err(Status::unimplemented(
+            "shutdown is sent by report responses of manager",
+        ))
     }
 }
 
@@ -368,12 +1010,23 @@ pub async fn start_chat_server(
 ) -> Result<tokio::task::JoinHandle<()>, Box<dyn std::error::Error>> {
     let addr: std::net::SocketAddr = config.url().parse()?;
     info!("start chat server at {}", addr);
+    let keys = Arc::new(KeySet::new(config)?);
+    info!("token keys: {:?}", keys);
 
-    let server = tonic::transport::Server::builder()
-        .add_service(ChatServiceServer::new(
-            ChatService::new(manager_addr.to_string(), config, sql_helper).await,
-        ))
-        .serve(addr);
+    // bind before registering, manager will connect back once registered.
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    let mut builder = tonic::transport::Server::builder();
+    if let Some(tls) = &config.tls {
+        builder = builder.tls_config(server_tls_config(tls)?)?;
+    }
+    let server = builder
+        .add_service(ChatServiceServer::new(ChatService::new(
+            manager_addr.to_string(),
+            config,
+            sql_helper,
+            keys,
+        )))
+        .serve_with_incoming(TcpListenerStream::new(listener));
 
     Ok(tokio::spawn(async move {
         if let Err(e) = server.await {
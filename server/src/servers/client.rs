@@ -1,8 +1,12 @@
-use crate::auth::interceptor::{encrypt, Claims};
+use crate::auth::interceptor::{encrypt, ServerClaims, MANAGER_AUDIENCE};
+use crate::auth::keys::KeySet;
+use crate::auth::tls::endpoint;
+use crate::config::TlsConfig;
 use abi::{
     pb::{
         channel_service_client::ChannelServiceClient,
-        // chat_service_client::ChatServiceClient,
+        chat_service_client::ChatServiceClient,
+        Channel,
         ReportRequest,
         ReportResponse,
         // ShutdownRequest,
@@ -11,24 +15,27 @@ use abi::{
 };
 use chrono;
 use log::info;
-use std::str::FromStr;
+use rand::Rng;
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::mpsc::Receiver;
 use tokio_stream::wrappers::ReceiverStream;
-use tonic::{transport::Endpoint, Request, Response, Status, Streaming};
+use tokio_stream::Stream;
+use tonic::{Request, Response, Status, Streaming};
 
 pub struct ChannelClient {
     inner: ChannelServiceClient<tonic::transport::Channel>,
-    secret: String,
+    keys: Arc<KeySet>,
 }
 
 impl ChannelClient {
-    pub async fn new(addr: &str, secret: &str) -> Self {
+    pub async fn new(addr: &str, keys: Arc<KeySet>, tls: Option<&TlsConfig>) -> abi::Result<Self> {
         info!("new channel client: {}", addr);
-        let conn = Endpoint::from_str(addr).unwrap().connect().await.unwrap();
-        Self {
+        let conn = endpoint(addr, tls)?.connect().await?;
+        Ok(Self {
             inner: ChannelServiceClient::new(conn),
-            secret: secret.to_string(),
-        }
+            keys,
+        })
     }
 
     pub async fn report(
@@ -37,13 +44,113 @@ impl ChannelClient {
         rx: Receiver<ReportRequest>,
     ) -> Result<Response<Streaming<ReportResponse>>, Status> {
         let stream = ReceiverStream::new(rx);
-        let claims = Claims {
-            exp: chrono::Utc::now().timestamp() + 60 * 60 * 24 * 30,
-            addr: chat_server_addr,
-            ..Claims::default()
-        };
-        let secret: &str = &self.secret;
-        let token = encrypt(secret, &claims);
+        let token = server_token(&self.keys, MANAGER_AUDIENCE, &chat_server_addr);
         self.inner.report(Request::new(stream).with(&token)).await
     }
 }
+
+/// Client for manager to push channel placement to some chat server.
+#[derive(Clone)]
+pub struct ChatClient {
+    inner: ChatServiceClient<tonic::transport::Channel>,
+    addr: String,
+    keys: Arc<KeySet>,
+}
+
+impl ChatClient {
+    /// Connection is established lazily, so chat server may be not serving yet.
+    pub fn new(addr: &str, keys: Arc<KeySet>, tls: Option<&TlsConfig>) -> abi::Result<Self> {
+        info!("new chat client: {}", addr);
+        let conn = endpoint(addr, tls)?.connect_lazy();
+        Ok(Self {
+            inner: ChatServiceClient::new(conn),
+            addr: addr.to_string(),
+            keys,
+        })
+    }
+
+    pub fn addr(&self) -> &str {
+        &self.addr
+    }
+
+    /// Push channels to chat server until `stream` ends.
+    pub async fn add(
+        &mut self,
+        stream: impl Stream<Item = Channel> + Send + 'static,
+    ) -> Result<Response<()>, Status> {
+        let token = server_token(&self.keys, &self.addr, &self.addr);
+        self.inner.add(Request::new(stream).with(&token)).await
+    }
+
+    /// Remove channels from chat server until `stream` ends.
+    pub async fn remove(
+        &mut self,
+        stream: impl Stream<Item = Channel> + Send + 'static,
+    ) -> Result<Response<()>, Status> {
+        let token = server_token(&self.keys, &self.addr, &self.addr);
+        self.inner.remove(Request::new(stream).with(&token)).await
+    }
+}
+
+// token between manager and chat server `chat_server_addr`, for `aud`.
+fn server_token(keys: &KeySet, aud: &str, chat_server_addr: &str) -> String {
+    let exp = chrono::Utc::now().timestamp() + 60 * 60 * 24 * 30;
+    encrypt(keys, &ServerClaims::new(aud, chat_server_addr, exp))
+}
+
+/// Exponential backoff with full jitter for reconnecting.
+///
+/// The n-th delay is picked randomly in `[0, min(max, min * 2^n)]`,
+/// so servers reconnecting to a restarted manager don't come in bursts.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    min: Duration,
+    max: Duration,
+    attempts: u32,
+}
+
+impl Backoff {
+    pub fn new(min: Duration, max: Duration) -> Self {
+        Self {
+            min,
+            max,
+            attempts: 0,
+        }
+    }
+
+    /// Get the delay before next attempt.
+    pub fn next_delay(&mut self) -> Duration {
+        let ceil = self
+            .min
+            .saturating_mul(2u32.saturating_pow(self.attempts))
+            .min(self.max);
+        self.attempts = self.attempts.saturating_add(1);
+        ceil.mul_f64(rand::thread_rng().gen_range(0.0..=1.0))
+    }
+
+    /// Start over after a successful attempt.
+    pub fn reset(&mut self) {
+        self.attempts = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let (min, max) = (Duration::from_millis(100), Duration::from_secs(1));
+        let mut backoff = Backoff::new(min, max);
+        for ceil in [100, 200, 400, 800, 1000, 1000] {
+            assert!(backoff.next_delay() <= Duration::from_millis(ceil));
+        }
+
+        // delays are jittered
+        let delays: Vec<_> = (0..10).map(|_| backoff.next_delay()).collect();
+        assert!(delays.iter().any(|d| *d != delays[0]));
+
+        backoff.reset();
+        assert!(backoff.next_delay() <= min);
+    }
+}
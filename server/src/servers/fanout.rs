@@ -0,0 +1,226 @@
+//! Fan-out of a channel's messages to its users.
+//!
+//! Unlike [`tokio::sync::broadcast`], every subscriber is known by its user id,
+//! so that a message is not sent back to the user it comes from.
+//!
+//! Every subscriber has its own bounded queue. When it's full, the oldest message is dropped,
+//! and the subscriber is told how many it missed by [`RecvError::Lagged`] on next receive.
+
+use abi::pb::Message;
+use dashmap::DashMap;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+
+/// Error of [`Subscriber::recv`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum RecvError {
+    /// The subscriber lagged behind, and so many oldest messages were dropped.
+    #[error("lagged, {0} messages dropped")]
+    Lagged(u64),
+    /// Unsubscribed, or the fan-out was dropped.
+    #[error("closed")]
+    Closed,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    messages: VecDeque<Message>,
+    dropped: u64,
+    closed: bool,
+}
+
+#[derive(Debug, Default)]
+struct Queue {
+    state: Mutex<State>,
+    notify: Notify,
+}
+
+impl Queue {
+    fn push(&self, msg: Message, capacity: usize) {
+        {
+            let mut state = self.state.lock().unwrap();
+            if state.closed {
+                return;
+            }
+            if state.messages.len() >= capacity {
+                state.messages.pop_front();
+                state.dropped += 1;
+            }
+            state.messages.push_back(msg);
+        }
+        self.notify.notify_one();
+    }
+
+    fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.notify.notify_one();
+    }
+}
+
+/// Receiving end of a user.
+#[derive(Debug)]
+pub struct Subscriber {
+    queue: Arc<Queue>,
+}
+
+impl Subscriber {
+    /// Receive the next message.
+    ///
+    /// Dropped messages are reported once before the messages after them.
+    /// Messages still queued are received before [`RecvError::Closed`].
+    pub async fn recv(&mut self) -> Result<Message, RecvError> {
+        loop {
+            {
+                let mut state = self.queue.state.lock().unwrap();
+                if state.dropped > 0 {
+                    return Err(RecvError::Lagged(std::mem::take(&mut state.dropped)));
+                }
+                if let Some(msg) = state.messages.pop_front() {
+                    return Ok(msg);
+                }
+                if state.closed {
+                    return Err(RecvError::Closed);
+                }
+            }
+            // a notification while checking is kept as a permit, so it's not missed
+            self.queue.notify.notified().await;
+        }
+    }
+}
+
+/// Fan-out of messages to subscribers, each queues at most `capacity` messages.
+#[derive(Debug)]
+pub struct FanOut {
+    capacity: usize,
+    queues: DashMap<String, Arc<Queue>>, // user id - queue
+}
+
+impl FanOut {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            queues: DashMap::new(),
+        }
+    }
+
+    /// Subscribe a user, an existing subscription of the user is closed.
+    pub fn subscribe(&self, user_id: &str) -> Subscriber {
+        let queue = Arc::new(Queue::default());
+        if let Some(old) = self.queues.insert(user_id.to_string(), Arc::clone(&queue)) {
+            old.close();
+        }
+        Subscriber { queue }
+    }
+
+    /// Unsubscribe a user, its subscriber gets the queued messages and then is closed.
+    pub fn unsubscribe(&self, user_id: &str) {
+        if let Some((_, queue)) = self.queues.remove(user_id) {
+            queue.close();
+        }
+    }
+
+    /// Send a message to every subscriber but `except`.
+    pub fn send(&self, msg: &Message, except: Option<&str>) {
+        // clone queues, avoid of holding the map's lock while pushing
+        let queues: Vec<_> = self
+            .queues
+            .iter()
+            .filter(|entry| Some(entry.key().as_str()) != except)
+            .map(|entry| Arc::clone(entry.value()))
+            .collect();
+        for queue in queues {
+            queue.push(msg.clone(), self.capacity);
+        }
+    }
+}
+
+impl Drop for FanOut {
+    fn drop(&mut self) {
+        for entry in self.queues.iter() {
+            entry.value().close();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use abi::pb::message::Content;
+    use std::time::Duration;
+    use tokio::time::timeout;
+
+    fn text(user_id: &str, text: &str) -> Message {
+        Message {
+            user_id: user_id.to_string(),
+            content: Some(Content::Text(text.to_string())),
+            ..Message::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_send_except_origin() {
+        let fanout = FanOut::new(4);
+        let mut a = fanout.subscribe("a");
+        let mut b = fanout.subscribe("b");
+        assert_eq!(fanout.queues.len(), 2);
+
+        fanout.send(&text("a", "from a"), Some("a"));
+        fanout.send(&text("b", "to all"), None);
+
+        assert_eq!(b.recv().await.unwrap(), text("a", "from a"));
+        assert_eq!(b.recv().await.unwrap(), text("b", "to all"));
+        assert_eq!(a.recv().await.unwrap(), text("b", "to all"));
+        assert!(timeout(Duration::from_millis(50), a.recv()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_lagged_drops_oldest() {
+        let fanout = FanOut::new(2);
+        let mut a = fanout.subscribe("a");
+        for i in 0..5 {
+            fanout.send(&text("b", &i.to_string()), None);
+        }
+
+        assert_eq!(a.recv().await, Err(RecvError::Lagged(3)));
+        assert_eq!(a.recv().await.unwrap(), text("b", "3"));
+        assert_eq!(a.recv().await.unwrap(), text("b", "4"));
+    }
+
+    #[tokio::test]
+    async fn test_recv_waits() {
+        let fanout = Arc::new(FanOut::new(2));
+        let mut a = fanout.subscribe("a");
+        let sender = Arc::clone(&fanout);
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            sender.send(&text("b", "late"), None);
+        });
+        let msg = timeout(Duration::from_secs(1), a.recv()).await.unwrap();
+        assert_eq!(msg.unwrap(), text("b", "late"));
+    }
+
+    #[tokio::test]
+    async fn test_closed() {
+        let fanout = FanOut::new(2);
+        let mut a = fanout.subscribe("a");
+        let mut b = fanout.subscribe("b");
+        fanout.send(&text("c", "bye"), None);
+
+        // queued messages are received before closed
+        fanout.unsubscribe("a");
+        assert_eq!(a.recv().await.unwrap(), text("c", "bye"));
+        assert_eq!(a.recv().await, Err(RecvError::Closed));
+        fanout.send(&text("c", "not for a"), None);
+        assert_eq!(fanout.queues.len(), 1);
+
+        // subscribing again closes the old subscriber
+        let mut b2 = fanout.subscribe("b");
+        assert_eq!(b.recv().await.unwrap(), text("c", "bye"));
+        assert_eq!(b.recv().await.unwrap(), text("c", "not for a"));
+        assert_eq!(b.recv().await, Err(RecvError::Closed));
+
+        drop(fanout);
+        assert_eq!(b2.recv().await, Err(RecvError::Closed));
+    }
+}
@@ -1,25 +1,27 @@
-use super::server::ServerManager;
-use crate::auth::interceptor::{encrypt, Claims};
+use super::dispatcher::Dispatcher;
+use crate::auth::interceptor::{encrypt, ListenClaims, ServerClaims, MANAGER_AUDIENCE};
+use crate::auth::keys::KeySet;
 use crate::auth::limiter::{FixedWindowLimiter, Limiter, LimiterConfig};
+use crate::auth::revocation::TokenVersions;
+use crate::auth::tls::check_peer;
 use crate::config::ServerConfig;
 use crate::db::SqlHelper;
 use crate::get_claims_from;
+use crate::servers::metric::ServerMetric;
 use abi::{
     error::*,
     pb::{
-        Channel, ChannelServer, ListResponse, ListenResponse, ReportRequest, ReportResponse,
-        ShutdownRequest,
+        Channel, ChannelServer, HistoryRequest, HistoryResponse, ListResponse, ListenResponse,
+        MemberRequest, ReportRequest, ReportResponse, Role,
     },
-    traits::Validator,
+    traits::{Validator, DEFAULT_HISTORY_LIMIT},
 };
 use chrono::Utc;
 use dashmap::DashMap;
 use log::{error, info};
-use std::collections::HashMap;
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::sync::mpsc::Sender;
-use tokio::sync::RwLock;
 use tokio_stream::wrappers::ReceiverStream;
 use tonic::{Request, Response, Status, Streaming};
 
@@ -32,31 +34,88 @@ use tonic::{Request, Response, Status, Streaming};
 ///
 /// *report* is for chat_server to report messages.
 ///
+/// *grant, revoke* moderators by the channel's owner, *kick, ban, unban* users by moderators,
+/// see [`Role::can_moderate`].
+///
 /// ChannelService will reload all channels from database to svr_manager when it starts.
 ///
-/// todo: client to communicate with chat
+/// Channels are pushed to their chat servers by [`Dispatcher`].
 #[derive(Debug)]
 pub struct ChannelService {
     config: ServerConfig,
     sql_helper: SqlHelper,
-    svr_manager: Arc<RwLock<ServerManager>>,
+    dispatcher: Dispatcher,
     channel_info: Arc<DashMap<i32, Channel>>, // channel info from servers
 
     limiter: FixedWindowLimiter,
+    versions: TokenVersions, // users' access tokens are checked against
+    keys: Arc<KeySet>,
 }
 
 impl ChannelService {
-    pub fn new(config: &ServerConfig, sql_helper: SqlHelper) -> Self {
+    pub fn new(
+        config: &ServerConfig,
+        sql_helper: SqlHelper,
+        versions: TokenVersions,
+        keys: Arc<KeySet>,
+    ) -> Self {
+        let channel_info = Arc::new(DashMap::new());
         Self {
             config: config.clone(),
+            dispatcher: Dispatcher::new(
+                config,
+                sql_helper.clone(),
+                channel_info.clone(),
+                keys.clone(),
+            ),
             sql_helper,
-            svr_manager: Arc::new(RwLock::new(ServerManager::new())),
-            channel_info: Arc::new(DashMap::new()),
+            channel_info,
             limiter: FixedWindowLimiter::new(LimiterConfig::new(
                 1,
                 Duration::from_secs(config.listen_interval),
             )),
+            versions,
+            keys,
+        }
+    }
+
+    /// Reload all channels from database, so that a restarted manager can take over chat servers.
+    pub async fn reload(&self) -> abi::Result<()> {
+        self.dispatcher.reload().await
+    }
+
+    // role of a user on the channel, which must exist
+    async fn role(&self, channel_id: &i32, user_id: &str) -> abi::Result<Role> {
+        self.sql_helper
+            .get_role(channel_id, user_id)
+            .await?
+            .ok_or(Error::ChannelNotFound)
+    }
+
+    // check `user_id` can moderate the user of `req`, return the latter's role
+    async fn check_moderate(&self, req: &MemberRequest, user_id: &str) -> abi::Result<Role> {
+        req.validate()?;
+        let role = self.role(&req.channel_id, user_id).await?;
+        if !self.sql_helper.exist_user(&req.user_id).await? {
+            return Err(Error::UserNotFound);
+        }
+        let target = self.role(&req.channel_id, &req.user_id).await?;
+        if !role.can_moderate(target) {
+            return Err(Error::PermissionDenied("user can't moderate the target"));
+        }
+        Ok(target)
+    }
+
+    // check `user_id` is the owner of the channel of `req`, return the role of its user
+    async fn check_owner(&self, req: &MemberRequest, user_id: &str) -> abi::Result<Role> {
+        req.validate()?;
+        if self.role(&req.channel_id, user_id).await? != Role::Owner {
+            return Err(Error::PermissionDenied("user is not the channel's owner"));
+        }
+        if !self.sql_helper.exist_user(&req.user_id).await? {
+            return Err(Error::UserNotFound);
         }
+        self.role(&req.channel_id, &req.user_id).await
     }
 }
 
@@ -68,7 +127,7 @@ impl abi::pb::channel_service_server::ChannelService for ChannelService {
     /// todo: change request.channel_id to vec![]
     async fn list(&self, request: Request<Channel>) -> Result<Response<ListResponse>, Status> {
         // Load channel ID from the request
-        let _ = get_claims_from!(request, &self.config.secret);
+        let _ = get_claims_from!(request, &self.keys, versions: &self.versions);
         let channel_id = request.get_ref().id;
         info!("list channel request: {:?}", channel_id);
 
@@ -88,13 +147,16 @@ impl abi::pb::channel_service_server::ChannelService for ChannelService {
             // Alternatively, get channels from SQL helper
             channels = self.sql_helper.get_channels(&channel_id).await?;
         }
-        Ok(Response::new(ListResponse { channels }))
+        Ok(Response::new(ListResponse {
+            channels,
+            servers: self.dispatcher.servers(),
+        }))
     }
 
     /// create channel, generate serial number as id, and set owner
     async fn create(&self, request: Request<Channel>) -> Result<Response<Channel>, Status> {
         let channel = request.get_ref().clone();
-        let claims = get_claims_from!(request, &self.config.secret);
+        let claims = get_claims_from!(request, &self.keys, versions: &self.versions);
         let user_id = claims.user_id;
         info!(
             "create channel request: {:?} by user: {:?}",
@@ -103,8 +165,9 @@ impl abi::pb::channel_service_server::ChannelService for ChannelService {
 
         channel.validate()?;
         let id = self.sql_helper.insert_channel(&channel, &user_id).await?;
-        self.svr_manager.write().await.add_channel(&id);
-        Ok(Response::new(Channel { id, ..channel }))
+        let channel = Channel { id, ..channel };
+        self.dispatcher.add_channel(&channel).await;
+        Ok(Response::new(channel))
     }
 
     /// delete channel by id
@@ -113,21 +176,15 @@ impl abi::pb::channel_service_server::ChannelService for ChannelService {
     /// check channel is not using, give server a shutdown signal
     async fn delete(&self, request: Request<Channel>) -> Result<Response<()>, Status> {
         let channel = request.get_ref();
-        let claims = get_claims_from!(request, &self.config.secret);
+        let claims = get_claims_from!(request, &self.keys, versions: &self.versions);
         let user_id = claims.user_id;
-        let owner_id = self.sql_helper.get_channel_owner(&channel.id).await?;
-        if let Some(owner_id) = owner_id {
-            if user_id == owner_id {
-                info!("delete channel request: {:?}", channel);
-                self.sql_helper.delete_channel(&channel.id).await?;
-                self.svr_manager.write().await.delete_channel(&channel.id);
-                Ok(Response::new(()))
-            } else {
-                Err(Error::PermissionDenied("user is not the channel's owner").into())
-            }
-        } else {
-            Err(Error::ChannelNotFound.into())
+        if self.role(&channel.id, &user_id).await? != Role::Owner {
+            return Err(Error::PermissionDenied("user is not the channel's owner").into());
         }
+        info!("delete channel request: {:?}", channel);
+        self.sql_helper.delete_channel(&channel.id).await?;
+        self.dispatcher.delete_channel(&channel.id).await;
+        Ok(Response::new(()))
     }
 
     /// user tries to listen to some channel
@@ -140,25 +197,18 @@ impl abi::pb::channel_service_server::ChannelService for ChannelService {
     ///
     async fn listen(&self, request: Request<Channel>) -> Result<Response<ListenResponse>, Status> {
         info!("listen channel request: {:?}", request);
-        let claims = get_claims_from!(request, &self.config.secret);
+        let claims = get_claims_from!(request, &self.keys, versions: &self.versions);
         let user_id = claims.user_id;
         self.limiter.is_allowed(&user_id).await?;
 
         let channel = request.get_ref();
-        let mgr = self.svr_manager.read().await;
-        let addr = mgr.get_server(&channel.id)?;
+        if self.role(&channel.id, &user_id).await? == Role::Banned {
+            return Err(Error::PermissionDenied("user is banned from the channel").into());
+        }
+        let addr = self.dispatcher.get_server(&channel.id).await?;
 
         Ok(Response::new(ListenResponse {
-            token: encrypt(
-                &self.config.secret,
-                &Claims {
-                    exp: Utc::now().timestamp() + 5,
-                    user_id: user_id.to_string(),
-                    channel_id: channel.id,
-                    addr: addr.clone(),
-                    ..Claims::default()
-                },
-            ),
+            token: listen_token(&self.keys, &user_id, channel.id, &addr),
             server: Some(ChannelServer {
                 addr: addr.clone(),
                 ..ChannelServer::default()
@@ -166,30 +216,140 @@ impl abi::pb::channel_service_server::ChannelService for ChannelService {
         }))
     }
 
+    /// text messages of a channel, newest first, a page of at most `limit` ones
+    /// older than `cursor`, messages are stored by chat servers.
+    async fn history(
+        &self,
+        request: Request<HistoryRequest>,
+    ) -> Result<Response<HistoryResponse>, Status> {
+        let claims = get_claims_from!(request, &self.keys, versions: &self.versions);
+        let req = request.get_ref();
+        req.validate()?;
+        if self
+            .sql_helper
+            .get_channels(&req.channel_id)
+            .await?
+            .is_empty()
+        {
+            return Err(Error::ChannelNotFound.into());
+        }
+        if self.role(&req.channel_id, &claims.user_id).await? == Role::Banned {
+            return Err(Error::PermissionDenied("user is banned from the channel").into());
+        }
+
+        let limit = match req.limit {
+            0 => DEFAULT_HISTORY_LIMIT,
+            limit => limit,
+        } as usize;
+        // one more to know if there is a next page
+        let mut messages = self
+            .sql_helper
+            .get_messages(&req.channel_id, req.cursor, limit as i64 + 1)
+            .await?;
+        let mut next_cursor = 0;
+        if messages.len() > limit {
+            messages.truncate(limit);
+            next_cursor = messages.last().map_or(0, |(id, _)| *id);
+        }
+        Ok(Response::new(HistoryResponse {
+            messages: messages.into_iter().map(|(_, msg)| msg).collect(),
+            next_cursor,
+        }))
+    }
+
+    async fn grant(&self, request: Request<MemberRequest>) -> Result<Response<()>, Status> {
+        let claims = get_claims_from!(request, &self.keys, versions: &self.versions);
+        let req = request.get_ref();
+        match self.check_owner(req, &claims.user_id).await? {
+            Role::Owner => Err(Error::InvalidRequest("user is the channel's owner").into()),
+            _ => {
+                info!("grant moderator request: {:?}", req);
+                self.sql_helper
+                    .set_role(&req.channel_id, &req.user_id, Role::Moderator)
+                    .await?;
+                Ok(Response::new(()))
+            }
+        }
+    }
+
+    async fn revoke(&self, request: Request<MemberRequest>) -> Result<Response<()>, Status> {
+        let claims = get_claims_from!(request, &self.keys, versions: &self.versions);
+        let req = request.get_ref();
+        if self.check_owner(req, &claims.user_id).await? != Role::Moderator {
+            return Err(Error::InvalidRequest("user is not a moderator").into());
+        }
+        info!("revoke moderator request: {:?}", req);
+        self.sql_helper
+            .set_role(&req.channel_id, &req.user_id, Role::Member)
+            .await?;
+        Ok(Response::new(()))
+    }
+
+    /// disconnect the user, it can listen again unless banned
+    async fn kick(&self, request: Request<MemberRequest>) -> Result<Response<()>, Status> {
+        let claims = get_claims_from!(request, &self.keys, versions: &self.versions);
+        let req = request.get_ref();
+        self.check_moderate(req, &claims.user_id).await?;
+        info!("kick request: {:?} by user: {}", req, claims.user_id);
+        self.dispatcher
+            .shutdown_user(req.channel_id, &req.user_id)
+            .await;
+        Ok(Response::new(()))
+    }
+
+    async fn ban(&self, request: Request<MemberRequest>) -> Result<Response<()>, Status> {
+        let claims = get_claims_from!(request, &self.keys, versions: &self.versions);
+        let req = request.get_ref();
+        self.check_moderate(req, &claims.user_id).await?;
+        info!("ban request: {:?} by user: {}", req, claims.user_id);
+        // banned before kicked, so it can't connect again in between
+        self.sql_helper
+            .set_role(&req.channel_id, &req.user_id, Role::Banned)
+            .await?;
+        self.dispatcher
+            .shutdown_user(req.channel_id, &req.user_id)
+            .await;
+        Ok(Response::new(()))
+    }
+
+    async fn unban(&self, request: Request<MemberRequest>) -> Result<Response<()>, Status> {
+        let claims = get_claims_from!(request, &self.keys, versions: &self.versions);
+        let req = request.get_ref();
+        if self.check_moderate(req, &claims.user_id).await? != Role::Banned {
+            return Err(Error::InvalidRequest("user is not banned").into());
+        }
+        info!("unban request: {:?} by user: {}", req, claims.user_id);
+        self.sql_helper
+            .set_role(&req.channel_id, &req.user_id, Role::Member)
+            .await?;
+        Ok(Response::new(()))
+    }
+
     type ReportStream = crate::TonicStream<ReportResponse>;
     // chat server will report to manager, here we use `token` as server_addr to identify server
-    // server and manager will use same `secret` to encrypt and decrypt token
+    // server and manager will use same `secret` to encrypt and decrypt token, see `KeySet`
     async fn report(
         &self,
         request: Request<Streaming<ReportRequest>>,
     ) -> Result<Response<Self::ReportStream>, Status> {
         info!("report request: {:?}", request);
-        let claims = get_claims_from!(request, &self.config.secret);
+        let claims: ServerClaims = get_claims_from!(request, &self.keys, MANAGER_AUDIENCE);
+        check_peer(&request, self.config.tls.as_ref(), &claims.addr)?;
         let server_addr = claims.addr;
 
-        let mgr = self.svr_manager.clone();
+        let dispatcher = self.dispatcher.clone();
         let channel_info = self.channel_info.clone();
         info!("server addr: {}", server_addr);
-        let empty_long_time = self.config.empty_live_time;
+        let config = self.config.clone();
         let (tx, rx) = tokio::sync::mpsc::channel(10);
 
         tokio::spawn(async move {
             handle_report(
                 tx,
-                mgr,
+                dispatcher,
                 channel_info,
                 server_addr,
-                empty_long_time,
+                &config,
                 request.into_inner(),
             )
             .await;
@@ -203,78 +363,65 @@ impl abi::pb::channel_service_server::ChannelService for ChannelService {
 
 async fn handle_report(
     tx: Sender<Result<ReportResponse, Status>>,
-    mgr: Arc<RwLock<ServerManager>>,
+    dispatcher: Dispatcher,
     channel_info: Arc<DashMap<i32, Channel>>,
     server_addr: String, // chat server addr
-    empty_long_time: i64,
+    config: &ServerConfig,
     mut stream: Streaming<ReportRequest>,
 ) {
-    info!("add server: {}", server_addr);
-    // if use let mgr = mgr.write().await, we will drop mgr after this line.
-    // to avoid of dead lock
-    mgr.write().await.add_server(&server_addr);
+    // register server by its first report, which carries its weight.
+    // weights of later reports are not taken, a server changes it by registering again.
+    let Ok(Some(first)) = stream.message().await else {
+        error!("server: {} closed before reporting", server_addr);
+        return;
+    };
+    let metric = ServerMetric::from(&first.metric.clone().unwrap_or_default());
+    info!("add server: {} with weight: {}", server_addr, metric.weight);
+    let Some(session) = dispatcher.add_server(&server_addr, metric.weight, tx).await else {
+        return;
+    };
     // change channel's belonging server
-    let mut empty_chn_ts = HashMap::new();
-    while let Ok(report) = stream.message().await {
-        if let Some(report) = report {
-            // todo: handle metric
-            info!("report: {:?} from: {}", report, &server_addr);
-            for channel in report.channels.into_iter() {
-                // check if channel is not belong to server, todo: shutdown and why it exists?
-                if let Ok(addr) = mgr.read().await.get_server(&channel.id) {
-                    if addr == server_addr {
-                        if check_long_empty_channel(&channel, &mut empty_chn_ts, &empty_long_time) {
-                            if let Err(e) = tx
-                                .send(Ok(ReportResponse {
-                                    shutdown: Some(ShutdownRequest {
-                                        user_id: None,
-                                        channel_id: channel.id,
-                                    }),
-                                }))
-                                .await
-                            {
-                                error!("shutdown channel: {:?} failed: {:?}", channel, e);
-                            }
-                            continue;
-                        }
-
-                        // accept it
-                        channel_info.insert(channel.id, channel);
-                    } else {
-                        error!("server: {:?} takes channel: {:?}, but it actually belongs to server: {:?}", server_addr, channel, addr);
-                    }
-                } else {
-                    error!("channel: {:?} doesn't belong to any server", channel);
+    let mut next = Ok(Some(first));
+    while let Ok(Some(report)) = next {
+        info!("report: {:?} from: {}", report, &server_addr);
+        let metric = ServerMetric::from(&report.metric.unwrap_or_default());
+        // reported by older servers without interval, take ours, nor can a server
+        // report slower than ours to put off its eviction
+        let interval = match metric.interval {
+            0 => config.report_duration,
+            interval => interval.min(config.report_duration),
+        };
+        dispatcher.update_metric(&server_addr, metric).await;
+        for channel in report.channels.into_iter() {
+            match dispatcher.get_server(&channel.id).await {
+                Ok(addr) if addr == server_addr => {
+                    channel_info.insert(channel.id, channel);
                 }
+                // the full state reported after reconnecting may have channels moved meanwhile
+                _ => dispatcher.reclaim(&server_addr, &channel).await,
             }
         }
-    }
-    mgr.write().await.delete_server(&server_addr);
-}
 
-// help to check long empty channel
-// true: long empty channel
-// todo: test it
-fn check_long_empty_channel(
-    channel: &Channel,
-    empty_chn_ts: &mut HashMap<i32, i64>,
-    t: &i64,
-) -> bool {
-    if channel.users.is_empty() {
-        if let Some(ts) = empty_chn_ts.get(&channel.id) {
-            if chrono::Utc::now().timestamp() - ts > *t {
-                // long empty channel, try to delete it
-                empty_chn_ts.remove(&channel.id);
-                return true;
+        // evict the server if it misses heartbeats
+        let timeout = Duration::from_secs(interval.saturating_mul(config.heartbeat_misses as u64));
+        next = match tokio::time::timeout(timeout, stream.message()).await {
+            Ok(next) => next,
+            Err(_) => {
+                error!(
+                    "server: {} missed {} heartbeats, evict it",
+                    server_addr, config.heartbeat_misses
+                );
+                break;
             }
-            // else {} do nothing, just keep the former record
-        } else {
-            // first time, record it
-            empty_chn_ts.insert(channel.id, chrono::Utc::now().timestamp());
-        }
-    } else {
-        // update empty_chn_ts, exists users, so remove from empty_chn_ts
-        empty_chn_ts.remove(&channel.id);
+        };
     }
-    false
+    dispatcher.delete_server(&server_addr, session).await;
+}
+
+/// Token for user to connect `addr` and join the channel, expires in 5 seconds.
+pub(super) fn listen_token(keys: &KeySet, user_id: &str, channel_id: i32, addr: &str) -> String {
+    encrypt(
+        keys,
+        &ListenClaims::new(user_id, channel_id, addr, Utc::now().timestamp() + 5),
+    )
 }
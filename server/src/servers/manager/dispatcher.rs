@@ -0,0 +1,398 @@
+use super::channel::listen_token;
+use super::server::{Moved, ServerManager};
+use crate::auth::keys::KeySet;
+use crate::config::{ServerConfig, TlsConfig};
+use crate::db::SqlHelper;
+use crate::servers::client::{Backoff, ChatClient};
+use crate::servers::metric::ServerMetric;
+use abi::pb::{
+    Channel, ChannelServer, Metric, MigrateRequest, ReportResponse, ShutdownRequest, User,
+};
+use dashmap::DashMap;
+use log::{error, info, warn};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc::Sender;
+use tokio::sync::{Mutex, RwLock};
+use tonic::Status;
+
+/// Streams for pushing channel placement to a registered chat server.
+///
+/// Both streams are reopened when they break, and closed when the handle is dropped.
+#[derive(Debug, Clone)]
+struct ChatServerHandle {
+    add_tx: Sender<Channel>,
+    remove_tx: Sender<Channel>,
+    report_tx: Sender<Result<ReportResponse, Status>>, // responses of server's report stream
+    session: u64, // of the registration, a server registered again has a new one
+}
+
+impl ChatServerHandle {
+    fn connect(
+        addr: &str,
+        keys: Arc<KeySet>,
+        tls: Option<&TlsConfig>,
+        report_tx: Sender<Result<ReportResponse, Status>>,
+        session: u64,
+    ) -> abi::Result<Self> {
+        let client = ChatClient::new(addr, keys, tls)?;
+        Ok(Self {
+            add_tx: spawn_stream(client.clone(), true),
+            remove_tx: spawn_stream(client, false),
+            report_tx,
+            session,
+        })
+    }
+}
+
+// backoff of reopening a broken add or remove stream
+const STREAM_BACKOFF_MIN: Duration = Duration::from_millis(100);
+const STREAM_BACKOFF_MAX: Duration = Duration::from_secs(10);
+
+// keep an add or remove stream open until its sender is dropped, and reopen it with backoff
+// when it breaks, so a live handle always reaches its server. Channels are taken by each
+// stream in turn, one in flight when the stream broke is lost.
+fn spawn_stream(mut client: ChatClient, add: bool) -> Sender<Channel> {
+    let kind = if add { "add" } else { "remove" };
+    let (tx, rx) = tokio::sync::mpsc::channel(100);
+    let (closed, rx) = (tx.downgrade(), Arc::new(Mutex::new(rx)));
+    tokio::spawn(async move {
+        let mut backoff = Backoff::new(STREAM_BACKOFF_MIN, STREAM_BACKOFF_MAX);
+        loop {
+            let rx = Arc::clone(&rx);
+            let stream = async_stream::stream! {
+                while let Some(channel) = rx.lock().await.recv().await {
+                    yield channel;
+                }
+            };
+            let result = if add {
+                client.add(stream).await
+            } else {
+                client.remove(stream).await
+            };
+            if closed.upgrade().is_none() {
+                return;
+            }
+            if let Err(e) = result {
+                error!(
+                    "{} stream to chat server: {} broke: {:?}",
+                    kind,
+                    client.addr(),
+                    e
+                );
+            } else {
+                backoff.reset();
+            }
+            tokio::time::sleep(backoff.next_delay()).await;
+        }
+    });
+    tx
+}
+
+/// Dispatcher keeps chat servers in sync with [`ServerManager`]:
+/// a channel assigned to some chat server is pushed to it by `ChatService::add`,
+/// and taken back by `ChatService::remove`.
+///
+/// When channels are reallocated, their old servers are asked to migrate users to the new ones.
+///
+/// Latest metric of each server is kept, a server above `max_cpu` takes no new channels.
+#[derive(Debug, Clone)]
+pub struct Dispatcher {
+    keys: Arc<KeySet>,
+    tls: Option<TlsConfig>, // to connect chat servers
+    max_cpu: f64,
+    sql_helper: SqlHelper,
+    svr_manager: Arc<RwLock<ServerManager>>,
+    servers: Arc<DashMap<String, ChatServerHandle>>, // chat server addr - handle
+    sessions: Arc<AtomicU64>,                        // last registration's session
+    metrics: Arc<DashMap<String, ServerMetric>>,     // chat server addr - latest metric
+    channel_info: Arc<DashMap<i32, Channel>>,        // channel info from servers
+}
+
+impl Dispatcher {
+    pub fn new(
+        config: &ServerConfig,
+        sql_helper: SqlHelper,
+        channel_info: Arc<DashMap<i32, Channel>>,
+        keys: Arc<KeySet>,
+    ) -> Self {
+        Self {
+            keys,
+            tls: config.tls.clone(),
+            max_cpu: config.max_cpu as f64,
+            sql_helper,
+            svr_manager: Arc::new(RwLock::new(ServerManager::new())),
+            servers: Arc::new(DashMap::new()),
+            sessions: Arc::new(AtomicU64::new(0)),
+            metrics: Arc::new(DashMap::new()),
+            channel_info,
+        }
+    }
+
+    /// Reload all channels from database, they are pushed once some server is registered.
+    pub async fn reload(&self) -> abi::Result<()> {
+        let channels = self.sql_helper.get_channels(&0).await?;
+        info!("reload {} channels", channels.len());
+        let mut mgr = self.svr_manager.write().await;
+        for channel in channels {
+            mgr.add_channel(&channel.id);
+        }
+        Ok(())
+    }
+
+    /// Register a chat server with weight, then push all channels assigned to it.
+    ///
+    /// Channels taken from other servers will be migrated.
+    /// Return the session of the registration, None if the server can't be connected.
+    pub async fn add_server(
+        &self,
+        addr: &str,
+        weight: u32,
+        report_tx: Sender<Result<ReportResponse, Status>>,
+    ) -> Option<u64> {
+        let session = self.sessions.fetch_add(1, Ordering::Relaxed) + 1;
+        let keys = self.keys.clone();
+        let handle =
+            match ChatServerHandle::connect(addr, keys, self.tls.as_ref(), report_tx, session) {
+                Ok(handle) => handle,
+                Err(e) => {
+                    error!("connect to chat server: {} failed: {:?}", addr, e);
+                    return None;
+                }
+            };
+        // release the lock before pushing
+        let moved = {
+            let mut mgr = self.svr_manager.write().await;
+            self.servers.insert(addr.to_string(), handle);
+            mgr.add_server(addr, weight)
+        };
+        self.realloc(moved).await;
+        Some(session)
+    }
+
+    /// Unregister a chat server of `session`, then push its channels to their new servers.
+    ///
+    /// A server registered again meanwhile is kept, only its old session is gone.
+    pub async fn delete_server(&self, addr: &str, session: u64) {
+        let moved = {
+            let mut mgr = self.svr_manager.write().await;
+            if self
+                .servers
+                .remove_if(addr, |_, handle| handle.session == session)
+                .is_none()
+            {
+                info!("chat server: {} is registered again, keep it", addr);
+                return;
+            }
+            self.metrics.remove(addr);
+            mgr.delete_server(addr)
+        };
+        self.realloc(moved).await;
+    }
+
+    /// Take back a channel reported by a server not owning it, kept from before the manager
+    /// restarted, or from before the server was evicted. Its users are migrated to the owner,
+    /// and a channel not placed any more, as it's deleted, is removed from the server.
+    pub async fn reclaim(&self, server: &str, channel: &Channel) {
+        match self.get_server(&channel.id).await {
+            Ok(owner) => {
+                warn!(
+                    "server: {} keeps channel: {}, which belongs to: {}",
+                    server, channel.id, owner
+                );
+                self.migrate(server, channel.id, &owner, &channel.users)
+                    .await;
+            }
+            Err(e) => {
+                warn!(
+                    "server: {} keeps channel: {}, which is not placed: {:?}",
+                    server, channel.id, e
+                );
+                let channel = Channel {
+                    id: channel.id,
+                    ..Channel::default()
+                };
+                self.send(server, channel, false).await;
+            }
+        }
+    }
+
+    /// Keep the latest metric of a server, and mark it saturated by its cpu.
+    pub async fn update_metric(&self, addr: &str, metric: ServerMetric) {
+        let saturated = metric.cpu >= self.max_cpu;
+        if saturated {
+            warn!(
+                "chat server: {} is saturated, cpu: {:.1}%",
+                addr, metric.cpu
+            );
+        }
+        self.metrics.insert(addr.to_string(), metric);
+        self.svr_manager
+            .write()
+            .await
+            .set_saturated(addr, saturated);
+    }
+
+    /// Get registered servers with their latest metric, ordered by addr.
+    pub fn servers(&self) -> Vec<ChannelServer> {
+        let mut servers: Vec<_> = self
+            .metrics
+            .iter()
+            .map(|entry| ChannelServer {
+                addr: entry.key().clone(),
+                metric: Some(Metric::from(entry.value())),
+                ..ChannelServer::default()
+            })
+            .collect();
+        servers.sort_by(|a, b| a.addr.cmp(&b.addr));
+        servers
+    }
+
+    /// Assign a new channel to some server, and push it.
+    pub async fn add_channel(&self, channel: &Channel) {
+        let server = {
+            let mut mgr = self.svr_manager.write().await;
+            mgr.add_channel(&channel.id);
+            mgr.get_server(&channel.id)
+        };
+        if let Ok(server) = server {
+            self.send(&server, channel.clone(), true).await;
+        }
+    }
+
+    /// Remove a channel from its server.
+    pub async fn delete_channel(&self, id: &i32) {
+        let server = {
+            let mut mgr = self.svr_manager.write().await;
+            let server = mgr.get_server(id);
+            mgr.delete_channel(id);
+            server
+        };
+        if let Ok(server) = server {
+            let channel = Channel {
+                id: *id,
+                ..Channel::default()
+            };
+            self.send(&server, channel, false).await;
+        }
+    }
+
+    /// Disconnect a user from a channel, by the channel's server.
+    pub async fn shutdown_user(&self, channel_id: i32, user_id: &str) {
+        let Ok(server) = self.get_server(&channel_id).await else {
+            return;
+        };
+        self.shutdown(&server, channel_id, Some(user_id)).await;
+    }
+
+    /// Get the server that a channel is assigned to.
+    pub async fn get_server(&self, channel_id: &i32) -> abi::Result<String> {
+        self.svr_manager.read().await.get_server(channel_id)
+    }
+
+    // push moved channels to new servers first, then migrate users from old servers,
+    // or disconnect them if no server takes the channel.
+    async fn realloc(&self, moved: Vec<Moved>) {
+        let mut placement: HashMap<String, Vec<i32>> = HashMap::new();
+        for m in moved.iter() {
+            if let Some(to) = &m.to {
+                placement.entry(to.clone()).or_default().push(m.channel_id);
+            }
+        }
+        self.push(placement).await;
+
+        for m in moved {
+            match (m.from, m.to) {
+                (Some(from), Some(to)) => {
+                    let users = self
+                        .channel_info
+                        .get(&m.channel_id)
+                        .map(|channel| channel.users.clone())
+                        .unwrap_or_default();
+                    self.migrate(&from, m.channel_id, &to, &users).await;
+                }
+                // no server is left to take it
+                (Some(from), None) => self.shutdown(&from, m.channel_id, None).await,
+                (None, _) => {}
+            }
+        }
+    }
+
+    // ask `server` to disconnect a user of the channel, or all of its users.
+    async fn shutdown(&self, server: &str, channel_id: i32, user_id: Option<&str>) {
+        let Some(handle) = self.servers.get(server).map(|v| v.value().clone()) else {
+            return;
+        };
+        info!("shutdown user: {:?} of channel: {}", user_id, channel_id);
+        let rsp = ReportResponse {
+            shutdown: Some(ShutdownRequest {
+                user_id: user_id.map(str::to_string),
+                channel_id,
+            }),
+            ..ReportResponse::default()
+        };
+        if let Err(e) = handle.report_tx.send(Ok(rsp)).await {
+            error!("shutdown channel: {} failed: {}", channel_id, e);
+        }
+    }
+
+    // ask `from` to drain the channel, its `users` will reconnect to `to` with fresh tokens.
+    // users not reported yet will get no token, and they should listen again.
+    async fn migrate(&self, from: &str, channel_id: i32, to: &str, users: &[User]) {
+        let Some(handle) = self.servers.get(from).map(|v| v.value().clone()) else {
+            return;
+        };
+        let tokens = users
+            .iter()
+            .map(|user| {
+                let token = listen_token(&self.keys, &user.id, channel_id, to);
+                (user.id.clone(), token)
+            })
+            .collect();
+        info!("migrate channel: {} from: {} to: {}", channel_id, from, to);
+        let rsp = ReportResponse {
+            migrate: Some(MigrateRequest {
+                channel_id,
+                addr: to.to_string(),
+                tokens,
+            }),
+            ..ReportResponse::default()
+        };
+        if let Err(e) = handle.report_tx.send(Ok(rsp)).await {
+            error!("migrate channel: {} failed: {}", channel_id, e);
+        }
+    }
+
+    // push channels grouped by server
+    async fn push(&self, placement: HashMap<String, Vec<i32>>) {
+        for (server, ids) in placement {
+            if ids.is_empty() {
+                continue;
+            }
+            match self.sql_helper.get_channels_by_ids(&ids).await {
+                Ok(channels) => {
+                    info!("push channels: {:?} to server: {}", ids, server);
+                    for channel in channels {
+                        self.send(&server, channel, true).await;
+                    }
+                }
+                Err(e) => error!("load channels: {:?} failed: {:?}", ids, e),
+            }
+        }
+    }
+
+    // add or remove a channel on server
+    async fn send(&self, server: &str, channel: Channel, add: bool) {
+        // clone sender, avoid of holding the map's lock across await
+        let Some(handle) = self.servers.get(server).map(|v| v.value().clone()) else {
+            warn!("chat server: {} not connected", server);
+            return;
+        };
+        let tx = if add { handle.add_tx } else { handle.remove_tx };
+        if let Err(e) = tx.send(channel).await {
+            error!("send channel to server: {} failed: {}", server, e);
+        }
+    }
+}
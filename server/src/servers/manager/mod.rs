@@ -3,25 +3,40 @@ use log::info;
 use user::*;
 mod channel;
 use channel::*;
-mod server;
-use crate::{config::ServerConfig, db::SqlHelper};
+mod dispatcher;
+pub mod server;
+use crate::{
+    auth::{keys::KeySet, revocation::TokenVersions, tls::server_tls_config},
+    config::ServerConfig,
+    db::SqlHelper,
+};
+use abi::error::Error;
 use abi::pb::{
     channel_service_server::ChannelServiceServer, user_service_server::UserServiceServer,
 };
+use std::sync::Arc;
 
 #[macro_export]
 macro_rules! get_claims_from {
-    ($request:expr, $secret:expr) => {{
+    // claims of the type expected, for the audience
+    ($request:expr, $keys:expr, $aud:expr) => {{
         let authorization = $request
             .metadata()
             .get("authorization")
             .ok_or_else(|| tonic::Status::unauthenticated("No auth token provided"))?
             .to_str()
             .map_err(|e| tonic::Status::unauthenticated(e.to_string()))?;
-        let token = &authorization["Bearer ".len()..];
+        let token = authorization
+            .strip_prefix("Bearer ")
+            .ok_or_else(|| tonic::Status::unauthenticated("No bearer token provided"))?;
 
-        let claims: $crate::auth::interceptor::Claims =
-            $crate::auth::interceptor::extract($secret, token)?;
+        $crate::auth::interceptor::extract($keys, token, $aud)?
+    }};
+    // user's access token for manager, which is not revoked by `TokenVersions`
+    ($request:expr, $keys:expr, versions: $versions:expr) => {{
+        let claims: $crate::auth::interceptor::AccessClaims =
+            $crate::get_claims_from!($request, $keys, $crate::auth::interceptor::MANAGER_AUDIENCE);
+        $versions.check(&claims).await?;
         claims
     }};
 }
@@ -31,13 +46,25 @@ pub async fn start_manager_server(
     sql_helper: SqlHelper,
     config: &ServerConfig,
 ) -> Result<tokio::task::JoinHandle<()>, Box<dyn std::error::Error>> {
-    let user_svc = UserService::new(config.secret.clone(), sql_helper.clone());
-    let channel_svc = ChannelService::new(config, sql_helper);
+    let keys = Arc::new(KeySet::new(config)?);
+    // manager signs users' tokens, chat servers may only verify them
+    if !keys.can_sign() {
+        return Err(Error::ConfigParse.into());
+    }
+    info!("token keys: {:?}", keys);
+    let versions = TokenVersions::new(sql_helper.clone());
+    let user_svc = UserService::new(config, sql_helper.clone(), versions.clone(), keys.clone())?;
+    let channel_svc = ChannelService::new(config, sql_helper, versions, keys);
+    channel_svc.reload().await?;
 
     let addr: std::net::SocketAddr = config.url().parse()?;
     info!("start manager server at {}", addr);
 
-    let server = tonic::transport::Server::builder()
+    let mut builder = tonic::transport::Server::builder();
+    if let Some(tls) = &config.tls {
+        builder = builder.tls_config(server_tls_config(tls)?)?;
+    }
+    let server = builder
         .add_service(UserServiceServer::new(user_svc))
         .add_service(ChannelServiceServer::new(channel_svc))
         .serve(addr);
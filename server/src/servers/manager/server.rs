@@ -1,69 +1,272 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::ops::Bound::{Excluded, Included, Unbounded};
 
 use log::info;
 
 use crate::hash::ConsistentHash;
 use abi::error::Error;
+
+/// A channel moved by reallocation, `None` means no server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Moved {
+    pub channel_id: i32,
+    pub from: Option<String>,
+    pub to: Option<String>,
+}
+
 /// A cache for servers:
 /// like the relation of server and channel.
 ///
-/// For now, range all the channels when some server status changed.
-#[derive(Debug)]
+/// Channels are placed by consistent hashing with bounded loads,
+/// so a server takes at most (1 + ε) times its fair share of channels by weight.
+///
+/// Channels are indexed by their hash, so a server change only touches the arcs of the ring
+/// it owns, and the channels moved are returned as a diff.
+#[derive(Debug, Default)]
 pub struct ServerManager {
     channel_to_server: HashMap<i32, Option<String>>,
+    channels: BTreeSet<(u64, i32)>, // channels ordered by hash
+    server_channels: HashMap<String, BTreeSet<i32>>, // server - its channels
+    loads: HashMap<String, usize>,  // server - number of channels
+    saturated: HashSet<String>,     // servers taking no new channels
     hash: ConsistentHash,
 }
 
 impl ServerManager {
     pub fn new() -> Self {
-        Self {
-            channel_to_server: HashMap::new(),
-            hash: ConsistentHash::new(),
+        Self::default()
+    }
+
+    // place a channel on the first server not full, `keys` is the total number of channels.
+    // saturated servers are taken as full, unless no other server has room.
+    fn place(&self, channel_id: &i32, keys: usize) -> Option<String> {
+        let key = channel_id.to_string();
+        if !self.saturated.is_empty() {
+            let mut loads = self.loads.clone();
+            for server in self.saturated.iter() {
+                loads.insert(server.clone(), usize::MAX);
+            }
+            if let Some(server) = self.hash.get_server_bounded(&key, &loads, keys) {
+                return Some(server.clone());
+            }
+        }
+        self.hash
+            .get_server_bounded(&key, &self.loads, keys)
+            .cloned()
+    }
+
+    // move a channel to `to`, and record it in `moved`.
+    // a channel moved more than once is recorded as a single move.
+    fn assign(&mut self, channel_id: i32, to: Option<String>, moved: &mut BTreeMap<i32, Moved>) {
+        let Some(server) = self.channel_to_server.get_mut(&channel_id) else {
+            return;
+        };
+        if *server == to {
+            return;
+        }
+        let from = std::mem::replace(server, to.clone());
+        if let Some(from) = &from {
+            if let Some(channels) = self.server_channels.get_mut(from) {
+                channels.remove(&channel_id);
+            }
+            if let Some(load) = self.loads.get_mut(from) {
+                *load -= 1;
+            }
+        }
+        if let Some(to) = &to {
+            self.server_channels
+                .entry(to.clone())
+                .or_default()
+                .insert(channel_id);
+            *self.loads.entry(to.clone()).or_default() += 1;
+        }
+
+        let m = moved.entry(channel_id).or_insert(Moved {
+            channel_id,
+            from,
+            to: None,
+        });
+        m.to = to;
+        if m.from == m.to {
+            moved.remove(&channel_id);
+        }
+    }
+
+    // channels whose hash is in the arc `(start, end]`, wrapping around the ring if `start >= end`.
+    fn channels_in(&self, start: u64, end: u64) -> Box<dyn Iterator<Item = i32> + '_> {
+        let lower = Excluded((start, i32::MAX));
+        let upper = Included((end, i32::MAX));
+        if start < end {
+            Box::new(self.channels.range((lower, upper)).map(|(_, id)| *id))
+        } else {
+            Box::new(
+                self.channels
+                    .range((lower, Unbounded))
+                    .chain(self.channels.range((Unbounded, upper)))
+                    .map(|(_, id)| *id),
+            )
         }
     }
 
-    // todo: use a performance method in avoid of range all the channels, and time cost will be O(N / M)
-    // N is the number of channels, M is the number of servers.
-    // for changed channel, send shutdown signal to its old server.
-    fn realloc(&mut self) {
-        for (channel_id, server) in self.channel_to_server.iter_mut() {
-            *server = self.hash.get_server(&channel_id.to_string()).cloned()
+    // move channels out of servers over capacity, which happens when capacities shrink.
+    // only the arcs of servers over capacity are walked, until their excess is found there.
+    // channels a server took from other arcs are moved last, larger ids first.
+    fn rebalance(&mut self, moved: &mut BTreeMap<i32, Moved>) {
+        let keys = self.channel_to_server.len();
+        let mut over: Vec<(String, usize)> = self
+            .loads
+            .iter()
+            .map(|(server, load)| {
+                let excess = load.saturating_sub(self.hash.capacity(server, keys));
+                (server.clone(), excess)
+            })
+            .filter(|(_, excess)| *excess > 0)
+            .collect();
+        over.sort_unstable();
+
+        let mut evicted = vec![];
+        for (server, excess) in over {
+            let owned = |id: &i32| self.channel_to_server[id].as_deref() == Some(server.as_str());
+            let mut ids: HashSet<i32> = self
+                .hash
+                .arcs(&server)
+                .into_iter()
+                .flat_map(|(start, end)| self.channels_in(start, end))
+                .filter(owned)
+                .take(excess)
+                .collect();
+            let rest: Vec<i32> = self.server_channels[&server]
+                .iter()
+                .rev()
+                .filter(|id| !ids.contains(id))
+                .take(excess - ids.len())
+                .copied()
+                .collect();
+            ids.extend(rest);
+            let mut ids: Vec<i32> = ids.into_iter().collect();
+            ids.sort_unstable();
+            for channel_id in ids {
+                self.assign(channel_id, None, moved);
+                evicted.push(channel_id);
+            }
         }
+        for channel_id in evicted {
+            let to = self.place(&channel_id, keys);
+            self.assign(channel_id, to, moved);
+        }
+    }
+
+    fn finish(&self, moved: BTreeMap<i32, Moved>) -> Vec<Moved> {
         info!(
-            "server manager reallocated, new relation: {:?}",
-            self.channel_to_server
+            "server manager reallocated, moved: {}, loads: {:?}",
+            moved.len(),
+            self.loads
         );
+        moved.into_values().collect()
     }
 
-    /// Add a server to the cache.
+    /// Add a server with weight to the cache, return the moved channels.
     ///
-    /// All channels will be reallocated to some server, time cost: O(N), N is the number of channels.
-    pub fn add_server(&mut self, server: &str) {
-        self.hash.add_server(server);
-        self.realloc();
+    /// Only channels in the arcs of the new server are taken, time cost: O(N / M),
+    /// N is the number of channels, M is the number of servers.
+    pub fn add_server(&mut self, server: &str, weight: u32) -> Vec<Moved> {
+        let mut moved = BTreeMap::new();
+        let was_empty = self.loads.is_empty();
+        self.hash.add_weighted_server(server, weight);
+        self.loads.entry(server.to_string()).or_default();
+        self.server_channels.entry(server.to_string()).or_default();
+
+        let keys = self.channel_to_server.len();
+        if was_empty {
+            // no server before, all channels are placed from scratch
+            let ids: Vec<i32> = self.channels.iter().map(|(_, id)| *id).collect();
+            for channel_id in ids {
+                let to = self.place(&channel_id, keys);
+                self.assign(channel_id, to, &mut moved);
+            }
+        } else {
+            let capacity = self.hash.capacity(server, keys);
+            for (start, end) in self.hash.arcs(server) {
+                let ids: Vec<i32> = self.channels_in(start, end).collect();
+                for channel_id in ids {
+                    if self.loads[server] >= capacity {
+                        break;
+                    }
+                    self.assign(channel_id, Some(server.to_string()), &mut moved);
+                }
+            }
+        }
+        self.rebalance(&mut moved);
+        self.finish(moved)
     }
 
-    /// Delete a server from the cache.
+    /// Delete a server from the cache, return the moved channels.
     ///
-    /// All channels will be reallocated to some server, time cost: O(N), N is the number of channels.
-    pub fn delete_server(&mut self, server: &str) {
+    /// Only channels of the server are reallocated, time cost: O(N / M),
+    /// N is the number of channels, M is the number of servers.
+    pub fn delete_server(&mut self, server: &str) -> Vec<Moved> {
+        let mut moved = BTreeMap::new();
         self.hash.remove_server(server);
-        self.realloc();
+        let ids: Vec<i32> = self
+            .server_channels
+            .get(server)
+            .map(|channels| channels.iter().copied().collect())
+            .unwrap_or_default();
+        for channel_id in ids.iter() {
+            self.assign(*channel_id, None, &mut moved);
+        }
+        self.server_channels.remove(server);
+        self.loads.remove(server);
+        self.saturated.remove(server);
+
+        let keys = self.channel_to_server.len();
+        for channel_id in ids {
+            let to = self.place(&channel_id, keys);
+            self.assign(channel_id, to, &mut moved);
+        }
+        self.rebalance(&mut moved);
+        self.finish(moved)
     }
 
-    /// Add a channel to the cache.
+    /// Mark a server as saturated or not, a saturated server keeps its channels,
+    /// and takes new channels only if no other server has room.
     ///
     /// time cost: O(1).
+    pub fn set_saturated(&mut self, server: &str, saturated: bool) {
+        if saturated {
+            self.saturated.insert(server.to_string());
+        } else {
+            self.saturated.remove(server);
+        }
+    }
+
+    /// Add a channel to the cache.
+    ///
+    /// time cost: O(log N).
     pub fn add_channel(&mut self, channel_id: &i32) {
-        let server = self.hash.get_server(&channel_id.to_string());
-        self.channel_to_server.insert(*channel_id, server.cloned());
+        if self.channel_to_server.contains_key(channel_id) {
+            return;
+        }
+        let server = self.place(channel_id, self.channel_to_server.len() + 1);
+        self.channel_to_server.insert(*channel_id, None);
+        self.channels
+            .insert((self.hash.hash(&channel_id.to_string()), *channel_id));
+        self.assign(*channel_id, server, &mut BTreeMap::new());
     }
 
     /// Delete a channel from the cache.
     ///
-    /// time cost: O(1).
+    /// Other channels are not moved, so loads are rebalanced at next server change.
+    ///
+    /// time cost: O(log N).
     pub fn delete_channel(&mut self, channel_id: &i32) {
+        if !self.channel_to_server.contains_key(channel_id) {
+            return;
+        }
+        self.assign(*channel_id, None, &mut BTreeMap::new());
         self.channel_to_server.remove(channel_id);
+        self.channels
+            .remove(&(self.hash.hash(&channel_id.to_string()), *channel_id));
     }
 
     /// Get the server that a channel is assigned to.
@@ -85,3 +288,145 @@ impl ServerManager {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manager(servers: &[(&str, u32)], channels: i32) -> ServerManager {
+        let mut mgr = ServerManager::new();
+        for (server, weight) in servers {
+            mgr.add_server(server, *weight);
+        }
+        for id in 1..=channels {
+            mgr.add_channel(&id);
+        }
+        mgr
+    }
+
+    // every channel is on some server, and no server is over capacity
+    fn assert_balanced(mgr: &ServerManager) {
+        let keys = mgr.channel_to_server.len();
+        for (server, channels) in mgr.server_channels.iter() {
+            assert_eq!(mgr.loads[server], channels.len());
+            assert!(channels.len() <= mgr.hash.capacity(server, keys));
+            for id in channels {
+                assert_eq!(mgr.get_server(id).unwrap(), *server);
+            }
+        }
+        let placed: usize = mgr.loads.values().sum();
+        assert_eq!(placed, keys);
+    }
+
+    #[test]
+    fn test_channels_without_server() {
+        let mut mgr = manager(&[], 10);
+        assert!(matches!(mgr.get_server(&1), Err(Error::ServerNotFound)));
+        assert!(matches!(mgr.get_server(&11), Err(Error::ChannelNotFound)));
+
+        // the first server takes all channels
+        let moved = mgr.add_server("server1", 1);
+        assert_eq!(moved.len(), 10);
+        assert!(moved
+            .iter()
+            .all(|m| m.from.is_none() && m.to.as_deref() == Some("server1")));
+
+        // the last server gives all channels up
+        let moved = mgr.delete_server("server1");
+        assert_eq!(moved.len(), 10);
+        assert!(moved.iter().all(|m| m.to.is_none()));
+        assert!(matches!(mgr.get_server(&1), Err(Error::ServerNotFound)));
+    }
+
+    #[test]
+    fn test_add_server_diff() {
+        let mut mgr = manager(&[("server1", 1), ("server2", 1), ("server3", 1)], 1000);
+        assert_balanced(&mgr);
+        let before = mgr.channel_to_server.clone();
+
+        let moved = mgr.add_server("server4", 1);
+        assert_balanced(&mgr);
+        assert!(!moved.is_empty());
+        // the diff is exactly the changed channels
+        for m in moved.iter() {
+            assert_eq!(before[&m.channel_id], m.from);
+            assert_eq!(mgr.channel_to_server[&m.channel_id], m.to);
+            assert_ne!(m.from, m.to);
+        }
+        let changed = before
+            .iter()
+            .filter(|(id, server)| mgr.channel_to_server[*id] != **server)
+            .count();
+        assert_eq!(changed, moved.len());
+        // few channels are moved besides the ones taken by the new server
+        let taken = moved
+            .iter()
+            .filter(|m| m.to.as_deref() == Some("server4"))
+            .count();
+        assert!(moved.len() <= 2 * taken);
+    }
+
+    #[test]
+    fn test_delete_server_diff() {
+        let mut mgr = manager(&[("server1", 1), ("server2", 2), ("server3", 1)], 1000);
+        let owned = mgr.server_channels["server2"].clone();
+
+        let moved = mgr.delete_server("server2");
+        assert_balanced(&mgr);
+        // only channels of the deleted server are moved
+        assert_eq!(moved.len(), owned.len());
+        for m in moved.iter() {
+            assert!(owned.contains(&m.channel_id));
+            assert_eq!(m.from.as_deref(), Some("server2"));
+        }
+        assert!(!mgr.loads.contains_key("server2"));
+    }
+
+    #[test]
+    fn test_delete_channel() {
+        let mut mgr = manager(&[("server1", 1), ("server2", 1)], 100);
+        for id in 1..=50 {
+            mgr.delete_channel(&id);
+        }
+        mgr.delete_channel(&1000);
+        assert!(matches!(mgr.get_server(&1), Err(Error::ChannelNotFound)));
+        assert_eq!(mgr.channels.len(), 50);
+
+        // loads may be over the shrunk capacity, until next server change
+        mgr.add_server("server3", 1);
+        assert_balanced(&mgr);
+    }
+
+    #[test]
+    fn test_saturated_server() {
+        let mut mgr = manager(&[("server1", 1), ("server2", 1)], 10);
+        mgr.set_saturated("server1", true);
+        for id in 11..=20 {
+            let keys = mgr.channel_to_server.len() + 1;
+            let full = mgr.loads["server2"] >= mgr.hash.capacity("server2", keys);
+            mgr.add_channel(&id);
+            if !full {
+                assert_eq!(mgr.get_server(&id).unwrap(), "server2");
+            }
+        }
+        assert!((11..=20).any(|id| mgr.get_server(&id).unwrap() == "server2"));
+
+        // all saturated, channels are placed as usual
+        mgr.set_saturated("server2", true);
+        mgr.add_channel(&21);
+        assert!(mgr.get_server(&21).is_ok());
+
+        mgr.set_saturated("server1", false);
+        mgr.delete_server("server2");
+        assert_eq!(mgr.loads["server1"], 21);
+        assert!(mgr.saturated.is_empty());
+    }
+
+    #[test]
+    fn test_add_existing_channel() {
+        let mut mgr = manager(&[("server1", 1)], 10);
+        mgr.add_channel(&1);
+        assert_eq!(mgr.loads["server1"], 10);
+        assert_eq!(mgr.channels.len(), 10);
+    }
+}
@@ -1,33 +1,169 @@
-use crate::auth::interceptor::{encrypt, Claims};
+use crate::auth::interceptor::{encrypt, AccessClaims};
+use crate::auth::keys::KeySet;
+use crate::auth::revocation::TokenVersions;
+use crate::config::ServerConfig;
 use crate::db::SqlHelper;
 use abi::error::Error;
-use abi::pb::{LoginRequest, LoginResponse, RegisterRequest};
-use argon2::Argon2;
-use chrono::{Duration, Utc};
-use log::info;
-use password_hash::{PasswordHasher, SaltString};
+use abi::pb::{LoginRequest, LoginResponse, LogoutRequest, RefreshRequest, RegisterRequest};
+use argon2::{Algorithm, Argon2, Params, Version};
+use chrono::Utc;
+use log::{error, info, warn};
+use password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
 use tonic::{Request, Response, Status};
 
+// the salt shared by all users' hashes of old versions, they're re-hashed at next login
+const LEGACY_SALT: &str = "dGhpc2lzbXlzYWx0";
+
+/// User Service Implements:
+/// *register*, *login*, *refresh* and *logout* on manager server.
+///
+/// Passwords are hashed by Argon2id with a random salt for every user,
+/// the salt and parameters are kept in the PHC string stored. Hashing takes tens of
+/// milliseconds of CPU, so it's done on blocking threads. A missing user fails to login
+/// as a wrong password does, and takes as long, so users can't be found out.
+///
+/// Login issues a short-lived access token and a refresh token. A refresh token is used once,
+/// it's rotated to a new one of the same family by *refresh*. Using it again means it's stolen,
+/// so the whole family and the user's access tokens are revoked.
+///
+/// Logout revokes the family of its refresh token, and the access tokens of every session,
+/// as access tokens only carry the user's token version. Other sessions keep their refresh
+/// tokens, they get new access tokens by *refresh* and go on.
 #[derive(Debug)]
 pub struct UserService {
-    secret: String,
+    keys: Arc<KeySet>,
     sql_helper: SqlHelper,
-    hash_salt: SaltString,
+    argon2: Argon2<'static>,
+    dummy_hash: String, // verified for a missing user
+    versions: TokenVersions,
+    access_token_ttl: i64,
+    refresh_token_ttl: i64,
+}
+
+/// Result of verifying a password against its stored hash.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Verified {
+    Valid,
+    // valid, but hashed with the legacy salt or other parameters, should be re-hashed
+    Outdated,
+    Invalid,
 }
 
 impl UserService {
-    pub fn new(secret: String, sql_helper: SqlHelper) -> Self {
-        Self {
-            secret, // change it!
+    pub fn new(
+        config: &ServerConfig,
+        sql_helper: SqlHelper,
+        versions: TokenVersions,
+        keys: Arc<KeySet>,
+    ) -> abi::Result<Self> {
+        let argon2 = argon2_from(config)?;
+        Ok(Self {
+            keys,
             sql_helper,
-            hash_salt: SaltString::from_b64("dGhpc2lzbXlzYWx0").unwrap(),
+            dummy_hash: hash_password(&argon2, &random_token()),
+            argon2,
+            versions,
+            access_token_ttl: config.access_token_ttl,
+            refresh_token_ttl: config.refresh_token_ttl,
+        })
+    }
+
+    // hash a password on a blocking thread
+    async fn hash(&self, password: &str) -> Result<String, Status> {
+        let (argon2, password) = (self.argon2.clone(), password.to_string());
+        tokio::task::spawn_blocking(move || hash_password(&argon2, &password))
+            .await
+            .map_err(|e| Status::internal(e.to_string()))
+    }
+
+    // verify a password on a blocking thread
+    async fn verify(&self, password: &str, hash: &str) -> Result<Verified, Status> {
+        let (argon2, password, hash) =
+            (self.argon2.clone(), password.to_string(), hash.to_string());
+        tokio::task::spawn_blocking(move || verify_password(&argon2, &password, &hash))
+            .await
+            .map_err(|e| Status::internal(e.to_string()))
+    }
+
+    // issue an access token, and a new refresh token of `family`
+    async fn issue(&self, user_id: &str, family: &str) -> abi::Result<LoginResponse> {
+        let now = Utc::now().timestamp();
+        let refresh_token = random_token();
+        self.sql_helper
+            .insert_refresh_token(
+                &hash_token(&refresh_token),
+                user_id,
+                family,
+                now + self.refresh_token_ttl,
+            )
+            .await?;
+
+        let expires_at = now + self.access_token_ttl;
+        Ok(LoginResponse {
+            token: encrypt(
+                &self.keys,
+                &AccessClaims::new(user_id, self.versions.current(user_id).await?, expires_at),
+            ),
+            refresh_token,
+            expires_at,
+        })
+    }
+}
+
+// a random token of 256 bits, in hex
+fn random_token() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+// refresh tokens are random enough, a fast hash keeps them safe in database
+fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+fn argon2_from(config: &ServerConfig) -> abi::Result<Argon2<'static>> {
+    let conf = &config.argon2;
+    let params = Params::new(conf.memory_cost, conf.time_cost, conf.parallelism, None)
+        .map_err(|_| Error::ConfigParse)?;
+    Ok(Argon2::new(Algorithm::Argon2id, Version::V0x13, params))
+}
+
+// hash a password with a new random salt
+fn hash_password(argon2: &Argon2, password: &str) -> String {
+    let salt = SaltString::generate(&mut OsRng);
+    let password_hash = argon2.hash_password(password.as_bytes(), &salt);
+    password_hash.unwrap().to_string()
+}
+
+// verify a password in constant time, by the salt and parameters of its stored hash
+fn verify_password(argon2: &Argon2, password: &str, hash: &str) -> Verified {
+    let hash = match PasswordHash::new(hash) {
+        Ok(hash) => hash,
+        Err(e) => {
+            error!("malformed password hash: {}", e);
+            return Verified::Invalid;
         }
+    };
+    if argon2.verify_password(password.as_bytes(), &hash).is_err() {
+        return Verified::Invalid;
     }
 
-    fn encrypt_password(&self, password: &str) -> String {
-        let argon2 = Argon2::default();
-        let password_hash = argon2.hash_password(password.as_bytes(), &self.hash_salt);
-        password_hash.unwrap().to_string()
+    let legacy = hash.salt.is_some_and(|salt| salt.as_str() == LEGACY_SALT);
+    let outdated = Params::try_from(&hash).map_or(true, |params| {
+        let current = argon2.params();
+        params.m_cost() != current.m_cost()
+            || params.t_cost() != current.t_cost()
+            || params.p_cost() != current.p_cost()
+    });
+    if legacy || outdated || hash.algorithm != Algorithm::Argon2id.ident() {
+        Verified::Outdated
+    } else {
+        Verified::Valid
     }
 }
 
@@ -38,42 +174,146 @@ impl abi::pb::user_service_server::UserService for UserService {
         request: Request<LoginRequest>,
     ) -> Result<Response<LoginResponse>, Status> {
         let req = request.get_ref();
-        info!("login request: {:?}", req);
-        let password_hash = self.encrypt_password(&req.password);
-        let real_hash = self.sql_helper.get_user_password(&req.user_id).await?;
-        if let Some(hash) = real_hash {
-            if hash == password_hash {
-                let expiration = Utc::now()
-                    .checked_add_signed(Duration::days(1))
-                    .unwrap()
-                    .timestamp();
-
-                Ok(Response::new(LoginResponse {
-                    token: encrypt(
-                        &self.secret,
-                        &Claims {
-                            exp: expiration,
-                            user_id: req.user_id.clone(),
-                            ..Claims::default()
-                        },
-                    ),
-                }))
-            } else {
-                Err(Error::InvalidPassword.into())
+        info!("login request: {:?}", req.user_id);
+        let hash = self.sql_helper.get_user_password(&req.user_id).await?;
+        let stored = hash.as_deref().unwrap_or(&self.dummy_hash);
+        match self.verify(&req.password, stored).await? {
+            _ if hash.is_none() => return Err(Error::InvalidPassword.into()),
+            Verified::Valid => {}
+            Verified::Outdated => {
+                // login goes on even if it fails, it's tried again next time
+                let password_hash = self.hash(&req.password).await?;
+                match self
+                    .sql_helper
+                    .update_user_password(&req.user_id, &password_hash)
+                    .await
+                {
+                    Ok(()) => info!("re-hash password of user: {}", req.user_id),
+                    Err(e) => error!("re-hash password of user: {} failed: {}", req.user_id, e),
+                }
             }
-        } else {
-            Err(Error::UserNotFound.into())
+            Verified::Invalid => return Err(Error::InvalidPassword.into()),
+        }
+
+        // every login starts a new family of refresh tokens
+        let family = random_token();
+        Ok(Response::new(self.issue(&req.user_id, &family).await?))
+    }
+
+    async fn refresh(
+        &self,
+        request: Request<RefreshRequest>,
+    ) -> Result<Response<LoginResponse>, Status> {
+        let token_hash = hash_token(&request.get_ref().refresh_token);
+        let (user_id, family, expires_at) =
+            self.sql_helper
+                .get_refresh_token(&token_hash)
+                .await?
+                .ok_or(Error::Unauthenticated("invalid refresh token"))?;
+        info!("refresh request: {:?}", user_id);
+
+        // rotated or logged out, whoever has it shouldn't
+        if !self.sql_helper.revoke_refresh_token(&token_hash).await? {
+            warn!(
+                "refresh token of user: {} reused, revoke its family",
+                user_id
+            );
+            self.sql_helper.revoke_refresh_family(&family).await?;
+            self.versions.revoke(&user_id).await?;
+            return Err(Error::Unauthenticated("refresh token reused").into());
         }
+        if expires_at <= Utc::now().timestamp() {
+            return Err(Error::Unauthenticated("refresh token expired").into());
+        }
+        Ok(Response::new(self.issue(&user_id, &family).await?))
+    }
+
+    /// Revoke the session of the refresh token, and the user's access tokens of all sessions.
+    async fn logout(&self, request: Request<LogoutRequest>) -> Result<Response<()>, Status> {
+        let token_hash = hash_token(&request.get_ref().refresh_token);
+        let (user_id, family, _) = self
+            .sql_helper
+            .get_refresh_token(&token_hash)
+            .await?
+            .ok_or(Error::Unauthenticated("invalid refresh token"))?;
+        info!("logout request: {:?}", user_id);
+
+        self.sql_helper.revoke_refresh_family(&family).await?;
+        self.versions.revoke(&user_id).await?;
+        Ok(Response::new(()))
     }
 
     async fn register(&self, request: Request<RegisterRequest>) -> Result<Response<()>, Status> {
         let req = request.into_inner();
-        info!("register request: {:?}", req);
+        info!("register request: {:?}", req.user_id);
 
-        let password_hash = self.encrypt_password(&req.password);
+        let password_hash = self.hash(&req.password).await?;
         self.sql_helper
             .insert_user(&req.user_id, &req.name, &password_hash)
             .await?;
         Ok(Response::new(()))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    fn argon2() -> Argon2<'static> {
+        argon2_from(&Config::load("../config/manager.yaml").unwrap().server).unwrap()
+    }
+
+    #[test]
+    fn test_hash_salted_per_user() {
+        let argon2 = argon2();
+        let (a, b) = (
+            hash_password(&argon2, "password"),
+            hash_password(&argon2, "password"),
+        );
+        assert_ne!(a, b);
+        assert!(a.starts_with("$argon2id$v=19$m=19456,t=2,p=1$"));
+
+        assert_eq!(verify_password(&argon2, "password", &a), Verified::Valid);
+        assert_eq!(verify_password(&argon2, "password", &b), Verified::Valid);
+        assert_eq!(verify_password(&argon2, "wrong", &a), Verified::Invalid);
+        assert_eq!(
+            verify_password(&argon2, "password", "bad"),
+            Verified::Invalid
+        );
+    }
+
+    #[test]
+    fn test_legacy_hash_outdated() {
+        let argon2 = argon2();
+        let legacy = Argon2::default()
+            .hash_password(b"password", &SaltString::from_b64(LEGACY_SALT).unwrap())
+            .unwrap()
+            .to_string();
+        assert_eq!(
+            verify_password(&argon2, "password", &legacy),
+            Verified::Outdated
+        );
+        assert_eq!(
+            verify_password(&argon2, "wrong", &legacy),
+            Verified::Invalid
+        );
+    }
+
+    #[test]
+    fn test_params_changed_outdated() {
+        let mut config = Config::load("../config/manager.yaml").unwrap().server;
+        let hash = hash_password(&argon2_from(&config).unwrap(), "password");
+
+        config.argon2.time_cost += 1;
+        let argon2 = argon2_from(&config).unwrap();
+        assert_eq!(
+            verify_password(&argon2, "password", &hash),
+            Verified::Outdated
+        );
+        assert!(hash_password(&argon2, "password").contains("t=3"));
+
+        config.argon2.memory_cost = 0;
+        assert!(argon2_from(&config).is_err());
+    }
+}
@@ -0,0 +1,333 @@
+//! Keys of [`Metric`] reported by chat servers, and how chat servers sample them.
+
+use crate::hash::MAX_WEIGHT;
+use abi::pb::Metric;
+use dashmap::DashMap;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Weight of the chat server, see [`crate::config::ServerConfig::weight`].
+pub const WEIGHT: &str = "weight";
+/// Seconds between two reports, see [`crate::config::ServerConfig::report_duration`].
+pub const INTERVAL: &str = "interval";
+/// Number of connected users.
+pub const USERS: &str = "users";
+/// Number of channels being served.
+pub const CHANNELS: &str = "channels";
+/// Bytes per second received from users.
+pub const BYTES_IN: &str = "bytes_in";
+/// Bytes per second sent to users.
+pub const BYTES_OUT: &str = "bytes_out";
+/// Times that users lagged behind their channel's broadcast, since the server started.
+pub const LAG_EVENTS: &str = "lag_events";
+/// Prefix of messages dropped for a lagging user since joining a channel, followed by the
+/// channel id and the user id, e.g. `dropped.1.alice`.
+pub const DROPPED: &str = "dropped.";
+/// Process CPU usage in percent of all cores.
+pub const CPU: &str = "cpu";
+/// Process resident memory in bytes.
+pub const RSS: &str = "rss";
+
+/// A snapshot of [`Metric`], missing or malformed values are taken as default.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServerMetric {
+    pub weight: u32,
+    pub interval: u64,
+    pub users: u64,
+    pub channels: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub lag_events: u64,
+    pub dropped: HashMap<(i32, String), u64>, // channel id, user id - dropped messages
+    pub cpu: f64,
+    pub rss: u64,
+}
+
+impl Default for ServerMetric {
+    fn default() -> Self {
+        Self {
+            weight: 1,
+            interval: 0,
+            users: 0,
+            channels: 0,
+            bytes_in: 0,
+            bytes_out: 0,
+            lag_events: 0,
+            dropped: HashMap::new(),
+            cpu: 0.0,
+            rss: 0,
+        }
+    }
+}
+
+impl From<&Metric> for ServerMetric {
+    fn from(metric: &Metric) -> Self {
+        fn get<T: std::str::FromStr>(metric: &Metric, key: &str, default: T) -> T {
+            metric
+                .kv
+                .get(key)
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(default)
+        }
+        let default = Self::default();
+        Self {
+            weight: get(metric, WEIGHT, default.weight).min(MAX_WEIGHT),
+            interval: get(metric, INTERVAL, default.interval),
+            users: get(metric, USERS, default.users),
+            channels: get(metric, CHANNELS, default.channels),
+            bytes_in: get(metric, BYTES_IN, default.bytes_in),
+            bytes_out: get(metric, BYTES_OUT, default.bytes_out),
+            lag_events: get(metric, LAG_EVENTS, default.lag_events),
+            dropped: metric
+                .kv
+                .iter()
+                .filter_map(|(k, v)| {
+                    let (channel_id, user_id) = k.strip_prefix(DROPPED)?.split_once('.')?;
+                    let key = (channel_id.parse().ok()?, user_id.to_string());
+                    Some((key, v.parse().ok()?))
+                })
+                .collect(),
+            cpu: get(metric, CPU, default.cpu),
+            rss: get(metric, RSS, default.rss),
+        }
+    }
+}
+
+impl From<&ServerMetric> for Metric {
+    fn from(metric: &ServerMetric) -> Self {
+        let mut kv = HashMap::from([
+            (WEIGHT.to_string(), metric.weight.to_string()),
+            (INTERVAL.to_string(), metric.interval.to_string()),
+            (USERS.to_string(), metric.users.to_string()),
+            (CHANNELS.to_string(), metric.channels.to_string()),
+            (BYTES_IN.to_string(), metric.bytes_in.to_string()),
+            (BYTES_OUT.to_string(), metric.bytes_out.to_string()),
+            (LAG_EVENTS.to_string(), metric.lag_events.to_string()),
+            (CPU.to_string(), format!("{:.1}", metric.cpu)),
+            (RSS.to_string(), metric.rss.to_string()),
+        ]);
+        kv.extend(metric.dropped.iter().map(|((channel_id, user_id), n)| {
+            (
+                format!("{}{}.{}", DROPPED, channel_id, user_id),
+                n.to_string(),
+            )
+        }));
+        Metric { kv }
+    }
+}
+
+/// Counters updated by users' connections on a chat server.
+#[derive(Debug, Default)]
+pub struct Counters {
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    lag_events: AtomicU64,
+    dropped: DashMap<(i32, String), u64>, // channel id, user id - dropped messages
+}
+
+impl Counters {
+    pub fn add_bytes_in(&self, n: usize) {
+        self.bytes_in.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub fn add_bytes_out(&self, n: usize) {
+        self.bytes_out.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub fn add_lag_event(&self) {
+        self.lag_events.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count messages dropped for a lagging user of a channel.
+    pub fn add_dropped(&self, channel_id: i32, user_id: &str, n: u64) {
+        *self
+            .dropped
+            .entry((channel_id, user_id.to_string()))
+            .or_default() += n;
+    }
+
+    /// Forget a user's counters on a channel, e.g. the user left it.
+    pub fn remove_user(&self, channel_id: i32, user_id: &str) {
+        self.dropped.remove(&(channel_id, user_id.to_string()));
+    }
+}
+
+/// Sampler turns counters into rates between two samples.
+#[derive(Debug)]
+pub struct Sampler {
+    counters: Arc<Counters>,
+    at: Instant,
+    cpu_time: Duration,
+    bytes_in: u64,
+    bytes_out: u64,
+}
+
+impl Sampler {
+    pub fn new(counters: Arc<Counters>) -> Self {
+        Self {
+            at: Instant::now(),
+            cpu_time: process_cpu_time().unwrap_or_default(),
+            bytes_in: counters.bytes_in.load(Ordering::Relaxed),
+            bytes_out: counters.bytes_out.load(Ordering::Relaxed),
+            counters,
+        }
+    }
+
+    /// Sample counters and process status, rates are computed since last sample.
+    ///
+    /// Fields known only by the caller, like `users`, are left as default.
+    pub fn sample(&mut self) -> ServerMetric {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.at).as_secs_f64();
+        let rate = |cur: u64, last: u64| {
+            if elapsed > 0.0 {
+                (cur.saturating_sub(last) as f64 / elapsed) as u64
+            } else {
+                0
+            }
+        };
+
+        let bytes_in = self.counters.bytes_in.load(Ordering::Relaxed);
+        let bytes_out = self.counters.bytes_out.load(Ordering::Relaxed);
+        let cpu_time = process_cpu_time().unwrap_or(self.cpu_time);
+        let cores = std::thread::available_parallelism().map_or(1, |n| n.get()) as f64;
+        let cpu = if elapsed > 0.0 {
+            cpu_time.saturating_sub(self.cpu_time).as_secs_f64() / elapsed / cores * 100.0
+        } else {
+            0.0
+        };
+
+        let metric = ServerMetric {
+            bytes_in: rate(bytes_in, self.bytes_in),
+            bytes_out: rate(bytes_out, self.bytes_out),
+            lag_events: self.counters.lag_events.load(Ordering::Relaxed),
+            dropped: self
+                .counters
+                .dropped
+                .iter()
+                .map(|entry| (entry.key().clone(), *entry.value()))
+                .collect(),
+            cpu,
+            rss: process_rss().unwrap_or(0),
+            ..ServerMetric::default()
+        };
+        (self.at, self.cpu_time, self.bytes_in, self.bytes_out) =
+            (now, cpu_time, bytes_in, bytes_out);
+        metric
+    }
+}
+
+/// CPU time in user and kernel mode of current process, read from `/proc/self/stat`.
+///
+/// Times in it are in clock ticks, whose rate `USER_HZ` is 100 on Linux.
+pub fn process_cpu_time() -> Option<Duration> {
+    const USER_HZ: u64 = 100;
+    let stat = std::fs::read_to_string("/proc/self/stat").ok()?;
+    // the command name, 2nd field, may contain spaces, so fields are counted from the 3rd one.
+    // utime and stime are the 14th and 15th fields.
+    let fields: Vec<&str> = stat.rsplit_once(')')?.1.split_whitespace().collect();
+    let utime: u64 = fields.get(11)?.parse().ok()?;
+    let stime: u64 = fields.get(12)?.parse().ok()?;
+    Some(Duration::from_millis((utime + stime) * 1000 / USER_HZ))
+}
+
+/// Resident memory of current process in bytes, read from `/proc/self/status`.
+pub fn process_rss() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+    let kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kb * 1024)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metric_round_trip() {
+        let metric = ServerMetric {
+            weight: 2,
+            interval: 3,
+            users: 10,
+            channels: 4,
+            bytes_in: 1000,
+            bytes_out: 9000,
+            lag_events: 1,
+            dropped: HashMap::from([((1, "alice".to_string()), 5), ((2, "bob.b".to_string()), 0)]),
+            cpu: 12.5,
+            rss: 1 << 20,
+        };
+        assert_eq!(ServerMetric::from(&Metric::from(&metric)), metric);
+    }
+
+    #[test]
+    fn test_metric_defaults() {
+        let metric = Metric {
+            kv: HashMap::from([
+                (WEIGHT.to_string(), "3".to_string()),
+                (USERS.to_string(), "not a number".to_string()),
+            ]),
+        };
+        let metric = ServerMetric::from(&metric);
+        assert_eq!(metric.weight, 3);
+        assert_eq!(metric.users, 0);
+        assert_eq!(ServerMetric::from(&Metric::default()).weight, 1);
+
+        let metric = Metric {
+            kv: HashMap::from([(WEIGHT.to_string(), "100000000".to_string())]),
+        };
+        assert_eq!(ServerMetric::from(&metric).weight, MAX_WEIGHT);
+    }
+
+    #[test]
+    fn test_proc() {
+        assert!(process_rss().unwrap() > 0);
+        let before = process_cpu_time().unwrap();
+        // burn some cpu
+        let mut x = 0u64;
+        for i in 0..50_000_000u64 {
+            x = x.wrapping_add(i * i);
+        }
+        std::hint::black_box(x);
+        assert!(process_cpu_time().unwrap() >= before);
+    }
+
+    #[test]
+    fn test_sampler() {
+        let counters = Arc::new(Counters::default());
+        let mut sampler = Sampler::new(counters.clone());
+        counters.add_bytes_in(1000);
+        counters.add_bytes_out(3000);
+        counters.add_lag_event();
+        counters.add_dropped(1, "alice", 3);
+        counters.add_dropped(1, "alice", 2);
+        counters.add_dropped(2, "alice", 1);
+        std::thread::sleep(Duration::from_millis(100));
+
+        let metric = sampler.sample();
+        // 1000 bytes in at most 100ms
+        assert!(metric.bytes_in > 0 && metric.bytes_in <= 10_000);
+        assert!(metric.bytes_out > metric.bytes_in);
+        assert_eq!(metric.lag_events, 1);
+        assert_eq!(
+            metric.dropped,
+            HashMap::from([((1, "alice".to_string()), 5), ((2, "alice".to_string()), 1)])
+        );
+        assert!(metric.rss > 0);
+
+        // rates are since last sample
+        std::thread::sleep(Duration::from_millis(10));
+        let metric = sampler.sample();
+        assert_eq!(metric.bytes_in, 0);
+        assert_eq!(metric.lag_events, 1);
+
+        // a user on two channels leaves one
+        counters.remove_user(1, "alice");
+        assert_eq!(
+            sampler.sample().dropped,
+            HashMap::from([((2, "alice".to_string()), 1)])
+        );
+    }
+}
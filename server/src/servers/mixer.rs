@@ -0,0 +1,185 @@
+//! Audio mixing on chat server, for channels in [`abi::pb::ChannelMode::Mix`].
+//!
+//! Users' audio is decoded and queued by speaker, and every [`FRAME_DURATION`] one frame of each
+//! speaker is taken and mixed. Every listener gets the mixed frame without their own voice,
+//! frames are in [`abi::codec`]'s format.
+
+pub use abi::codec::{FRAME_DURATION, FRAME_SIZE};
+use std::collections::{HashMap, VecDeque};
+
+// a speaker's audio beyond this is dropped, so that it can't fall too far behind
+const MAX_QUEUED_FRAMES: usize = 10;
+
+/// Mixer aligns speakers' audio by frames, and mixes a frame for each listener.
+#[derive(Debug, Default)]
+pub struct Mixer {
+    queues: HashMap<String, VecDeque<f32>>, // speaker - queued samples
+}
+
+impl Mixer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queue samples of a speaker.
+    ///
+    /// The oldest samples are dropped if the speaker is more than a few frames ahead.
+    pub fn push(&mut self, user_id: &str, samples: impl IntoIterator<Item = f32>) {
+        let queue = self.queues.entry(user_id.to_string()).or_default();
+        queue.extend(samples);
+        let max = MAX_QUEUED_FRAMES * FRAME_SIZE;
+        if queue.len() > max {
+            queue.drain(..queue.len() - max);
+        }
+    }
+
+    /// Forget a speaker, e.g. the user left.
+    pub fn remove(&mut self, user_id: &str) {
+        self.queues.remove(user_id);
+    }
+
+    /// Take the next frame of every speaker, and mix them for each listener without own voice.
+    ///
+    /// A speaker with less than a frame queued is padded with silence.
+    /// Listeners hearing nobody are left out, so silence is not sent.
+    /// Mixed samples are clamped into `[-1, 1]`.
+    pub fn mix<'a>(
+        &mut self,
+        listeners: impl IntoIterator<Item = &'a str>,
+    ) -> HashMap<String, Vec<f32>> {
+        let mut frames = HashMap::new();
+        self.queues.retain(|user_id, queue| {
+            if queue.is_empty() {
+                return false;
+            }
+            let n = queue.len().min(FRAME_SIZE);
+            let mut frame: Vec<f32> = queue.drain(..n).collect();
+            frame.resize(FRAME_SIZE, 0.0);
+            frames.insert(user_id.clone(), frame);
+            true
+        });
+        if frames.is_empty() {
+            return HashMap::new();
+        }
+
+        let mut total = vec![0.0; FRAME_SIZE];
+        for frame in frames.values() {
+            for (t, v) in total.iter_mut().zip(frame) {
+                *t += v;
+            }
+        }
+
+        let mut mixed = HashMap::new();
+        for listener in listeners {
+            let own = frames.get(listener);
+            if own.is_some() && frames.len() == 1 {
+                continue; // only hears self
+            }
+            let frame = match own {
+                Some(own) => total.iter().zip(own).map(|(t, v)| t - v).collect(),
+                None => total.clone(),
+            };
+            mixed.insert(listener.to_string(), clamp(frame));
+        }
+        mixed
+    }
+}
+
+fn clamp(mut frame: Vec<f32>) -> Vec<f32> {
+    frame.iter_mut().for_each(|v| *v = v.clamp(-1.0, 1.0));
+    frame
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use abi::codec::SAMPLE_RATE;
+    use std::f32::consts::PI;
+
+    fn sine(freq: f32, amplitude: f32, len: usize) -> Vec<f32> {
+        (0..len)
+            .map(|i| amplitude * (2.0 * PI * freq * i as f32 / SAMPLE_RATE as f32).sin())
+            .collect()
+    }
+
+    fn assert_close(actual: &[f32], expected: &[f32]) {
+        assert_eq!(actual.len(), expected.len());
+        for (i, (a, e)) in actual.iter().zip(expected).enumerate() {
+            assert!((a - e).abs() < 1e-5, "sample {}: {} != {}", i, a, e);
+        }
+    }
+
+    #[test]
+    fn test_mix_excludes_own_voice() {
+        let (a, b) = (sine(440.0, 0.3, FRAME_SIZE), sine(660.0, 0.3, FRAME_SIZE));
+        let mut mixer = Mixer::new();
+        mixer.push("a", a.clone());
+        mixer.push("b", b.clone());
+
+        let mixed = mixer.mix(["a", "b", "c"]);
+        assert_close(&mixed["a"], &b);
+        assert_close(&mixed["b"], &a);
+        let sum: Vec<f32> = a.iter().zip(&b).map(|(x, y)| x + y).collect();
+        assert_close(&mixed["c"], &sum);
+
+        // all consumed
+        assert!(mixer.mix(["a", "b", "c"]).is_empty());
+    }
+
+    #[test]
+    fn test_mix_aligns_by_frame() {
+        let a = sine(440.0, 0.5, 3 * FRAME_SIZE);
+        let b = sine(1000.0, 0.5, FRAME_SIZE / 2);
+        let mut mixer = Mixer::new();
+        // a's audio comes in pieces
+        for chunk in a.chunks(100) {
+            mixer.push("a", chunk.to_vec());
+        }
+        mixer.push("b", b.clone());
+
+        // b is padded with silence
+        let mut padded = b.clone();
+        padded.resize(FRAME_SIZE, 0.0);
+        let frame = mixer.mix(["a", "c"]);
+        assert_close(&frame["a"], &padded);
+        let sum: Vec<f32> = a[..FRAME_SIZE]
+            .iter()
+            .zip(&padded)
+            .map(|(x, y)| x + y)
+            .collect();
+        assert_close(&frame["c"], &sum);
+
+        // then a alone, and a doesn't hear self
+        for i in 1..3 {
+            let frame = mixer.mix(["a", "c"]);
+            assert!(!frame.contains_key("a"));
+            assert_close(&frame["c"], &a[i * FRAME_SIZE..(i + 1) * FRAME_SIZE]);
+        }
+        assert!(mixer.mix(["a", "c"]).is_empty());
+    }
+
+    #[test]
+    fn test_mix_clamps() {
+        let mut mixer = Mixer::new();
+        mixer.push("a", sine(440.0, 0.9, FRAME_SIZE));
+        mixer.push("b", sine(440.0, 0.9, FRAME_SIZE));
+
+        let frame = &mixer.mix(["c"])["c"];
+        assert!(frame.iter().all(|v| (-1.0..=1.0).contains(v)));
+        assert!(frame.contains(&1.0));
+    }
+
+    #[test]
+    fn test_push_drops_oldest() {
+        let mut mixer = Mixer::new();
+        let a = sine(440.0, 0.5, (MAX_QUEUED_FRAMES + 2) * FRAME_SIZE);
+        mixer.push("a", a.clone());
+
+        // the first 2 frames are dropped
+        let frame = mixer.mix(["b"]);
+        assert_close(&frame["b"], &a[2 * FRAME_SIZE..3 * FRAME_SIZE]);
+
+        mixer.remove("a");
+        assert!(mixer.mix(["b"]).is_empty());
+    }
+}
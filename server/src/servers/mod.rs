@@ -1,3 +1,6 @@
 pub mod chat_server;
 mod client;
+mod fanout;
 pub mod manager;
+mod metric;
+mod mixer;
@@ -1,5 +1,5 @@
 use abi::pb::channel_service_client::ChannelServiceClient;
-use abi::pb::Channel;
+use abi::pb::{Channel, ChannelMode};
 use std::collections::HashSet;
 use std::str::FromStr;
 use std::time::Duration;
@@ -28,6 +28,8 @@ async fn test_channels() {
                 name: "test1".to_string(),
                 users: vec![],
                 limit: 5,
+                mode: ChannelMode::Forward.into(),
+                buffer_size: 0,
             })
             .with(&token),
         )
@@ -42,12 +44,27 @@ async fn test_channels() {
                 name: "test2".to_string(),
                 users: vec![],
                 limit: 6,
+                mode: ChannelMode::Forward.into(),
+                buffer_size: 0,
             })
             .with(&token),
         )
         .await
         .unwrap()
         .into_inner();
+    // buffer size out of range
+    let rsp = chan_client
+        .create(
+            Request::new(Channel {
+                name: "test3".to_string(),
+                buffer_size: -1,
+                ..Default::default()
+            })
+            .with(&token),
+        )
+        .await;
+    assert_eq!(rsp.unwrap_err().code(), tonic::Code::InvalidArgument);
+
     //list single
     let mut req_chan = Channel {
         id: channel1.id,
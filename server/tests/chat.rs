@@ -1,13 +1,22 @@
+use abi::codec;
 use abi::pb::channel_service_client::ChannelServiceClient;
 use abi::pb::chat_service_client::ChatServiceClient;
 use abi::pb::message::Content;
-use abi::pb::{Channel, Message};
-use std::collections::HashSet;
+use abi::pb::{
+    Channel, ChannelMode, Codec, HistoryRequest, MemberRequest, Message, Metric, Reconnect,
+    ReportRequest, ReportResponse, Roster, ServerMute, ShutdownRequest, User, VoiceState,
+};
+use echo_server::auth::interceptor::{
+    encrypt, AccessClaims, ListenClaims, ServerClaims, MANAGER_AUDIENCE,
+};
+use echo_server::auth::keys::KeySet;
+use echo_server::config::{JwtConfig, KeyAlgorithm, KeyConfig, ServerConfig};
+use std::collections::{HashMap, HashSet};
 use std::str::FromStr;
 use tokio::time::timeout;
 use tokio::time::Duration;
 use tonic::transport::Endpoint;
-use tonic::{Request, Status, Streaming};
+use tonic::{Code, Request, Status, Streaming};
 mod common;
 use abi::traits::WithToken;
 use common::server::*;
@@ -83,18 +92,21 @@ async fn test_chat() {
             let inbound = chat_client.conn(req).await.unwrap().into_inner();
             receivers.push(inbound);
         }
-        // b. choose one send messages, check others' recv()
+        // b. choose one send messages, check all recv(), the sender by echo
         let expected = vec![
             Message {
                 content: Some(Content::Text("hello".into())),
+                echo: true,
                 ..Default::default()
             },
             Message {
                 content: Some(Content::Text("world".into())),
+                echo: true,
                 ..Default::default()
             },
             Message {
                 content: Some(Content::Text("hello".into())),
+                echo: true,
                 ..Default::default()
             },
         ];
@@ -128,7 +140,7 @@ async fn check_inbound(
     let mut count = 0;
 
     while count < expected.len() {
-        match timeout(timeout_duration, stream.message()).await {
+        match timeout(timeout_duration, next_message(stream)).await {
             Ok(Ok(Some(msg))) => {
                 if msg.content != expected[count].content {
                     return Err(Status::internal(format!(
@@ -226,7 +238,7 @@ async fn test_chat_disconnect() {
 
             // close tx, and check inbound
             drop(tx);
-            let msg = inbound.message().await.unwrap();
+            let msg = next_message(&mut inbound).await.unwrap();
             assert!(msg.is_none());
         }
     }
@@ -310,18 +322,21 @@ async fn test_conn_wrong_server() {
             let inbound = chat_client.conn(req).await.unwrap().into_inner();
             receivers.push(inbound);
         }
-        // b. choose one send messages, check others' recv()
+        // b. choose one send messages, check all recv(), the sender by echo
         let expected = vec![
             Message {
                 content: Some(Content::Text("hello".to_string())),
+                echo: true,
                 ..Default::default()
             },
             Message {
                 content: Some(Content::Text("world".to_string())),
+                echo: true,
                 ..Default::default()
             },
             Message {
                 content: Some(Content::Text("hello".to_string())),
+                echo: true,
                 ..Default::default()
             },
         ];
@@ -432,7 +447,7 @@ async fn test_report() {
             tokio::time::sleep(Duration::from_secs(3)).await;
             // check list channels
 
-            let req = Request::new(channel.clone()).with(&chat_token);
+            let req = Request::new(channel.clone()).with(token);
             let rsp = chan_client.list(req).await.unwrap().into_inner();
             println!("rsp: {:?}", rsp);
             assert_eq!(rsp.channels.len(), 1);
@@ -444,7 +459,7 @@ async fn test_report() {
             tokio::time::sleep(Duration::from_secs(3)).await;
 
             // check list channels
-            let req = Request::new(channel.clone()).with(&chat_token);
+            let req = Request::new(channel.clone()).with(token);
             let rsp = chan_client.list(req).await.unwrap().into_inner();
             println!("rsp: {:?}", rsp);
             assert_eq!(rsp.channels.len(), 1);
@@ -460,3 +475,1786 @@ async fn test_report() {
     join_handle.abort();
     drop(tdb);
 }
+
+// listen to a channel by manager, then connect to its chat server.
+async fn connect_channel(
+    chan_client: &mut ChannelServiceClient<tonic::transport::Channel>,
+    channel: &Channel,
+    token: &str,
+) -> (tokio::sync::mpsc::Sender<Message>, Streaming<Message>) {
+    let rsp = chan_client
+        .listen(Request::new(channel.clone()).with(token))
+        .await
+        .unwrap()
+        .into_inner();
+
+    let chat_addr = rsp.server.unwrap().addr;
+    let chat_conn = Endpoint::from_str(&chat_addr)
+        .unwrap()
+        .connect()
+        .await
+        .unwrap();
+    let mut chat_client = ChatServiceClient::new(chat_conn);
+
+    let (tx, rx) = tokio::sync::mpsc::channel(10);
+    let stream = tokio_stream::wrappers::ReceiverStream::new(rx);
+    let req = Request::new(stream).with(&rsp.token);
+    let inbound = chat_client.conn(req).await.unwrap().into_inner();
+    (tx, inbound)
+}
+
+// channel created after chat server registered, should be pushed to it.
+#[tokio::test]
+async fn test_add_channel() {
+    let (config, join_handle, tdb) = init_manager_server(50454).await;
+    let addr = config.server.url_with(false);
+    let conn = Endpoint::from_str(&addr).unwrap().connect().await.unwrap();
+    let token = register_login("test", conn.clone()).await;
+    let mut chan_client = ChannelServiceClient::new(conn.clone());
+
+    // 1. add 1 server before any channel exists
+    let (_, handle) = init_chat_server(50455, &tdb, &addr).await;
+
+    // 2. create channel, it's pushed to the only server
+    let channel = chan_client
+        .create(
+            Request::new(Channel {
+                name: "channel_0".to_string(),
+                limit: 10,
+                ..Default::default()
+            })
+            .with(&token),
+        )
+        .await
+        .unwrap()
+        .into_inner();
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    // 3. users can chat on it
+    let mut receivers = vec![];
+    let mut senders = vec![];
+    for i in 1..3 {
+        let token = register_login(&format!("test_{}", i), conn.clone()).await;
+        let (tx, inbound) = connect_channel(&mut chan_client, &channel, &token).await;
+        senders.push(tx);
+        receivers.push(inbound);
+    }
+    let expected = vec![Message {
+        content: Some(Content::Text("hello".into())),
+        echo: true,
+        ..Default::default()
+    }];
+    senders[0].send(expected[0].clone()).await.unwrap();
+    for stream in receivers.iter_mut() {
+        check_inbound(stream, &expected, Duration::from_secs(10))
+            .await
+            .unwrap();
+    }
+
+    handle.abort();
+    join_handle.abort();
+    drop(tdb);
+}
+
+// deleted channel is removed from chat server: users are disconnected, and new joins are refused.
+#[tokio::test]
+async fn test_remove_channel() {
+    let (config, join_handle, tdb) = init_manager_server(50554).await;
+    let addr = config.server.url_with(false);
+    let conn = Endpoint::from_str(&addr).unwrap().connect().await.unwrap();
+    let token = register_login("test", conn.clone()).await;
+    let mut chan_client = ChannelServiceClient::new(conn.clone());
+
+    let channel = chan_client
+        .create(
+            Request::new(Channel {
+                name: "channel_0".to_string(),
+                limit: 10,
+                ..Default::default()
+            })
+            .with(&token),
+        )
+        .await
+        .unwrap()
+        .into_inner();
+    let (_, handle) = init_chat_server(50555, &tdb, &addr).await;
+
+    // 1. user_1 connects, user_2 only gets a token
+    let token_1 = register_login("test_1", conn.clone()).await;
+    let (_tx, mut inbound) = connect_channel(&mut chan_client, &channel, &token_1).await;
+
+    let token_2 = register_login("test_2", conn.clone()).await;
+    let rsp = chan_client
+        .listen(Request::new(channel.clone()).with(&token_2))
+        .await
+        .unwrap()
+        .into_inner();
+
+    // 2. owner deletes channel
+    chan_client
+        .delete(Request::new(channel.clone()).with(&token))
+        .await
+        .unwrap();
+
+    // 3. user_1 is disconnected
+    let msg = timeout(Duration::from_secs(5), next_message(&mut inbound))
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(msg.is_none());
+
+    // 4. user_2 can't join
+    let chat_conn = Endpoint::from_str(&rsp.server.unwrap().addr)
+        .unwrap()
+        .connect()
+        .await
+        .unwrap();
+    let mut chat_client = ChatServiceClient::new(chat_conn);
+    let (_tx, rx) = tokio::sync::mpsc::channel(10);
+    let stream = tokio_stream::wrappers::ReceiverStream::new(rx);
+    let rsp = chat_client
+        .conn(Request::new(stream).with(&rsp.token))
+        .await;
+    assert_eq!(rsp.unwrap_err().code(), tonic::Code::NotFound);
+
+    // 5. the deprecated shutdown rpc fails instead of panicking
+    let rsp = chat_client
+        .shutdown(Request::new(ShutdownRequest {
+            user_id: None,
+            channel_id: channel.id,
+        }))
+        .await;
+    assert_eq!(rsp.unwrap_err().code(), tonic::Code::Unimplemented);
+
+    handle.abort();
+    join_handle.abort();
+    drop(tdb);
+}
+
+// channels taken by a new chat server are migrated: their users get a reconnect message with a fresh token.
+#[tokio::test]
+async fn test_migrate_channel() {
+    let (config, join_handle, tdb) = init_manager_server(50654).await;
+    let addr = config.server.url_with(false);
+    let conn = Endpoint::from_str(&addr).unwrap().connect().await.unwrap();
+    let token = register_login("test", conn.clone()).await;
+    let mut chan_client = ChannelServiceClient::new(conn.clone());
+
+    // 1. create 8 channels on 1 server, one user for each
+    let (_, handle_a) = init_chat_server(50655, &tdb, &addr).await;
+    let mut conns = vec![];
+    for i in 0..8 {
+        let channel = chan_client
+            .create(
+                Request::new(Channel {
+                    name: format!("channel_{}", i),
+                    limit: 10,
+                    ..Default::default()
+                })
+                .with(&token),
+            )
+            .await
+            .unwrap()
+            .into_inner();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let token = register_login(&format!("test_{}", i), conn.clone()).await;
+        conns.push(connect_channel(&mut chan_client, &channel, &token).await);
+    }
+    // wait for reporting users
+    tokio::time::sleep(Duration::from_secs(4)).await;
+
+    // 2. add another server, some channels move to it
+    let (config_b, handle_b) = init_chat_server(50656, &tdb, &addr).await;
+    let addr_b = config_b.server.url_with(false);
+
+    let mut moved = 0;
+    for (_tx, inbound) in conns.iter_mut() {
+        let Ok(msg) = timeout(Duration::from_secs(1), next_message(inbound)).await else {
+            continue; // not moved
+        };
+        let Some(Content::Reconnect(reconnect)) = msg.unwrap().unwrap().content else {
+            panic!("expect reconnect message");
+        };
+        assert_eq!(reconnect.addr, addr_b);
+        assert!(!reconnect.token.is_empty());
+        // old connection is closed
+        assert!(next_message(inbound).await.unwrap().is_none());
+
+        // reconnect to new server
+        let chat_conn = Endpoint::from_str(&reconnect.addr)
+            .unwrap()
+            .connect()
+            .await
+            .unwrap();
+        let mut chat_client = ChatServiceClient::new(chat_conn);
+        let (_tx, rx) = tokio::sync::mpsc::channel(10);
+        let stream = tokio_stream::wrappers::ReceiverStream::new(rx);
+        let rsp = chat_client
+            .conn(Request::new(stream).with(&reconnect.token))
+            .await;
+        assert!(rsp.is_ok());
+        moved += 1;
+    }
+    assert!(moved > 0);
+
+    handle_a.abort();
+    handle_b.abort();
+    join_handle.abort();
+    drop(tdb);
+}
+
+// only the server moves users, a reconnect sent by a user goes to no one.
+#[tokio::test]
+async fn test_user_reconnect() {
+    let (config, join_handle, tdb) = init_manager_server(52354).await;
+    let addr = config.server.url_with(false);
+    let conn = Endpoint::from_str(&addr).unwrap().connect().await.unwrap();
+    let token = register_login("test", conn.clone()).await;
+    let token2 = register_login("test_2", conn.clone()).await;
+    let mut chan_client = ChannelServiceClient::new(conn);
+    let channel = chan_client
+        .create(
+            Request::new(Channel {
+                name: "reconnect".to_string(),
+                limit: 10,
+                ..Default::default()
+            })
+            .with(&token),
+        )
+        .await
+        .unwrap()
+        .into_inner();
+    let (_, handle) = init_chat_server(52355, &tdb, &addr).await;
+
+    let (tx1, _rx1) = connect_channel(&mut chan_client, &channel, &token).await;
+    let (_tx2, mut rx2) = connect_channel(&mut chan_client, &channel, &token2).await;
+
+    tx1.send(Message {
+        content: Some(Content::Reconnect(Reconnect {
+            addr: "http://127.0.0.1:1".to_string(),
+            token: "fake".to_string(),
+        })),
+        ..Default::default()
+    })
+    .await
+    .unwrap();
+    tx1.send(Message {
+        content: Some(Content::Text("after".into())),
+        ..Default::default()
+    })
+    .await
+    .unwrap();
+    let msg = timeout(Duration::from_secs(5), next_message(&mut rx2))
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    assert_eq!(msg.content, Some(Content::Text("after".into())));
+
+    handle.abort();
+    join_handle.abort();
+    drop(tdb);
+}
+
+// manager is killed and restarted under a live chat session,
+// users keep chatting meanwhile, and chat server registers again with full state.
+#[tokio::test]
+async fn test_manager_restart() {
+    let (config, join_handle, tdb) = init_manager_server(50754).await;
+    join_handle.abort(); // only for the database, manager is run by `ManagerProcess`
+    let mut config = config;
+    config.server.port = 50755;
+    let manager = ManagerProcess::start(&config, &tdb).await;
+    let addr = config.server.url_with(false);
+
+    let conn = Endpoint::from_str(&addr).unwrap().connect().await.unwrap();
+    let token = register_login("test", conn.clone()).await;
+    let mut chan_client = ChannelServiceClient::new(conn);
+    let channel = chan_client
+        .create(
+            Request::new(Channel {
+                name: "channel".to_string(),
+                limit: 10,
+                ..Default::default()
+            })
+            .with(&token),
+        )
+        .await
+        .unwrap()
+        .into_inner();
+    let (_, handle) = init_chat_server(50756, &tdb, &addr).await;
+
+    let (tx1, mut rx1) = connect_channel(&mut chan_client, &channel, &token).await;
+    let token2 = register_login(
+        "test_2",
+        Endpoint::from_str(&addr).unwrap().connect().await.unwrap(),
+    )
+    .await;
+    let (_tx2, mut rx2) = connect_channel(&mut chan_client, &channel, &token2).await;
+
+    let text = |s: &str| Message {
+        content: Some(Content::Text(s.into())),
+        echo: true,
+        ..Default::default()
+    };
+    let timeout_duration = Duration::from_secs(5);
+
+    // 1. kill manager, the session keeps working
+    manager.kill();
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    tx1.send(text("hello")).await.unwrap();
+    for rx in [&mut rx1, &mut rx2] {
+        check_inbound(rx, &[text("hello")], timeout_duration)
+            .await
+            .unwrap();
+    }
+
+    // 2. restart manager, chat server reconnects and reports its users
+    let _manager = ManagerProcess::start(&config, &tdb).await;
+    let conn = Endpoint::from_str(&addr).unwrap().connect().await.unwrap();
+    let mut chan_client = ChannelServiceClient::new(conn);
+    let reported = timeout(Duration::from_secs(20), async {
+        loop {
+            let rsp = chan_client
+                .list(Request::new(channel.clone()).with(&token))
+                .await
+                .unwrap()
+                .into_inner();
+            if rsp.channels.first().is_some_and(|c| c.users.len() == 2) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(200)).await;
+        }
+    })
+    .await;
+    assert!(
+        reported.is_ok(),
+        "chat server didn't report to restarted manager"
+    );
+
+    // 3. new user joins the same live channel by restarted manager
+    let token3 = register_login(
+        "test_3",
+        Endpoint::from_str(&addr).unwrap().connect().await.unwrap(),
+    )
+    .await;
+    let (_tx3, mut rx3) = connect_channel(&mut chan_client, &channel, &token3).await;
+    tx1.send(text("world")).await.unwrap();
+    for rx in [&mut rx1, &mut rx2, &mut rx3] {
+        check_inbound(rx, &[text("world")], timeout_duration)
+            .await
+            .unwrap();
+    }
+
+    handle.abort();
+    drop(tdb);
+}
+
+// chat server's heartbeat is listed with its metric, and it's evicted after missing heartbeats.
+#[tokio::test]
+async fn test_heartbeat_eviction() {
+    let (config, join_handle, tdb) = init_manager_server(50854).await;
+    let addr = config.server.url_with(false);
+    let conn = Endpoint::from_str(&addr).unwrap().connect().await.unwrap();
+    let token = register_login("test", conn.clone()).await;
+    let mut chan_client = ChannelServiceClient::new(conn);
+
+    // mock a chat server, which reports once and then hangs,
+    // its huge interval can't put off the eviction
+    let server_addr = "http://127.0.0.1:50855".to_string();
+    let server_token = encrypt(
+        &KeySet::new(&config.server).unwrap(),
+        &ServerClaims::new(
+            MANAGER_AUDIENCE,
+            &server_addr,
+            chrono::Utc::now().timestamp() + 60,
+        ),
+    );
+    let metric = Metric {
+        kv: HashMap::from([
+            ("interval".to_string(), u64::MAX.to_string()),
+            ("users".to_string(), "7".to_string()),
+        ]),
+    };
+    let (tx, rx) = tokio::sync::mpsc::channel(10);
+    tx.send(ReportRequest {
+        metric: Some(metric),
+        channels: vec![],
+    })
+    .await
+    .unwrap();
+    let stream = tokio_stream::wrappers::ReceiverStream::new(rx);
+    let mut responses = chan_client
+        .report(Request::new(stream).with(&server_token))
+        .await
+        .unwrap()
+        .into_inner();
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    let list = |mut client: ChannelServiceClient<tonic::transport::Channel>| {
+        let token = token.clone();
+        async move {
+            client
+                .list(Request::new(Channel::default()).with(&token))
+                .await
+                .unwrap()
+                .into_inner()
+                .servers
+        }
+    };
+    let servers = list(chan_client.clone()).await;
+    assert_eq!(servers.len(), 1);
+    assert_eq!(servers[0].addr, server_addr);
+    assert_eq!(servers[0].metric.as_ref().unwrap().kv["users"], "7");
+
+    // 3 heartbeats of manager's 1s are missed, the report stream is closed by manager
+    let closed = timeout(Duration::from_secs(10), responses.message()).await;
+    assert!(matches!(closed, Ok(Ok(None)) | Ok(Err(_))));
+    assert!(list(chan_client).await.is_empty());
+
+    drop(tx);
+    join_handle.abort();
+    drop(tdb);
+}
+
+// mock a chat server at `port`, which reports every 500ms with an interval of 1s.
+async fn mock_report(
+    config: &ServerConfig,
+    chan_client: &mut ChannelServiceClient<tonic::transport::Channel>,
+    port: u16,
+) -> (
+    tokio::sync::mpsc::Sender<ReportRequest>,
+    Streaming<ReportResponse>,
+    tokio::task::JoinHandle<()>,
+) {
+    let server_addr = format!("http://127.0.0.1:{}", port);
+    let server_token = encrypt(
+        &KeySet::new(config).unwrap(),
+        &ServerClaims::new(
+            MANAGER_AUDIENCE,
+            &server_addr,
+            chrono::Utc::now().timestamp() + 60,
+        ),
+    );
+    let report = ReportRequest {
+        metric: Some(Metric {
+            kv: HashMap::from([("interval".to_string(), "1".to_string())]),
+        }),
+        channels: vec![],
+    };
+    let (tx, rx) = tokio::sync::mpsc::channel(10);
+    tx.send(report.clone()).await.unwrap();
+    let stream = tokio_stream::wrappers::ReceiverStream::new(rx);
+    let responses = chan_client
+        .report(Request::new(stream).with(&server_token))
+        .await
+        .unwrap()
+        .into_inner();
+    let heartbeat = tx.clone();
+    let reporting = tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            if heartbeat.send(report.clone()).await.is_err() {
+                break;
+            }
+        }
+    });
+    (tx, responses, reporting)
+}
+
+// a chat server registered again outlives its old report stream,
+// and a channel reported by a server not owning it is migrated to the owner.
+#[tokio::test]
+async fn test_report_sessions() {
+    let (config, join_handle, tdb) = init_manager_server(52254).await;
+    let addr = config.server.url_with(false);
+    let conn = Endpoint::from_str(&addr).unwrap().connect().await.unwrap();
+    let token = register_login("test", conn.clone()).await;
+    let mut chan_client = ChannelServiceClient::new(conn);
+    let channel = chan_client
+        .create(
+            Request::new(Channel {
+                name: "sessions".to_string(),
+                limit: 10,
+                ..Default::default()
+            })
+            .with(&token),
+        )
+        .await
+        .unwrap()
+        .into_inner();
+    let list = |mut client: ChannelServiceClient<tonic::transport::Channel>| {
+        let token = token.clone();
+        async move {
+            client
+                .list(Request::new(Channel::default()).with(&token))
+                .await
+                .unwrap()
+                .into_inner()
+                .servers
+        }
+    };
+
+    // 1. the first stream hangs, and the server registers again by another one
+    let (hung, _, hung_reporting) = mock_report(&config.server, &mut chan_client, 52255).await;
+    hung_reporting.abort();
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    let (tx1, mut responses1, reporting1) =
+        mock_report(&config.server, &mut chan_client, 52255).await;
+    // the hung stream is evicted after missing 3 heartbeats, the live one is kept
+    tokio::time::sleep(Duration::from_secs(5)).await;
+    let servers = list(chan_client.clone()).await;
+    assert_eq!(servers.len(), 1);
+    assert_eq!(servers[0].addr, "http://127.0.0.1:52255");
+    drop(hung);
+
+    // 2. another server, the channel is on one of them
+    let (tx2, mut responses2, reporting2) =
+        mock_report(&config.server, &mut chan_client, 52256).await;
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    assert_eq!(list(chan_client.clone()).await.len(), 2);
+    let owner = chan_client
+        .listen(Request::new(channel.clone()).with(&token))
+        .await
+        .unwrap()
+        .into_inner()
+        .server
+        .unwrap()
+        .addr;
+    let (tx, responses) = if owner.ends_with("52255") {
+        (&tx2, &mut responses2)
+    } else {
+        (&tx1, &mut responses1)
+    };
+
+    // 3. the other server reports the channel with a user, it's asked to migrate the user
+    tx.send(ReportRequest {
+        metric: Some(Metric {
+            kv: HashMap::from([("interval".to_string(), "1".to_string())]),
+        }),
+        channels: vec![Channel {
+            users: vec![abi::pb::User {
+                id: "test".to_string(),
+                ..Default::default()
+            }],
+            ..channel.clone()
+        }],
+    })
+    .await
+    .unwrap();
+    let migrate = timeout(Duration::from_secs(5), async {
+        loop {
+            let rsp = responses.message().await.unwrap().unwrap();
+            // moved when the second server registered, without users
+            match rsp.migrate {
+                Some(req) if req.tokens.contains_key("test") => break req,
+                _ => continue,
+            }
+        }
+    })
+    .await
+    .unwrap();
+    assert_eq!(migrate.channel_id, channel.id);
+    assert_eq!(migrate.addr, owner);
+
+    reporting1.abort();
+    reporting2.abort();
+    join_handle.abort();
+    drop(tdb);
+}
+
+// audio on a mix channel is mixed by chat server, and a speaker doesn't hear self,
+// nor is a pause of the speaker concealed.
+#[tokio::test]
+async fn test_mix_channel() {
+    let (config, join_handle, tdb) = init_manager_server(50954).await;
+    let addr = config.server.url_with(false);
+    let conn = Endpoint::from_str(&addr).unwrap().connect().await.unwrap();
+    let token = register_login("test", conn.clone()).await;
+    let token2 = register_login("test_2", conn.clone()).await;
+    let mut chan_client = ChannelServiceClient::new(conn);
+    let channel = chan_client
+        .create(
+            Request::new(Channel {
+                name: "mix".to_string(),
+                limit: 10,
+                mode: ChannelMode::Mix.into(),
+                ..Default::default()
+            })
+            .with(&token),
+        )
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(channel.mode(), ChannelMode::Mix);
+    let (_, handle) = init_chat_server(50955, &tdb, &addr).await;
+
+    let (tx1, mut rx1) = connect_channel(&mut chan_client, &channel, &token).await;
+    let (_tx2, mut rx2) = connect_channel(&mut chan_client, &channel, &token2).await;
+
+    // 25 frames of 20ms 440Hz sine, in Opus
+    let samples: Vec<f32> = (0..25 * codec::FRAME_SIZE)
+        .map(|i| {
+            let t = i as f32 / codec::SAMPLE_RATE as f32;
+            0.5 * (2.0 * std::f32::consts::PI * 440.0 * t).sin()
+        })
+        .collect();
+    // sent in real time, as the mixer keeps only a few frames ahead
+    let mut encoder = codec::Encoder::new().unwrap();
+    let mut interval = tokio::time::interval(codec::FRAME_DURATION);
+    for frame in encoder.encode(&samples).unwrap() {
+        interval.tick().await;
+        tx1.send(Message {
+            content: Some(Content::AudioData(frame.data)),
+            codec: Codec::Opus.into(),
+            seq: frame.seq,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    }
+    tx1.send(Message {
+        content: Some(Content::Text("hello".into())),
+        echo: true,
+        ..Default::default()
+    })
+    .await
+    .unwrap();
+
+    // listener gets the speaker's audio re-encoded in frames, and the text
+    let mut decoder = codec::Decoder::new().unwrap();
+    let mut received = vec![];
+    let mut text = None;
+    while received.len() < samples.len() || text.is_none() {
+        let msg = timeout(Duration::from_secs(5), next_message(&mut rx2))
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        let (codec, seq) = (msg.codec(), msg.seq);
+        match msg.content {
+            Some(Content::AudioData(data)) => {
+                assert_eq!(codec, Codec::Opus);
+                let frame = decoder.decode(seq, &data).unwrap();
+                assert_eq!(frame.len(), codec::FRAME_SIZE);
+                received.extend(frame);
+            }
+            Some(Content::Text(t)) => text = Some(t),
+            other => panic!("unexpected message: {:?}", other),
+        }
+    }
+    // encoded twice, the sine is kept with about the same energy
+    let rms = |s: &[f32]| (s.iter().map(|v| v * v).sum::<f32>() / s.len() as f32).sqrt();
+    let steady = 5 * codec::FRAME_SIZE..20 * codec::FRAME_SIZE;
+    let ratio = rms(&received[steady.clone()]) / rms(&samples[steady]);
+    assert!((0.8..1.2).contains(&ratio), "rms ratio: {}", ratio);
+    assert_eq!(text.as_deref(), Some("hello"));
+
+    // speaker gets only the text echoed
+    let msg = timeout(Duration::from_secs(5), next_message(&mut rx1))
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    assert_eq!(msg.content, Some(Content::Text("hello".into())));
+    assert!(msg.echo);
+    assert!(timeout(Duration::from_millis(500), next_message(&mut rx1))
+        .await
+        .is_err());
+
+    // after a pause, as by voice activity detection, the speaker resumes 50 frames later,
+    // and the listener gets just the frames sent, nothing concealed in between
+    let mut interval = tokio::time::interval(codec::FRAME_DURATION);
+    for frame in encoder.encode(&samples[..10 * codec::FRAME_SIZE]).unwrap() {
+        interval.tick().await;
+        tx1.send(Message {
+            content: Some(Content::AudioData(frame.data)),
+            codec: Codec::Opus.into(),
+            seq: frame.seq + 50,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    }
+    let mut frames = 0;
+    while let Ok(msg) = timeout(Duration::from_millis(500), next_message(&mut rx2)).await {
+        if let Some(Content::AudioData(_)) = msg.unwrap().unwrap().content {
+            frames += 1;
+        }
+    }
+    assert_eq!(frames, 10);
+
+    handle.abort();
+    join_handle.abort();
+    drop(tdb);
+}
+
+// a slow listener skips stale audio instead of being disconnected, drops are reported in
+// metric, and text is never dropped. Nor does it delay the speaker's events to others.
+#[tokio::test]
+async fn test_slow_listener() {
+    let (config, join_handle, tdb) = init_manager_server(51054).await;
+    let addr = config.server.url_with(false);
+    let conn = Endpoint::from_str(&addr).unwrap().connect().await.unwrap();
+    let token = register_login("test", conn.clone()).await;
+    let token2 = register_login("test_2", conn.clone()).await;
+    let token3 = register_login("test_3", conn.clone()).await;
+    let mut chan_client = ChannelServiceClient::new(conn);
+    let channel = chan_client
+        .create(
+            Request::new(Channel {
+                name: "small".to_string(),
+                limit: 10,
+                buffer_size: 4,
+                ..Default::default()
+            })
+            .with(&token),
+        )
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(channel.buffer_size, 4);
+    let (_, handle) = init_chat_server(51055, &tdb, &addr).await;
+
+    let (tx1, _rx1) = connect_channel(&mut chan_client, &channel, &token).await;
+    let (_tx2, mut rx2) = connect_channel(&mut chan_client, &channel, &token2).await;
+    let (_tx3, mut rx3) = connect_channel(&mut chan_client, &channel, &token3).await;
+    let reader = tokio::spawn(async move { next_voice(&mut rx3).await });
+
+    // listener doesn't read while much audio and some text are sent
+    let count = 500;
+    for i in 0..count {
+        tx1.send(Message {
+            content: Some(Content::AudioData(vec![0; 16384])),
+            codec: Codec::Opus.into(),
+            seq: i,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        if i % 100 == 99 {
+            tx1.send(Message {
+                content: Some(Content::Text(format!("text {}", i / 100))),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        }
+    }
+    // a reading listener is told the speaker's voice state at once
+    let mute = VoiceState {
+        self_mute: true,
+        ..Default::default()
+    };
+    tx1.send(Message {
+        content: Some(Content::Voice(mute)),
+        ..Default::default()
+    })
+    .await
+    .unwrap();
+    let (user_id, state) = timeout(Duration::from_secs(5), reader)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!((user_id.as_str(), state), ("test", mute));
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    // still connected, gets all text, and part of audio
+    let (mut audio, mut texts) = (0, vec![]);
+    while texts.len() < 5 {
+        let msg = timeout(Duration::from_secs(5), next_message(&mut rx2))
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        match msg.content {
+            Some(Content::AudioData(_)) => audio += 1,
+            Some(Content::Text(t)) => texts.push(t),
+            // the speaker's mute, ahead of the audio
+            Some(Content::Voice(_)) => {}
+            other => panic!("unexpected message: {:?}", other),
+        }
+    }
+    let expected: Vec<_> = (0..5).map(|i| format!("text {}", i)).collect();
+    assert_eq!(texts, expected);
+    assert!(audio < count, "audio: {}", audio);
+
+    // drops of the listener are reported
+    let mut dropped = None;
+    for _ in 0..10 {
+        let servers = chan_client
+            .list(Request::new(Channel::default()).with(&token))
+            .await
+            .unwrap()
+            .into_inner()
+            .servers;
+        dropped = servers.first().and_then(|server| {
+            let key = format!("dropped.{}.test_2", channel.id);
+            server.metric.as_ref()?.kv.get(&key).cloned()
+        });
+        if dropped.is_some() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+    let dropped: u32 = dropped.unwrap().parse().unwrap();
+    assert!(dropped > 0);
+
+    handle.abort();
+    join_handle.abort();
+    drop(tdb);
+}
+
+// a user not reading its stream doesn't block texts to others, it's disconnected instead.
+#[tokio::test]
+async fn test_stalled_listener() {
+    let (config, join_handle, tdb) = init_manager_server(52454).await;
+    let addr = config.server.url_with(false);
+    let conn = Endpoint::from_str(&addr).unwrap().connect().await.unwrap();
+    let token = register_login("test", conn.clone()).await;
+    let token2 = register_login("test_2", conn.clone()).await;
+    let token3 = register_login("test_3", conn.clone()).await;
+    let mut chan_client = ChannelServiceClient::new(conn);
+    let channel = chan_client
+        .create(
+            Request::new(Channel {
+                name: "stalled".to_string(),
+                limit: 10,
+                ..Default::default()
+            })
+            .with(&token),
+        )
+        .await
+        .unwrap()
+        .into_inner();
+    let (_, handle) = init_chat_server(52455, &tdb, &addr).await;
+
+    let (tx1, _rx1) = connect_channel(&mut chan_client, &channel, &token).await;
+    let (_tx2, mut rx2) = connect_channel(&mut chan_client, &channel, &token2).await;
+    let (_tx3, mut rx3) = connect_channel(&mut chan_client, &channel, &token3).await;
+
+    // big texts fill the transport of the stalled user, then its queue
+    let count = 1000;
+    let reader = tokio::spawn(async move {
+        let mut texts = vec![];
+        while texts.len() < count {
+            match next_message(&mut rx2).await.unwrap().unwrap().content {
+                Some(Content::Text(text)) => texts.push(text),
+                Some(Content::Voice(_)) => {}
+                other => panic!("unexpected message: {:?}", other),
+            }
+        }
+        texts
+    });
+    let text = |i: usize| format!("{} {}", i, "x".repeat(32 * 1024));
+    for i in 0..count {
+        tx1.send(Message {
+            content: Some(Content::Text(text(i))),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    }
+
+    // the reading user gets all texts in order
+    let texts = timeout(Duration::from_secs(60), reader)
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(texts.iter().enumerate().all(|(i, t)| *t == text(i)));
+
+    // the stalled user's stream ends after what it's queued
+    let drained = async { while let Ok(Some(_)) = rx3.message().await {} };
+    assert!(timeout(Duration::from_secs(10), drained).await.is_ok());
+
+    handle.abort();
+    join_handle.abort();
+    drop(tdb);
+}
+
+// a speaker doesn't get its own audio back, and text only when echo is asked,
+// which comes with the server's timestamp.
+#[tokio::test]
+async fn test_no_echo() {
+    let (config, join_handle, tdb) = init_manager_server(51154).await;
+    let addr = config.server.url_with(false);
+    let conn = Endpoint::from_str(&addr).unwrap().connect().await.unwrap();
+    let token = register_login("test", conn.clone()).await;
+    let token2 = register_login("test_2", conn.clone()).await;
+    let mut chan_client = ChannelServiceClient::new(conn);
+    let channel = chan_client
+        .create(
+            Request::new(Channel {
+                name: "echo".to_string(),
+                limit: 10,
+                ..Default::default()
+            })
+            .with(&token),
+        )
+        .await
+        .unwrap()
+        .into_inner();
+    let (_, handle) = init_chat_server(51155, &tdb, &addr).await;
+
+    let (tx1, mut rx1) = connect_channel(&mut chan_client, &channel, &token).await;
+    let (_tx2, mut rx2) = connect_channel(&mut chan_client, &channel, &token2).await;
+
+    let messages = [
+        Message {
+            content: Some(Content::AudioData(vec![1; 8])),
+            codec: Codec::Opus.into(),
+            ..Default::default()
+        },
+        Message {
+            content: Some(Content::Text("no echo".into())),
+            ..Default::default()
+        },
+        Message {
+            content: Some(Content::Text("echo".into())),
+            echo: true,
+            ..Default::default()
+        },
+    ];
+    let start = chrono::Utc::now().timestamp_millis();
+    for msg in messages.iter().cloned() {
+        tx1.send(msg).await.unwrap();
+    }
+
+    // the other user gets all, text and audio are sent on their own ways
+    let mut received = vec![];
+    for _ in 0..messages.len() {
+        let msg = timeout(Duration::from_secs(5), next_message(&mut rx2))
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        received.push(msg.content);
+    }
+    for msg in messages.iter() {
+        assert!(received.contains(&msg.content), "missing {:?}", msg);
+    }
+
+    // the speaker gets only the acknowledgement
+    let msg = timeout(Duration::from_secs(5), next_message(&mut rx1))
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    assert_eq!(msg.content, Some(Content::Text("echo".into())));
+    assert!(msg.echo);
+    assert_eq!(msg.user_id, "test");
+    assert!(msg.timestamp >= start, "timestamp: {}", msg.timestamp);
+    assert!(timeout(Duration::from_millis(500), next_message(&mut rx1))
+        .await
+        .is_err());
+
+    handle.abort();
+    join_handle.abort();
+    drop(tdb);
+}
+
+// text is stored by chat server, and paged through from manager by cursor, newest first.
+#[tokio::test]
+async fn test_history() {
+    let (config, join_handle, tdb) = init_manager_server(51254).await;
+    let addr = config.server.url_with(false);
+    let conn = Endpoint::from_str(&addr).unwrap().connect().await.unwrap();
+    let token = register_login("test", conn.clone()).await;
+    let mut chan_client = ChannelServiceClient::new(conn);
+    let channel = chan_client
+        .create(
+            Request::new(Channel {
+                name: "history".to_string(),
+                limit: 10,
+                ..Default::default()
+            })
+            .with(&token),
+        )
+        .await
+        .unwrap()
+        .into_inner();
+    let (_, handle) = init_chat_server(51255, &tdb, &addr).await;
+
+    // texts are stored before echoed, audio is not stored
+    let (tx, mut rx) = connect_channel(&mut chan_client, &channel, &token).await;
+    tx.send(Message {
+        content: Some(Content::AudioData(vec![1; 8])),
+        codec: Codec::Opus.into(),
+        ..Default::default()
+    })
+    .await
+    .unwrap();
+    let texts: Vec<_> = (0..5).map(|i| format!("text {}", i)).collect();
+    let expected: Vec<_> = texts
+        .iter()
+        .map(|text| Message {
+            content: Some(Content::Text(text.clone())),
+            echo: true,
+            ..Default::default()
+        })
+        .collect();
+    for msg in expected.iter() {
+        tx.send(msg.clone()).await.unwrap();
+    }
+    check_inbound(&mut rx, &expected, Duration::from_secs(5))
+        .await
+        .unwrap();
+
+    // pages of 2, newest first
+    let mut pages = vec![];
+    let mut cursor = 0;
+    loop {
+        let rsp = chan_client
+            .history(
+                Request::new(HistoryRequest {
+                    channel_id: channel.id,
+                    cursor,
+                    limit: 2,
+                })
+                .with(&token),
+            )
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(rsp.messages.iter().all(|m| m.user_id == "test"));
+        assert!(rsp.messages.iter().all(|m| m.timestamp > 0));
+        let page: Vec<_> = rsp
+            .messages
+            .into_iter()
+            .map(|m| match m.content {
+                Some(Content::Text(text)) => text,
+                other => panic!("unexpected content: {:?}", other),
+            })
+            .collect();
+        pages.push(page);
+        if rsp.next_cursor == 0 {
+            break;
+        }
+        cursor = rsp.next_cursor;
+    }
+    assert_eq!(
+        pages,
+        vec![
+            vec!["text 4", "text 3"],
+            vec!["text 2", "text 1"],
+            vec!["text 0"],
+        ]
+    );
+
+    // default limit takes all
+    let rsp = chan_client
+        .history(
+            Request::new(HistoryRequest {
+                channel_id: channel.id,
+                ..Default::default()
+            })
+            .with(&token),
+        )
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(rsp.messages.len(), texts.len());
+    assert_eq!(rsp.next_cursor, 0);
+
+    // bad requests
+    for (req, code) in [
+        (
+            HistoryRequest {
+                channel_id: channel.id,
+                limit: -1,
+                ..Default::default()
+            },
+            tonic::Code::InvalidArgument,
+        ),
+        (
+            HistoryRequest {
+                channel_id: channel.id,
+                cursor: -1,
+                ..Default::default()
+            },
+            tonic::Code::InvalidArgument,
+        ),
+        (
+            HistoryRequest {
+                channel_id: channel.id + 1,
+                ..Default::default()
+            },
+            tonic::Code::NotFound,
+        ),
+    ] {
+        let status = chan_client
+            .history(Request::new(req).with(&token))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), code);
+    }
+
+    handle.abort();
+    join_handle.abort();
+    drop(tdb);
+}
+
+// every kind of token is refused by RPCs expecting another kind, or for another audience.
+#[tokio::test]
+async fn test_token_kinds() {
+    let (config, join_handle, tdb) = init_manager_server(51554).await;
+    let keys = KeySet::new(&config.server).unwrap();
+    let addr = config.server.url_with(false);
+    let conn = Endpoint::from_str(&addr).unwrap().connect().await.unwrap();
+    let access = register_login("test", conn.clone()).await;
+    let mut chan_client = ChannelServiceClient::new(conn);
+    let channel = chan_client
+        .create(
+            Request::new(Channel {
+                name: "tokens".to_string(),
+                limit: 10,
+                ..Default::default()
+            })
+            .with(&access),
+        )
+        .await
+        .unwrap()
+        .into_inner();
+    let (chat_config, handle) = init_chat_server(51555, &tdb, &addr).await;
+    let chat_addr = chat_config.server.url_with(false);
+    let chat_conn = Endpoint::from_str(&chat_addr)
+        .unwrap()
+        .connect()
+        .await
+        .unwrap();
+    let mut chat_client = ChatServiceClient::new(chat_conn);
+
+    let exp = chrono::Utc::now().timestamp() + 60;
+    let listen = chan_client
+        .listen(Request::new(channel.clone()).with(&access))
+        .await
+        .unwrap()
+        .into_inner()
+        .token;
+    let report = encrypt(&keys, &ServerClaims::new(MANAGER_AUDIENCE, &chat_addr, exp));
+    let push = encrypt(&keys, &ServerClaims::new(&chat_addr, &chat_addr, exp));
+    let other_listen = encrypt(
+        &keys,
+        &ListenClaims::new("test", channel.id, "http://127.0.0.1:51556", exp),
+    );
+
+    // user-facing RPCs on manager take only access tokens
+    for token in [&listen, &report, &push, "", "not-a-jwt"] {
+        let status = chan_client
+            .list(Request::new(Channel::default()).with(token))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::Unauthenticated, "{}", status.message());
+        let status = chan_client
+            .listen(Request::new(channel.clone()).with(token))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::Unauthenticated, "{}", status.message());
+    }
+
+    // reports take only server tokens for manager
+    for token in [&access, &listen, &push] {
+        let stream = tokio_stream::iter(Vec::<ReportRequest>::new());
+        let status = chan_client
+            .report(Request::new(stream).with(token))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::Unauthenticated, "{}", status.message());
+    }
+
+    // channels are pushed to chat server only by server tokens for it
+    for token in [&access, &listen, &report] {
+        let stream = tokio_stream::iter(vec![Channel {
+            id: channel.id + 1,
+            limit: 10,
+            ..Default::default()
+        }]);
+        let status = chat_client
+            .add(Request::new(stream).with(token))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::Unauthenticated, "{}", status.message());
+        let stream = tokio_stream::iter(vec![channel.clone()]);
+        let status = chat_client
+            .remove(Request::new(stream).with(token))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::Unauthenticated, "{}", status.message());
+    }
+
+    // users join only by listen tokens for this chat server
+    for token in [&access, &report, &push, &other_listen] {
+        let stream = tokio_stream::iter(Vec::<Message>::new());
+        let status = chat_client
+            .conn(Request::new(stream).with(token))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::Unauthenticated, "{}", status.message());
+    }
+    let (tx, rx) = tokio::sync::mpsc::channel::<Message>(1);
+    let stream = tokio_stream::wrappers::ReceiverStream::new(rx);
+    chat_client
+        .conn(Request::new(stream).with(&listen))
+        .await
+        .unwrap();
+    drop(tx);
+
+    handle.abort();
+    join_handle.abort();
+    drop(tdb);
+}
+
+// users' tokens signed by manager's private key, chat server verifies them by public keys only.
+#[tokio::test]
+async fn test_key_pair_tokens() {
+    let key = |kid: &str, alg, path: &str| KeyConfig {
+        kid: kid.to_string(),
+        alg,
+        path: format!("tests/keys/{}", path),
+    };
+    // rotating from the RSA key to the Ed25519 one
+    let public_keys = vec![
+        key("old", KeyAlgorithm::RS256, "rsa.pub.pem"),
+        key("new", KeyAlgorithm::EdDSA, "ed25519.pub.pem"),
+    ];
+    let jwt = JwtConfig {
+        signing_key: Some(key("new", KeyAlgorithm::EdDSA, "ed25519.pem")),
+        verifying_keys: public_keys.clone(),
+    };
+    let (config, join_handle, tdb) =
+        init_manager_server_with(51654, |config| config.server.jwt = Some(jwt)).await;
+    let addr = config.server.url_with(false);
+    let conn = Endpoint::from_str(&addr).unwrap().connect().await.unwrap();
+    let token = register_login("test", conn.clone()).await;
+    let mut chan_client = ChannelServiceClient::new(conn.clone());
+    let (chat_config, handle) = init_chat_server_with(51655, &tdb, &addr, |config| {
+        config.server.jwt = Some(JwtConfig {
+            signing_key: None,
+            verifying_keys: public_keys,
+        })
+    })
+    .await;
+    let chat_addr = chat_config.server.url_with(false);
+
+    let channel = chan_client
+        .create(
+            Request::new(Channel {
+                name: "key_pair".to_string(),
+                limit: 10,
+                ..Default::default()
+            })
+            .with(&token),
+        )
+        .await
+        .unwrap()
+        .into_inner();
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    // users chat by tokens signed by the private key
+    let mut senders = vec![];
+    let mut receivers = vec![];
+    for i in 1..3 {
+        let token = register_login(&format!("test_{}", i), conn.clone()).await;
+        let (tx, inbound) = connect_channel(&mut chan_client, &channel, &token).await;
+        senders.push(tx);
+        receivers.push(inbound);
+    }
+    let expected = vec![Message {
+        content: Some(Content::Text("signed".into())),
+        echo: true,
+        ..Default::default()
+    }];
+    senders[0].send(expected[0].clone()).await.unwrap();
+    for stream in receivers.iter_mut() {
+        check_inbound(stream, &expected, Duration::from_secs(10))
+            .await
+            .unwrap();
+    }
+
+    let exp = chrono::Utc::now().timestamp() + 60;
+    // tokens of the old key are still accepted while rotating
+    let mut old_config = config.server.clone();
+    old_config.jwt = Some(JwtConfig {
+        signing_key: Some(key("old", KeyAlgorithm::RS256, "rsa.pem")),
+        verifying_keys: vec![key("old", KeyAlgorithm::RS256, "rsa.pub.pem")],
+    });
+    let old = encrypt(
+        &KeySet::new(&old_config).unwrap(),
+        &AccessClaims::new("test", 0, exp),
+    );
+    chan_client
+        .list(Request::new(Channel::default()).with(&old))
+        .await
+        .unwrap();
+
+    // users' tokens forged by the shared secret are refused
+    let secret = KeySet::from_secret(&config.server.secret);
+    let access = encrypt(&secret, &AccessClaims::new("test", 0, exp));
+    let status = chan_client
+        .list(Request::new(Channel::default()).with(&access))
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::Unauthenticated, "{}", status.message());
+    let listen = encrypt(
+        &secret,
+        &ListenClaims::new("test", channel.id, &chat_addr, exp),
+    );
+    let chat_conn = Endpoint::from_str(&chat_addr)
+        .unwrap()
+        .connect()
+        .await
+        .unwrap();
+    let stream = tokio_stream::iter(Vec::<Message>::new());
+    let status = ChatServiceClient::new(chat_conn)
+        .conn(Request::new(stream).with(&listen))
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::Unauthenticated, "{}", status.message());
+
+    handle.abort();
+    join_handle.abort();
+    drop(tdb);
+}
+
+// owner grants moderators, moderators kick, ban and unban members, banned users can't join
+// nor read history.
+#[tokio::test]
+async fn test_roles() {
+    let (config, join_handle, tdb) = init_manager_server(51854).await;
+    let addr = config.server.url_with(false);
+    let conn = Endpoint::from_str(&addr).unwrap().connect().await.unwrap();
+    let owner = register_login("owner", conn.clone()).await;
+    let moderator = register_login("moderator", conn.clone()).await;
+    let member = register_login("member", conn.clone()).await;
+    let mut chan_client = ChannelServiceClient::new(conn);
+    let channel = chan_client
+        .create(
+            Request::new(Channel {
+                name: "roles".to_string(),
+                limit: 10,
+                ..Default::default()
+            })
+            .with(&owner),
+        )
+        .await
+        .unwrap()
+        .into_inner();
+    let (_, handle) = init_chat_server(51855, &tdb, &addr).await;
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    let req = |user_id: &str| MemberRequest {
+        channel_id: channel.id,
+        user_id: user_id.to_string(),
+    };
+    let code = |rsp: Result<tonic::Response<()>, Status>| rsp.unwrap_err().code();
+    // the connection is closed by the chat server
+    async fn closed(inbound: &mut Streaming<Message>) -> bool {
+        matches!(
+            timeout(Duration::from_secs(5), next_message(inbound)).await,
+            Ok(Ok(None)) | Ok(Err(_))
+        )
+    }
+
+    // members moderate nobody, only the owner grants
+    let rsp = chan_client
+        .kick(Request::new(req("owner")).with(&member))
+        .await;
+    assert_eq!(code(rsp), Code::PermissionDenied);
+    let rsp = chan_client
+        .grant(Request::new(req("member")).with(&moderator))
+        .await;
+    assert_eq!(code(rsp), Code::PermissionDenied);
+    let rsp = chan_client
+        .grant(Request::new(req("owner")).with(&owner))
+        .await;
+    assert_eq!(code(rsp), Code::InvalidArgument);
+    let rsp = chan_client
+        .kick(Request::new(req("nobody")).with(&owner))
+        .await;
+    assert_eq!(code(rsp), Code::NotFound);
+    chan_client
+        .grant(Request::new(req("moderator")).with(&owner))
+        .await
+        .unwrap();
+
+    // kicked, then joins again
+    let (_tx, mut inbound) = connect_channel(&mut chan_client, &channel, &member).await;
+    chan_client
+        .kick(Request::new(req("member")).with(&moderator))
+        .await
+        .unwrap();
+    assert!(closed(&mut inbound).await);
+    tokio::time::sleep(Duration::from_millis(1100)).await; // listen interval
+    let (_tx, mut inbound) = connect_channel(&mut chan_client, &channel, &member).await;
+
+    // banned, a listen token issued before can't be used either
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    let listen = chan_client
+        .listen(Request::new(channel.clone()).with(&member))
+        .await
+        .unwrap()
+        .into_inner();
+    let rsp = chan_client
+        .ban(Request::new(req("owner")).with(&moderator))
+        .await;
+    assert_eq!(code(rsp), Code::PermissionDenied);
+    chan_client
+        .ban(Request::new(req("member")).with(&moderator))
+        .await
+        .unwrap();
+    assert!(closed(&mut inbound).await);
+    let mut chat_client = ChatServiceClient::new(
+        Endpoint::from_str(&listen.server.unwrap().addr)
+            .unwrap()
+            .connect()
+            .await
+            .unwrap(),
+    );
+    let stream = tokio_stream::iter(Vec::<Message>::new());
+    let status = chat_client
+        .conn(Request::new(stream).with(&listen.token))
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::PermissionDenied);
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    let status = chan_client
+        .listen(Request::new(channel.clone()).with(&member))
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::PermissionDenied);
+    // nor read the channel's history
+    let history = || HistoryRequest {
+        channel_id: channel.id,
+        ..Default::default()
+    };
+    let status = chan_client
+        .history(Request::new(history()).with(&member))
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::PermissionDenied);
+
+    // a revoked moderator can't unban, the owner can
+    chan_client
+        .revoke(Request::new(req("moderator")).with(&owner))
+        .await
+        .unwrap();
+    let rsp = chan_client
+        .unban(Request::new(req("member")).with(&moderator))
+        .await;
+    assert_eq!(code(rsp), Code::PermissionDenied);
+    chan_client
+        .unban(Request::new(req("member")).with(&owner))
+        .await
+        .unwrap();
+    let rsp = chan_client
+        .unban(Request::new(req("member")).with(&owner))
+        .await;
+    assert_eq!(code(rsp), Code::InvalidArgument);
+    chan_client
+        .history(Request::new(history()).with(&member))
+        .await
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    connect_channel(&mut chan_client, &channel, &member).await;
+
+    handle.abort();
+    join_handle.abort();
+    drop(tdb);
+}
+
+// voice state of a user, waiting over other messages.
+async fn next_voice(inbound: &mut Streaming<Message>) -> (String, VoiceState) {
+    loop {
+        let msg = timeout(Duration::from_secs(5), inbound.message())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        if let Some(Content::Voice(state)) = msg.content {
+            return (msg.user_id, state);
+        }
+    }
+}
+
+// voice state of `user_id`, broadcast to every user.
+async fn broadcast_voice(inbounds: &mut [Streaming<Message>], user_id: &str) -> VoiceState {
+    let mut states = vec![];
+    for inbound in inbounds.iter_mut() {
+        let (id, state) = next_voice(inbound).await;
+        assert_eq!(id, user_id);
+        states.push(state);
+    }
+    assert!(states.windows(2).all(|pair| pair[0] == pair[1]));
+    states[0]
+}
+
+// whether some audio arrives in a while.
+async fn hears(inbound: &mut Streaming<Message>) -> bool {
+    let wait = async {
+        while let Ok(Some(msg)) = inbound.message().await {
+            if let Some(Content::AudioData(_)) = msg.content {
+                return true;
+            }
+        }
+        false
+    };
+    timeout(Duration::from_millis(500), wait)
+        .await
+        .unwrap_or(false)
+}
+
+// muted users' audio goes nowhere, deafened users get none, moderators mute others.
+#[tokio::test]
+async fn test_voice_state() {
+    let (config, join_handle, tdb) = init_manager_server(51954).await;
+    let addr = config.server.url_with(false);
+    let conn = Endpoint::from_str(&addr).unwrap().connect().await.unwrap();
+    let owner = register_login("owner", conn.clone()).await;
+    let speaker = register_login("speaker", conn.clone()).await;
+    let listener = register_login("listener", conn.clone()).await;
+    let mut chan_client = ChannelServiceClient::new(conn);
+    let channel = chan_client
+        .create(
+            Request::new(Channel {
+                name: "voice".to_string(),
+                limit: 10,
+                ..Default::default()
+            })
+            .with(&owner),
+        )
+        .await
+        .unwrap()
+        .into_inner();
+    let (_, handle) = init_chat_server(51955, &tdb, &addr).await;
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    let (owner_tx, owner_rx) = connect_channel(&mut chan_client, &channel, &owner).await;
+    let (speaker_tx, speaker_rx) = connect_channel(&mut chan_client, &channel, &speaker).await;
+    let (listener_tx, listener_rx) = connect_channel(&mut chan_client, &channel, &listener).await;
+    let mut rxs = [owner_rx, speaker_rx, listener_rx];
+    let audio = Message {
+        content: Some(Content::AudioData(vec![1; 8])),
+        codec: Codec::Opus.into(),
+        ..Default::default()
+    };
+    let voice = |self_mute, self_deaf| Message {
+        content: Some(Content::Voice(VoiceState {
+            self_mute,
+            self_deaf,
+            server_mute: false,
+        })),
+        ..Default::default()
+    };
+    let server_mute = |mute| Message {
+        content: Some(Content::ServerMute(ServerMute {
+            user_id: "speaker".to_string(),
+            mute,
+        })),
+        ..Default::default()
+    };
+
+    // self-mute, broadcast to all
+    speaker_tx.send(voice(true, false)).await.unwrap();
+    let state = broadcast_voice(&mut rxs, "speaker").await;
+    assert!(state.self_mute && state.muted());
+    speaker_tx.send(audio.clone()).await.unwrap();
+    assert!(!hears(&mut rxs[2]).await);
+    speaker_tx.send(voice(false, false)).await.unwrap();
+    assert!(!broadcast_voice(&mut rxs, "speaker").await.muted());
+    speaker_tx.send(audio.clone()).await.unwrap();
+    assert!(hears(&mut rxs[2]).await);
+
+    // deafened, while others still hear
+    listener_tx.send(voice(false, true)).await.unwrap();
+    assert!(broadcast_voice(&mut rxs, "listener").await.deafened());
+    speaker_tx.send(audio.clone()).await.unwrap();
+    assert!(!hears(&mut rxs[2]).await);
+    assert!(hears(&mut rxs[0]).await);
+
+    // a member can't server-mute, the owner can, and self-unmuting keeps it
+    listener_tx.send(server_mute(true)).await.unwrap();
+    speaker_tx.send(audio.clone()).await.unwrap();
+    assert!(hears(&mut rxs[0]).await);
+    owner_tx.send(server_mute(true)).await.unwrap();
+    let state = broadcast_voice(&mut rxs, "speaker").await;
+    assert!(state.server_mute && !state.self_mute);
+    speaker_tx.send(voice(false, false)).await.unwrap();
+    assert!(broadcast_voice(&mut rxs, "speaker").await.server_mute);
+    speaker_tx.send(audio.clone()).await.unwrap();
+    assert!(!hears(&mut rxs[0]).await);
+
+    // states reported to manager
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    let rsp = chan_client
+        .list(Request::new(channel.clone()).with(&owner))
+        .await
+        .unwrap()
+        .into_inner();
+    let states: HashMap<_, _> = rsp.channels[0]
+        .users
+        .iter()
+        .map(|user| (user.id.as_str(), user.voice.unwrap()))
+        .collect();
+    assert_eq!(states.len(), 3);
+    assert!(states["speaker"].server_mute);
+    assert!(states["listener"].self_deaf);
+    assert_eq!(states["owner"], VoiceState::default());
+
+    // server-mute is kept over reconnecting
+    drop(speaker_tx);
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    let (speaker_tx, speaker_rx) = connect_channel(&mut chan_client, &channel, &speaker).await;
+    rxs[1] = speaker_rx;
+    speaker_tx.send(audio.clone()).await.unwrap();
+    assert!(!hears(&mut rxs[0]).await);
+    owner_tx.send(server_mute(false)).await.unwrap();
+    assert!(!broadcast_voice(&mut rxs, "speaker").await.server_mute);
+    speaker_tx.send(audio).await.unwrap();
+    assert!(hears(&mut rxs[0]).await);
+
+    handle.abort();
+    join_handle.abort();
+    drop(tdb);
+}
+
+// next message on the stream, events included.
+async fn recv(inbound: &mut Streaming<Message>) -> Message {
+    timeout(Duration::from_secs(5), inbound.message())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap()
+}
+
+// users are told who is on the channel, who joins, leaves or talks.
+#[tokio::test]
+async fn test_presence() {
+    let (config, join_handle, tdb) = init_manager_server(52054).await;
+    let addr = config.server.url_with(false);
+    let conn = Endpoint::from_str(&addr).unwrap().connect().await.unwrap();
+    let owner = register_login("owner", conn.clone()).await;
+    let member = register_login("member", conn.clone()).await;
+    let mut chan_client = ChannelServiceClient::new(conn);
+    let channel = chan_client
+        .create(
+            Request::new(Channel {
+                name: "presence".to_string(),
+                limit: 10,
+                ..Default::default()
+            })
+            .with(&owner),
+        )
+        .await
+        .unwrap()
+        .into_inner();
+    let (_, handle) = init_chat_server(52055, &tdb, &addr).await;
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    // the roster comes first, with the joining user
+    let (owner_tx, mut owner_rx) = connect_channel(&mut chan_client, &channel, &owner).await;
+    let msg = recv(&mut owner_rx).await;
+    let Some(Content::Roster(roster)) = msg.content else {
+        panic!("not a roster: {:?}", msg);
+    };
+    let ids: Vec<_> = roster.users.iter().map(|user| user.id.as_str()).collect();
+    assert_eq!(ids, ["owner"]);
+
+    let (member_tx, mut member_rx) = connect_channel(&mut chan_client, &channel, &member).await;
+    let msg = recv(&mut member_rx).await;
+    let Some(Content::Roster(roster)) = msg.content else {
+        panic!("not a roster: {:?}", msg);
+    };
+    let ids: HashSet<_> = roster.users.iter().map(|user| user.id.as_str()).collect();
+    assert_eq!(ids, HashSet::from(["owner", "member"]));
+    let msg = recv(&mut owner_rx).await;
+    let Some(Content::Join(user)) = msg.content else {
+        panic!("not a join: {:?}", msg);
+    };
+    assert_eq!(
+        (msg.user_id.as_str(), user.id.as_str()),
+        ("member", "member")
+    );
+    assert_eq!(user.voice, Some(VoiceState::default()));
+
+    // events can't be sent by users
+    let spoofed = [
+        Content::Leave(()),
+        Content::Roster(Roster::default()),
+        Content::Join(User {
+            id: "ghost".to_string(),
+            ..Default::default()
+        }),
+        Content::Speaking(true),
+    ];
+    for content in spoofed {
+        owner_tx
+            .send(Message {
+                content: Some(content),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+    }
+    owner_tx
+        .send(Message {
+            content: Some(Content::Text("still here".into())),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    let msg = recv(&mut member_rx).await;
+    assert_eq!(msg.content, Some(Content::Text("still here".into())));
+
+    // talking starts with audio, and stops after a while without
+    for seq in 0..3 {
+        member_tx
+            .send(Message {
+                content: Some(Content::AudioData(vec![1; 8])),
+                codec: Codec::Opus.into(),
+                seq,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+    }
+    let mut speaking = vec![];
+    while speaking.len() < 2 {
+        let msg = recv(&mut owner_rx).await;
+        if let Some(Content::Speaking(talking)) = msg.content {
+            assert_eq!(msg.user_id, "member");
+            speaking.push(talking);
+        }
+    }
+    assert_eq!(speaking, [true, false]);
+    // the speaker knows by itself
+    assert!(timeout(Duration::from_millis(200), member_rx.message())
+        .await
+        .is_err());
+
+    // left by disconnecting, or by being kicked
+    drop((member_tx, member_rx));
+    let msg = recv(&mut owner_rx).await;
+    assert_eq!(msg.user_id, "member");
+    assert_eq!(msg.content, Some(Content::Leave(())));
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    let (_member_tx, _member_rx) = connect_channel(&mut chan_client, &channel, &member).await;
+    let msg = recv(&mut owner_rx).await;
+    assert!(matches!(msg.content, Some(Content::Join(_))));
+    chan_client
+        .kick(
+            Request::new(MemberRequest {
+                channel_id: channel.id,
+                user_id: "member".to_string(),
+            })
+            .with(&owner),
+        )
+        .await
+        .unwrap();
+    let msg = recv(&mut owner_rx).await;
+    assert_eq!(msg.user_id, "member");
+    assert_eq!(msg.content, Some(Content::Leave(())));
+
+    handle.abort();
+    join_handle.abort();
+    drop(tdb);
+}
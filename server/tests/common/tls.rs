@@ -0,0 +1,61 @@
+use echo_server::config::TlsConfig;
+use rcgen::{BasicConstraints, CertificateParams, DnType, IsCa, KeyPair};
+use tempfile::TempDir;
+
+/// A throwaway CA, with certificates it issues written into a temporary dir.
+#[allow(dead_code)]
+pub struct TestCa {
+    dir: TempDir,
+    cert: rcgen::Certificate,
+    key: KeyPair,
+}
+
+#[allow(dead_code)]
+impl TestCa {
+    pub fn new() -> Self {
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params
+            .distinguished_name
+            .push(DnType::CommonName, "echo test ca");
+        let cert = params.self_signed(&key).unwrap();
+        let ca = Self {
+            dir: tempfile::tempdir().unwrap(),
+            cert,
+            key,
+        };
+        ca.write("ca.pem", &ca.cert.pem());
+        ca
+    }
+
+    /// Root certificate in PEM, for clients to trust.
+    pub fn pem(&self) -> String {
+        self.cert.pem()
+    }
+
+    /// Issue a certificate for local addrs, and the TLS config of a server holding it.
+    pub fn issue(&self, name: &str) -> TlsConfig {
+        let key = KeyPair::generate().unwrap();
+        let names = ["localhost", "127.0.0.1", "0.0.0.0"].map(String::from);
+        let mut params = CertificateParams::new(names).unwrap();
+        params.distinguished_name.push(DnType::CommonName, name);
+        let cert = params.signed_by(&key, &self.cert, &self.key).unwrap();
+        TlsConfig {
+            cert: self.write(&format!("{}.pem", name), &cert.pem()),
+            key: self.write(&format!("{}.key", name), &key.serialize_pem()),
+            ca: self
+                .dir
+                .path()
+                .join("ca.pem")
+                .to_string_lossy()
+                .into_owned(),
+        }
+    }
+
+    fn write(&self, file: &str, pem: &str) -> String {
+        let path = self.dir.path().join(file);
+        std::fs::write(&path, pem).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+}
@@ -0,0 +1,200 @@
+use abi::pb::channel_service_client::ChannelServiceClient;
+use abi::pb::chat_service_client::ChatServiceClient;
+use abi::pb::message::Content;
+use abi::pb::user_service_client::UserServiceClient;
+use abi::pb::{Channel, Message, RegisterRequest, ReportRequest};
+use abi::traits::WithToken;
+use echo_server::auth::interceptor::{encrypt, ServerClaims, MANAGER_AUDIENCE};
+use echo_server::auth::keys::KeySet;
+use echo_server::config::TlsConfig;
+use std::str::FromStr;
+use tokio::time::{timeout, Duration};
+use tonic::transport::{Certificate, ClientTlsConfig, Endpoint, Identity};
+use tonic::{Code, Request, Streaming};
+mod common;
+use common::server::*;
+use common::tls::TestCa;
+
+// connect as a user trusting `ca`, or as a server holding `identity` too.
+async fn connect(
+    addr: &str,
+    ca: &TestCa,
+    identity: Option<&TlsConfig>,
+) -> Result<tonic::transport::Channel, tonic::transport::Error> {
+    let mut tls = ClientTlsConfig::new().ca_certificate(Certificate::from_pem(ca.pem()));
+    if let Some(conf) = identity {
+        let (cert, key) = (
+            std::fs::read(&conf.cert).unwrap(),
+            std::fs::read(&conf.key).unwrap(),
+        );
+        tls = tls.identity(Identity::from_pem(cert, key));
+    }
+    Endpoint::from_str(addr)?.tls_config(tls)?.connect().await
+}
+
+// listen to a channel by manager, then connect to its chat server by TLS.
+async fn connect_channel(
+    chan_client: &mut ChannelServiceClient<tonic::transport::Channel>,
+    channel: &Channel,
+    token: &str,
+    ca: &TestCa,
+) -> (tokio::sync::mpsc::Sender<Message>, Streaming<Message>) {
+    let rsp = chan_client
+        .listen(Request::new(channel.clone()).with(token))
+        .await
+        .unwrap()
+        .into_inner();
+    let chat_addr = rsp.server.unwrap().addr;
+    assert!(chat_addr.starts_with("https://"));
+
+    let mut chat_client = ChatServiceClient::new(connect(&chat_addr, ca, None).await.unwrap());
+    let (tx, rx) = tokio::sync::mpsc::channel(10);
+    let stream = tokio_stream::wrappers::ReceiverStream::new(rx);
+    let req = Request::new(stream).with(&rsp.token);
+    let inbound = chat_client.conn(req).await.unwrap().into_inner();
+    (tx, inbound)
+}
+
+// users chat by TLS, manager and chat server link by mutual TLS.
+#[tokio::test]
+async fn test_tls_chat() {
+    let ca = TestCa::new();
+    let (manager_tls, chat_tls) = (ca.issue("manager"), ca.issue("chat"));
+    let (config, join_handle, tdb) =
+        init_manager_server_with(51754, |config| config.server.tls = Some(manager_tls)).await;
+    let addr = config.server.addr();
+    assert!(addr.starts_with("https://"));
+    let (_, handle) = init_chat_server_with(51755, &tdb, &addr, |config| {
+        config.server.tls = Some(chat_tls)
+    })
+    .await;
+
+    let conn = connect(&addr, &ca, None).await.unwrap();
+    let token = register_login("test", conn.clone()).await;
+    let mut chan_client = ChannelServiceClient::new(conn.clone());
+    let channel = chan_client
+        .create(
+            Request::new(Channel {
+                name: "tls".to_string(),
+                limit: 10,
+                ..Default::default()
+            })
+            .with(&token),
+        )
+        .await
+        .unwrap()
+        .into_inner();
+    // pushed to chat server by mutual TLS
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    let mut senders = vec![];
+    let mut receivers = vec![];
+    for i in 1..3 {
+        let token = register_login(&format!("test_{}", i), conn.clone()).await;
+        let (tx, inbound) = connect_channel(&mut chan_client, &channel, &token, &ca).await;
+        senders.push(tx);
+        receivers.push(inbound);
+    }
+    let text = Message {
+        content: Some(Content::Text("over tls".into())),
+        echo: true,
+        ..Default::default()
+    };
+    senders[0].send(text.clone()).await.unwrap();
+    for stream in receivers.iter_mut() {
+        let msg = timeout(Duration::from_secs(10), next_message(stream))
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(msg.content, text.content);
+    }
+
+    handle.abort();
+    join_handle.abort();
+    drop(tdb);
+}
+
+// plaintext, or servers of an unknown CA, are refused by both ends.
+#[tokio::test]
+async fn test_tls_refused() {
+    let ca = TestCa::new();
+    let manager_tls = ca.issue("manager");
+    let (config, join_handle, tdb) =
+        init_manager_server_with(51756, |config| config.server.tls = Some(manager_tls)).await;
+    let addr = config.server.addr();
+    let register = |conn| async move {
+        UserServiceClient::new(conn)
+            .register(RegisterRequest {
+                user_id: "test".to_string(),
+                password: "test_password".to_string(),
+                name: "test_name".to_string(),
+            })
+            .await
+    };
+
+    // plaintext to a TLS listener
+    let plain = Endpoint::from_str(&config.server.url_with(false))
+        .unwrap()
+        .connect_lazy();
+    assert!(register(plain).await.is_err());
+    // a user not trusting the CA
+    let other = TestCa::new();
+    assert!(connect(&addr, &other, None).await.is_err());
+    assert!(register(connect(&addr, &ca, None).await.unwrap())
+        .await
+        .is_ok());
+
+    // reports need a certificate of the CA, besides the server token
+    let chat_addr = "https://127.0.0.1:51757";
+    let token = encrypt(
+        &KeySet::from_secret(&config.server.secret),
+        &ServerClaims::new(
+            MANAGER_AUDIENCE,
+            chat_addr,
+            chrono::Utc::now().timestamp() + 60,
+        ),
+    );
+    let report = |conn| {
+        let token = token.clone();
+        async move {
+            let stream = tokio_stream::iter(Vec::<ReportRequest>::new());
+            ChannelServiceClient::new(conn)
+                .report(Request::new(stream).with(&token))
+                .await
+        }
+    };
+    let status = report(connect(&addr, &ca, None).await.unwrap())
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::Unauthenticated, "{}", status.message());
+    // a certificate of another CA fails the handshake
+    let foreign = other.issue("chat");
+    let refused = match connect(&addr, &ca, Some(&foreign)).await {
+        Ok(conn) => report(conn).await.is_err(),
+        Err(_) => true,
+    };
+    assert!(refused);
+    let chat = ca.issue("chat");
+    report(connect(&addr, &ca, Some(&chat)).await.unwrap())
+        .await
+        .unwrap();
+    // a certificate of the CA, but not of the addr in the token
+    let token = encrypt(
+        &KeySet::from_secret(&config.server.secret),
+        &ServerClaims::new(
+            MANAGER_AUDIENCE,
+            "https://10.0.0.1:51757",
+            chrono::Utc::now().timestamp() + 60,
+        ),
+    );
+    let stream = tokio_stream::iter(Vec::<ReportRequest>::new());
+    let status = ChannelServiceClient::new(connect(&addr, &ca, Some(&chat)).await.unwrap())
+        .report(Request::new(stream).with(&token))
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::Unauthenticated, "{}", status.message());
+
+    join_handle.abort();
+    drop(tdb);
+}
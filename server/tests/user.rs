@@ -1,12 +1,19 @@
 use abi::pb::{
     channel_service_client::ChannelServiceClient, user_service_client::UserServiceClient, Channel,
-    LoginRequest, RegisterRequest,
+    LoginRequest, LoginResponse, LogoutRequest, RefreshRequest, RegisterRequest,
 };
+use abi::traits::WithToken;
+use argon2::Argon2;
+use echo_server::auth::interceptor::{encrypt, AccessClaims};
+use echo_server::auth::keys::KeySet;
+use password_hash::{PasswordHasher, SaltString};
 use std::str::FromStr;
 use tonic::transport::Endpoint;
 use tonic::Request;
 mod common;
-use common::server::init_manager_server;
+use common::server::{init_manager_server, init_manager_server_with};
+use tonic::transport::Channel as Conn;
+use tonic::Code;
 
 #[tokio::test]
 async fn test_register_and_login() {
@@ -33,6 +40,19 @@ async fn test_register_and_login() {
         .await
         .unwrap();
 
+    // a missing user can't be told from a wrong password
+    for user_id in ["test", "missing"] {
+        let err = client
+            .login(LoginRequest {
+                user_id: user_id.to_string(),
+                password: "wrong_password".to_string(),
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), Code::InvalidArgument);
+        assert_eq!(err.message(), "Invalid password");
+    }
+
     join_handle.abort();
     drop(tdb);
 }
@@ -84,3 +104,227 @@ async fn test_interceptor() {
     join_handle.abort();
     drop(tdb);
 }
+
+// a user hashed with the old fixed salt logs in as before, and is re-hashed with its own salt.
+#[tokio::test]
+async fn test_legacy_hash_rehashed() {
+    let (config, join_handle, tdb) = init_manager_server(51354).await;
+    let pool = tdb.get_pool().await;
+    let legacy = Argon2::default()
+        .hash_password(
+            b"test_password",
+            &SaltString::from_b64("dGhpc2lzbXlzYWx0").unwrap(),
+        )
+        .unwrap()
+        .to_string();
+    sqlx::query("INSERT INTO chat.users (id, name, password_hash) VALUES ($1, $2, $3)")
+        .bind("test")
+        .bind("test_name")
+        .bind(&legacy)
+        .execute(&pool)
+        .await
+        .unwrap();
+    let stored = || async {
+        sqlx::query_scalar::<_, String>("SELECT password_hash FROM chat.users WHERE id = 'test'")
+            .fetch_one(&pool)
+            .await
+            .unwrap()
+    };
+
+    let mut client = UserServiceClient::connect(config.server.url_with(false))
+        .await
+        .unwrap();
+    let login = |password: &str| LoginRequest {
+        user_id: "test".to_string(),
+        password: password.to_string(),
+    };
+
+    // a wrong password doesn't touch the hash
+    let status = client.login(login("wrong")).await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::InvalidArgument);
+    assert_eq!(stored().await, legacy);
+
+    client.login(login("test_password")).await.unwrap();
+    let rehashed = stored().await;
+    assert_ne!(rehashed, legacy);
+    assert!(rehashed.starts_with("$argon2id$"));
+    assert!(!rehashed.contains("dGhpc2lzbXlzYWx0"));
+
+    // logs in by the new hash, which is kept
+    client.login(login("test_password")).await.unwrap();
+    assert_eq!(stored().await, rehashed);
+
+    join_handle.abort();
+    drop(tdb);
+}
+
+async fn register_login(client: &mut UserServiceClient<Conn>) -> LoginResponse {
+    client
+        .register(RegisterRequest {
+            user_id: "test".to_string(),
+            password: "test_password".to_string(),
+            name: "test_name".to_string(),
+        })
+        .await
+        .unwrap();
+    login(client).await
+}
+
+async fn login(client: &mut UserServiceClient<Conn>) -> LoginResponse {
+    client
+        .login(LoginRequest {
+            user_id: "test".to_string(),
+            password: "test_password".to_string(),
+        })
+        .await
+        .unwrap()
+        .into_inner()
+}
+
+async fn refresh(
+    client: &mut UserServiceClient<Conn>,
+    refresh_token: &str,
+) -> Result<LoginResponse, tonic::Status> {
+    client
+        .refresh(RefreshRequest {
+            refresh_token: refresh_token.to_string(),
+        })
+        .await
+        .map(|rsp| rsp.into_inner())
+}
+
+// code of listing channels with an access token
+async fn list(chan_client: &mut ChannelServiceClient<Conn>, token: &str) -> Code {
+    match chan_client
+        .list(Request::new(Channel::default()).with(token))
+        .await
+    {
+        Ok(_) => Code::Ok,
+        Err(status) => status.code(),
+    }
+}
+
+#[tokio::test]
+async fn test_refresh_rotation() {
+    let (config, join_handle, tdb) = init_manager_server(51454).await;
+    let conn = Endpoint::from_str(&config.server.url_with(false))
+        .unwrap()
+        .connect()
+        .await
+        .unwrap();
+    let mut client = UserServiceClient::new(conn.clone());
+    let mut chan_client = ChannelServiceClient::new(conn);
+
+    let first = register_login(&mut client).await;
+    assert!(!first.refresh_token.is_empty());
+    assert_eq!(list(&mut chan_client, &first.token).await, Code::Ok);
+
+    // refresh rotates the refresh token, both access tokens work
+    let second = refresh(&mut client, &first.refresh_token).await.unwrap();
+    assert_ne!(second.refresh_token, first.refresh_token);
+    assert_eq!(list(&mut chan_client, &second.token).await, Code::Ok);
+    assert_eq!(list(&mut chan_client, &first.token).await, Code::Ok);
+    let third = refresh(&mut client, &second.refresh_token).await.unwrap();
+
+    // reusing a rotated token revokes the family and all access tokens
+    let status = refresh(&mut client, &first.refresh_token)
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::Unauthenticated);
+    assert!(status.message().contains("reused"), "{}", status.message());
+    let status = refresh(&mut client, &third.refresh_token)
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::Unauthenticated);
+    for token in [&first.token, &second.token, &third.token] {
+        assert_eq!(list(&mut chan_client, token).await, Code::Unauthenticated);
+    }
+
+    // a new login works again
+    let again = login(&mut client).await;
+    assert_eq!(list(&mut chan_client, &again.token).await, Code::Ok);
+    refresh(&mut client, &again.refresh_token).await.unwrap();
+
+    let status = refresh(&mut client, "unknown").await.unwrap_err();
+    assert_eq!(status.code(), Code::Unauthenticated);
+
+    join_handle.abort();
+    drop(tdb);
+}
+
+#[tokio::test]
+async fn test_logout() {
+    let (config, join_handle, tdb) = init_manager_server(51455).await;
+    let conn = Endpoint::from_str(&config.server.url_with(false))
+        .unwrap()
+        .connect()
+        .await
+        .unwrap();
+    let mut client = UserServiceClient::new(conn.clone());
+    let mut chan_client = ChannelServiceClient::new(conn);
+
+    // logged in on two devices
+    let phone = register_login(&mut client).await;
+    let laptop = login(&mut client).await;
+
+    client
+        .logout(LogoutRequest {
+            refresh_token: phone.refresh_token.clone(),
+        })
+        .await
+        .unwrap();
+
+    // access tokens of all sessions are revoked, and the refresh token of the device
+    for token in [&phone.token, &laptop.token] {
+        assert_eq!(list(&mut chan_client, token).await, Code::Unauthenticated);
+    }
+    let status = refresh(&mut client, &phone.refresh_token)
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::Unauthenticated);
+
+    // the other device refreshes its access token
+    let laptop = refresh(&mut client, &laptop.refresh_token).await.unwrap();
+    assert_eq!(list(&mut chan_client, &laptop.token).await, Code::Ok);
+
+    join_handle.abort();
+    drop(tdb);
+}
+
+#[tokio::test]
+async fn test_token_expiry() {
+    let (config, join_handle, tdb) = init_manager_server_with(51456, |config| {
+        config.server.access_token_ttl = 60;
+        config.server.refresh_token_ttl = 1;
+    })
+    .await;
+    let conn = Endpoint::from_str(&config.server.url_with(false))
+        .unwrap()
+        .connect()
+        .await
+        .unwrap();
+    let mut client = UserServiceClient::new(conn.clone());
+    let mut chan_client = ChannelServiceClient::new(conn);
+
+    let rsp = register_login(&mut client).await;
+    let now = chrono::Utc::now().timestamp();
+    assert!((now + 58..=now + 60).contains(&rsp.expires_at));
+
+    // an expired access token is refused, beyond the leeway for clock skew
+    let expired = encrypt(
+        &KeySet::new(&config.server).unwrap(),
+        &AccessClaims::new("test", 0, now - 120),
+    );
+    assert_eq!(
+        list(&mut chan_client, &expired).await,
+        Code::Unauthenticated
+    );
+
+    tokio::time::sleep(std::time::Duration::from_secs(2)).await;
+    let status = refresh(&mut client, &rsp.refresh_token).await.unwrap_err();
+    assert_eq!(status.code(), Code::Unauthenticated);
+    assert!(status.message().contains("expired"), "{}", status.message());
+
+    join_handle.abort();
+    drop(tdb);
+}
@@ -0,0 +1,113 @@
+mod common;
+
+use abi::codec::SAMPLE_RATE;
+use common::server::{init_chat_server, init_manager_server};
+use echo_client::audio::memory::{MemorySink, MemorySource};
+use echo_client::audio::vad::energy_db;
+use echo_client::client::{Client, Event};
+use std::f32::consts::PI;
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tokio::time::timeout;
+
+async fn client(addr: &str, id: &str) -> Client {
+    let mut client = Client::new(addr.to_string()).await.unwrap();
+    let password = format!("{}_password", id);
+    client
+        .register(id.to_string(), password.clone(), format!("{}_name", id))
+        .await
+        .unwrap();
+    client.login(id.to_string(), password).await.unwrap();
+    client
+}
+
+// a vowel with harmonics, loud enough to be told from silence
+fn vowel(ms: usize) -> Vec<f32> {
+    (0..SAMPLE_RATE as usize * ms / 1000)
+        .map(|i| {
+            let phase = 2.0 * PI * 150.0 * i as f32 / SAMPLE_RATE as f32;
+            (1..=4)
+                .map(|k| (k as f32 * phase).sin() / k as f32)
+                .sum::<f32>()
+                * 0.2
+        })
+        .collect()
+}
+
+// wait for an event matching `f`, skipping others
+async fn wait_event(events: &mut broadcast::Receiver<Event>, f: impl Fn(&Event) -> bool) {
+    timeout(Duration::from_secs(5), async {
+        loop {
+            let event = events.recv().await.unwrap();
+            if f(&event) {
+                break;
+            }
+        }
+    })
+    .await
+    .unwrap()
+}
+
+// clients talk and hear through a chat server, without sound cards.
+#[tokio::test]
+async fn test_voice_heard() {
+    let (config, join_handle, tdb) = init_manager_server(52154).await;
+    let addr = config.server.url_with(false);
+    let mut talker = client(&addr, "talker").await;
+    let mut listener = client(&addr, "listener").await;
+    let channel = talker
+        .create_channel("voice".to_string(), 10)
+        .await
+        .unwrap();
+    let (_, handle) = init_chat_server(52155, &tdb, &addr).await;
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    let (said, source) = MemorySource::new();
+    let (sink, _) = MemorySink::new();
+    talker.set_audio(source, sink);
+    let (_, source) = MemorySource::new();
+    let (sink, mut heard) = MemorySink::new();
+    listener.set_audio(source, sink);
+
+    let (shutdown, _) = broadcast::channel(1);
+    let mut events = listener.events();
+    let rx = shutdown.subscribe();
+    let listening = tokio::spawn(async move { listener.communicate(channel.id, rx).await });
+    wait_event(&mut events, |event| matches!(event, Event::Roster { .. })).await;
+    let rx = shutdown.subscribe();
+    let talking = tokio::spawn(async move { talker.communicate(channel.id, rx).await });
+    wait_event(
+        &mut events,
+        |event| matches!(event, Event::Join { user } if user.id == "talker"),
+    )
+    .await;
+
+    // silence is not heard
+    while heard.try_recv().is_ok() {}
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    while let Ok(frame) = heard.try_recv() {
+        assert!(energy_db(&frame) < -100.0);
+    }
+
+    said.send(vowel(1000)).unwrap();
+    wait_event(
+        &mut events,
+        |event| matches!(event, Event::Speaking { user_id, speaking: true } if user_id == "talker"),
+    )
+    .await;
+    timeout(Duration::from_secs(5), async {
+        while energy_db(&heard.recv().await.unwrap()) < -30.0 {}
+    })
+    .await
+    .expect("talker is not heard");
+    wait_event(&mut events, |event| {
+        matches!(event, Event::Speaking { user_id, speaking: false } if user_id == "talker")
+    })
+    .await;
+
+    shutdown.send(()).unwrap();
+    talking.await.unwrap().unwrap();
+    listening.await.unwrap().unwrap();
+    handle.abort();
+    join_handle.abort();
+}